@@ -0,0 +1,19 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+#![no_main]
+
+use keylime::payload;
+use libfuzzer_sys::fuzz_target;
+use std::path::{Component, Path};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(actions) = payload::parse_action_list(data) {
+        // The accepted actions never refer to files outside the payload
+        for action in actions {
+            assert!(Path::new(action)
+                .components()
+                .all(|c| matches!(c, Component::Normal(_) | Component::CurDir)));
+        }
+    }
+});
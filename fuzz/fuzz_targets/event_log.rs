@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+#![no_main]
+
+use keylime::{algorithms::HashAlgorithm, event_log};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(events) = event_log::parse(data) {
+        for alg in [HashAlgorithm::Sha1, HashAlgorithm::Sha256] {
+            let _ = event_log::replay(&events, alg);
+        }
+    }
+});
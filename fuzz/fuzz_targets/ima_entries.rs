@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+#![no_main]
+
+use keylime::ima::{self, Encode};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for entry in ima::parse_entries(data).flatten() {
+        let mut buf = Vec::new();
+        let _ = entry.event_data.encode(&mut buf);
+        let _ = entry.event_data.path();
+    }
+});
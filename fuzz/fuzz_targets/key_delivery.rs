@@ -0,0 +1,21 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+#![no_main]
+
+use keylime::key_delivery::{self, KeylimeUKey, KeylimeVKey};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(ukey) = serde_json::from_slice::<KeylimeUKey>(data) {
+        let _ = key_delivery::decode_encrypted_key(&ukey.encrypted_key);
+        let _ = ukey.decode_auth_tag();
+        let _ = ukey.decode_payload();
+        let _ = ukey.session();
+        let _ = ukey.payload_delivery();
+    }
+    if let Ok(vkey) = serde_json::from_slice::<KeylimeVKey>(data) {
+        let _ = key_delivery::decode_encrypted_key(&vkey.encrypted_key);
+        let _ = vkey.session();
+    }
+});
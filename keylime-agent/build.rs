@@ -0,0 +1,15 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Generate the gRPC server from the protocol definition, using the
+    // vendored protoc so that it is not required in the build environment
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/keylime_agent.proto"], &["proto"])?;
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+use crate::{common::JsonWrapper, fips::FipsStatus, QuoteData};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct AgentInfo {
+    pub agent_uuid: String,
+    pub tpm_hash_alg: String,
+    pub tpm_enc_alg: String,
+    pub tpm_sign_alg: String,
+    pub fips: FipsStatus,
+}
+
+// This is the handler for the GET request for the agent information
+pub(crate) async fn info(
+    req: HttpRequest,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    info!(
+        "GET invoked from {:?} with uri {}",
+        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+        req.uri()
+    );
+
+    let response = JsonWrapper::success(AgentInfo {
+        agent_uuid: data.agent_uuid.clone(),
+        tpm_hash_alg: data.hash_alg.to_string(),
+        tpm_enc_alg: data.enc_alg.to_string(),
+        tpm_sign_alg: data.sign_alg.to_string(),
+        fips: data.fips,
+    });
+
+    HttpResponse::Ok().json(response)
+}
+
+#[cfg(feature = "testing")]
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use actix_web::{test, web, App};
+
+    #[actix_rt::test]
+    async fn test_agent_info() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/agent/info"),
+                web::get().to(info),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/agent/info"))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let body: JsonWrapper<AgentInfo> = test::read_body_json(resp).await;
+        assert_eq!(body.results.agent_uuid, quotedata.agent_uuid);
+        assert_eq!(body.results.tpm_hash_alg, "sha256");
+        assert!(!body.results.fips.enabled);
+    }
+}
@@ -0,0 +1,148 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Asset tag and geolocation evidence provisioned in the TPM NV storage
+//!
+//! The operator can provision an opaque blob, e.g. an asset tag or the
+//! geolocation of the machine, in a TPM NV index, optionally together with
+//! a signature over it in a second index. The blob is read at startup and
+//! sent to the registrar and in the quotes, so that the verifier policies
+//! can enforce placement rules, e.g. for data sovereignty.
+
+use crate::{
+    config::KeylimeConfig,
+    crypto,
+    error::{Error, Result},
+    serialization::{
+        deserialize_as_base64, deserialize_maybe_base64, serialize_as_base64,
+        serialize_maybe_base64,
+    },
+    tpm_sharing,
+};
+use keylime::tpm;
+use log::*;
+use openssl::{hash::MessageDigest, sign::Verifier, x509::X509};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// The asset tag read from the TPM NV storage
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct AssetTag {
+    pub nv_index: String,
+    #[serde(
+        serialize_with = "serialize_as_base64",
+        deserialize_with = "deserialize_as_base64"
+    )]
+    pub data: Vec<u8>,
+    #[serde(
+        default,
+        serialize_with = "serialize_maybe_base64",
+        deserialize_with = "deserialize_maybe_base64",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub signature: Option<Vec<u8>>,
+}
+
+impl AssetTag {
+    /// Verify the signature over the data with the public key of the
+    /// certificate, using SHA-256
+    pub(crate) fn verify(&self, cert: &X509) -> Result<bool> {
+        let Some(signature) = &self.signature else {
+            return Ok(false);
+        };
+        let key = cert.public_key()?;
+        let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+        verifier.update(&self.data)?;
+        Ok(verifier.verify(signature)?)
+    }
+}
+
+/// Read the asset tag from the NV indexes set in the configuration, if any
+pub(crate) fn read(
+    ctx: &mut tpm::Context,
+    config: &KeylimeConfig,
+) -> Result<Option<AssetTag>> {
+    let index = match config.agent.asset_tag_nv_index.as_ref() {
+        "" => return Ok(None),
+        index => tpm_sharing::parse_handle(index)?,
+    };
+    let data = ctx.read_nv(index).map_err(|e| {
+        error!(
+            "Unable to read the asset tag from NV index 0x{index:08x}: {e}"
+        );
+        Error::Tpm(e)
+    })?;
+
+    let signature = match config.agent.asset_tag_signature_nv_index.as_ref() {
+        "" => None,
+        sig_index => {
+            let sig_index = tpm_sharing::parse_handle(sig_index)?;
+            Some(ctx.read_nv(sig_index).map_err(|e| {
+                error!("Unable to read the asset tag signature from NV index 0x{sig_index:08x}: {e}");
+                Error::Tpm(e)
+            })?)
+        }
+    };
+
+    let tag = AssetTag {
+        nv_index: format!("0x{index:08x}"),
+        data,
+        signature,
+    };
+
+    if !config.agent.asset_tag_cert.is_empty() {
+        let cert =
+            crypto::load_x509(Path::new(&config.agent.asset_tag_cert))?;
+        if !tag.verify(&cert)? {
+            let message = format!(
+                "The signature of the asset tag in NV index {} does not match the certificate {}",
+                tag.nv_index, config.agent.asset_tag_cert
+            );
+            error!("{message}");
+            return Err(Error::Configuration(message));
+        }
+        info!("Verified the signature of the asset tag");
+    }
+
+    info!(
+        "Read asset tag of {} bytes from NV index {}",
+        tag.data.len(),
+        tag.nv_index
+    );
+    Ok(Some(tag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::testing::rsa_generate;
+    use openssl::sign::Signer;
+
+    #[test]
+    fn test_verify() {
+        let key = rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+
+        let data = b"country=DE;site=FRA1".to_vec();
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap(); //#[allow_ci]
+        signer.update(&data).unwrap(); //#[allow_ci]
+        let signature = signer.sign_to_vec().unwrap(); //#[allow_ci]
+
+        let mut tag = AssetTag {
+            nv_index: "0x01c10000".to_string(),
+            data,
+            signature: Some(signature),
+        };
+        assert!(tag.verify(&cert).unwrap()); //#[allow_ci]
+
+        let json = serde_json::to_value(&tag).unwrap(); //#[allow_ci]
+        let decoded: AssetTag = serde_json::from_value(json).unwrap(); //#[allow_ci]
+        assert_eq!(decoded, tag);
+
+        tag.data = b"country=US".to_vec();
+        assert!(!tag.verify(&cert).unwrap()); //#[allow_ci]
+
+        tag.signature = None;
+        assert!(!tag.verify(&cert).unwrap()); //#[allow_ci]
+    }
+}
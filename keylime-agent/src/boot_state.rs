@@ -0,0 +1,225 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Detection of changes of the boot chain across the agent restarts
+//!
+//! The digest of the measured boot log and the values of the PCRs 0 to 9,
+//! which hold the measurements of the firmware, the boot loader and the
+//! kernel, are stored with the agent data. They are identical on every boot
+//! of an unchanged machine, so a difference after a restart shows that the
+//! firmware, its configuration or the booted kernel changed, e.g. after a
+//! firmware update or a kexec. Each change increments the boot epoch, which
+//! is included in the quotes so that the verifier can correlate attestation
+//! failures with the changes of the boot chain.
+
+use crate::{
+    events::{AgentEvent, CloudEvent},
+    http_client::{self, HttpClient},
+    Result,
+};
+use keylime::{algorithms::HashAlgorithm, tpm};
+use log::*;
+use openssl::hash::{hash, MessageDigest};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The PCRs measuring the boot chain, 0 to 9
+pub(crate) const BOOT_PCRS_MASK: u32 = 0x3ff;
+
+/// The boot chain state compared across the agent restarts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BootState {
+    /// Number of changes of the boot chain seen by the agent
+    pub epoch: u64,
+    /// SHA-256 digest of the measured boot log, if available
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_digest: Option<String>,
+    pub pcr_bank: String,
+    /// Hex encoded values of the boot PCRs, by index
+    pub pcrs: BTreeMap<u32, String>,
+}
+
+/// A change of the boot chain since the previous agent run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BootChange {
+    pub epoch: u64,
+    pub log_changed: bool,
+    pub pcrs: Vec<u32>,
+}
+
+impl BootChange {
+    pub(crate) fn event(&self) -> AgentEvent {
+        AgentEvent::BootChanged {
+            epoch: self.epoch,
+            log_changed: self.log_changed,
+            pcrs: self.pcrs.clone(),
+        }
+    }
+}
+
+impl BootState {
+    /// Read the current state, starting at epoch 0
+    pub(crate) fn read(
+        ctx: &mut tpm::Context,
+        pcr_bank: HashAlgorithm,
+        mb_log: Option<&[u8]>,
+    ) -> Result<Self> {
+        let pcrs = ctx
+            .read_pcrs(BOOT_PCRS_MASK, pcr_bank)?
+            .into_iter()
+            .map(|(pcr, value)| (pcr, hex::encode(value)))
+            .collect();
+        let log_digest = match mb_log {
+            Some(log) => {
+                Some(hex::encode(hash(MessageDigest::sha256(), log)?))
+            }
+            None => None,
+        };
+
+        Ok(BootState {
+            epoch: 0,
+            log_digest,
+            pcr_bank: pcr_bank.to_string(),
+            pcrs,
+        })
+    }
+
+    /// Carry the epoch over from the previous state, incrementing it if the
+    /// boot chain changed. Returns the change, if any.
+    pub(crate) fn follow(
+        &mut self,
+        previous: &BootState,
+    ) -> Option<BootChange> {
+        // The log may not be readable on every run, e.g. due to permissions
+        let log_changed = match (&self.log_digest, &previous.log_digest) {
+            (Some(current), Some(previous)) => current != previous,
+            _ => false,
+        };
+        // The values of a different bank can not be compared
+        let pcrs: Vec<u32> = match self.pcr_bank == previous.pcr_bank {
+            true => self
+                .pcrs
+                .iter()
+                .filter(|(pcr, value)| previous.pcrs.get(pcr) != Some(value))
+                .map(|(pcr, _)| *pcr)
+                .collect(),
+            false => Vec::new(),
+        };
+
+        if !log_changed && pcrs.is_empty() {
+            self.epoch = previous.epoch;
+            return None;
+        }
+        self.epoch = previous.epoch + 1;
+        Some(BootChange {
+            epoch: self.epoch,
+            log_changed,
+            pcrs,
+        })
+    }
+}
+
+/// Record the change prominently, as the verifier may now fail to validate
+/// the measured boot log against its policy
+pub(crate) fn report(change: &BootChange) {
+    let pcrs = change
+        .pcrs
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+    warn!(
+        "The boot chain changed since the previous run (boot epoch {}): measured boot log {}, PCRs changed: [{pcrs}]",
+        change.epoch,
+        match change.log_changed {
+            true => "changed",
+            false => "unchanged",
+        }
+    );
+}
+
+/// Send the event of the change to the verifier, so that it does not have
+/// to wait for the next failed attestation to learn about it
+pub(crate) async fn notify(
+    client: HttpClient,
+    url: String,
+    event: CloudEvent,
+) {
+    let body = match serde_json::to_vec(&event) {
+        Ok(body) => body,
+        Err(e) => {
+            warn!("Unable to serialize the boot change event: {e}");
+            return;
+        }
+    };
+    let result = client
+        .retry(&url, || async {
+            let response = client
+                .request(reqwest::Method::POST, &url)
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    crate::events::CLOUDEVENTS_CONTENT_TYPE,
+                )
+                .body(body.clone())
+                .send()
+                .await?;
+            http_client::check_status(&url, response.status().as_u16())
+        })
+        .await;
+    match result {
+        Ok(()) => info!("Notified {url} of the boot chain change"),
+        Err(e) => {
+            warn!("Failed to notify {url} of the boot chain change: {e}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(log: &str, pcr0: &str) -> BootState {
+        BootState {
+            epoch: 0,
+            log_digest: Some(log.to_string()),
+            pcr_bank: "sha256".to_string(),
+            pcrs: BTreeMap::from([
+                (0, pcr0.to_string()),
+                (7, "77".to_string()),
+            ]),
+        }
+    }
+
+    #[test]
+    fn test_follow() {
+        let mut previous = state("log", "00");
+        previous.epoch = 3;
+
+        // Restarted or rebooted without changes
+        let mut current = state("log", "00");
+        assert_eq!(current.follow(&previous), None);
+        assert_eq!(current.epoch, 3);
+
+        // Firmware update
+        let mut current = state("log2", "01");
+        assert_eq!(
+            current.follow(&previous),
+            Some(BootChange {
+                epoch: 4,
+                log_changed: true,
+                pcrs: vec![0],
+            })
+        );
+        assert_eq!(current.epoch, 4);
+
+        // The log could not be read, only the PCRs are compared
+        let mut current = state("log2", "00");
+        current.log_digest = None;
+        assert_eq!(current.follow(&previous), None);
+
+        // A different bank is not compared
+        let mut current = state("log", "01");
+        current.pcr_bank = "sha384".to_string();
+        assert_eq!(current.follow(&previous), None);
+    }
+}
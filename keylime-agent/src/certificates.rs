@@ -0,0 +1,384 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Inventory of the certificates the agent relies on
+//!
+//! The agent trusts or presents several certificates: its own server
+//! certificate, the CA certificates of the verifier and tenant, the
+//! certificate used to check the signature of the revocation messages and
+//! the CA certificates of the registrar. An expired certificate only shows
+//! up when it is used, which for the revocation certificate means a missed
+//! revocation. The inventory lists them with their expiry dates and is
+//! checked periodically to warn ahead of the expiry.
+
+use crate::{
+    clock::{self, SharedClock, Validity},
+    common::JsonWrapper,
+    crypto,
+};
+use actix_web::{web, HttpResponse, Responder};
+use log::*;
+use openssl::{asn1::Asn1Time, nid::Nid, x509::X509};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc::Receiver;
+
+/// The role of a certificate in the agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CertificateKind {
+    Server,
+    KeylimeCa,
+    Revocation,
+    RegistrarCa,
+}
+
+impl fmt::Display for CertificateKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CertificateKind::Server => write!(f, "server"),
+            CertificateKind::KeylimeCa => write!(f, "keylime_ca"),
+            CertificateKind::Revocation => write!(f, "revocation"),
+            CertificateKind::RegistrarCa => write!(f, "registrar_ca"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CertificateStatus {
+    Valid,
+    Expiring,
+    Expired,
+    NotYetValid,
+    Unavailable,
+}
+
+/// A certificate listed in the inventory
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CertificateInfo {
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub not_after: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub days_remaining: Option<i64>,
+    pub status: CertificateStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The result of a check of all the certificates
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CertificateReport {
+    pub certificates: Vec<CertificateInfo>,
+    /// Number of days before the expiry from which a certificate is
+    /// reported as expiring
+    pub warning_days: u32,
+    /// The days remaining until the first certificate expires, negative if
+    /// a certificate is expired already
+    pub min_days_remaining: Option<i64>,
+}
+
+#[derive(Debug)]
+enum Source {
+    /// Certificates held in memory for the lifetime of the agent
+    Loaded(Vec<X509>),
+    /// Certificates read from the file on every check, as the file can be
+    /// replaced while the agent is running
+    File(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) struct Inventory {
+    entries: Vec<(CertificateKind, Source)>,
+    warning_days: u32,
+    clock: SharedClock,
+}
+
+#[derive(Debug)]
+pub(crate) enum CertificatesMessage {
+    Shutdown,
+}
+
+/// The number of whole days from now to the expiry of the certificate
+fn days_remaining(cert: &X509, now: &Asn1Time) -> Option<i64> {
+    now.diff(cert.not_after()).ok().map(|d| d.days.into())
+}
+
+fn subject(cert: &X509) -> Option<String> {
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|e| e.data().as_utf8().ok())
+        .map(|s| s.to_string())
+}
+
+impl Inventory {
+    pub(crate) fn new(clock: SharedClock, warning_days: u32) -> Self {
+        Inventory {
+            entries: Vec::new(),
+            warning_days,
+            clock,
+        }
+    }
+
+    pub(crate) fn add_loaded(&mut self, kind: CertificateKind, cert: X509) {
+        self.entries.push((kind, Source::Loaded(vec![cert])));
+    }
+
+    pub(crate) fn add_file(
+        &mut self,
+        kind: CertificateKind,
+        path: impl AsRef<Path>,
+    ) {
+        self.entries
+            .push((kind, Source::File(path.as_ref().to_path_buf())));
+    }
+
+    fn info(
+        &self,
+        kind: CertificateKind,
+        path: Option<&Path>,
+        cert: &X509,
+        now: &Asn1Time,
+    ) -> CertificateInfo {
+        let days = days_remaining(cert, now);
+        let status = match clock::validity(cert, self.clock.as_ref()) {
+            Ok(Validity::Expired) => CertificateStatus::Expired,
+            Ok(Validity::NotYetValid) => CertificateStatus::NotYetValid,
+            Ok(Validity::Valid)
+                if days.is_some_and(|d| d < self.warning_days.into()) =>
+            {
+                CertificateStatus::Expiring
+            }
+            Ok(Validity::Valid) => CertificateStatus::Valid,
+            Err(_) => CertificateStatus::Unavailable,
+        };
+        CertificateInfo {
+            kind: kind.to_string(),
+            path: path.map(|p| p.display().to_string()),
+            subject: subject(cert),
+            not_before: Some(cert.not_before().to_string()),
+            not_after: Some(cert.not_after().to_string()),
+            days_remaining: days,
+            status,
+            error: None,
+        }
+    }
+
+    /// Check the expiry of all the certificates
+    pub(crate) fn check(&self) -> CertificateReport {
+        let now = Asn1Time::from_unix(self.clock.now().timestamp()).ok();
+        let mut certificates = Vec::new();
+
+        for (kind, source) in &self.entries {
+            let (path, loaded) = match source {
+                Source::Loaded(certs) => (None, Ok(certs.clone())),
+                Source::File(path) => {
+                    (Some(path.as_path()), crypto::load_x509_cert_chain(path))
+                }
+            };
+            match (loaded, &now) {
+                (Ok(certs), Some(now)) if !certs.is_empty() => {
+                    for cert in &certs {
+                        certificates.push(self.info(*kind, path, cert, now));
+                    }
+                }
+                (result, _) => certificates.push(CertificateInfo {
+                    kind: kind.to_string(),
+                    path: path.map(|p| p.display().to_string()),
+                    subject: None,
+                    not_before: None,
+                    not_after: None,
+                    days_remaining: None,
+                    status: CertificateStatus::Unavailable,
+                    error: Some(match result {
+                        Err(e) => e.to_string(),
+                        Ok(_) if now.is_none() => {
+                            "unable to read the clock".to_string()
+                        }
+                        Ok(_) => "no certificate found".to_string(),
+                    }),
+                }),
+            }
+        }
+
+        let min_days_remaining =
+            certificates.iter().filter_map(|c| c.days_remaining).min();
+
+        CertificateReport {
+            certificates,
+            warning_days: self.warning_days,
+            min_days_remaining,
+        }
+    }
+}
+
+/// Log the certificates which are expired, about to expire or can not be
+/// read
+pub(crate) fn report(report: &CertificateReport) {
+    for cert in &report.certificates {
+        let name = match (&cert.subject, &cert.path) {
+            (Some(subject), _) => {
+                format!("{} certificate '{subject}'", cert.kind)
+            }
+            (None, Some(path)) => format!("{} certificate {path}", cert.kind),
+            (None, None) => format!("{} certificate", cert.kind),
+        };
+        let not_after = cert.not_after.as_deref().unwrap_or_default();
+        match cert.status {
+            CertificateStatus::Valid => {
+                debug!("The {name} expires on {not_after}")
+            }
+            CertificateStatus::Expiring => warn!(
+                "The {name} expires in {} days, on {not_after}",
+                cert.days_remaining.unwrap_or_default()
+            ),
+            CertificateStatus::Expired => {
+                error!("The {name} expired on {not_after}")
+            }
+            CertificateStatus::NotYetValid => warn!(
+                "The {name} is not valid before {}",
+                cert.not_before.as_deref().unwrap_or_default()
+            ),
+            CertificateStatus::Unavailable => error!(
+                "Unable to check the {name}: {}",
+                cert.error.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+}
+
+/// Periodically check the expiry of the certificates
+pub(crate) async fn worker(
+    inventory: web::Data<Inventory>,
+    interval: Duration,
+    mut certificates_rx: Receiver<CertificatesMessage>,
+) -> crate::Result<()> {
+    debug!("Starting certificates worker");
+
+    // The certificates were checked at startup, skip the immediate tick
+    let mut ticker = tokio::time::interval(interval);
+    _ = ticker.tick().await;
+    loop {
+        tokio::select! {
+            message = certificates_rx.recv() => {
+                match message {
+                    Some(CertificatesMessage::Shutdown) | None => {
+                        certificates_rx.close();
+                        break;
+                    }
+                }
+            }
+            _ = ticker.tick() => report(&inventory.check()),
+        }
+    }
+
+    debug!("Shutting down certificates worker");
+    Ok(())
+}
+
+/// Handles the requests for the inventory of the certificates
+pub(crate) async fn inventory(
+    inventory: web::Data<Inventory>,
+) -> impl Responder {
+    HttpResponse::Ok().json(JsonWrapper::success(inventory.check()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::Clock;
+    use chrono::{DateTime, TimeZone, Utc};
+    use openssl::{
+        asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa,
+        x509::X509NameBuilder,
+    };
+    use std::sync::Arc;
+
+    #[derive(Debug)]
+    struct FixedClock(i64);
+
+    impl Clock for FixedClock {
+        fn now(&self) -> DateTime<Utc> {
+            Utc.timestamp_opt(self.0, 0).unwrap() //#[allow_ci]
+        }
+    }
+
+    const NOW: i64 = 1_700_000_000;
+    const DAY: i64 = 86400;
+
+    fn cert(name: &str, not_after: i64) -> X509 {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(); //#[allow_ci]
+        let mut subject = X509NameBuilder::new().unwrap(); //#[allow_ci]
+        subject.append_entry_by_nid(Nid::COMMONNAME, name).unwrap(); //#[allow_ci]
+        let subject = subject.build();
+        let mut builder = X509::builder().unwrap(); //#[allow_ci]
+        builder.set_subject_name(&subject).unwrap(); //#[allow_ci]
+        builder.set_issuer_name(&subject).unwrap(); //#[allow_ci]
+        builder.set_pubkey(&key).unwrap(); //#[allow_ci]
+        builder
+            .set_not_before(&Asn1Time::from_unix(NOW - 100 * DAY).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .set_not_after(&Asn1Time::from_unix(not_after).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder.sign(&key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        builder.build()
+    }
+
+    #[test]
+    fn test_check() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let revocation = dir.path().join("revocation.crt");
+        crypto::write_x509(&cert("revocation", NOW - DAY), &revocation)
+            .unwrap(); //#[allow_ci]
+
+        let mut inventory = Inventory::new(Arc::new(FixedClock(NOW)), 30);
+        inventory.add_loaded(
+            CertificateKind::Server,
+            cert("server", NOW + 365 * DAY),
+        );
+        inventory.add_loaded(
+            CertificateKind::KeylimeCa,
+            cert("ca", NOW + 10 * DAY + 1),
+        );
+        inventory.add_file(CertificateKind::Revocation, &revocation);
+        inventory.add_file(
+            CertificateKind::RegistrarCa,
+            dir.path().join("missing.crt"),
+        );
+
+        let report = inventory.check();
+        let status: Vec<_> =
+            report.certificates.iter().map(|c| c.status).collect();
+        assert_eq!(
+            status,
+            [
+                CertificateStatus::Valid,
+                CertificateStatus::Expiring,
+                CertificateStatus::Expired,
+                CertificateStatus::Unavailable,
+            ]
+        );
+        assert_eq!(report.certificates[0].days_remaining, Some(365));
+        assert_eq!(report.certificates[0].subject.as_deref(), Some("server"));
+        assert_eq!(report.certificates[1].days_remaining, Some(10));
+        assert_eq!(report.certificates[2].kind, "revocation");
+        assert_eq!(
+            report.certificates[2].path.as_deref(),
+            Some(revocation.display().to_string().as_str())
+        );
+        assert!(report.certificates[3].error.is_some());
+        assert_eq!(report.min_days_remaining, Some(-1));
+    }
+}
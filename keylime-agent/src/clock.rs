@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Source of the wall clock time used by the agent
+//!
+//! The timestamps of the signed responses, the events and the evidence, and
+//! the validity checks of the certificates all read the time from a
+//! [`Clock`], so that the tests can run at a fixed time.
+//!
+//! Devices without a working RTC may boot with a clock set to the epoch or
+//! to the firmware build date. The TLS handshakes then fail because the
+//! certificates are not yet valid, which is reported by OpenSSL without any
+//! mention of the clock. The checks here detect such a skew and warn about
+//! it explicitly.
+
+use chrono::{DateTime, SecondsFormat, TimeZone, Utc};
+use log::*;
+use openssl::{asn1::Asn1Time, x509::X509Ref};
+use std::{error::Error as StdError, fmt::Debug, sync::Arc};
+
+/// No clock set correctly reads a time before the release of this agent
+const MIN_PLAUSIBLE_TIME: i64 = 1_672_531_200; // 2023-01-01T00:00:00Z
+
+pub(crate) trait Clock: Debug + Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Seconds since the UNIX epoch, 0 if the clock is before the epoch
+    fn unix_time(&self) -> u64 {
+        self.now().timestamp().try_into().unwrap_or(0)
+    }
+
+    fn rfc3339(&self, format: SecondsFormat) -> String {
+        self.now().to_rfc3339_opts(format, true)
+    }
+}
+
+pub(crate) type SharedClock = Arc<dyn Clock>;
+
+/// The system wall clock
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+pub(crate) fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// Whether the clock reads a time which cannot be right, as on devices
+/// booting without a working RTC
+pub(crate) fn is_implausible(clock: &dyn Clock) -> bool {
+    clock.now().timestamp() < MIN_PLAUSIBLE_TIME
+}
+
+/// Warn if the clock cannot be right. Returns whether it was implausible.
+pub(crate) fn check_plausible(clock: &dyn Clock) -> bool {
+    if is_implausible(clock) {
+        warn!(
+            "The system clock reads {}, which is before {}: the clock is likely not set (missing or dead RTC?). TLS certificate validation will fail until the clock is synchronized",
+            clock.rfc3339(SecondsFormat::Secs),
+            Utc.timestamp_opt(MIN_PLAUSIBLE_TIME, 0)
+                .unwrap() //#[allow_ci]
+                .to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        return true;
+    }
+    false
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Validity {
+    Valid,
+    NotYetValid,
+    Expired,
+}
+
+/// The validity of the certificate at the time of the clock
+pub(crate) fn validity(
+    cert: &X509Ref,
+    clock: &dyn Clock,
+) -> Result<Validity, openssl::error::ErrorStack> {
+    let now = Asn1Time::from_unix(clock.now().timestamp())?;
+    if cert.not_before() > now {
+        Ok(Validity::NotYetValid)
+    } else if cert.not_after() < now {
+        Ok(Validity::Expired)
+    } else {
+        Ok(Validity::Valid)
+    }
+}
+
+/// Warn if the certificate is not valid at the time of the clock, pointing
+/// at the clock when the certificate is not valid yet. Returns whether the
+/// certificate is valid.
+pub(crate) fn check_certificate(
+    cert: &X509Ref,
+    description: &str,
+    clock: &dyn Clock,
+) -> bool {
+    match validity(cert, clock) {
+        Ok(Validity::Valid) => true,
+        Ok(Validity::NotYetValid) => {
+            warn!(
+                "The {description} is not valid before {}, but the system clock reads {}: the clock may be skewed",
+                cert.not_before(),
+                clock.rfc3339(SecondsFormat::Secs)
+            );
+            false
+        }
+        Ok(Validity::Expired) => {
+            warn!(
+                "The {description} expired on {} (system clock reads {})",
+                cert.not_after(),
+                clock.rfc3339(SecondsFormat::Secs)
+            );
+            false
+        }
+        Err(e) => {
+            warn!("Unable to check the validity of the {description}: {e}");
+            false
+        }
+    }
+}
+
+/// Whether the error, or any of its sources, is a certificate validation
+/// failure due to the validity period of the certificate
+pub(crate) fn is_time_validation_error(
+    err: &(dyn StdError + 'static),
+) -> bool {
+    let mut source = Some(err);
+    while let Some(e) = source {
+        let message = e.to_string();
+        if message.contains("certificate is not yet valid")
+            || message.contains("certificate has expired")
+        {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Warn about the clock if the connection failed because a certificate was
+/// not valid at the time of the clock
+pub(crate) fn warn_on_skew(
+    err: &(dyn StdError + 'static),
+    peer: &str,
+    clock: &dyn Clock,
+) {
+    if is_time_validation_error(err) {
+        warn!(
+            "TLS validation of {peer} failed due to the certificate validity period while the system clock reads {}: check that the clock is synchronized with {peer}",
+            clock.rfc3339(SecondsFormat::Secs)
+        );
+    }
+}
+
+/// Clock at a time set by the tests
+#[cfg(test)]
+#[derive(Debug)]
+pub(crate) struct FixedClock(std::sync::Mutex<DateTime<Utc>>);
+
+#[cfg(test)]
+impl FixedClock {
+    pub(crate) fn at(unix_time: i64) -> Self {
+        FixedClock(std::sync::Mutex::new(
+            Utc.timestamp_opt(unix_time, 0).unwrap(), //#[allow_ci]
+        ))
+    }
+
+    pub(crate) fn advance(&self, duration: chrono::Duration) {
+        let mut now = self.0.lock().unwrap(); //#[allow_ci]
+        *now += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap() //#[allow_ci]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto;
+
+    #[test]
+    fn test_fixed_clock() {
+        let clock = FixedClock::at(1_700_000_000);
+        assert_eq!(clock.unix_time(), 1_700_000_000);
+        assert_eq!(
+            clock.rfc3339(SecondsFormat::Secs),
+            "2023-11-14T22:13:20Z"
+        );
+        clock.advance(chrono::Duration::seconds(10));
+        assert_eq!(clock.unix_time(), 1_700_000_010);
+        assert!(!is_implausible(&clock));
+
+        assert!(is_implausible(&FixedClock::at(0)));
+        assert_eq!(FixedClock::at(-1).unix_time(), 0);
+    }
+
+    #[test]
+    fn test_validity() {
+        let key = crypto::testing::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let cert = crypto::generate_x509(&key, "uuid").unwrap(); //#[allow_ci]
+        let now = SystemClock.now().timestamp();
+
+        assert_eq!(
+            validity(&cert, &SystemClock).unwrap(), //#[allow_ci]
+            Validity::Valid
+        );
+        assert_eq!(
+            validity(&cert, &FixedClock::at(0)).unwrap(), //#[allow_ci]
+            Validity::NotYetValid
+        );
+        assert_eq!(
+            validity(&cert, &FixedClock::at(now + 400 * 86400)).unwrap(), //#[allow_ci]
+            Validity::Expired
+        );
+        assert!(!check_certificate(&cert, "test", &FixedClock::at(0)));
+    }
+
+    #[test]
+    fn test_time_validation_error() {
+        #[derive(Debug, thiserror::Error)]
+        #[error("error trying to connect")]
+        struct Outer(#[source] std::io::Error);
+
+        let inner = std::io::Error::other(
+            "certificate verify failed: (certificate is not yet valid)",
+        );
+        assert!(is_time_validation_error(&Outer(inner)));
+        let inner = std::io::Error::other(
+            "certificate verify failed: (self-signed certificate)",
+        );
+        assert!(!is_time_validation_error(&Outer(inner)));
+    }
+}
@@ -3,6 +3,9 @@
 
 use crate::error::{Error, Result};
 use crate::permissions;
+use crate::{
+    boot_state::BootState, kernel_boot::KernelBoot, tpm_state::TpmState,
+};
 use keylime::algorithms::{
     EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
 };
@@ -36,16 +39,65 @@ use tss_esapi::{
  * Constants and static variables
  */
 pub const API_VERSION: &str = "v2.1";
+/// The latest API version, serving the endpoints and fields added since
+/// the older versions
+pub const LATEST_API_VERSION: &str = "v2.2";
+/// The API versions served by the agent, from the oldest to the latest
+pub const SUPPORTED_API_VERSIONS: [&str; 2] =
+    [API_VERSION, LATEST_API_VERSION];
+/// The API version adding the local appraisal of the measurement list
+pub const LOCAL_APPRAISAL_API_VERSION: &str = "v2.2";
+/// The API version adding the evidence in the EAT format
+pub const EAT_API_VERSION: &str = "v2.2";
+/// The API version adding the certification of TPM keys
+pub const CERTIFY_API_VERSION: &str = "v2.2";
+/// The API version adding the statistics of the connections
+pub const CONNECTIONS_API_VERSION: &str = "v2.2";
+/// The API version adding the ranged measured boot log
+pub const MEASURED_BOOT_API_VERSION: &str = "v2.2";
+/// The API version adding the list and wipe of the delivered keys
+pub const DELIVERED_KEYS_API_VERSION: &str = "v2.2";
+/// The API version adding the information about the agent
+pub const AGENT_INFO_API_VERSION: &str = "v2.2";
+/// The API version adding the inventory of the certificates
+pub const CERTIFICATES_API_VERSION: &str = "v2.2";
+/// The API version adding the delivery of both key halves in one request
+pub const KEYS_BATCH_API_VERSION: &str = "v2.2";
+/// The API version adding the watch list of files
+pub const FILE_WATCH_API_VERSION: &str = "v2.2";
+/// The API version adding the relay of the quotes of nested agents
+pub const RELAY_API_VERSION: &str = "v2.2";
+/// The API version adding the forecast of the PCR values after a reboot
+pub const PCR_FORECAST_API_VERSION: &str = "v2.2";
+/// The API version adding the fields of the quotes since v2.1, like the
+/// agent nonce, the boot information and the continuity information
+pub const QUOTE_FIELDS_API_VERSION: &str = "v2.2";
 pub const TPM_DATA_PCR: usize = 16;
 pub const IMA_PCR: usize = 10;
 pub static RSA_PUBLICKEY_EXPORTABLE: &str = "rsa placeholder";
 pub static KEY: &str = "secret";
 pub const AGENT_UUID_LEN: usize = 36;
-pub const AUTH_TAG_LEN: usize = 48;
 pub const AES_128_KEY_LEN: usize = 16;
 pub const AES_256_KEY_LEN: usize = 32;
 pub const AES_BLOCK_SIZE: usize = 16;
 
+/// Whether the API version provides the features added in the minimum
+/// version, both being supported versions
+pub(crate) fn api_version_at_least(version: &str, minimum: &str) -> bool {
+    let position = |v| SUPPORTED_API_VERSIONS.iter().position(|s| *s == v);
+    position(version) >= position(minimum)
+}
+
+/// The supported API version in the first segment of the request path, if
+/// any
+pub(crate) fn request_api_version(path: &str) -> Option<&'static str> {
+    let version = path.trim_start_matches('/').split('/').next()?;
+    SUPPORTED_API_VERSIONS
+        .iter()
+        .find(|supported| **supported == version)
+        .copied()
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub(crate) struct APIVersion {
     major: u32,
@@ -117,6 +169,18 @@ impl SymmKey {
         }
         Ok(Self { bytes: outbuf })
     }
+
+    /// Overwrite the key with zeros before discarding it
+    pub(crate) fn zeroize(&mut self) {
+        for byte in self.bytes.iter_mut() {
+            // Volatile writes are not optimized away as dead stores
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+        std::sync::atomic::compiler_fence(
+            std::sync::atomic::Ordering::SeqCst,
+        );
+        self.bytes.clear();
+    }
 }
 
 impl AsRef<[u8]> for SymmKey {
@@ -140,27 +204,69 @@ impl TryFrom<&[u8]> for SymmKey {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// The hash algorithms accepted for the auth tag HMAC, SHA-384 first as the
+/// algorithm used by the tenant unless configured otherwise
+pub const AUTH_TAG_ALGORITHMS: [HashAlgorithm; 3] = [
+    HashAlgorithm::Sha384,
+    HashAlgorithm::Sha256,
+    HashAlgorithm::Sha512,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct AuthTag {
+    alg: HashAlgorithm,
     bytes: Vec<u8>,
 }
 
+impl AuthTag {
+    /// Create an auth tag computed using HMAC with the given hash algorithm
+    pub fn new(
+        alg: HashAlgorithm,
+        v: &[u8],
+    ) -> std::result::Result<Self, String> {
+        if !AUTH_TAG_ALGORITHMS.contains(&alg) {
+            return Err(format!(
+                "hash algorithm {alg} is not supported for the auth tag"
+            ));
+        }
+        let len = MessageDigest::from(alg).size();
+        if v.len() != len {
+            return Err(format!(
+                "auth tag length {} does not correspond to valid {} HMAC",
+                v.len(),
+                alg.to_string().to_uppercase(),
+            ));
+        }
+        Ok(AuthTag {
+            alg,
+            bytes: v.to_vec(),
+        })
+    }
+
+    pub fn alg(&self) -> HashAlgorithm {
+        self.alg
+    }
+}
+
 impl AsRef<[u8]> for AuthTag {
     fn as_ref(&self) -> &[u8] {
         self.bytes.as_slice()
     }
 }
 
+// The algorithm is implied by the tag length when not given explicitly
 impl TryFrom<&[u8]> for AuthTag {
     type Error = String;
 
     fn try_from(v: &[u8]) -> std::result::Result<Self, Self::Error> {
-        match v.len() {
-            AUTH_TAG_LEN => {
-                Ok(AuthTag { bytes: v.to_vec() })
-            }
-            other => Err(format!(
-                "auth tag length {other} does not correspond to valid SHA-384 HMAC",
+        match AUTH_TAG_ALGORITHMS
+            .iter()
+            .find(|alg| MessageDigest::from(**alg).size() == v.len())
+        {
+            Some(alg) => AuthTag::new(*alg, v),
+            None => Err(format!(
+                "auth tag length {} does not correspond to valid SHA-256, SHA-384 or SHA-512 HMAC",
+                v.len()
             )),
         }
     }
@@ -189,6 +295,17 @@ impl From<Vec<u8>> for EncryptedData {
     }
 }
 
+// EK certificate cached to avoid reading it from the TPM NVRAM on every
+// start. The certificate is only valid for the EK public key it was read with.
+// Only a certificate actually read is cached; a missing certificate, which
+// may be due to a transient error or provisioned later, is read again on the
+// next start.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct EKCertCache {
+    ek_public: Vec<u8>,
+    ek_cert: Vec<u8>,
+}
+
 // TPM data and agent related that can be persisted and loaded on agent startup.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct AgentData {
@@ -197,6 +314,17 @@ pub(crate) struct AgentData {
     ak_public: Vec<u8>,
     ak_private: Vec<u8>,
     ek_hash: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ek_cert_cache: Option<EKCertCache>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tpm_state: Option<TpmState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_state: Option<BootState>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_boot: Option<KernelBoot>,
+    /// Number of times the agent was started with this agent data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_starts: Option<u64>,
 }
 
 impl AgentData {
@@ -215,6 +343,11 @@ impl AgentData {
             ak_public,
             ak_private,
             ek_hash,
+            ek_cert_cache: None,
+            tpm_state: None,
+            boot_state: None,
+            kernel_boot: None,
+            agent_starts: None,
         })
     }
 
@@ -230,6 +363,13 @@ impl AgentData {
         Ok(())
     }
 
+    /// Replace the AK, keeping the EK information
+    pub(crate) fn set_ak(&mut self, ak: &tpm::AKResult) -> Result<()> {
+        self.ak_public = ak.public.marshall()?;
+        self.ak_private = ak.private.to_vec();
+        Ok(())
+    }
+
     pub(crate) fn get_ak(&self) -> Result<tpm::AKResult> {
         let public = Public::unmarshall(&self.ak_public)?;
         let private = Private::try_from(self.ak_private.clone())?;
@@ -247,6 +387,44 @@ impl AgentData {
             && sign_alg == self.ak_sign_alg
             && ek_hash.to_vec() == self.ek_hash
     }
+
+    /// Get the cached EK certificate for the given EK public key.
+    ///
+    /// Returns None if there is no cached certificate or if it was cached for
+    /// a different EK, meaning the certificate has to be read from the TPM.
+    pub(crate) fn cached_ek_cert(
+        &self,
+        ek_public: &Public,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(cache) = &self.ek_cert_cache else {
+            return Ok(None);
+        };
+
+        if cache.ek_public != ek_public.marshall()? {
+            debug!("Discarding EK certificate cached for a different EK");
+            return Ok(None);
+        }
+
+        Ok(Some(cache.ek_cert.clone()))
+    }
+
+    /// Cache the EK certificate read for the given EK public key. Nothing is
+    /// cached if no certificate was read, so that it is read again on the
+    /// next start.
+    pub(crate) fn cache_ek_cert(
+        &mut self,
+        ek_public: &Public,
+        ek_cert: Option<Vec<u8>>,
+    ) -> Result<()> {
+        self.ek_cert_cache = match ek_cert {
+            Some(ek_cert) => Some(EKCertCache {
+                ek_public: ek_public.marshall()?,
+                ek_cert,
+            }),
+            None => None,
+        };
+        Ok(())
+    }
 }
 
 /// Calculate the SHA-256 hash of the TPM public key in PEM format
@@ -281,6 +459,41 @@ mod tests {
         Context,
     };
 
+    #[test]
+    fn test_auth_tag() {
+        let tag = AuthTag::try_from(&[0u8; 32][..]).unwrap(); //#[allow_ci]
+        assert_eq!(tag.alg(), HashAlgorithm::Sha256);
+        let tag = AuthTag::try_from(&[0u8; 48][..]).unwrap(); //#[allow_ci]
+        assert_eq!(tag.alg(), HashAlgorithm::Sha384);
+        assert!(AuthTag::try_from(&[0u8; 20][..]).is_err());
+
+        assert!(AuthTag::new(HashAlgorithm::Sha512, &[0u8; 64]).is_ok());
+        assert!(AuthTag::new(HashAlgorithm::Sha512, &[0u8; 48]).is_err());
+        assert!(AuthTag::new(HashAlgorithm::Sha1, &[0u8; 20]).is_err());
+    }
+
+    #[test]
+    fn test_api_version_at_least() {
+        assert!(api_version_at_least("v2.2", EAT_API_VERSION));
+        assert!(!api_version_at_least("v2.1", EAT_API_VERSION));
+        assert!(api_version_at_least("v2.1", API_VERSION));
+    }
+
+    #[test]
+    fn test_request_api_version() {
+        assert_eq!(
+            request_api_version("/v2.2/quotes/identity"),
+            Some("v2.2")
+        );
+        assert_eq!(
+            request_api_version("/v2.1/quotes/integrity"),
+            Some("v2.1")
+        );
+        assert_eq!(request_api_version("/v1.0/quotes/identity"), None);
+        assert_eq!(request_api_version("/version"), None);
+        assert_eq!(request_api_version(""), None);
+    }
+
     #[cfg(feature = "testing")]
     #[test]
     fn test_agent_data() -> Result<()> {
@@ -330,6 +543,74 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_agent_data_without_cache() {
+        // Agent data stored by previous versions does not contain the EK
+        // certificate cache
+        let data: AgentData = serde_json::from_str(
+            r#"{
+                "ak_hash_alg": "Sha256",
+                "ak_sign_alg": "RsaSsa",
+                "ak_public": [],
+                "ak_private": [],
+                "ek_hash": []
+            }"#,
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(data.ek_cert_cache.is_none());
+        assert!(data.agent_starts.is_none());
+
+        let serialized = serde_json::to_string(&data).unwrap(); //#[allow_ci]
+        assert!(!serialized.contains("ek_cert_cache"));
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_agent_data_ek_cert_cache() -> Result<()> {
+        let config = KeylimeConfig::default();
+
+        let mut ctx = tpm::Context::new()?;
+
+        let tpm_encryption_alg = EncryptionAlgorithm::try_from(
+            config.agent.tpm_encryption_alg.as_str(),
+        )?;
+        let tpm_hash_alg =
+            HashAlgorithm::try_from(config.agent.tpm_hash_alg.as_str())?;
+        let tpm_signing_alg =
+            SignAlgorithm::try_from(config.agent.tpm_signing_alg.as_str())?;
+
+        let ek_result = ctx.create_ek_object(tpm_encryption_alg, None)?;
+        let ek_hash = hash_ek_pubkey(ek_result.public.clone())?;
+        let ak = ctx.create_ak(
+            ek_result.key_handle,
+            tpm_hash_alg,
+            tpm_signing_alg,
+        )?;
+
+        let mut data = AgentData::create(
+            tpm_hash_alg,
+            tpm_signing_alg,
+            &ak,
+            ek_hash.as_bytes(),
+        )?;
+        assert_eq!(data.cached_ek_cert(&ek_result.public)?, None);
+
+        data.cache_ek_cert(&ek_result.public, Some(vec![1, 2, 3]))?;
+        assert_eq!(
+            data.cached_ek_cert(&ek_result.public)?,
+            Some(vec![1, 2, 3])
+        );
+
+        // The cache is not used for a different key
+        assert_eq!(data.cached_ek_cert(&ak.public)?, None);
+
+        // A missing certificate is not cached
+        data.cache_ek_cert(&ek_result.public, None)?;
+        assert!(data.ek_cert_cache.is_none());
+        assert_eq!(data.cached_ek_cert(&ek_result.public)?, None);
+        Ok(())
+    }
+
     #[cfg(feature = "testing")]
     #[test]
     fn test_hash() -> Result<()> {
@@ -1,7 +1,10 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2022 Keylime Authors
 
-use crate::{error::Error, permissions, tpm};
+use crate::{
+    error::Error, http_client, peer_tls, permissions, resources, tpm,
+    tpm_sharing,
+};
 use config::{
     builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment,
     File, FileFormat, Map, Source, Value,
@@ -10,6 +13,7 @@ use glob::glob;
 use keylime::{
     algorithms::{EncryptionAlgorithm, HashAlgorithm, SignAlgorithm},
     list_parser::parse_list,
+    payload::PayloadDelivery,
 };
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -17,7 +21,6 @@ use std::{
     env,
     path::{Path, PathBuf},
 };
-use uuid::Uuid;
 
 pub static CONFIG_VERSION: &str = "2.0";
 pub static DEFAULT_UUID: &str = "d432fbb3-d2f1-4a97-9ef7-75bd81c00000";
@@ -67,6 +70,87 @@ pub static DEFAULT_IMA_ML_PATH: &str =
     "/sys/kernel/security/ima/ascii_runtime_measurements";
 pub static DEFAULT_MEASUREDBOOT_ML_PATH: &str =
     "/sys/kernel/security/tpm0/binary_boot_measurements";
+pub static DEFAULT_MEMORY_BUDGET: &str = "0";
+pub static DEFAULT_RLIMIT_NOFILE: u64 = 0;
+pub static DEFAULT_RLIMIT_AS: &str = "0";
+pub static DEFAULT_ENABLE_LOCAL_APPRAISAL: bool = false;
+pub static DEFAULT_LOCAL_APPRAISAL_DB: &str = "local_appraisal.db";
+pub static DEFAULT_LOCAL_APPRAISAL_INTERVAL: u32 = 60;
+pub static DEFAULT_ENABLE_GRPC: bool = false;
+pub static DEFAULT_GRPC_PORT: u32 = 9003;
+pub static DEFAULT_CLOUDEVENTS_SINK: &str = "";
+pub static DEFAULT_CLOUDEVENTS_AUTH_TOKEN: &str = "";
+pub static DEFAULT_SPIRE_ATTESTATION_PATH: &str = "";
+pub static DEFAULT_ATTESTATION_STATUS_PATH: &str = "";
+pub static DEFAULT_KUBERNETES_NODE_NAME: &str = "";
+pub static DEFAULT_VERIFIER_IDENTITIES: &str = "";
+pub static DEFAULT_AK_REACTIVATION_ATTEMPTS: u32 = 1;
+pub static DEFAULT_ENABLE_KEY_CERTIFICATION: bool = false;
+pub static DEFAULT_LUKS_DEVICE: &str = "";
+pub static DEFAULT_LUKS_NAME: &str = "keylime";
+pub static DEFAULT_LUKS_ENROLL_KEY_FILE: &str = "";
+pub static DEFAULT_PAYLOAD_HOOK: &str = "";
+pub static DEFAULT_TPM_STATE_CHECK_INTERVAL: u32 = 300;
+pub static DEFAULT_HTTP_WORKERS: u32 = 0;
+pub static DEFAULT_HTTP_CLIENT_REQUEST_TIMEOUT: u64 = 5000;
+pub static DEFAULT_HTTP_KEEP_ALIVE: u64 = 5;
+pub static DEFAULT_HTTP_MAX_CONNECTIONS: u32 = 256;
+pub static DEFAULT_TLS_HANDSHAKE_TIMEOUT: u64 = 3000;
+pub static DEFAULT_AUTH_TAG_ALGORITHMS: &str = "sha384, sha256, sha512";
+pub static DEFAULT_UUID_PATH: &str = "agent_uuid";
+pub static DEFAULT_ADDITIONAL_AGENTS_DIR: &str = "";
+pub static DEFAULT_SELF_ATTESTATION_INTERVAL: u32 = 0;
+pub static DEFAULT_ENABLE_RESPONSE_SIGNING: bool = false;
+pub static DEFAULT_ENABLE_H2C: bool = false;
+pub static DEFAULT_QUOTE_ALLOWED_PCRS: &str = "";
+pub static DEFAULT_QUOTE_PCR_BANKS: &str = "";
+pub static DEFAULT_CERT_DIR: &str = "certs";
+pub static DEFAULT_TPMDATA_DIR: &str = "tpmdata";
+pub static DEFAULT_SECURE_DIR: &str = "secure";
+pub static DEFAULT_AUDIT_DIR: &str = "audit";
+pub static DEFAULT_SECURE_MOUNT_CONTEXT: &str = "";
+pub static DEFAULT_WORK_DIR_CONTEXT: &str = "";
+pub static DEFAULT_PAYLOAD_APPARMOR_PROFILE: &str = "";
+pub static DEFAULT_PAYLOAD_DELIVERY: &str = "files";
+pub static DEFAULT_PAYLOAD_HANDOFF_SOCKET: &str = "";
+pub static DEFAULT_PAYLOAD_HANDOFF_PEER_UID: &str = "";
+pub static DEFAULT_KEY_WIPE_PCR: &str = "";
+pub static DEFAULT_REGISTRAR_TLS: &str = "disabled";
+pub static DEFAULT_REGISTRAR_TLS_CA: &str = "";
+pub static DEFAULT_REGISTRAR_TLS_PINS: &str = "";
+pub static DEFAULT_REGISTRAR_TLS_SERVER_NAME: &str = "";
+pub static DEFAULT_FIPS_MODE: bool = false;
+pub static DEFAULT_CERT_EXPIRY_WARNING_DAYS: u32 = 30;
+pub static DEFAULT_CERT_EXPIRY_CHECK_INTERVAL: u32 = 86400;
+pub static DEFAULT_OUTBOUND_TIMEOUT: u64 = 30000;
+pub static DEFAULT_OUTBOUND_CONNECT_TIMEOUT: u64 = 5000;
+pub static DEFAULT_OUTBOUND_RETRIES: u32 = 3;
+pub static DEFAULT_OUTBOUND_RETRY_DELAY: u64 = 500;
+pub static DEFAULT_OUTBOUND_MAX_RETRY_DELAY: u64 = 30000;
+pub static DEFAULT_OUTBOUND_PROXY: &str = "";
+pub static DEFAULT_BOOT_CHANGE_NOTIFY_URL: &str = "";
+pub static DEFAULT_REFUSE_QUOTES_AFTER_KEXEC: bool = false;
+pub static DEFAULT_TPM_LOCK_PATH: &str = "";
+pub static DEFAULT_TPM_PERSISTENT_HANDLES: &str = "";
+pub static DEFAULT_TPM_REQUIRE_RESOURCE_MANAGER: bool = false;
+pub static DEFAULT_ASSET_TAG_NV_INDEX: &str = "";
+pub static DEFAULT_ASSET_TAG_SIGNATURE_NV_INDEX: &str = "";
+pub static DEFAULT_ASSET_TAG_CERT: &str = "";
+pub static DEFAULT_KEY_TTL: u64 = 0;
+pub static DEFAULT_ENABLE_AGENT_NONCE: bool = false;
+pub static DEFAULT_SEALED_CONFIG_PCRS: &str = "0,2,4,7";
+pub static DEFAULT_ENABLE_FILE_WATCH: bool = false;
+pub static DEFAULT_FILE_WATCH_PCR: &str = "";
+pub static DEFAULT_RELAY_GUESTS: &str = "";
+pub static DEFAULT_RELAY_GUEST_TLS: &str = "disabled";
+pub static DEFAULT_RELAY_GUEST_TLS_CA: &str = "";
+pub static DEFAULT_RELAY_GUEST_TLS_PINS: &str = "";
+pub static DEFAULT_ENABLE_PCR_FORECAST: bool = false;
+pub static DEFAULT_HASH_BACKEND: &str = "openssl";
+pub static DEFAULT_EVENT_HOOK: &str = "";
+pub static DEFAULT_EVENT_HOOK_EVENTS: &str =
+    "registered,attestation.served,payload.executed,revocation.received";
+pub static DEFAULT_EVENT_HOOK_TIMEOUT: u64 = 5;
 pub static DEFAULT_CONFIG: &str = "/etc/keylime/agent.conf";
 pub static DEFAULT_CONFIG_SYS: &str = "/usr/etc/keylime/agent.conf";
 
@@ -114,6 +198,86 @@ pub(crate) struct EnvConfig {
     pub agent_data_path: Option<String>,
     pub ima_ml_path: Option<String>,
     pub measuredboot_ml_path: Option<String>,
+    pub memory_budget: Option<String>,
+    pub rlimit_nofile: Option<u64>,
+    pub rlimit_as: Option<String>,
+    pub enable_local_appraisal: Option<bool>,
+    pub local_appraisal_db: Option<String>,
+    pub local_appraisal_interval: Option<u32>,
+    pub enable_grpc: Option<bool>,
+    pub grpc_port: Option<u32>,
+    pub cloudevents_sink: Option<String>,
+    pub cloudevents_auth_token: Option<String>,
+    pub spire_attestation_path: Option<String>,
+    pub attestation_status_path: Option<String>,
+    pub kubernetes_node_name: Option<String>,
+    pub verifier_identities: Option<String>,
+    pub ak_reactivation_attempts: Option<u32>,
+    pub enable_key_certification: Option<bool>,
+    pub luks_device: Option<String>,
+    pub luks_name: Option<String>,
+    pub luks_enroll_key_file: Option<String>,
+    pub payload_hook: Option<String>,
+    pub tpm_state_check_interval: Option<u32>,
+    pub http_workers: Option<u32>,
+    pub http_client_request_timeout: Option<u64>,
+    pub http_keep_alive: Option<u64>,
+    pub http_max_connections: Option<u32>,
+    pub tls_handshake_timeout: Option<u64>,
+    pub auth_tag_algorithms: Option<String>,
+    pub uuid_path: Option<String>,
+    pub additional_agents_dir: Option<String>,
+    pub self_attestation_interval: Option<u32>,
+    pub enable_response_signing: Option<bool>,
+    pub enable_h2c: Option<bool>,
+    pub quote_allowed_pcrs: Option<String>,
+    pub quote_pcr_banks: Option<String>,
+    pub cert_dir: Option<String>,
+    pub tpmdata_dir: Option<String>,
+    pub secure_dir: Option<String>,
+    pub audit_dir: Option<String>,
+    pub secure_mount_context: Option<String>,
+    pub work_dir_context: Option<String>,
+    pub payload_apparmor_profile: Option<String>,
+    pub payload_delivery: Option<String>,
+    pub payload_handoff_socket: Option<String>,
+    pub payload_handoff_peer_uid: Option<String>,
+    pub key_wipe_pcr: Option<String>,
+    pub registrar_tls: Option<String>,
+    pub registrar_tls_ca: Option<String>,
+    pub registrar_tls_pins: Option<String>,
+    pub registrar_tls_server_name: Option<String>,
+    pub fips_mode: Option<bool>,
+    pub cert_expiry_warning_days: Option<u32>,
+    pub cert_expiry_check_interval: Option<u32>,
+    pub outbound_timeout: Option<u64>,
+    pub outbound_connect_timeout: Option<u64>,
+    pub outbound_retries: Option<u32>,
+    pub outbound_retry_delay: Option<u64>,
+    pub outbound_max_retry_delay: Option<u64>,
+    pub outbound_proxy: Option<String>,
+    pub boot_change_notify_url: Option<String>,
+    pub refuse_quotes_after_kexec: Option<bool>,
+    pub tpm_lock_path: Option<String>,
+    pub tpm_persistent_handles: Option<String>,
+    pub tpm_require_resource_manager: Option<bool>,
+    pub asset_tag_nv_index: Option<String>,
+    pub asset_tag_signature_nv_index: Option<String>,
+    pub asset_tag_cert: Option<String>,
+    pub key_ttl: Option<u64>,
+    pub enable_agent_nonce: Option<bool>,
+    pub sealed_config_pcrs: Option<String>,
+    pub enable_file_watch: Option<bool>,
+    pub file_watch_pcr: Option<String>,
+    pub relay_guests: Option<String>,
+    pub relay_guest_tls: Option<String>,
+    pub relay_guest_tls_ca: Option<String>,
+    pub relay_guest_tls_pins: Option<String>,
+    pub enable_pcr_forecast: Option<bool>,
+    pub hash_backend: Option<String>,
+    pub event_hook: Option<String>,
+    pub event_hook_events: Option<String>,
+    pub event_hook_timeout: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
@@ -160,6 +324,86 @@ pub(crate) struct AgentConfig {
     pub agent_data_path: String,
     pub ima_ml_path: String,
     pub measuredboot_ml_path: String,
+    pub memory_budget: String,
+    pub rlimit_nofile: u64,
+    pub rlimit_as: String,
+    pub enable_local_appraisal: bool,
+    pub local_appraisal_db: String,
+    pub local_appraisal_interval: u32,
+    pub enable_grpc: bool,
+    pub grpc_port: u32,
+    pub cloudevents_sink: String,
+    pub cloudevents_auth_token: String,
+    pub spire_attestation_path: String,
+    pub attestation_status_path: String,
+    pub kubernetes_node_name: String,
+    pub verifier_identities: String,
+    pub ak_reactivation_attempts: u32,
+    pub enable_key_certification: bool,
+    pub luks_device: String,
+    pub luks_name: String,
+    pub luks_enroll_key_file: String,
+    pub payload_hook: String,
+    pub tpm_state_check_interval: u32,
+    pub http_workers: u32,
+    pub http_client_request_timeout: u64,
+    pub http_keep_alive: u64,
+    pub http_max_connections: u32,
+    pub tls_handshake_timeout: u64,
+    pub auth_tag_algorithms: String,
+    pub uuid_path: String,
+    pub additional_agents_dir: String,
+    pub self_attestation_interval: u32,
+    pub enable_response_signing: bool,
+    pub enable_h2c: bool,
+    pub quote_allowed_pcrs: String,
+    pub quote_pcr_banks: String,
+    pub cert_dir: String,
+    pub tpmdata_dir: String,
+    pub secure_dir: String,
+    pub audit_dir: String,
+    pub secure_mount_context: String,
+    pub work_dir_context: String,
+    pub payload_apparmor_profile: String,
+    pub payload_delivery: String,
+    pub payload_handoff_socket: String,
+    pub payload_handoff_peer_uid: String,
+    pub key_wipe_pcr: String,
+    pub registrar_tls: String,
+    pub registrar_tls_ca: String,
+    pub registrar_tls_pins: String,
+    pub registrar_tls_server_name: String,
+    pub fips_mode: bool,
+    pub cert_expiry_warning_days: u32,
+    pub cert_expiry_check_interval: u32,
+    pub outbound_timeout: u64,
+    pub outbound_connect_timeout: u64,
+    pub outbound_retries: u32,
+    pub outbound_retry_delay: u64,
+    pub outbound_max_retry_delay: u64,
+    pub outbound_proxy: String,
+    pub boot_change_notify_url: String,
+    pub refuse_quotes_after_kexec: bool,
+    pub tpm_lock_path: String,
+    pub tpm_persistent_handles: String,
+    pub tpm_require_resource_manager: bool,
+    pub asset_tag_nv_index: String,
+    pub asset_tag_signature_nv_index: String,
+    pub asset_tag_cert: String,
+    pub key_ttl: u64,
+    pub enable_agent_nonce: bool,
+    pub sealed_config_pcrs: String,
+    pub enable_file_watch: bool,
+    pub file_watch_pcr: String,
+    pub relay_guests: String,
+    pub relay_guest_tls: String,
+    pub relay_guest_tls_ca: String,
+    pub relay_guest_tls_pins: String,
+    pub enable_pcr_forecast: bool,
+    pub hash_backend: String,
+    pub event_hook: String,
+    pub event_hook_events: String,
+    pub event_hook_timeout: u64,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
@@ -343,6 +587,346 @@ impl EnvConfig {
                 v.to_string().into(),
             );
         }
+        if let Some(ref v) = self.memory_budget {
+            _ = agent
+                .insert("memory_budget".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.rlimit_nofile {
+            _ = agent.insert("rlimit_nofile".to_string(), v.into());
+        }
+        if let Some(ref v) = self.rlimit_as {
+            _ = agent.insert("rlimit_as".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.enable_local_appraisal {
+            _ = agent.insert("enable_local_appraisal".to_string(), v.into());
+        }
+        if let Some(ref v) = self.local_appraisal_db {
+            _ = agent.insert(
+                "local_appraisal_db".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.local_appraisal_interval {
+            _ = agent
+                .insert("local_appraisal_interval".to_string(), v.into());
+        }
+        if let Some(v) = self.enable_grpc {
+            _ = agent.insert("enable_grpc".to_string(), v.into());
+        }
+        if let Some(v) = self.grpc_port {
+            _ = agent.insert("grpc_port".to_string(), v.into());
+        }
+        if let Some(ref v) = self.cloudevents_sink {
+            _ = agent
+                .insert("cloudevents_sink".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.cloudevents_auth_token {
+            _ = agent.insert(
+                "cloudevents_auth_token".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.spire_attestation_path {
+            _ = agent.insert(
+                "spire_attestation_path".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.attestation_status_path {
+            _ = agent.insert(
+                "attestation_status_path".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.kubernetes_node_name {
+            _ = agent.insert(
+                "kubernetes_node_name".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.verifier_identities {
+            _ = agent.insert(
+                "verifier_identities".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.ak_reactivation_attempts {
+            _ = agent
+                .insert("ak_reactivation_attempts".to_string(), v.into());
+        }
+        if let Some(v) = self.enable_key_certification {
+            _ = agent
+                .insert("enable_key_certification".to_string(), v.into());
+        }
+        if let Some(ref v) = self.luks_device {
+            _ = agent.insert("luks_device".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.luks_name {
+            _ = agent.insert("luks_name".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.luks_enroll_key_file {
+            _ = agent.insert(
+                "luks_enroll_key_file".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.payload_hook {
+            _ = agent
+                .insert("payload_hook".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.tpm_state_check_interval {
+            _ = agent
+                .insert("tpm_state_check_interval".to_string(), v.into());
+        }
+        if let Some(v) = self.http_workers {
+            _ = agent.insert("http_workers".to_string(), v.into());
+        }
+        if let Some(v) = self.http_client_request_timeout {
+            _ = agent
+                .insert("http_client_request_timeout".to_string(), v.into());
+        }
+        if let Some(v) = self.http_keep_alive {
+            _ = agent.insert("http_keep_alive".to_string(), v.into());
+        }
+        if let Some(v) = self.http_max_connections {
+            _ = agent.insert("http_max_connections".to_string(), v.into());
+        }
+        if let Some(v) = self.tls_handshake_timeout {
+            _ = agent.insert("tls_handshake_timeout".to_string(), v.into());
+        }
+        if let Some(ref v) = self.auth_tag_algorithms {
+            _ = agent.insert(
+                "auth_tag_algorithms".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.uuid_path {
+            _ = agent.insert("uuid_path".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.additional_agents_dir {
+            _ = agent.insert(
+                "additional_agents_dir".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.self_attestation_interval {
+            _ = agent
+                .insert("self_attestation_interval".to_string(), v.into());
+        }
+        if let Some(v) = self.enable_response_signing {
+            _ = agent.insert("enable_response_signing".to_string(), v.into());
+        }
+        if let Some(v) = self.enable_h2c {
+            _ = agent.insert("enable_h2c".to_string(), v.into());
+        }
+        if let Some(ref v) = self.quote_allowed_pcrs {
+            _ = agent.insert(
+                "quote_allowed_pcrs".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.quote_pcr_banks {
+            _ = agent
+                .insert("quote_pcr_banks".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.cert_dir {
+            _ = agent.insert("cert_dir".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.tpmdata_dir {
+            _ = agent.insert("tpmdata_dir".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.secure_dir {
+            _ = agent.insert("secure_dir".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.audit_dir {
+            _ = agent.insert("audit_dir".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.secure_mount_context {
+            _ = agent.insert(
+                "secure_mount_context".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.work_dir_context {
+            _ = agent
+                .insert("work_dir_context".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.payload_apparmor_profile {
+            _ = agent.insert(
+                "payload_apparmor_profile".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.payload_delivery {
+            _ = agent
+                .insert("payload_delivery".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.payload_handoff_socket {
+            _ = agent.insert(
+                "payload_handoff_socket".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.payload_handoff_peer_uid {
+            _ = agent.insert(
+                "payload_handoff_peer_uid".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.key_wipe_pcr {
+            _ = agent
+                .insert("key_wipe_pcr".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.registrar_tls {
+            _ = agent
+                .insert("registrar_tls".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.registrar_tls_ca {
+            _ = agent
+                .insert("registrar_tls_ca".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.registrar_tls_pins {
+            _ = agent.insert(
+                "registrar_tls_pins".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.registrar_tls_server_name {
+            _ = agent.insert(
+                "registrar_tls_server_name".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.fips_mode {
+            _ = agent.insert("fips_mode".to_string(), v.into());
+        }
+        if let Some(v) = self.cert_expiry_warning_days {
+            _ = agent
+                .insert("cert_expiry_warning_days".to_string(), v.into());
+        }
+        if let Some(v) = self.cert_expiry_check_interval {
+            _ = agent
+                .insert("cert_expiry_check_interval".to_string(), v.into());
+        }
+        if let Some(v) = self.outbound_timeout {
+            _ = agent.insert("outbound_timeout".to_string(), v.into());
+        }
+        if let Some(v) = self.outbound_connect_timeout {
+            _ = agent
+                .insert("outbound_connect_timeout".to_string(), v.into());
+        }
+        if let Some(v) = self.outbound_retries {
+            _ = agent.insert("outbound_retries".to_string(), v.into());
+        }
+        if let Some(v) = self.outbound_retry_delay {
+            _ = agent.insert("outbound_retry_delay".to_string(), v.into());
+        }
+        if let Some(v) = self.outbound_max_retry_delay {
+            _ = agent
+                .insert("outbound_max_retry_delay".to_string(), v.into());
+        }
+        if let Some(ref v) = self.outbound_proxy {
+            _ = agent
+                .insert("outbound_proxy".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.boot_change_notify_url {
+            _ = agent.insert(
+                "boot_change_notify_url".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.refuse_quotes_after_kexec {
+            _ = agent
+                .insert("refuse_quotes_after_kexec".to_string(), v.into());
+        }
+        if let Some(ref v) = self.tpm_lock_path {
+            _ = agent
+                .insert("tpm_lock_path".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.tpm_persistent_handles {
+            _ = agent.insert(
+                "tpm_persistent_handles".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.tpm_require_resource_manager {
+            _ = agent
+                .insert("tpm_require_resource_manager".to_string(), v.into());
+        }
+        if let Some(ref v) = self.asset_tag_nv_index {
+            _ = agent.insert(
+                "asset_tag_nv_index".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.asset_tag_signature_nv_index {
+            _ = agent.insert(
+                "asset_tag_signature_nv_index".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.asset_tag_cert {
+            _ = agent
+                .insert("asset_tag_cert".to_string(), v.to_string().into());
+        }
+        if let Some(v) = self.key_ttl {
+            _ = agent.insert("key_ttl".to_string(), v.into());
+        }
+        if let Some(v) = self.enable_agent_nonce {
+            _ = agent.insert("enable_agent_nonce".to_string(), v.into());
+        }
+        if let Some(ref v) = self.sealed_config_pcrs {
+            _ = agent.insert(
+                "sealed_config_pcrs".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.enable_file_watch {
+            _ = agent.insert("enable_file_watch".to_string(), v.into());
+        }
+        if let Some(ref v) = self.file_watch_pcr {
+            _ = agent
+                .insert("file_watch_pcr".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.relay_guests {
+            _ = agent
+                .insert("relay_guests".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.relay_guest_tls {
+            _ = agent
+                .insert("relay_guest_tls".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.relay_guest_tls_ca {
+            _ = agent.insert(
+                "relay_guest_tls_ca".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(ref v) = self.relay_guest_tls_pins {
+            _ = agent.insert(
+                "relay_guest_tls_pins".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.enable_pcr_forecast {
+            _ = agent.insert("enable_pcr_forecast".to_string(), v.into());
+        }
+        if let Some(ref v) = self.hash_backend {
+            _ = agent
+                .insert("hash_backend".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.event_hook {
+            _ = agent.insert("event_hook".to_string(), v.to_string().into());
+        }
+        if let Some(ref v) = self.event_hook_events {
+            _ = agent.insert(
+                "event_hook_events".to_string(),
+                v.to_string().into(),
+            );
+        }
+        if let Some(v) = self.event_hook_timeout {
+            _ = agent.insert("event_hook_timeout".to_string(), v.into());
+        }
         agent
     }
 
@@ -360,6 +944,77 @@ impl KeylimeConfig {
         // Replace keywords with actual values
         config_translate_keywords(&config)
     }
+
+    /// Load the configuration of the additional logical agents served by
+    /// this process. Each file in the 'additional_agents_dir' directory
+    /// overrides options of the main configuration for one agent.
+    pub fn additional_agents(&self) -> Result<Vec<Self>, Error> {
+        if self.agent.additional_agents_dir.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let pattern = Path::new(&self.agent.additional_agents_dir)
+            .join("*.conf")
+            .display()
+            .to_string();
+        let mut paths = glob(&pattern)
+            .map_err(Error::GlobPattern)?
+            .filter_map(|entry| entry.ok())
+            .collect::<Vec<_>>();
+        paths.sort();
+
+        let mut agents = Vec::new();
+        for path in paths {
+            debug!(
+                "Loading additional agent configuration from {}",
+                path.display()
+            );
+            let setting = config_get_setting()?
+                .add_source(
+                    File::new(&path.display().to_string(), FileFormat::Toml)
+                        .required(true),
+                )
+                .build()?;
+            let mut config: KeylimeConfig = setting.try_deserialize()?;
+            config.agent.additional_agents_dir = String::new();
+            agents.push(config_translate_keywords(&config)?);
+        }
+
+        validate_additional_agents(self, &agents)?;
+        Ok(agents)
+    }
+}
+
+/// The logical agents can not share their working directory or ports
+fn validate_additional_agents(
+    main: &KeylimeConfig,
+    agents: &[KeylimeConfig],
+) -> Result<(), Error> {
+    let all = std::iter::once(main).chain(agents).collect::<Vec<_>>();
+    for (i, a) in all.iter().enumerate() {
+        for b in &all[i + 1..] {
+            let conflict = if a.agent.keylime_dir == b.agent.keylime_dir {
+                Some(format!("keylime_dir {}", a.agent.keylime_dir))
+            } else if a.agent.port == b.agent.port {
+                Some(format!("port {}", a.agent.port))
+            } else if a.agent.enable_grpc
+                && b.agent.enable_grpc
+                && a.agent.grpc_port == b.agent.grpc_port
+            {
+                Some(format!("grpc_port {}", a.agent.grpc_port))
+            } else {
+                None
+            };
+
+            if let Some(conflict) = conflict {
+                return Err(Error::Configuration(format!(
+                    "The agents {} and {} are configured with the same {conflict}",
+                    a.agent.uuid, b.agent.uuid
+                )));
+            }
+        }
+    }
+    Ok(())
 }
 
 impl Source for EnvConfig {
@@ -541,6 +1196,312 @@ impl Source for KeylimeConfig {
             self.agent.measuredboot_ml_path.to_string().into(),
         );
 
+        _ = m.insert(
+            "memory_budget".to_string(),
+            self.agent.memory_budget.to_string().into(),
+        );
+        _ = m.insert(
+            "rlimit_nofile".to_string(),
+            self.agent.rlimit_nofile.into(),
+        );
+        _ = m.insert(
+            "rlimit_as".to_string(),
+            self.agent.rlimit_as.to_string().into(),
+        );
+        _ = m.insert(
+            "enable_local_appraisal".to_string(),
+            self.agent.enable_local_appraisal.into(),
+        );
+        _ = m.insert(
+            "local_appraisal_db".to_string(),
+            self.agent.local_appraisal_db.to_string().into(),
+        );
+        _ = m.insert(
+            "local_appraisal_interval".to_string(),
+            self.agent.local_appraisal_interval.into(),
+        );
+        _ = m
+            .insert("enable_grpc".to_string(), self.agent.enable_grpc.into());
+        _ = m.insert("grpc_port".to_string(), self.agent.grpc_port.into());
+        _ = m.insert(
+            "cloudevents_sink".to_string(),
+            self.agent.cloudevents_sink.to_string().into(),
+        );
+        _ = m.insert(
+            "cloudevents_auth_token".to_string(),
+            self.agent.cloudevents_auth_token.to_string().into(),
+        );
+        _ = m.insert(
+            "spire_attestation_path".to_string(),
+            self.agent.spire_attestation_path.to_string().into(),
+        );
+        _ = m.insert(
+            "attestation_status_path".to_string(),
+            self.agent.attestation_status_path.to_string().into(),
+        );
+        _ = m.insert(
+            "kubernetes_node_name".to_string(),
+            self.agent.kubernetes_node_name.to_string().into(),
+        );
+        _ = m.insert(
+            "verifier_identities".to_string(),
+            self.agent.verifier_identities.to_string().into(),
+        );
+        _ = m.insert(
+            "ak_reactivation_attempts".to_string(),
+            self.agent.ak_reactivation_attempts.into(),
+        );
+        _ = m.insert(
+            "enable_key_certification".to_string(),
+            self.agent.enable_key_certification.into(),
+        );
+        _ = m.insert(
+            "luks_device".to_string(),
+            self.agent.luks_device.to_string().into(),
+        );
+        _ = m.insert(
+            "luks_name".to_string(),
+            self.agent.luks_name.to_string().into(),
+        );
+        _ = m.insert(
+            "luks_enroll_key_file".to_string(),
+            self.agent.luks_enroll_key_file.to_string().into(),
+        );
+        _ = m.insert(
+            "payload_hook".to_string(),
+            self.agent.payload_hook.to_string().into(),
+        );
+        _ = m.insert(
+            "tpm_state_check_interval".to_string(),
+            self.agent.tpm_state_check_interval.into(),
+        );
+        _ = m.insert(
+            "http_workers".to_string(),
+            self.agent.http_workers.into(),
+        );
+        _ = m.insert(
+            "http_client_request_timeout".to_string(),
+            self.agent.http_client_request_timeout.into(),
+        );
+        _ = m.insert(
+            "http_keep_alive".to_string(),
+            self.agent.http_keep_alive.into(),
+        );
+        _ = m.insert(
+            "http_max_connections".to_string(),
+            self.agent.http_max_connections.into(),
+        );
+        _ = m.insert(
+            "tls_handshake_timeout".to_string(),
+            self.agent.tls_handshake_timeout.into(),
+        );
+        _ = m.insert(
+            "auth_tag_algorithms".to_string(),
+            self.agent.auth_tag_algorithms.to_string().into(),
+        );
+        _ = m.insert(
+            "uuid_path".to_string(),
+            self.agent.uuid_path.to_string().into(),
+        );
+        _ = m.insert(
+            "additional_agents_dir".to_string(),
+            self.agent.additional_agents_dir.to_string().into(),
+        );
+        _ = m.insert(
+            "self_attestation_interval".to_string(),
+            self.agent.self_attestation_interval.into(),
+        );
+        _ = m.insert(
+            "enable_response_signing".to_string(),
+            self.agent.enable_response_signing.into(),
+        );
+        _ = m.insert("enable_h2c".to_string(), self.agent.enable_h2c.into());
+        _ = m.insert(
+            "quote_allowed_pcrs".to_string(),
+            self.agent.quote_allowed_pcrs.to_string().into(),
+        );
+        _ = m.insert(
+            "quote_pcr_banks".to_string(),
+            self.agent.quote_pcr_banks.to_string().into(),
+        );
+        _ = m.insert(
+            "cert_dir".to_string(),
+            self.agent.cert_dir.to_string().into(),
+        );
+        _ = m.insert(
+            "tpmdata_dir".to_string(),
+            self.agent.tpmdata_dir.to_string().into(),
+        );
+        _ = m.insert(
+            "secure_dir".to_string(),
+            self.agent.secure_dir.to_string().into(),
+        );
+        _ = m.insert(
+            "audit_dir".to_string(),
+            self.agent.audit_dir.to_string().into(),
+        );
+        _ = m.insert(
+            "secure_mount_context".to_string(),
+            self.agent.secure_mount_context.to_string().into(),
+        );
+        _ = m.insert(
+            "work_dir_context".to_string(),
+            self.agent.work_dir_context.to_string().into(),
+        );
+        _ = m.insert(
+            "payload_apparmor_profile".to_string(),
+            self.agent.payload_apparmor_profile.to_string().into(),
+        );
+        _ = m.insert(
+            "payload_delivery".to_string(),
+            self.agent.payload_delivery.to_string().into(),
+        );
+        _ = m.insert(
+            "payload_handoff_socket".to_string(),
+            self.agent.payload_handoff_socket.to_string().into(),
+        );
+        _ = m.insert(
+            "payload_handoff_peer_uid".to_string(),
+            self.agent.payload_handoff_peer_uid.to_string().into(),
+        );
+        _ = m.insert(
+            "key_wipe_pcr".to_string(),
+            self.agent.key_wipe_pcr.to_string().into(),
+        );
+        _ = m.insert(
+            "registrar_tls".to_string(),
+            self.agent.registrar_tls.to_string().into(),
+        );
+        _ = m.insert(
+            "registrar_tls_ca".to_string(),
+            self.agent.registrar_tls_ca.to_string().into(),
+        );
+        _ = m.insert(
+            "registrar_tls_pins".to_string(),
+            self.agent.registrar_tls_pins.to_string().into(),
+        );
+        _ = m.insert(
+            "registrar_tls_server_name".to_string(),
+            self.agent.registrar_tls_server_name.to_string().into(),
+        );
+        _ = m.insert("fips_mode".to_string(), self.agent.fips_mode.into());
+        _ = m.insert(
+            "cert_expiry_warning_days".to_string(),
+            self.agent.cert_expiry_warning_days.into(),
+        );
+        _ = m.insert(
+            "cert_expiry_check_interval".to_string(),
+            self.agent.cert_expiry_check_interval.into(),
+        );
+        _ = m.insert(
+            "outbound_timeout".to_string(),
+            self.agent.outbound_timeout.into(),
+        );
+        _ = m.insert(
+            "outbound_connect_timeout".to_string(),
+            self.agent.outbound_connect_timeout.into(),
+        );
+        _ = m.insert(
+            "outbound_retries".to_string(),
+            self.agent.outbound_retries.into(),
+        );
+        _ = m.insert(
+            "outbound_retry_delay".to_string(),
+            self.agent.outbound_retry_delay.into(),
+        );
+        _ = m.insert(
+            "outbound_max_retry_delay".to_string(),
+            self.agent.outbound_max_retry_delay.into(),
+        );
+        _ = m.insert(
+            "outbound_proxy".to_string(),
+            self.agent.outbound_proxy.to_string().into(),
+        );
+        _ = m.insert(
+            "boot_change_notify_url".to_string(),
+            self.agent.boot_change_notify_url.to_string().into(),
+        );
+        _ = m.insert(
+            "refuse_quotes_after_kexec".to_string(),
+            self.agent.refuse_quotes_after_kexec.into(),
+        );
+        _ = m.insert(
+            "tpm_lock_path".to_string(),
+            self.agent.tpm_lock_path.to_string().into(),
+        );
+        _ = m.insert(
+            "tpm_persistent_handles".to_string(),
+            self.agent.tpm_persistent_handles.to_string().into(),
+        );
+        _ = m.insert(
+            "tpm_require_resource_manager".to_string(),
+            self.agent.tpm_require_resource_manager.into(),
+        );
+        _ = m.insert(
+            "asset_tag_nv_index".to_string(),
+            self.agent.asset_tag_nv_index.to_string().into(),
+        );
+        _ = m.insert(
+            "asset_tag_signature_nv_index".to_string(),
+            self.agent.asset_tag_signature_nv_index.to_string().into(),
+        );
+        _ = m.insert(
+            "asset_tag_cert".to_string(),
+            self.agent.asset_tag_cert.to_string().into(),
+        );
+        _ = m.insert("key_ttl".to_string(), self.agent.key_ttl.into());
+        _ = m.insert(
+            "enable_agent_nonce".to_string(),
+            self.agent.enable_agent_nonce.into(),
+        );
+        _ = m.insert(
+            "sealed_config_pcrs".to_string(),
+            self.agent.sealed_config_pcrs.to_string().into(),
+        );
+        _ = m.insert(
+            "enable_file_watch".to_string(),
+            self.agent.enable_file_watch.into(),
+        );
+        _ = m.insert(
+            "file_watch_pcr".to_string(),
+            self.agent.file_watch_pcr.to_string().into(),
+        );
+        _ = m.insert(
+            "relay_guests".to_string(),
+            self.agent.relay_guests.to_string().into(),
+        );
+        _ = m.insert(
+            "relay_guest_tls".to_string(),
+            self.agent.relay_guest_tls.to_string().into(),
+        );
+        _ = m.insert(
+            "relay_guest_tls_ca".to_string(),
+            self.agent.relay_guest_tls_ca.to_string().into(),
+        );
+        _ = m.insert(
+            "relay_guest_tls_pins".to_string(),
+            self.agent.relay_guest_tls_pins.to_string().into(),
+        );
+        _ = m.insert(
+            "enable_pcr_forecast".to_string(),
+            self.agent.enable_pcr_forecast.into(),
+        );
+        _ = m.insert(
+            "hash_backend".to_string(),
+            self.agent.hash_backend.to_string().into(),
+        );
+        _ = m.insert(
+            "event_hook".to_string(),
+            self.agent.event_hook.to_string().into(),
+        );
+        _ = m.insert(
+            "event_hook_events".to_string(),
+            self.agent.event_hook_events.to_string().into(),
+        );
+        _ = m.insert(
+            "event_hook_timeout".to_string(),
+            self.agent.event_hook_timeout.into(),
+        );
         Ok(Map::from([("agent".to_string(), m.into())]))
     }
 
@@ -607,6 +1568,97 @@ impl Default for AgentConfig {
             iak_idevid_template: DEFAULT_IAK_IDEVID_TEMPLATE.to_string(),
             ima_ml_path: "default".to_string(),
             measuredboot_ml_path: "default".to_string(),
+            memory_budget: DEFAULT_MEMORY_BUDGET.to_string(),
+            rlimit_nofile: DEFAULT_RLIMIT_NOFILE,
+            rlimit_as: DEFAULT_RLIMIT_AS.to_string(),
+            enable_local_appraisal: DEFAULT_ENABLE_LOCAL_APPRAISAL,
+            local_appraisal_db: "default".to_string(),
+            local_appraisal_interval: DEFAULT_LOCAL_APPRAISAL_INTERVAL,
+            enable_grpc: DEFAULT_ENABLE_GRPC,
+            grpc_port: DEFAULT_GRPC_PORT,
+            cloudevents_sink: DEFAULT_CLOUDEVENTS_SINK.to_string(),
+            cloudevents_auth_token: DEFAULT_CLOUDEVENTS_AUTH_TOKEN
+                .to_string(),
+            spire_attestation_path: DEFAULT_SPIRE_ATTESTATION_PATH
+                .to_string(),
+            attestation_status_path: DEFAULT_ATTESTATION_STATUS_PATH
+                .to_string(),
+            kubernetes_node_name: DEFAULT_KUBERNETES_NODE_NAME.to_string(),
+            verifier_identities: DEFAULT_VERIFIER_IDENTITIES.to_string(),
+            ak_reactivation_attempts: DEFAULT_AK_REACTIVATION_ATTEMPTS,
+            enable_key_certification: DEFAULT_ENABLE_KEY_CERTIFICATION,
+            luks_device: DEFAULT_LUKS_DEVICE.to_string(),
+            luks_name: DEFAULT_LUKS_NAME.to_string(),
+            luks_enroll_key_file: DEFAULT_LUKS_ENROLL_KEY_FILE.to_string(),
+            payload_hook: DEFAULT_PAYLOAD_HOOK.to_string(),
+            tpm_state_check_interval: DEFAULT_TPM_STATE_CHECK_INTERVAL,
+            http_workers: DEFAULT_HTTP_WORKERS,
+            http_client_request_timeout: DEFAULT_HTTP_CLIENT_REQUEST_TIMEOUT,
+            http_keep_alive: DEFAULT_HTTP_KEEP_ALIVE,
+            http_max_connections: DEFAULT_HTTP_MAX_CONNECTIONS,
+            tls_handshake_timeout: DEFAULT_TLS_HANDSHAKE_TIMEOUT,
+            auth_tag_algorithms: DEFAULT_AUTH_TAG_ALGORITHMS.to_string(),
+            uuid_path: "default".to_string(),
+            additional_agents_dir: DEFAULT_ADDITIONAL_AGENTS_DIR.to_string(),
+            self_attestation_interval: DEFAULT_SELF_ATTESTATION_INTERVAL,
+            enable_response_signing: DEFAULT_ENABLE_RESPONSE_SIGNING,
+            enable_h2c: DEFAULT_ENABLE_H2C,
+            quote_allowed_pcrs: DEFAULT_QUOTE_ALLOWED_PCRS.to_string(),
+            quote_pcr_banks: DEFAULT_QUOTE_PCR_BANKS.to_string(),
+            cert_dir: "default".to_string(),
+            tpmdata_dir: "default".to_string(),
+            secure_dir: "default".to_string(),
+            audit_dir: "default".to_string(),
+            secure_mount_context: DEFAULT_SECURE_MOUNT_CONTEXT.to_string(),
+            work_dir_context: DEFAULT_WORK_DIR_CONTEXT.to_string(),
+            payload_apparmor_profile: DEFAULT_PAYLOAD_APPARMOR_PROFILE
+                .to_string(),
+            payload_delivery: DEFAULT_PAYLOAD_DELIVERY.to_string(),
+            payload_handoff_socket: DEFAULT_PAYLOAD_HANDOFF_SOCKET
+                .to_string(),
+            payload_handoff_peer_uid: DEFAULT_PAYLOAD_HANDOFF_PEER_UID
+                .to_string(),
+            key_wipe_pcr: DEFAULT_KEY_WIPE_PCR.to_string(),
+            registrar_tls: DEFAULT_REGISTRAR_TLS.to_string(),
+            registrar_tls_ca: DEFAULT_REGISTRAR_TLS_CA.to_string(),
+            registrar_tls_pins: DEFAULT_REGISTRAR_TLS_PINS.to_string(),
+            registrar_tls_server_name: DEFAULT_REGISTRAR_TLS_SERVER_NAME
+                .to_string(),
+            fips_mode: DEFAULT_FIPS_MODE,
+            cert_expiry_warning_days: DEFAULT_CERT_EXPIRY_WARNING_DAYS,
+            cert_expiry_check_interval: DEFAULT_CERT_EXPIRY_CHECK_INTERVAL,
+            outbound_timeout: DEFAULT_OUTBOUND_TIMEOUT,
+            outbound_connect_timeout: DEFAULT_OUTBOUND_CONNECT_TIMEOUT,
+            outbound_retries: DEFAULT_OUTBOUND_RETRIES,
+            outbound_retry_delay: DEFAULT_OUTBOUND_RETRY_DELAY,
+            outbound_max_retry_delay: DEFAULT_OUTBOUND_MAX_RETRY_DELAY,
+            outbound_proxy: DEFAULT_OUTBOUND_PROXY.to_string(),
+            boot_change_notify_url: DEFAULT_BOOT_CHANGE_NOTIFY_URL
+                .to_string(),
+            refuse_quotes_after_kexec: DEFAULT_REFUSE_QUOTES_AFTER_KEXEC,
+            tpm_lock_path: DEFAULT_TPM_LOCK_PATH.to_string(),
+            tpm_persistent_handles: DEFAULT_TPM_PERSISTENT_HANDLES
+                .to_string(),
+            tpm_require_resource_manager:
+                DEFAULT_TPM_REQUIRE_RESOURCE_MANAGER,
+            asset_tag_nv_index: DEFAULT_ASSET_TAG_NV_INDEX.to_string(),
+            asset_tag_signature_nv_index:
+                DEFAULT_ASSET_TAG_SIGNATURE_NV_INDEX.to_string(),
+            asset_tag_cert: DEFAULT_ASSET_TAG_CERT.to_string(),
+            key_ttl: DEFAULT_KEY_TTL,
+            enable_agent_nonce: DEFAULT_ENABLE_AGENT_NONCE,
+            sealed_config_pcrs: DEFAULT_SEALED_CONFIG_PCRS.to_string(),
+            enable_file_watch: DEFAULT_ENABLE_FILE_WATCH,
+            file_watch_pcr: DEFAULT_FILE_WATCH_PCR.to_string(),
+            relay_guests: DEFAULT_RELAY_GUESTS.to_string(),
+            relay_guest_tls: DEFAULT_RELAY_GUEST_TLS.to_string(),
+            relay_guest_tls_ca: DEFAULT_RELAY_GUEST_TLS_CA.to_string(),
+            relay_guest_tls_pins: DEFAULT_RELAY_GUEST_TLS_PINS.to_string(),
+            enable_pcr_forecast: DEFAULT_ENABLE_PCR_FORECAST,
+            hash_backend: DEFAULT_HASH_BACKEND.to_string(),
+            event_hook: DEFAULT_EVENT_HOOK.to_string(),
+            event_hook_events: DEFAULT_EVENT_HOOK_EVENTS.to_string(),
+            event_hook_timeout: DEFAULT_EVENT_HOOK_TIMEOUT,
         }
     }
 }
@@ -705,8 +1757,6 @@ fn config_get_setting() -> Result<ConfigBuilder<DefaultState>, Error> {
 fn config_translate_keywords(
     config: &KeylimeConfig,
 ) -> Result<KeylimeConfig, Error> {
-    let uuid = get_uuid(&config.agent.uuid);
-
     let env_keylime_dir = env::var("KEYLIME_DIR").ok();
     let keylime_dir = match env_keylime_dir {
         Some(ref dir) => {
@@ -737,10 +1787,27 @@ fn config_translate_keywords(
 
     let root_path = Path::new("/");
 
-    let mut agent_data_path = config_get_file_path(
+    // The files are placed by default in a subdirectory of the keylime_dir
+    // according to the component using them
+    let [cert_dir, tpmdata_dir, secure_dir, audit_dir] = [
+        ("cert_dir", &config.agent.cert_dir, DEFAULT_CERT_DIR),
+        (
+            "tpmdata_dir",
+            &config.agent.tpmdata_dir,
+            DEFAULT_TPMDATA_DIR,
+        ),
+        ("secure_dir", &config.agent.secure_dir, DEFAULT_SECURE_DIR),
+        ("audit_dir", &config.agent.audit_dir, DEFAULT_AUDIT_DIR),
+    ]
+    .map(|(option, path, default)| {
+        config_get_file_path(option, path, keylime_dir, default)
+    });
+
+    let mut agent_data_path = config_get_default_file_path(
         "agent_data_path",
         &config.agent.agent_data_path,
         keylime_dir,
+        Path::new(&tpmdata_dir),
         DEFAULT_AGENT_DATA_PATH,
     );
 
@@ -758,17 +1825,19 @@ fn config_translate_keywords(
         DEFAULT_MEASUREDBOOT_ML_PATH,
     );
 
-    let mut server_key = config_get_file_path(
+    let mut server_key = config_get_default_file_path(
         "server_key",
         &config.agent.server_key,
         keylime_dir,
+        Path::new(&cert_dir),
         DEFAULT_SERVER_KEY,
     );
 
-    let mut server_cert = config_get_file_path(
+    let mut server_cert = config_get_default_file_path(
         "server_cert",
         &config.agent.server_cert,
         keylime_dir,
+        Path::new(&cert_dir),
         DEFAULT_SERVER_CERT,
     );
 
@@ -776,30 +1845,65 @@ fn config_translate_keywords(
         parse_list(&config.agent.trusted_client_ca)?
             .iter()
             .map(|t| {
-                config_get_file_path(
+                config_get_default_file_path(
                     "trusted_client_ca",
                     t,
                     keylime_dir,
+                    Path::new(&cert_dir),
                     DEFAULT_TRUSTED_CLIENT_CA,
                 )
             })
             .collect::<Vec<_>>()
             .join(", ");
 
-    let mut iak_cert = config_get_file_path(
+    let mut iak_cert = config_get_default_file_path(
         "iak_cert",
         &config.agent.iak_cert,
         keylime_dir,
+        Path::new(&cert_dir),
         DEFAULT_IAK_CERT,
     );
 
-    let mut idevid_cert = config_get_file_path(
+    let mut idevid_cert = config_get_default_file_path(
         "idevid_cert",
         &config.agent.idevid_cert,
         keylime_dir,
+        Path::new(&cert_dir),
         DEFAULT_IDEVID_CERT,
     );
 
+    let uuid_path = config_get_default_file_path(
+        "uuid_path",
+        &config.agent.uuid_path,
+        keylime_dir,
+        Path::new(&tpmdata_dir),
+        DEFAULT_UUID_PATH,
+    );
+
+    let mut local_appraisal_db = config_get_default_file_path(
+        "local_appraisal_db",
+        &config.agent.local_appraisal_db,
+        keylime_dir,
+        Path::new(&tpmdata_dir),
+        DEFAULT_LOCAL_APPRAISAL_DB,
+    );
+
+    // The verifier identities are optional, so an empty path is kept
+    let verifier_identities = match config.agent.verifier_identities.as_ref()
+    {
+        "" => "".to_string(),
+        path => {
+            config_get_file_path("verifier_identities", path, keylime_dir, "")
+        }
+    };
+
+    let registrar_tls_ca = match config.agent.registrar_tls_ca.as_ref() {
+        "" => "".to_string(),
+        path => {
+            config_get_file_path("registrar_tls_ca", path, keylime_dir, "")
+        }
+    };
+
     let ek_handle = match config.agent.ek_handle.as_ref() {
         "generate" => "".to_string(),
         "" => "".to_string(),
@@ -827,17 +1931,171 @@ fn config_translate_keywords(
         };
     }
 
-    let mut revocation_cert = config_get_file_path(
+    // Validate the default payload delivery mode. The socket is also needed
+    // when the mode is only requested for some payloads, so it is checked
+    // when the payload is handed off.
+    let payload_delivery =
+        PayloadDelivery::try_from(config.agent.payload_delivery.as_ref())
+            .map_err(|e| {
+                error!("Invalid option 'payload_delivery': {e}");
+                Error::Configuration(format!(
+                    "Invalid option 'payload_delivery': {e}"
+                ))
+            })?;
+    if payload_delivery != PayloadDelivery::Files
+        && config.agent.payload_handoff_socket.is_empty()
+    {
+        error!("The option 'payload_delivery' is set as '{payload_delivery}' but 'payload_handoff_socket' was set as empty");
+        return Err(Error::Configuration(format!("The option 'payload_delivery' is set as '{payload_delivery}' but 'payload_handoff_socket' was set as empty")));
+    }
+
+    if !config.agent.key_wipe_pcr.is_empty()
+        && !matches!(config.agent.key_wipe_pcr.parse::<u32>(), Ok(0..=23))
+    {
+        error!("Invalid option 'key_wipe_pcr': {} is not a PCR index between 0 and 23", config.agent.key_wipe_pcr);
+        return Err(Error::Configuration(format!("Invalid option 'key_wipe_pcr': {} is not a PCR index between 0 and 23", config.agent.key_wipe_pcr)));
+    }
+    if !config.agent.file_watch_pcr.is_empty()
+        && !matches!(config.agent.file_watch_pcr.parse::<u32>(), Ok(0..=23))
+    {
+        error!("Invalid option 'file_watch_pcr': {} is not a PCR index between 0 and 23", config.agent.file_watch_pcr);
+        return Err(Error::Configuration(format!("Invalid option 'file_watch_pcr': {} is not a PCR index between 0 and 23", config.agent.file_watch_pcr)));
+    }
+    if let Err(e) = keylime::hashing::backend(&config.agent.hash_backend) {
+        error!("Invalid option 'hash_backend': {e}");
+        return Err(Error::Configuration(format!(
+            "Invalid option 'hash_backend': {e}"
+        )));
+    }
+    if !config.agent.payload_handoff_peer_uid.is_empty()
+        && config
+            .agent
+            .payload_handoff_peer_uid
+            .parse::<u32>()
+            .is_err()
+    {
+        error!(
+            "Invalid user ID '{}' set in option 'payload_handoff_peer_uid'",
+            config.agent.payload_handoff_peer_uid
+        );
+        return Err(Error::Configuration(format!(
+            "Invalid user ID '{}' set in option 'payload_handoff_peer_uid'",
+            config.agent.payload_handoff_peer_uid
+        )));
+    }
+
+    if let Err(e) =
+        peer_tls::Verification::try_from(config.agent.registrar_tls.as_ref())
+    {
+        error!("Invalid option 'registrar_tls': {e}");
+        return Err(Error::Configuration(format!(
+            "Invalid option 'registrar_tls': {e}"
+        )));
+    }
+    if let Err(e) = peer_tls::parse_pins(&config.agent.registrar_tls_pins) {
+        error!("Invalid option 'registrar_tls_pins': {e}");
+        return Err(Error::Configuration(format!(
+            "Invalid option 'registrar_tls_pins': {e}"
+        )));
+    }
+    if let Err(e) = http_client::parse_proxy(&config.agent.outbound_proxy) {
+        error!("Invalid option 'outbound_proxy': {e}");
+        return Err(Error::Configuration(format!(
+            "Invalid option 'outbound_proxy': {e}"
+        )));
+    }
+
+    match tpm_sharing::HandleRange::parse(
+        &config.agent.tpm_persistent_handles,
+    ) {
+        Ok(range) => {
+            if !ek_handle.is_empty()
+                && !tpm_sharing::parse_handle(&ek_handle)
+                    .is_ok_and(|handle| range.contains(handle))
+            {
+                error!("The EK handle {ek_handle} is not in the range set in option 'tpm_persistent_handles' ({range})");
+                return Err(Error::Configuration(format!(
+                    "The EK handle {ek_handle} is not in the range set in option 'tpm_persistent_handles' ({range})"
+                )));
+            }
+        }
+        Err(e) => {
+            error!("Invalid option 'tpm_persistent_handles': {e}");
+            return Err(Error::Configuration(format!(
+                "Invalid option 'tpm_persistent_handles': {e}"
+            )));
+        }
+    }
+
+    for (option, value) in [
+        ("asset_tag_nv_index", &config.agent.asset_tag_nv_index),
+        (
+            "asset_tag_signature_nv_index",
+            &config.agent.asset_tag_signature_nv_index,
+        ),
+    ] {
+        if value.is_empty() {
+            continue;
+        }
+        if !tpm_sharing::parse_handle(value).is_ok_and(|h| h >> 24 == 0x01) {
+            error!("Invalid NV index '{value}' set in option '{option}'");
+            return Err(Error::Configuration(format!(
+                "Invalid NV index '{value}' set in option '{option}'"
+            )));
+        }
+    }
+    if config.agent.asset_tag_nv_index.is_empty()
+        && (!config.agent.asset_tag_signature_nv_index.is_empty()
+            || !config.agent.asset_tag_cert.is_empty())
+    {
+        error!("The asset tag signature is set, but 'asset_tag_nv_index' is empty");
+        return Err(Error::Configuration(
+            "The asset tag signature is set, but 'asset_tag_nv_index' is empty"
+                .to_string(),
+        ));
+    }
+
+    // Validate the memory sizes used for resource limits
+    for (option, value) in [
+        ("memory_budget", &config.agent.memory_budget),
+        ("rlimit_as", &config.agent.rlimit_as),
+    ] {
+        if let Err(e) = resources::parse_size(value) {
+            error!("Invalid size '{value}' set in option '{option}': {e}");
+            return Err(Error::Configuration(format!(
+                "Invalid size '{value}' set in option '{option}': {e}"
+            )));
+        }
+    }
+
+    let mut revocation_cert = config_get_default_file_path(
         "revocation_cert",
         &config.agent.revocation_cert,
         keylime_dir,
-        &format!("secure/unzipped/{DEFAULT_REVOCATION_CERT}"),
+        Path::new(&secure_dir),
+        &format!("unzipped/{DEFAULT_REVOCATION_CERT}"),
     );
 
+    // The attestation results written by the agent are optional
+    let [spire_attestation_path, attestation_status_path] = [
+        (
+            "spire_attestation_path",
+            &config.agent.spire_attestation_path,
+        ),
+        (
+            "attestation_status_path",
+            &config.agent.attestation_status_path,
+        ),
+    ]
+    .map(|(option, path)| match path.as_ref() {
+        "" => "".to_string(),
+        path => config_get_file_path(option, path, Path::new(&audit_dir), ""),
+    });
+
     Ok(KeylimeConfig {
         agent: AgentConfig {
             keylime_dir: keylime_dir.display().to_string(),
-            uuid,
+            uuid_path,
             server_key,
             server_cert,
             iak_cert,
@@ -848,6 +2106,15 @@ fn config_translate_keywords(
             ima_ml_path,
             measuredboot_ml_path,
             revocation_cert,
+            local_appraisal_db,
+            verifier_identities,
+            registrar_tls_ca,
+            cert_dir,
+            tpmdata_dir,
+            secure_dir,
+            audit_dir,
+            spire_attestation_path,
+            attestation_status_path,
             ..config.agent.clone()
         },
     })
@@ -882,27 +2149,24 @@ fn config_get_file_path(
     }
 }
 
-fn get_uuid(agent_uuid_config: &str) -> String {
-    match agent_uuid_config {
-        "hash_ek" => {
-            info!("Using hashed EK as UUID");
-            // DO NOT change this to something else. It is used later to set the correct value.
-            "hash_ek".into()
-        }
-        "generate" => {
-            let agent_uuid = Uuid::new_v4();
-            info!("Generated a new UUID: {}", &agent_uuid);
-            agent_uuid.to_string()
-        }
-        uuid_config => match Uuid::parse_str(uuid_config) {
-            Ok(uuid_config) => uuid_config.to_string(),
-            Err(_) => {
-                warn!("Misformatted UUID: {}", &uuid_config);
-                let agent_uuid = Uuid::new_v4();
-                info!("Using generated UUID: {}", &uuid_config);
-                agent_uuid.to_string()
-            }
-        },
+/// Expand a file path from the configuration file, placing the default file
+/// in the directory of the component using it.
+///
+/// This is the same as `config_get_file_path`, except that the default file
+/// is relative from the component directory rather than the work_dir. Other
+/// relative paths are still relative from the work_dir.
+fn config_get_default_file_path(
+    option: &str,
+    path: &str,
+    work_dir: &Path,
+    component_dir: &Path,
+    default: &str,
+) -> String {
+    match path {
+        "default" | "" => {
+            config_get_file_path(option, path, component_dir, default)
+        }
+        v => config_get_file_path(option, v, work_dir, default),
     }
 }
 
@@ -1053,21 +2317,38 @@ mod tests {
     }
 
     #[test]
-    fn test_get_uuid() {
-        assert_eq!(get_uuid("hash_ek"), "hash_ek");
-        let _ = Uuid::parse_str(&get_uuid("generate")).unwrap(); //#[allow_ci]
-        assert_eq!(
-            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C00000"),
-            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
-        );
-        assert_ne!(
-            get_uuid("D432FBB3-D2F1-4A97-9EF7-75BD81C0000X"),
-            "d432fbb3-d2f1-4a97-9ef7-75bd81c0000X"
-        );
-        let _ = Uuid::parse_str(&get_uuid(
-            "D432FBB3-D2F1-4A97-9EF7-75BD81C0000X",
-        ))
-        .unwrap(); //#[allow_ci]
+    fn test_validate_additional_agents() {
+        let main = KeylimeConfig::default();
+        let mut other = KeylimeConfig::default();
+        assert!(validate_additional_agents(&main, &[other.clone()]).is_err());
+
+        other.agent.keylime_dir = "/var/lib/keylime-tenant".to_string();
+        assert!(validate_additional_agents(&main, &[other.clone()]).is_err());
+
+        other.agent.port = main.agent.port + 1;
+        assert!(validate_additional_agents(&main, &[other.clone()]).is_ok());
+        assert!(validate_additional_agents(&main, &[]).is_ok());
+    }
+
+    #[test]
+    fn get_memory_budget_invalid() {
+        let test_config = KeylimeConfig {
+            agent: AgentConfig {
+                memory_budget: "64x".to_string(),
+                ..Default::default()
+            },
+        };
+        let result = config_translate_keywords(&test_config);
+        assert!(result.is_err());
+
+        let test_config = KeylimeConfig {
+            agent: AgentConfig {
+                memory_budget: "64m".to_string(),
+                ..Default::default()
+            },
+        };
+        let result = config_translate_keywords(&test_config);
+        assert!(result.is_ok());
     }
 
     #[test]
@@ -1119,6 +2400,92 @@ mod tests {
             ("AGENT_DATA_PATH", "override_agent_data_path"),
             ("IMA_ML_PATH", "override_ima_ml_path"),
             ("MEASUREDBOOT_ML_PATH", "override_measuredboot_ml_path"),
+            ("MEMORY_BUDGET", "override_memory_budget"),
+            ("RLIMIT_NOFILE", "9999"),
+            ("RLIMIT_AS", "override_rlimit_as"),
+            ("ENABLE_LOCAL_APPRAISAL", "true"),
+            ("LOCAL_APPRAISAL_DB", "override_local_appraisal_db"),
+            ("LOCAL_APPRAISAL_INTERVAL", "9999"),
+            ("ENABLE_GRPC", "true"),
+            ("GRPC_PORT", "9999"),
+            ("CLOUDEVENTS_SINK", "override_cloudevents_sink"),
+            ("CLOUDEVENTS_AUTH_TOKEN", "override_cloudevents_auth_token"),
+            ("SPIRE_ATTESTATION_PATH", "override_spire_attestation_path"),
+            (
+                "ATTESTATION_STATUS_PATH",
+                "override_attestation_status_path",
+            ),
+            ("KUBERNETES_NODE_NAME", "override_kubernetes_node_name"),
+            ("VERIFIER_IDENTITIES", "override_verifier_identities"),
+            ("AK_REACTIVATION_ATTEMPTS", "5"),
+            ("ENABLE_KEY_CERTIFICATION", "true"),
+            ("LUKS_DEVICE", "/dev/sda2"),
+            ("LUKS_NAME", "luks-test"),
+            ("LUKS_ENROLL_KEY_FILE", "/root/luks.key"),
+            ("PAYLOAD_HOOK", "/usr/local/bin/payload-hook"),
+            ("TPM_STATE_CHECK_INTERVAL", "60"),
+            ("HTTP_WORKERS", "2"),
+            ("HTTP_CLIENT_REQUEST_TIMEOUT", "1000"),
+            ("HTTP_KEEP_ALIVE", "10"),
+            ("HTTP_MAX_CONNECTIONS", "64"),
+            ("TLS_HANDSHAKE_TIMEOUT", "1000"),
+            ("AUTH_TAG_ALGORITHMS", "sha256"),
+            ("UUID_PATH", "override_uuid_path"),
+            ("ADDITIONAL_AGENTS_DIR", "override_additional_agents_dir"),
+            ("SELF_ATTESTATION_INTERVAL", "60"),
+            ("ENABLE_RESPONSE_SIGNING", "true"),
+            ("ENABLE_H2C", "true"),
+            ("QUOTE_ALLOWED_PCRS", "0, 1, 7"),
+            ("QUOTE_PCR_BANKS", "sha256, sha384"),
+            ("CERT_DIR", "override_cert_dir"),
+            ("TPMDATA_DIR", "override_tpmdata_dir"),
+            ("SECURE_DIR", "override_secure_dir"),
+            ("AUDIT_DIR", "override_audit_dir"),
+            ("SECURE_MOUNT_CONTEXT", "override_secure_mount_context"),
+            ("WORK_DIR_CONTEXT", "override_work_dir_context"),
+            (
+                "PAYLOAD_APPARMOR_PROFILE",
+                "override_payload_apparmor_profile",
+            ),
+            ("PAYLOAD_DELIVERY", "override_payload_delivery"),
+            ("PAYLOAD_HANDOFF_SOCKET", "override_payload_handoff_socket"),
+            ("PAYLOAD_HANDOFF_PEER_UID", "1000"),
+            ("KEY_WIPE_PCR", "23"),
+            ("REGISTRAR_TLS", "pin"),
+            ("REGISTRAR_TLS_CA", "/etc/ca.crt"),
+            ("REGISTRAR_TLS_PINS", "sha256//AAAA"),
+            ("REGISTRAR_TLS_SERVER_NAME", "registrar.example.com"),
+            ("FIPS_MODE", "false"),
+            ("CERT_EXPIRY_WARNING_DAYS", "14"),
+            ("CERT_EXPIRY_CHECK_INTERVAL", "3600"),
+            ("OUTBOUND_TIMEOUT", "10000"),
+            ("OUTBOUND_CONNECT_TIMEOUT", "1000"),
+            ("OUTBOUND_RETRIES", "5"),
+            ("OUTBOUND_RETRY_DELAY", "100"),
+            ("OUTBOUND_MAX_RETRY_DELAY", "60000"),
+            ("OUTBOUND_PROXY", "proxy.example.com:3128"),
+            ("BOOT_CHANGE_NOTIFY_URL", "http://localhost:8890/boot"),
+            ("REFUSE_QUOTES_AFTER_KEXEC", "true"),
+            ("TPM_LOCK_PATH", "/run/keylime/tpm.lock"),
+            ("TPM_PERSISTENT_HANDLES", "0x81010000-0x8101ffff"),
+            ("TPM_REQUIRE_RESOURCE_MANAGER", "true"),
+            ("ASSET_TAG_NV_INDEX", "0x01c10000"),
+            ("ASSET_TAG_SIGNATURE_NV_INDEX", "0x01c10001"),
+            ("ASSET_TAG_CERT", "/var/lib/keylime/asset_tag.pem"),
+            ("KEY_TTL", "3600"),
+            ("ENABLE_AGENT_NONCE", "true"),
+            ("SEALED_CONFIG_PCRS", "override_sealed_config_pcrs"),
+            ("ENABLE_FILE_WATCH", "true"),
+            ("FILE_WATCH_PCR", "12"),
+            ("RELAY_GUESTS", "vm1@192.168.122.10:9002"),
+            ("RELAY_GUEST_TLS", "ca"),
+            ("RELAY_GUEST_TLS_CA", "override_relay_guest_tls_ca"),
+            ("RELAY_GUEST_TLS_PINS", "override_relay_guest_tls_pins"),
+            ("ENABLE_PCR_FORECAST", "true"),
+            ("HASH_BACKEND", "sha2"),
+            ("EVENT_HOOK", "exec:/usr/libexec/node-ready"),
+            ("EVENT_HOOK_EVENTS", "registered"),
+            ("EVENT_HOOK_TIMEOUT", "30"),
         ]);
 
         for (c, v) in override_map.into_iter() {
@@ -1164,6 +2531,54 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_work_dir_layout() {
+        let default = KeylimeConfig::default();
+        let keylime_dir = Path::new(&default.agent.keylime_dir);
+        let in_dir =
+            |path: &str| keylime_dir.join(path).display().to_string();
+        assert_eq!(default.agent.cert_dir, in_dir("certs"));
+        assert_eq!(
+            default.agent.server_key,
+            in_dir("certs/server-private.pem")
+        );
+        assert_eq!(
+            default.agent.trusted_client_ca,
+            in_dir("certs/cv_ca/cacert.crt")
+        );
+        assert_eq!(
+            default.agent.agent_data_path,
+            in_dir("tpmdata/agent_data.json")
+        );
+        assert_eq!(default.agent.uuid_path, in_dir("tpmdata/agent_uuid"));
+        assert_eq!(default.agent.attestation_status_path, "");
+
+        let config = KeylimeConfig {
+            agent: AgentConfig {
+                cert_dir: "/etc/keylime/certs".to_string(),
+                secure_dir: "tmpfs".to_string(),
+                server_cert: "server.crt".to_string(),
+                attestation_status_path: "status".to_string(),
+                ..Default::default()
+            },
+        };
+        let config = config_translate_keywords(&config).unwrap(); //#[allow_ci]
+        assert_eq!(
+            config.agent.server_key,
+            "/etc/keylime/certs/server-private.pem"
+        );
+        // Relative paths set explicitly are still relative from keylime_dir
+        assert_eq!(config.agent.server_cert, in_dir("server.crt"));
+        assert_eq!(
+            config.agent.revocation_cert,
+            in_dir("tmpfs/unzipped/RevocationNotifier-cert.crt")
+        );
+        assert_eq!(
+            config.agent.attestation_status_path,
+            in_dir("audit/status")
+        );
+    }
+
     #[test]
     fn test_config_get_file_path() {
         let workdir = Path::new("/workdir");
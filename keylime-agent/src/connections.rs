@@ -0,0 +1,197 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Statistics of the connections to the REST API
+//!
+//! Verifiers polling many agents benefit from keeping the connections open,
+//! and from multiplexing the requests over HTTP/2, which is negotiated via
+//! ALPN when mTLS is enabled, or used with prior knowledge (h2c) when
+//! enabled on plain HTTP. The connections are tracked from the accept to
+//! the close, together with the number of requests served on each, so that
+//! the reuse of the connections can be checked.
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{
+    dev::{Extensions, ServiceRequest},
+    http::Version,
+    rt::net::TcpStream,
+    web, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+#[derive(Debug)]
+struct Connection {
+    peer: Option<String>,
+    tls: bool,
+    alpn: Option<String>,
+    opened: Instant,
+    requests: AtomicU64,
+}
+
+/// Counters of the connections accepted by all the workers
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionStats {
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Arc<Connection>>>,
+    http1_requests: AtomicU64,
+    http2_requests: AtomicU64,
+}
+
+/// Stored in the connection data, removes the connection from the active
+/// ones when the connection is closed
+struct ConnectionGuard {
+    id: u64,
+    connection: Arc<Connection>,
+    stats: Arc<ConnectionStats>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        _ = self.stats.active.lock().unwrap().remove(&self.id); //#[allow_ci]
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ConnectionInfo {
+    pub id: u64,
+    pub peer: Option<String>,
+    pub tls: bool,
+    pub alpn: Option<String>,
+    pub age_secs: u64,
+    pub requests: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct StatsResponse {
+    pub total_connections: u64,
+    pub active_connections: usize,
+    pub http1_requests: u64,
+    pub http2_requests: u64,
+    pub connections: Vec<ConnectionInfo>,
+}
+
+impl ConnectionStats {
+    /// Track a new connection, to be used as the server on_connect callback
+    pub(crate) fn on_connect(
+        self: &Arc<Self>,
+        conn: &dyn Any,
+        ext: &mut Extensions,
+    ) {
+        let (peer, tls, alpn) = if let Some(stream) =
+            conn.downcast_ref::<TlsStream<TcpStream>>()
+        {
+            (
+                stream.get_ref().peer_addr().ok(),
+                true,
+                stream
+                    .ssl()
+                    .selected_alpn_protocol()
+                    .map(|p| String::from_utf8_lossy(p).to_string()),
+            )
+        } else if let Some(stream) = conn.downcast_ref::<TcpStream>() {
+            (stream.peer_addr().ok(), false, None)
+        } else {
+            (None, false, None)
+        };
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Arc::new(Connection {
+            peer: peer.map(|p| p.to_string()),
+            tls,
+            alpn,
+            opened: Instant::now(),
+            requests: AtomicU64::new(0),
+        });
+        _ = self
+            .active
+            .lock()
+            .unwrap() //#[allow_ci]
+            .insert(id, connection.clone());
+        _ = ext.insert(ConnectionGuard {
+            id,
+            connection,
+            stats: self.clone(),
+        });
+    }
+
+    /// Count a request on its connection
+    pub(crate) fn on_request(&self, req: &ServiceRequest) {
+        if let Some(guard) = req.conn_data::<ConnectionGuard>() {
+            _ = guard.connection.requests.fetch_add(1, Ordering::Relaxed);
+        }
+        let counter = match req.version() {
+            Version::HTTP_2 => &self.http2_requests,
+            _ => &self.http1_requests,
+        };
+        _ = counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(&self) -> StatsResponse {
+        let active = self.active.lock().unwrap(); //#[allow_ci]
+        let mut connections: Vec<ConnectionInfo> = active
+            .iter()
+            .map(|(id, c)| ConnectionInfo {
+                id: *id,
+                peer: c.peer.clone(),
+                tls: c.tls,
+                alpn: c.alpn.clone(),
+                age_secs: c.opened.elapsed().as_secs(),
+                requests: c.requests.load(Ordering::Relaxed),
+            })
+            .collect();
+        connections.sort_by_key(|c| c.id);
+
+        StatsResponse {
+            total_connections: self.next_id.load(Ordering::Relaxed),
+            active_connections: active.len(),
+            http1_requests: self.http1_requests.load(Ordering::Relaxed),
+            http2_requests: self.http2_requests.load(Ordering::Relaxed),
+            connections,
+        }
+    }
+}
+
+/// Handles the requests for the statistics of the connections
+pub(crate) async fn stats(
+    stats: web::Data<ConnectionStats>,
+) -> impl Responder {
+    HttpResponse::Ok()
+        .json(crate::common::JsonWrapper::success(stats.snapshot()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_connection_stats() {
+        let stats = Arc::new(ConnectionStats::default());
+
+        let mut ext = Extensions::new();
+        stats.on_connect(&(), &mut ext);
+        let req = TestRequest::default().to_srv_request();
+        stats.on_request(&req);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total_connections, 1);
+        assert_eq!(snapshot.active_connections, 1);
+        assert_eq!(snapshot.http1_requests, 1);
+        assert!(!snapshot.connections[0].tls);
+
+        // The connection is removed when its data is dropped on close
+        drop(ext);
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.total_connections, 1);
+        assert_eq!(snapshot.active_connections, 0);
+    }
+}
@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Compact encoding of the quote responses as CBOR with a COSE signature
+//!
+//! When requested through the Accept header, the quote is encoded as a CBOR
+//! map, where the binary evidence is kept as byte strings, and wrapped in a
+//! COSE_Sign1 structure signed with the NK, whose public key is bound to the
+//! TPM quote. JSON remains the default response format.
+
+use crate::{quotes_handler::KeylimeQuote, Error, Result};
+use actix_web::{http::header::ACCEPT, HttpRequest};
+use base64::{engine::general_purpose, Engine as _};
+use ciborium::value::Value;
+use coset::{
+    iana, CborSerializable, CoseSign1Builder, HeaderBuilder,
+    TaggedCborSerializable,
+};
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkey::{PKey, Private},
+    rsa::Padding,
+    sign::{RsaPssSaltlen, Signer},
+};
+
+/// Content type of the COSE signed quote responses
+pub(crate) const COSE_CONTENT_TYPE: &str =
+    "application/cose; cose-type=\"cose-sign1\"";
+
+/// Content type of the payload of the COSE_Sign1 structure
+const PAYLOAD_CONTENT_TYPE: &str = "application/cbor";
+
+/// Check whether the client accepts the COSE signed quote responses
+pub(crate) fn accepts_cose(req: &HttpRequest) -> bool {
+    accepts(req, "application/cose")
+}
+
+/// Check whether the media type is listed in the Accept header, ignoring
+/// its parameters
+pub(crate) fn accepts(req: &HttpRequest, media_type: &str) -> bool {
+    req.headers()
+        .get_all(ACCEPT)
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|accepted| {
+            let essence = accepted.split(';').next().unwrap_or("").trim();
+            essence.eq_ignore_ascii_case(media_type)
+        })
+}
+
+/// Encode the quote as a CBOR map with the fields of the JSON response.
+/// The measured boot log is decoded into a byte string, while the other
+/// fields keep their JSON representation.
+fn quote_to_cbor(quote: KeylimeQuote) -> Result<Vec<u8>> {
+    let mut map = Value::serialized(&quote)?
+        .into_map()
+        .map_err(|_| Error::Other("Quote is not a CBOR map".to_string()))?;
+    for (key, value) in map.iter_mut() {
+        if key.as_text() == Some("mb_measurement_list") {
+            if let Some(ml) = value.as_text() {
+                *value = Value::Bytes(general_purpose::STANDARD.decode(ml)?);
+            }
+        }
+    }
+
+    let mut encoded = Vec::new();
+    ciborium::into_writer(&Value::Map(map), &mut encoded)?;
+    Ok(encoded)
+}
+
+/// Sign the data using RSASSA-PSS with SHA-256, as specified for the PS256
+/// COSE algorithm
+fn sign_ps256(key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>> {
+    let mut signer = Signer::new(MessageDigest::sha256(), key)?;
+    signer.set_rsa_padding(Padding::PKCS1_PSS)?;
+    signer.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+    signer.set_rsa_mgf1_md(MessageDigest::sha256())?;
+    signer.update(data)?;
+    Ok(signer.sign_to_vec()?)
+}
+
+/// Wrap the payload in a tagged COSE_Sign1 structure signed with the NK.
+/// The key ID is the SHA-256 digest of the DER encoded NK public key.
+pub(crate) fn sign_payload(
+    payload: Vec<u8>,
+    content_type: &str,
+    key: &PKey<Private>,
+) -> Result<Vec<u8>> {
+    let key_id = hash(MessageDigest::sha256(), &key.public_key_to_der()?)?;
+    let protected = HeaderBuilder::new()
+        .algorithm(iana::Algorithm::PS256)
+        .content_type(content_type.to_string())
+        .key_id(key_id.to_vec())
+        .build();
+
+    let sign1 = CoseSign1Builder::new()
+        .protected(protected)
+        .payload(payload)
+        .try_create_signature(b"", |tbs| sign_ps256(key, tbs))?
+        .build();
+
+    Ok(sign1.to_tagged_vec()?)
+}
+
+/// Encode the quote as a tagged COSE_Sign1 structure signed with the NK
+pub(crate) fn sign_quote(
+    quote: KeylimeQuote,
+    key: &PKey<Private>,
+) -> Result<Vec<u8>> {
+    sign_payload(quote_to_cbor(quote)?, PAYLOAD_CONTENT_TYPE, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asset_tag::AssetTag,
+        crypto::testing::rsa_generate,
+        kernel_boot::{BootInfo, BootKind},
+        quotes_handler::{Continuity, PcrSelection},
+    };
+    use actix_web::test::TestRequest;
+    use coset::CoseSign1;
+    use openssl::sign::Verifier;
+
+    #[test]
+    fn test_accepts_cose() {
+        let req = TestRequest::default()
+            .insert_header((
+                ACCEPT,
+                "application/json, application/cose; cose-type=\"cose-sign1\"",
+            ))
+            .to_http_request();
+        assert!(accepts_cose(&req));
+
+        let req = TestRequest::default()
+            .insert_header((ACCEPT, "application/json"))
+            .to_http_request();
+        assert!(!accepts_cose(&req));
+        assert!(!accepts_cose(&TestRequest::default().to_http_request()));
+    }
+
+    #[test]
+    fn test_sign_quote() {
+        let key = rsa_generate(2048).unwrap(); //#[allow_ci]
+        let quote = KeylimeQuote {
+            quote: "r1234".to_string(),
+            hash_alg: "sha256".to_string(),
+            enc_alg: "rsa".to_string(),
+            sign_alg: "rsassa".to_string(),
+            mb_measurement_list: Some(
+                general_purpose::STANDARD.encode(b"\x00\x01"),
+            ),
+            ..Default::default()
+        };
+
+        let encoded = sign_quote(quote, &key).unwrap(); //#[allow_ci]
+        let sign1 = CoseSign1::from_tagged_slice(&encoded).unwrap(); //#[allow_ci]
+        assert_eq!(
+            sign1.protected.header.alg,
+            Some(coset::Algorithm::Assigned(iana::Algorithm::PS256))
+        );
+
+        sign1
+            .verify_signature(b"", |sig, data| {
+                let mut verifier =
+                    Verifier::new(MessageDigest::sha256(), &key)?;
+                verifier.set_rsa_padding(Padding::PKCS1_PSS)?;
+                verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)?;
+                verifier.set_rsa_mgf1_md(MessageDigest::sha256())?;
+                verifier.update(data)?;
+                match verifier.verify(sig)? {
+                    true => Ok(()),
+                    false => Err(Error::Other("invalid signature".into())),
+                }
+            })
+            .unwrap(); //#[allow_ci]
+
+        let payload: Value =
+            ciborium::from_reader(sign1.payload.unwrap().as_slice()).unwrap(); //#[allow_ci]
+        let map = payload.into_map().unwrap(); //#[allow_ci]
+        assert!(map.contains(&(Value::from("quote"), Value::from("r1234"))));
+        assert!(map.contains(&(
+            Value::from("mb_measurement_list"),
+            Value::Bytes(vec![0, 1])
+        )));
+        assert!(!map.iter().any(|(k, _)| k == &Value::from("pubkey")));
+    }
+
+    #[test]
+    fn test_quote_fields() {
+        let quote = KeylimeQuote {
+            quote: "r1234".to_string(),
+            hash_alg: "sha256".to_string(),
+            enc_alg: "rsa".to_string(),
+            sign_alg: "rsassa".to_string(),
+            pubkey: Some("pubkey".to_string()),
+            ima_measurement_list: Some("ima".to_string()),
+            mb_measurement_list: Some(
+                general_purpose::STANDARD.encode(b"\x00\x01"),
+            ),
+            mb_measurement_list_etag: Some("etag".to_string()),
+            ima_measurement_list_entry: Some(1),
+            pcr_selection: Some(PcrSelection::default()),
+            boot_epoch: Some(2),
+            boot: Some(BootInfo {
+                boot_id: "boot".to_string(),
+                kernel_build_id: None,
+                kernel_version: "6.1".to_string(),
+                boot_kind: BootKind::Reboot,
+            }),
+            asset_tag: Some(AssetTag {
+                nv_index: "0x1500020".to_string(),
+                data: vec![1, 2],
+                signature: None,
+            }),
+            agent_nonce: Some("00".repeat(32)),
+            continuity: Some(Continuity {
+                version: 1,
+                agent_starts: 3,
+                tpm_reset_count: 4,
+                tpm_restart_count: 5,
+            }),
+        };
+
+        // The COSE payload has the same fields as the JSON response
+        let json = serde_json::to_value(&quote).unwrap(); //#[allow_ci]
+        let payload: Value =
+            ciborium::from_reader(quote_to_cbor(quote).unwrap().as_slice()) //#[allow_ci]
+                .unwrap(); //#[allow_ci]
+        let mut cbor_fields: Vec<String> = payload
+            .into_map()
+            .unwrap() //#[allow_ci]
+            .into_iter()
+            .filter_map(|(k, _)| k.into_text().ok())
+            .collect();
+        let mut json_fields: Vec<String> =
+            json.as_object().unwrap().keys().cloned().collect(); //#[allow_ci]
+        cbor_fields.sort();
+        json_fields.sort();
+        assert_eq!(cbor_fields, json_fields);
+        assert!(cbor_fields.contains(&"agent_nonce".to_string()));
+        assert!(cbor_fields.contains(&"continuity".to_string()));
+    }
+}
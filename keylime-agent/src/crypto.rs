@@ -28,7 +28,7 @@ use std::{
 };
 
 use crate::{
-    Error, Result, AES_128_KEY_LEN, AES_256_KEY_LEN, AES_BLOCK_SIZE,
+    fips, Error, Result, AES_128_KEY_LEN, AES_256_KEY_LEN, AES_BLOCK_SIZE,
 };
 
 // Read a X509 cert in DER format from path
@@ -59,7 +59,9 @@ pub(crate) fn load_x509(input_cert_path: &Path) -> Result<X509> {
     Ok(cert)
 }
 
-fn load_x509_cert_chain(input_cert_path: &Path) -> Result<Vec<X509>> {
+pub(crate) fn load_x509_cert_chain(
+    input_cert_path: &Path,
+) -> Result<Vec<X509>> {
     let contents = read_to_string(input_cert_path).map_err(Error::from)?;
 
     X509::stack_from_pem(contents.as_bytes()).map_err(Error::Crypto)
@@ -275,9 +277,13 @@ pub(crate) fn generate_mtls_context(
     mtls_cert: &X509,
     key: &PKey<Private>,
     keylime_ca_certs: Vec<X509>,
+    fips_mode: bool,
 ) -> Result<SslAcceptorBuilder> {
     let mut ssl_context_builder =
         SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    if fips_mode {
+        fips::restrict_tls(&mut ssl_context_builder)?;
+    }
     ssl_context_builder.set_certificate(mtls_cert);
     ssl_context_builder.set_private_key(key);
 
@@ -392,13 +398,21 @@ pub(crate) fn rsa_oaep_decrypt(
  * Sign message and return HMAC result string
  */
 pub(crate) fn compute_hmac(key: &[u8], data: &[u8]) -> Result<Vec<u8>> {
-    let pkey = PKey::hmac(key)?;
     // SHA-384 is used as the underlying hash algorithm.
     //
     // Reference:
     // https://keylime-docs.readthedocs.io/en/latest/rest_apis.html#post--v1.0-keys-ukey
     // https://github.com/keylime/keylime/blob/910b38b296038b187a020c095dc747e9c46cbef3/keylime/crypto.py#L151
-    let mut signer = Signer::new(MessageDigest::sha384(), &pkey)?;
+    compute_hmac_with_digest(MessageDigest::sha384(), key, data)
+}
+
+pub(crate) fn compute_hmac_with_digest(
+    digest: MessageDigest,
+    key: &[u8],
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let pkey = PKey::hmac(key)?;
+    let mut signer = Signer::new(digest, &pkey)?;
     signer.update(data)?;
     signer.sign_to_vec().map_err(Error::Crypto)
 }
@@ -408,16 +422,20 @@ pub(crate) fn verify_hmac(
     data: &[u8],
     hmac: &[u8],
 ) -> Result<()> {
-    let pkey = PKey::hmac(key)?;
-    // SHA-384 is used as the underlying hash algorithm.
-    //
-    // Reference:
-    // https://keylime-docs.readthedocs.io/en/latest/rest_apis.html#post--v1.0-keys-ukey
-    // https://github.com/keylime/keylime/blob/910b38b296038b187a020c095dc747e9c46cbef3/keylime/crypto.py#L151
-    let mut signer = Signer::new(MessageDigest::sha384(), &pkey)?;
-    signer.update(data)?;
+    // SHA-384 is used as the underlying hash algorithm, see compute_hmac()
+    verify_hmac_with_digest(MessageDigest::sha384(), key, data, hmac)
+}
+
+pub(crate) fn verify_hmac_with_digest(
+    digest: MessageDigest,
+    key: &[u8],
+    data: &[u8],
+    hmac: &[u8],
+) -> Result<()> {
+    let computed = compute_hmac_with_digest(digest, key, data)?;
 
-    if !memcmp::eq(&signer.sign_to_vec()?, hmac) {
+    // memcmp::eq panics on inputs of different lengths
+    if computed.len() != hmac.len() || !memcmp::eq(&computed, hmac) {
         return Err(Error::Other("hmac check failed".to_string()));
     }
 
@@ -560,6 +578,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_verify_hmac_with_digest() {
+        let key = b"mysecret";
+        let message = b"hellothere";
+        let mac = hex::decode(
+            "54641c220fd9b77f2a20e0977d13ffcb297b801b2eaf958c58b7e5370aa7abc2",
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(verify_hmac_with_digest(
+            MessageDigest::sha256(),
+            key,
+            message,
+            &mac
+        )
+        .is_ok());
+        assert!(verify_hmac(key, message, &mac).is_err());
+    }
+
     // Test KDF to ensure derived password matches result derived from Python
     // functions.
     #[test]
@@ -820,7 +856,8 @@ mod tests {
         let loaded_list = r.unwrap(); //#[allow_ci]
         assert!(loaded_list.len() == 2);
 
-        let r = generate_mtls_context(&loaded_a, &privkey, loaded_list);
+        let r =
+            generate_mtls_context(&loaded_a, &privkey, loaded_list, false);
         assert!(r.is_ok());
     }
 }
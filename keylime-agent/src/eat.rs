@@ -0,0 +1,490 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Evidence in the IETF RATS Entity Attestation Token (EAT) format
+//!
+//! The TPM quote and the event logs are mapped into an EAT claims set, so
+//! that relying parties which are not Keylime verifiers can consume the agent
+//! evidence. The claims set is returned as an unprotected JSON or CBOR claims
+//! set, or as a CWT signed with the NK, according to the Accept header:
+//!
+//! - application/eat-ucs+json (default)
+//! - application/eat-ucs+cbor
+//! - application/eat+cwt
+//!
+//! The endpoint is served from API version 2.2.
+//!
+//! The evidence is kept in submodules using private claims: "tpm" for the
+//! quote, "ima" for the IMA measurement list and "measured_boot" for the
+//! UEFI event log.
+
+use crate::{
+    common::JsonWrapper,
+    cose,
+    quotes_handler::{self, KeylimeQuote},
+    service::{self, IntegrityQuote, PcrRequest},
+    verifiers, QuoteData, Result,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine as _,
+};
+use ciborium::value::Value as CborValue;
+use log::*;
+use openssl::hash::{hash, MessageDigest};
+use serde::Deserialize;
+use serde_json::{json, Map, Value as JsonValue};
+
+pub(crate) const EAT_UCS_JSON_CONTENT_TYPE: &str = "application/eat-ucs+json";
+pub(crate) const EAT_UCS_CBOR_CONTENT_TYPE: &str = "application/eat-ucs+cbor";
+pub(crate) const EAT_CWT_CONTENT_TYPE: &str = "application/eat+cwt";
+
+/// Profile identifying the claims used by the agent
+pub(crate) const EAT_PROFILE: &str = "tag:keylime.dev,2023:agent-evidence";
+
+// CWT claim keys registered for the EAT claims
+const CLAIM_IAT: i64 = 6;
+const CLAIM_EAT_NONCE: i64 = 10;
+const CLAIM_UEID: i64 = 256;
+const CLAIM_EAT_PROFILE: i64 = 265;
+const CLAIM_SUBMODS: i64 = 266;
+
+// Type byte of the UEID for random identifiers
+const UEID_TYPE_RAND: u8 = 0x01;
+
+#[derive(Deserialize)]
+pub struct EatParams {
+    nonce: String,
+    agent_nonce: Option<String>,
+    mask: Option<String>,
+    pcrs: Option<String>,
+    pcr_bank: Option<String>,
+    ima_ml_entry: Option<String>,
+}
+
+/// A claim value, encoded as a text string in JSON and as a byte string in
+/// CBOR when binary
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Claim {
+    Text(String),
+    Bytes(Vec<u8>),
+    Integer(u64),
+}
+
+impl Claim {
+    fn to_json(&self) -> JsonValue {
+        match self {
+            Claim::Text(t) => json!(t),
+            Claim::Bytes(b) => json!(URL_SAFE_NO_PAD.encode(b)),
+            Claim::Integer(i) => json!(i),
+        }
+    }
+
+    fn to_cbor(&self) -> CborValue {
+        match self {
+            Claim::Text(t) => CborValue::from(t.as_str()),
+            Claim::Bytes(b) => CborValue::Bytes(b.clone()),
+            Claim::Integer(i) => CborValue::from(*i),
+        }
+    }
+}
+
+/// The EAT claims set built from an integrity quote
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct EatClaims {
+    nonce: String,
+    iat: u64,
+    ueid: Vec<u8>,
+    submods: Vec<(&'static str, Vec<(&'static str, Claim)>)>,
+}
+
+impl EatClaims {
+    /// Build the claims from the quote. The UEID is derived from the agent
+    /// UUID, as the UUID may be derived from the EK and must not be exposed
+    /// directly.
+    pub(crate) fn new(
+        agent_uuid: &str,
+        nonce: &str,
+        iat: u64,
+        quote: KeylimeQuote,
+    ) -> Result<Self> {
+        let mut ueid = vec![UEID_TYPE_RAND];
+        ueid.extend_from_slice(&hash(
+            MessageDigest::sha256(),
+            agent_uuid.as_bytes(),
+        )?);
+
+        let mut tpm = vec![
+            ("keylime_quote", Claim::Text(quote.quote)),
+            ("keylime_hash_alg", Claim::Text(quote.hash_alg)),
+            ("keylime_enc_alg", Claim::Text(quote.enc_alg)),
+            ("keylime_sign_alg", Claim::Text(quote.sign_alg)),
+        ];
+        if let Some(pubkey) = quote.pubkey {
+            tpm.push(("keylime_pubkey", Claim::Text(pubkey)));
+        }
+        if let Some(selection) = quote.pcr_selection {
+            let pcrs = selection
+                .pcrs
+                .iter()
+                .map(u32::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+            tpm.push(("keylime_pcr_bank", Claim::Text(selection.hash_alg)));
+            tpm.push(("keylime_pcrs", Claim::Text(pcrs)));
+        }
+        if let Some(epoch) = quote.boot_epoch {
+            tpm.push(("keylime_boot_epoch", Claim::Integer(epoch)));
+        }
+        if let Some(agent_nonce) = quote.agent_nonce {
+            tpm.push(("keylime_agent_nonce", Claim::Text(agent_nonce)));
+        }
+        if let Some(continuity) = quote.continuity {
+            tpm.push((
+                "keylime_agent_starts",
+                Claim::Integer(continuity.agent_starts),
+            ));
+            tpm.push((
+                "keylime_tpm_reset_count",
+                Claim::Integer(continuity.tpm_reset_count.into()),
+            ));
+            tpm.push((
+                "keylime_tpm_restart_count",
+                Claim::Integer(continuity.tpm_restart_count.into()),
+            ));
+        }
+        let mut submods = vec![("tpm", tpm)];
+
+        if let Some(boot) = quote.boot {
+            let mut kernel = vec![
+                ("keylime_boot_id", Claim::Text(boot.boot_id)),
+                ("keylime_kernel_version", Claim::Text(boot.kernel_version)),
+                (
+                    "keylime_boot_kind",
+                    Claim::Text(boot.boot_kind.to_string()),
+                ),
+            ];
+            if let Some(build_id) = boot.kernel_build_id {
+                kernel
+                    .push(("keylime_kernel_build_id", Claim::Text(build_id)));
+            }
+            submods.push(("kernel", kernel));
+        }
+
+        if let Some(tag) = quote.asset_tag {
+            let mut asset = vec![
+                ("keylime_nv_index", Claim::Text(tag.nv_index)),
+                ("keylime_asset_tag", Claim::Bytes(tag.data)),
+            ];
+            if let Some(signature) = tag.signature {
+                asset.push((
+                    "keylime_asset_tag_signature",
+                    Claim::Bytes(signature),
+                ));
+            }
+            submods.push(("asset_tag", asset));
+        }
+
+        if let Some(ml) = quote.ima_measurement_list {
+            let mut ima =
+                vec![("keylime_ima_measurement_list", Claim::Text(ml))];
+            if let Some(entry) = quote.ima_measurement_list_entry {
+                ima.push((
+                    "keylime_ima_measurement_list_entry",
+                    Claim::Integer(entry),
+                ));
+            }
+            submods.push(("ima", ima));
+        }
+
+        if let Some(ml) = quote.mb_measurement_list {
+            submods.push((
+                "measured_boot",
+                vec![(
+                    "keylime_tcg_event_log",
+                    Claim::Bytes(STANDARD.decode(ml)?),
+                )],
+            ));
+        }
+
+        Ok(EatClaims {
+            nonce: nonce.to_string(),
+            iat,
+            ueid,
+            submods,
+        })
+    }
+
+    pub(crate) fn to_json(&self) -> JsonValue {
+        let mut submods = Map::new();
+        for (name, claims) in &self.submods {
+            let claims: Map<String, JsonValue> = claims
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_json()))
+                .collect();
+            _ = submods.insert(name.to_string(), claims.into());
+        }
+
+        json!({
+            "eat_profile": EAT_PROFILE,
+            "eat_nonce": self.nonce,
+            "iat": self.iat,
+            "ueid": URL_SAFE_NO_PAD.encode(&self.ueid),
+            "submods": submods,
+        })
+    }
+
+    pub(crate) fn to_cbor(&self) -> Result<Vec<u8>> {
+        let submods = self
+            .submods
+            .iter()
+            .map(|(name, claims)| {
+                let claims = claims
+                    .iter()
+                    .map(|(k, v)| (CborValue::from(*k), v.to_cbor()))
+                    .collect();
+                (CborValue::from(*name), CborValue::Map(claims))
+            })
+            .collect();
+
+        let claims = CborValue::Map(vec![
+            (
+                CborValue::from(CLAIM_EAT_PROFILE),
+                CborValue::from(EAT_PROFILE),
+            ),
+            (
+                CborValue::from(CLAIM_EAT_NONCE),
+                CborValue::Bytes(self.nonce.as_bytes().to_vec()),
+            ),
+            (CborValue::from(CLAIM_IAT), CborValue::from(self.iat)),
+            (
+                CborValue::from(CLAIM_UEID),
+                CborValue::Bytes(self.ueid.clone()),
+            ),
+            (CborValue::from(CLAIM_SUBMODS), CborValue::Map(submods)),
+        ]);
+
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&claims, &mut encoded)?;
+        Ok(encoded)
+    }
+}
+
+fn encode(
+    req: &HttpRequest,
+    claims: &EatClaims,
+    data: &QuoteData,
+) -> Result<HttpResponse> {
+    if cose::accepts(req, EAT_CWT_CONTENT_TYPE) {
+        let body = cose::sign_payload(
+            claims.to_cbor()?,
+            EAT_UCS_CBOR_CONTENT_TYPE,
+            &data.priv_key,
+        )?;
+        return Ok(HttpResponse::Ok()
+            .content_type(EAT_CWT_CONTENT_TYPE)
+            .body(body));
+    }
+
+    if cose::accepts(req, EAT_UCS_CBOR_CONTENT_TYPE) {
+        return Ok(HttpResponse::Ok()
+            .content_type(EAT_UCS_CBOR_CONTENT_TYPE)
+            .body(claims.to_cbor()?));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type(EAT_UCS_JSON_CONTENT_TYPE)
+        .body(claims.to_json().to_string()))
+}
+
+// This is an evidence request from a RATS relying party or verifier. It
+// returns the same evidence as the integrity quote, including the public key
+// and the whole located IMA measurement list, as an EAT claims set.
+pub async fn eat(
+    req: HttpRequest,
+    param: web::Query<EatParams>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let result = service::integrity_quote(
+        &data,
+        &param.nonce,
+        param.agent_nonce.as_deref(),
+        &PcrRequest {
+            mask: param.mask.as_deref(),
+            pcrs: param.pcrs.as_deref(),
+            pcr_bank: param.pcr_bank.as_deref(),
+        },
+        "0",
+        param.ima_ml_entry.as_deref(),
+        None,
+        verifiers::evidence_scope(&req),
+    )
+    .and_then(|q| {
+        let IntegrityQuote {
+            mut quote,
+            ima_ml,
+            mut reservations,
+        } = q;
+        if let Some((file, range)) = ima_ml {
+            quote.ima_measurement_list = Some(service::read_ima_ml(
+                &data,
+                &file,
+                &range,
+                &mut reservations,
+            )?);
+        }
+        Ok(quotes_handler::for_api_version(&req, quote))
+    });
+
+    let quote = match result {
+        Ok(quote) => quote,
+        Err(e) => {
+            warn!(
+                "GET EAT evidence returning {} response. {e}",
+                e.status().as_u16()
+            );
+            return e.to_response();
+        }
+    };
+
+    let iat = data.clock.unix_time();
+
+    match EatClaims::new(&data.agent_uuid, &param.nonce, iat, quote)
+        .and_then(|claims| encode(&req, &claims, &data))
+    {
+        Ok(response) => {
+            info!("GET EAT evidence returning 200 response");
+            response
+        }
+        Err(e) => {
+            warn!("GET EAT evidence returning 500 response. {e}");
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                "Unable to retrieve evidence".to_string(),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        asset_tag::AssetTag,
+        kernel_boot::{BootInfo, BootKind},
+        quotes_handler::{Continuity, PcrSelection, CONTINUITY_VERSION},
+    };
+
+    fn quote() -> KeylimeQuote {
+        KeylimeQuote {
+            quote: "r1234".to_string(),
+            hash_alg: "sha256".to_string(),
+            enc_alg: "rsa".to_string(),
+            sign_alg: "rsassa".to_string(),
+            pubkey: Some("pubkey".to_string()),
+            ima_measurement_list: Some("10 abcd ima-ng\n".to_string()),
+            ima_measurement_list_entry: Some(3),
+            mb_measurement_list: Some(STANDARD.encode(b"\x00\x01")),
+            mb_measurement_list_etag: None,
+            pcr_selection: Some(PcrSelection {
+                hash_alg: "sha384".to_string(),
+                pcrs: vec![0, 16],
+            }),
+            boot_epoch: Some(2),
+            boot: Some(BootInfo {
+                boot_id: "boot-id".to_string(),
+                kernel_build_id: Some("abcd".to_string()),
+                kernel_version: "Linux version 6.5.0".to_string(),
+                boot_kind: BootKind::Kexec,
+            }),
+            asset_tag: Some(AssetTag {
+                nv_index: "0x01c10000".to_string(),
+                data: b"DE".to_vec(),
+                signature: None,
+            }),
+            agent_nonce: Some("00ff".to_string()),
+            continuity: Some(Continuity {
+                version: CONTINUITY_VERSION,
+                agent_starts: 7,
+                tpm_reset_count: 12,
+                tpm_restart_count: 0,
+            }),
+        }
+    }
+
+    #[test]
+    fn test_claims_json() {
+        let claims =
+            EatClaims::new("agent-uuid", "nonce1234", 1700000000, quote())
+                .unwrap(); //#[allow_ci]
+        let json = claims.to_json();
+
+        assert_eq!(json["eat_profile"], EAT_PROFILE);
+        assert_eq!(json["eat_nonce"], "nonce1234");
+        assert_eq!(json["iat"], 1700000000);
+        let ueid = URL_SAFE_NO_PAD
+            .decode(json["ueid"].as_str().unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        assert_eq!(ueid.len(), 33);
+        assert_eq!(ueid[0], UEID_TYPE_RAND);
+
+        assert_eq!(json["submods"]["tpm"]["keylime_quote"], "r1234");
+        assert_eq!(json["submods"]["tpm"]["keylime_pubkey"], "pubkey");
+        assert_eq!(json["submods"]["tpm"]["keylime_pcr_bank"], "sha384");
+        assert_eq!(json["submods"]["tpm"]["keylime_pcrs"], "0,16");
+        assert_eq!(json["submods"]["tpm"]["keylime_boot_epoch"], 2);
+        assert_eq!(json["submods"]["tpm"]["keylime_agent_nonce"], "00ff");
+        assert_eq!(json["submods"]["tpm"]["keylime_agent_starts"], 7);
+        assert_eq!(json["submods"]["tpm"]["keylime_tpm_reset_count"], 12);
+        assert_eq!(json["submods"]["tpm"]["keylime_tpm_restart_count"], 0);
+        assert_eq!(json["submods"]["kernel"]["keylime_boot_kind"], "kexec");
+        assert_eq!(json["submods"]["asset_tag"]["keylime_asset_tag"], "REU");
+        assert_eq!(
+            json["submods"]["ima"]["keylime_ima_measurement_list_entry"],
+            3
+        );
+        assert_eq!(
+            json["submods"]["measured_boot"]["keylime_tcg_event_log"],
+            "AAE"
+        );
+    }
+
+    #[test]
+    fn test_claims_cbor() {
+        let claims = EatClaims::new(
+            "agent-uuid",
+            "nonce1234",
+            1700000000,
+            KeylimeQuote {
+                ima_measurement_list: None,
+                mb_measurement_list: None,
+                boot: None,
+                asset_tag: None,
+                ..quote()
+            },
+        )
+        .unwrap(); //#[allow_ci]
+
+        let decoded: CborValue =
+            ciborium::from_reader(claims.to_cbor().unwrap().as_slice()) //#[allow_ci]
+                .unwrap(); //#[allow_ci]
+        let map = decoded.into_map().unwrap(); //#[allow_ci]
+        assert!(map.contains(&(
+            CborValue::from(CLAIM_EAT_NONCE),
+            CborValue::Bytes(b"nonce1234".to_vec())
+        )));
+        assert!(map.contains(&(
+            CborValue::from(CLAIM_IAT),
+            CborValue::from(1700000000u64)
+        )));
+
+        let (_, submods) = map
+            .iter()
+            .find(|(k, _)| k == &CborValue::from(CLAIM_SUBMODS))
+            .unwrap(); //#[allow_ci]
+        let submods = submods.as_map().unwrap(); //#[allow_ci]
+        assert_eq!(submods.len(), 1);
+        assert_eq!(submods[0].0, CborValue::from("tpm"));
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Network endpoints given by an IP address or a hostname and a port
+//!
+//! IPv6 addresses have to be enclosed in brackets when followed by a port,
+//! as in "[::1]:8890", both in URLs and in socket addresses. The options
+//! accept IPv6 addresses with or without the brackets, which are added back
+//! only when the endpoint is formatted with its port.
+
+use std::{fmt, net::Ipv6Addr};
+
+/// A host and port to connect to or listen on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Endpoint {
+    host: String,
+    port: u32,
+}
+
+impl Endpoint {
+    pub(crate) fn new(host: &str, port: u32) -> Self {
+        let host = host.trim();
+        let host = host
+            .strip_prefix('[')
+            .and_then(|h| h.strip_suffix(']'))
+            .unwrap_or(host);
+
+        Endpoint {
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    /// The host without brackets, as used outside URLs
+    pub(crate) fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub(crate) fn port(&self) -> u32 {
+        self.port
+    }
+
+    pub(crate) fn is_ipv6(&self) -> bool {
+        self.host.parse::<Ipv6Addr>().is_ok()
+    }
+
+    /// The URL for the path in this endpoint, using the given scheme
+    pub(crate) fn url(&self, scheme: &str, path: &str) -> String {
+        match path {
+            "" => format!("{scheme}://{self}"),
+            p if p.starts_with('/') => format!("{scheme}://{self}{p}"),
+            p => format!("{scheme}://{self}/{p}"),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_ipv6() {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        let e = Endpoint::new("127.0.0.1", 8890);
+        assert_eq!(e.to_string(), "127.0.0.1:8890");
+        assert!(!e.is_ipv6());
+
+        let e = Endpoint::new("registrar.example.com", 8890);
+        assert_eq!(e.to_string(), "registrar.example.com:8890");
+        assert_eq!(
+            e.url("http", "v2.1/agents/uuid"),
+            "http://registrar.example.com:8890/v2.1/agents/uuid"
+        );
+
+        let e = Endpoint::new("fd00::1", 8890);
+        assert!(e.is_ipv6());
+        assert_eq!(e.to_string(), "[fd00::1]:8890");
+        assert_eq!(e.url("https", ""), "https://[fd00::1]:8890");
+
+        // Brackets in the configuration are accepted
+        let e = Endpoint::new("[::1]", 9002);
+        assert_eq!(e.host(), "::1");
+        assert_eq!(e.to_string(), "[::1]:9002");
+        assert_eq!(
+            e.to_string().parse::<std::net::SocketAddr>().unwrap(), //#[allow_ci]
+            "[::1]:9002".parse().unwrap() //#[allow_ci]
+        );
+    }
+}
@@ -0,0 +1,211 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Enrollment bundle for the offline provisioning of verifiers
+//!
+//! When the agent can not reach the registrar, e.g. in air-gapped
+//! environments, the data sent on registration is exported to a file
+//! instead, together with the measured boot log and a snapshot of the IMA
+//! measurement list to be used as references. The evidence is encoded as
+//! CBOR and bound to the TPM by a quote using its SHA-256 digest as nonce.
+//! The evidence and the quote are wrapped in a COSE_Sign1 structure signed
+//! with the NK, whose public key is bound to the quote as well.
+
+use crate::{cose, Result};
+use ciborium::value::Value;
+use keylime::{
+    algorithms::{HashAlgorithm, SignAlgorithm},
+    tpm,
+};
+use log::*;
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkey::{PKey, Private, Public},
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use tss_esapi::handles::KeyHandle;
+
+/// Content type of the payload of the COSE_Sign1 structure
+const BUNDLE_CONTENT_TYPE: &str = "application/vnd.keylime.enrollment+cbor";
+
+/// PCRs included in the quote: the boot PCRs and the IMA PCR
+const BUNDLE_PCR_MASK: u32 = 0x7ff;
+
+/// The data describing the agent to the verifier
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Evidence {
+    pub agent_uuid: String,
+    /// The marshalled TPM2B_PUBLIC of the EK
+    pub ek_tpm: Vec<u8>,
+    /// The DER encoded EK certificate
+    pub ek_cert: Option<Vec<u8>>,
+    /// The marshalled TPM2B_PUBLIC of the AK
+    pub aik_tpm: Vec<u8>,
+    /// The PEM encoded mTLS certificate
+    pub mtls_cert: Option<String>,
+    pub hash_alg: String,
+    pub sign_alg: String,
+    pub mb_measurement_list: Option<Vec<u8>>,
+    pub ima_measurement_list: Option<String>,
+    /// Seconds since the Unix epoch
+    pub created: u64,
+}
+
+impl Evidence {
+    fn to_cbor(&self) -> Result<Vec<u8>> {
+        let mut map = vec![
+            (
+                Value::from("agent_uuid"),
+                Value::from(self.agent_uuid.as_str()),
+            ),
+            (Value::from("ek_tpm"), Value::Bytes(self.ek_tpm.clone())),
+            (Value::from("aik_tpm"), Value::Bytes(self.aik_tpm.clone())),
+            (Value::from("hash_alg"), Value::from(self.hash_alg.as_str())),
+            (Value::from("sign_alg"), Value::from(self.sign_alg.as_str())),
+            (Value::from("created"), Value::from(self.created)),
+        ];
+        if let Some(cert) = &self.ek_cert {
+            map.push((Value::from("ekcert"), Value::Bytes(cert.clone())));
+        }
+        if let Some(cert) = &self.mtls_cert {
+            map.push((Value::from("mtls_cert"), Value::from(cert.as_str())));
+        }
+        if let Some(ml) = &self.mb_measurement_list {
+            map.push((
+                Value::from("mb_measurement_list"),
+                Value::Bytes(ml.clone()),
+            ));
+        }
+        if let Some(ml) = &self.ima_measurement_list {
+            map.push((
+                Value::from("ima_measurement_list"),
+                Value::from(ml.as_str()),
+            ));
+        }
+
+        let mut encoded = Vec::new();
+        ciborium::into_writer(&Value::Map(map), &mut encoded)?;
+        Ok(encoded)
+    }
+}
+
+/// Encode the bundle payload: the CBOR encoded evidence, kept as a byte
+/// string so that its digest can be checked against the quote nonce, and
+/// the quote string
+fn payload(evidence: Vec<u8>, quote: String) -> Result<Vec<u8>> {
+    let map = vec![
+        (Value::from("evidence"), Value::Bytes(evidence)),
+        (Value::from("quote"), Value::from(quote)),
+    ];
+    let mut encoded = Vec::new();
+    ciborium::into_writer(&Value::Map(map), &mut encoded)?;
+    Ok(encoded)
+}
+
+/// Create the enrollment bundle, quoting the evidence with the AK
+pub(crate) fn create(
+    ctx: &mut tpm::Context,
+    ak_handle: KeyHandle,
+    evidence: &Evidence,
+    nk_pub: &PKey<Public>,
+    nk_priv: &PKey<Private>,
+) -> Result<Vec<u8>> {
+    let encoded = evidence.to_cbor()?;
+    let nonce = hash(MessageDigest::sha256(), &encoded)?;
+    let quote = ctx.quote(
+        &nonce,
+        BUNDLE_PCR_MASK,
+        nk_pub,
+        ak_handle,
+        HashAlgorithm::try_from(evidence.hash_alg.as_str())?,
+        SignAlgorithm::try_from(evidence.sign_alg.as_str())?,
+    )?;
+
+    cose::sign_payload(payload(encoded, quote)?, BUNDLE_CONTENT_TYPE, nk_priv)
+}
+
+/// Write the bundle to the directory, naming the file after the agent UUID
+pub(crate) fn export(
+    dir: &Path,
+    agent_uuid: &str,
+    bundle: &[u8],
+) -> Result<PathBuf> {
+    let path = dir.join(format!("{agent_uuid}.bundle"));
+    fs::write(&path, bundle)?;
+    info!("Enrollment bundle written to {}", path.display());
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn evidence() -> Evidence {
+        Evidence {
+            agent_uuid: "d432fbb3-d2f1-4a97-9ef7-75bd81c00000".to_string(),
+            ek_tpm: vec![1, 2],
+            aik_tpm: vec![3, 4],
+            hash_alg: "sha256".to_string(),
+            sign_alg: "rsassa".to_string(),
+            ima_measurement_list: Some("10 entry\n".to_string()),
+            created: 1000,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_evidence_cbor() {
+        let encoded = evidence().to_cbor().unwrap(); //#[allow_ci]
+        let map: Value = ciborium::from_reader(encoded.as_slice()).unwrap(); //#[allow_ci]
+        let map = map.into_map().unwrap(); //#[allow_ci]
+        assert!(
+            map.contains(&(Value::from("ek_tpm"), Value::Bytes(vec![1, 2])))
+        );
+        assert!(map.contains(&(
+            Value::from("ima_measurement_list"),
+            Value::from("10 entry\n")
+        )));
+        assert!(!map.iter().any(|(k, _)| k == &Value::from("ekcert")));
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = export(dir.path(), "uuid", &encoded).unwrap(); //#[allow_ci]
+        assert_eq!(path, dir.path().join("uuid.bundle"));
+        assert_eq!(fs::read(path).unwrap(), encoded); //#[allow_ci]
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_create() {
+        use coset::{CoseSign1, TaggedCborSerializable};
+
+        let data = crate::QuoteData::fixture().unwrap(); //#[allow_ci]
+        let mut evidence = evidence();
+        evidence.hash_alg = data.hash_alg.to_string();
+        evidence.sign_alg = data.sign_alg.to_string();
+
+        let bundle = {
+            let mut ctx = data.tpm();
+            create(
+                &mut ctx,
+                data.ak_handle,
+                &evidence,
+                &data.pub_key,
+                &data.priv_key,
+            )
+            .unwrap() //#[allow_ci]
+        };
+
+        let sign1 = CoseSign1::from_tagged_slice(&bundle).unwrap(); //#[allow_ci]
+        let payload: Value =
+            ciborium::from_reader(sign1.payload.unwrap().as_slice()).unwrap(); //#[allow_ci]
+        let map = payload.into_map().unwrap(); //#[allow_ci]
+        assert!(map.contains(&(
+            Value::from("evidence"),
+            Value::Bytes(evidence.to_cbor().unwrap()) //#[allow_ci]
+        )));
+        assert!(map.iter().any(|(k, _)| k == &Value::from("quote")));
+    }
+}
@@ -1,11 +1,45 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use crate::common::JsonWrapper;
+use actix_web::{http::StatusCode, HttpResponse};
+use std::fmt;
 use thiserror::Error;
 use tss_esapi::{
     constants::response_code::Tss2ResponseCodeKind, Error::Tss2Error,
 };
 
+/// The source of an error, used to decide how it is reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorKind {
+    /// Errors returned by the TPM or the TSS
+    Tpm,
+    /// Errors accessing files, devices and the network
+    Io,
+    /// Invalid or missing configuration, not solved by retrying
+    Config,
+    /// Malformed or unexpected messages, from the clients or the servers
+    Protocol,
+    /// Errors in the cryptographic operations
+    Crypto,
+    /// Everything else, e.g. the failure of an internal channel
+    Internal,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            ErrorKind::Tpm => "TPM",
+            ErrorKind::Io => "IO",
+            ErrorKind::Config => "configuration",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Crypto => "crypto",
+            ErrorKind::Internal => "internal",
+        };
+        f.write_str(kind)
+    }
+}
+
 #[derive(Error, Debug)]
 pub(crate) enum Error {
     #[error("HttpServer error: {0}")]
@@ -31,8 +65,18 @@ pub(crate) enum Error {
     Configuration(String),
     #[error("Reqwest error: {0}")]
     Reqwest(#[from] reqwest::Error),
-    #[error("Registrar error: received {code} from {addr}")]
-    Registrar { addr: String, code: u16 },
+    #[error("HTTP error: {0}")]
+    Hyper(#[from] hyper::Error),
+    #[error("TLS error: {0}")]
+    Tls(String),
+    #[error("HTTP error: received {code} from {url}")]
+    Http { url: String, code: u16 },
+    #[error("Registrar error: received {code} from {addr}: {status}")]
+    Registrar {
+        addr: String,
+        code: u16,
+        status: String,
+    },
     #[error("Serialization/deserialization error: {0}")]
     Serde(#[from] serde_json::Error),
     #[error("Permission error")]
@@ -87,20 +131,166 @@ pub(crate) enum Error {
     Sender(String),
     #[error("Error receiving internal message: {0}")]
     Receiver(String),
+    #[error("Access denied: {0}")]
+    AccessDenied(String),
+    #[error("Payload error: {0}")]
+    Payload(#[from] keylime::payload::PayloadError),
     #[error("List parser error: {0}")]
     ListParser(#[from] keylime::list_parser::Error),
+    #[error("Event log error: {0}")]
+    EventLog(#[from] keylime::event_log::EventLogError),
+    #[error("Hashing error: {0}")]
+    Hashing(#[from] keylime::hashing::HashingError),
     #[error("Zip error: {0}")]
     Zip(#[from] zip::result::ZipError),
+    #[error("CBOR encoding error: {0}")]
+    Cbor(#[from] ciborium::ser::Error<std::io::Error>),
+    #[error("CBOR value error: {0}")]
+    CborValue(#[from] ciborium::value::Error),
+    #[error("COSE error: {0}")]
+    Cose(#[from] coset::CoseError),
+    #[cfg(feature = "local-appraisal")]
+    #[error("SQLite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[cfg(feature = "local-appraisal")]
+    #[error("Regex error: {0}")]
+    Regex(#[from] regex::Error),
+    #[cfg(feature = "grpc")]
+    #[error("gRPC transport error: {0}")]
+    GrpcTransport(#[from] tonic::transport::Error),
     #[error("{0}")]
     Other(String),
+    #[error("{context}: {source}")]
+    Context {
+        context: String,
+        #[source]
+        source: Box<Error>,
+    },
 }
 
-impl actix_web::ResponseError for Error {}
+impl actix_web::ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        if let Error::ActixWeb(e) = self.root() {
+            return e.as_response_error().status_code();
+        }
+        if self.is_retryable() {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        match self.kind() {
+            ErrorKind::Protocol => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        HttpResponse::build(status)
+            .json(JsonWrapper::error(status.as_u16(), self.to_string()))
+    }
+}
 
 impl Error {
-    pub(crate) fn http_code(&self) -> Result<u16> {
+    /// The innermost error, skipping the added context
+    pub(crate) fn root(&self) -> &Error {
         match self {
-            Error::Registrar { addr, code } => Ok(*code),
+            Error::Context { source, .. } => source.root(),
+            other => other,
+        }
+    }
+
+    pub(crate) fn kind(&self) -> ErrorKind {
+        match self.root() {
+            Error::Tss2 { .. } | Error::Tpm(_) | Error::TpmInUse => {
+                ErrorKind::Tpm
+            }
+            Error::Io(_)
+            | Error::Glob(_)
+            | Error::PathPersist(_)
+            | Error::Persist(_)
+            | Error::Zip(_)
+            | Error::SecureMount(_) => ErrorKind::Io,
+            #[cfg(feature = "local-appraisal")]
+            Error::Sqlite(_) => ErrorKind::Io,
+            Error::Config(_)
+            | Error::Configuration(_)
+            | Error::GlobPattern(_)
+            | Error::Permission => ErrorKind::Config,
+            #[cfg(feature = "local-appraisal")]
+            Error::Regex(_) => ErrorKind::Config,
+            Error::ActixWeb(_)
+            | Error::InvalidRequest
+            | Error::Conversion(_)
+            | Error::Reqwest(_)
+            | Error::Hyper(_)
+            | Error::Http { .. }
+            | Error::Registrar { .. }
+            | Error::Serde(_)
+            | Error::Utf8(_)
+            | Error::Uuid(_)
+            | Error::NumParse(_)
+            | Error::Base64(_)
+            | Error::ParseBool(_)
+            | Error::FromHex(_)
+            | Error::PickyAsn1(_)
+            | Error::ListParser(_)
+            | Error::EventLog(_)
+            | Error::Hashing(_)
+            | Error::Payload(_)
+            | Error::Cbor(_)
+            | Error::CborValue(_)
+            | Error::Cose(_) => ErrorKind::Protocol,
+            #[cfg(feature = "with-zmq")]
+            Error::Zmq(_) => ErrorKind::Protocol,
+            #[cfg(feature = "grpc")]
+            Error::GrpcTransport(_) => ErrorKind::Protocol,
+            Error::Crypto(_) | Error::Algorithm(_) | Error::Tls(_) => {
+                ErrorKind::Crypto
+            }
+            _ => ErrorKind::Internal,
+        }
+    }
+
+    /// Whether the operation may succeed if retried later, e.g. when the
+    /// TPM is busy or the registrar is not reachable. Misconfigurations and
+    /// malformed messages are never retryable.
+    pub(crate) fn is_retryable(&self) -> bool {
+        match self.root() {
+            Error::Tss2 { err, .. } => keylime::tpm::is_retryable(err),
+            Error::Tpm(e) => e.is_retryable(),
+            Error::TpmInUse => true,
+            Error::Io(e) => matches!(
+                e.kind(),
+                std::io::ErrorKind::Interrupted
+                    | std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::ConnectionRefused
+                    | std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::NotConnected
+                    | std::io::ErrorKind::BrokenPipe
+            ),
+            Error::Reqwest(e) => e.is_timeout() || e.is_connect(),
+            Error::Hyper(e) => e.is_timeout() || e.is_closed(),
+            Error::Http { code, .. } | Error::Registrar { code, .. } => {
+                *code == 429 || *code >= 500
+            }
+            _ => false,
+        }
+    }
+
+    /// Add a description of the operation which failed
+    pub(crate) fn context(self, context: impl Into<String>) -> Error {
+        Error::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    pub(crate) fn http_code(&self) -> Result<u16> {
+        match self.root() {
+            Error::Http { code, .. } | Error::Registrar { code, .. } => {
+                Ok(*code)
+            }
             other => Err(Error::Other(format!(
                 "cannot get http code for Error type {other}"
             ))),
@@ -108,7 +298,7 @@ impl Error {
     }
 
     pub(crate) fn exe_code(&self) -> Result<Option<i32>> {
-        match self {
+        match self.root() {
             Error::Execution(code, _) => Ok(code.to_owned()),
             other => Err(Error::Other(format!(
                 "cannot get execution status code for Error type {other}"
@@ -117,7 +307,7 @@ impl Error {
     }
 
     pub(crate) fn stderr(&self) -> Result<String> {
-        match self {
+        match self.root() {
             Error::Execution(_, stderr) => Ok(stderr.to_owned()),
             other => Err(Error::Other(format!(
                 "cannot get stderr for Error type {other}"
@@ -149,3 +339,88 @@ impl From<tss_esapi::Error> for Error {
 }
 
 pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+/// Add context to the errors of any result convertible to this crate's
+pub(crate) trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+
+    fn with_context<C, F>(self, f: F) -> Result<T>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C;
+}
+
+impl<T, E: Into<Error>> Context<T> for std::result::Result<T, E> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+
+    fn with_context<C, F>(self, f: F) -> Result<T>
+    where
+        C: Into<String>,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|e| e.into().context(f()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::ResponseError;
+    use std::io;
+
+    #[test]
+    fn test_classification() {
+        let e = Error::Configuration("missing option".to_string());
+        assert_eq!(e.kind(), ErrorKind::Config);
+        assert!(!e.is_retryable());
+        assert_eq!(e.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let e = Error::TpmInUse;
+        assert_eq!(e.kind(), ErrorKind::Tpm);
+        assert!(e.is_retryable());
+        assert_eq!(e.status_code(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let e = Error::Registrar {
+            addr: "127.0.0.1".to_string(),
+            code: 503,
+            status: String::new(),
+        };
+        assert!(e.is_retryable());
+        let e = Error::Registrar {
+            addr: "127.0.0.1".to_string(),
+            code: 400,
+            status: String::new(),
+        };
+        assert!(!e.is_retryable());
+        assert_eq!(e.status_code(), StatusCode::BAD_REQUEST);
+
+        let e = Error::from(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert!(e.is_retryable());
+        let e = Error::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(!e.is_retryable());
+    }
+
+    #[test]
+    fn test_context() {
+        let r: std::result::Result<(), io::Error> =
+            Err(io::Error::from(io::ErrorKind::TimedOut));
+        let e = r
+            .context("reading the measurement list")
+            .with_context(|| format!("quoting with nonce {}", "abc"))
+            .unwrap_err(); //#[allow_ci]
+
+        assert_eq!(e.kind(), ErrorKind::Io);
+        assert!(e.is_retryable());
+        assert!(matches!(e.root(), Error::Io(_)));
+        assert!(e.to_string().starts_with(
+            "quoting with nonce abc: reading the measurement list: "
+        ));
+        let source = std::error::Error::source(&e).unwrap(); //#[allow_ci]
+        assert!(source
+            .to_string()
+            .starts_with("reading the measurement list"));
+    }
+}
@@ -62,7 +62,7 @@ pub(crate) async fn api_default(req: HttpRequest) -> impl Responder {
     match req.head().method {
         http::Method::GET => {
             error = 400;
-            message = "Not Implemented: Use /keys/ or /quotes/ interfaces";
+            message = "Not Implemented: Use /keys/, /quotes/ or /evidence/ interfaces";
             response = HttpResponse::BadRequest()
                 .json(JsonWrapper::error(error, message));
         }
@@ -103,13 +103,19 @@ pub(crate) async fn keys_default(req: HttpRequest) -> impl Responder {
     match req.head().method {
         http::Method::GET => {
             error = 400;
-            message = "URI not supported, only /pubkey and /verify are supported for GET in /keys/ interface";
+            message = "URI not supported, only /delivered, /pubkey and /verify are supported for GET in /keys/ interface";
             response = HttpResponse::BadRequest()
                 .json(JsonWrapper::error(error, message));
         }
         http::Method::POST => {
             error = 400;
-            message = "URI not supported, only /ukey and /vkey are supported for POST in /keys/ interface";
+            message = "URI not supported, only /ukey, /vkey and /certify are supported for POST in /keys/ interface";
+            response = HttpResponse::BadRequest()
+                .json(JsonWrapper::error(error, message));
+        }
+        http::Method::DELETE => {
+            error = 400;
+            message = "URI not supported, only /delivered is supported for DELETE in /keys/ interface";
             response = HttpResponse::BadRequest()
                 .json(JsonWrapper::error(error, message));
         }
@@ -120,6 +126,7 @@ pub(crate) async fn keys_default(req: HttpRequest) -> impl Responder {
                 .insert_header(http::header::Allow(vec![
                     http::Method::GET,
                     http::Method::POST,
+                    http::Method::DELETE,
                 ]))
                 .json(JsonWrapper::error(error, message));
         }
@@ -166,6 +173,37 @@ pub(crate) async fn quotes_default(req: HttpRequest) -> impl Responder {
     response
 }
 
+pub(crate) async fn evidence_default(req: HttpRequest) -> impl Responder {
+    let error;
+    let response;
+    let message;
+
+    match req.head().method {
+        http::Method::GET => {
+            error = 400;
+            message = "URI not supported, only /eat and /measured_boot are supported for GET in /evidence/ interface";
+            response = HttpResponse::BadRequest()
+                .json(JsonWrapper::error(error, message));
+        }
+        _ => {
+            error = 405;
+            message = "Method is not supported in /evidence/ interface";
+            response = HttpResponse::MethodNotAllowed()
+                .insert_header(http::header::Allow(vec![http::Method::GET]))
+                .json(JsonWrapper::error(error, message));
+        }
+    };
+
+    warn!(
+        "{} returning {} response. {}",
+        req.head().method,
+        error,
+        message
+    );
+
+    response
+}
+
 pub(crate) async fn notifications_default(
     req: HttpRequest,
 ) -> impl Responder {
@@ -301,7 +339,19 @@ mod tests {
             assert_eq!(result.code, 400);
         }
 
-        let req = test::TestRequest::delete().uri("/").to_request();
+        if allow.contains("DELETE") {
+            let req = test::TestRequest::delete().uri("/").to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert!(resp.status().is_client_error());
+
+            let result: JsonWrapper<Value> = test::read_body_json(resp).await;
+
+            assert_eq!(result.results, json!({}));
+            assert_eq!(result.code, 400);
+        }
+
+        let req = test::TestRequest::put().uri("/").to_request();
 
         let resp = test::call_service(&app, req).await;
         assert!(resp.status().is_client_error());
@@ -329,7 +379,8 @@ mod tests {
 
     #[actix_rt::test]
     async fn test_keys_default() {
-        test_default(web::resource("/").to(keys_default), "GET, POST").await
+        test_default(web::resource("/").to(keys_default), "GET, POST, DELETE")
+            .await
     }
 
     #[actix_rt::test]
@@ -337,6 +388,11 @@ mod tests {
         test_default(web::resource("/").to(quotes_default), "GET").await
     }
 
+    #[actix_rt::test]
+    async fn test_evidence_default() {
+        test_default(web::resource("/").to(evidence_default), "GET").await
+    }
+
     #[actix_rt::test]
     async fn test_notifications_default() {
         test_default(web::resource("/").to(notifications_default), "POST")
@@ -0,0 +1,511 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Emission of agent lifecycle events as CloudEvents
+//!
+//! The events are queued by the EventPublisher without blocking the caller
+//! and delivered to the configured sink by the events worker, using the
+//! structured content mode of the CloudEvents specification v1.0.
+
+use crate::{
+    clock::{self, Clock, SharedClock},
+    http_client::{self, HttpClient},
+    Error, Result,
+};
+use chrono::SecondsFormat;
+use log::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::mpsc::{error::TrySendError, Receiver, Sender};
+use uuid::Uuid;
+
+/// Number of events queued before new events are dropped
+pub(crate) const EVENTS_QUEUE_SIZE: usize = 64;
+
+pub(crate) const CLOUDEVENTS_CONTENT_TYPE: &str =
+    "application/cloudevents+json";
+
+/// An event in the CloudEvents v1.0 JSON format
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub(crate) struct CloudEvent {
+    pub specversion: String,
+    pub id: String,
+    pub source: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub time: String,
+    pub datacontenttype: String,
+    pub data: Value,
+}
+
+/// The agent lifecycle events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum AgentEvent {
+    Registered {
+        registrar: String,
+    },
+    AttestationServed {
+        quote: &'static str,
+    },
+    KeysDelivered,
+    KeysWiped,
+    KeysExpired,
+    PayloadExecuted,
+    PayloadRejected {
+        reason: String,
+    },
+    RevocationReceived {
+        processed: bool,
+    },
+    AkRegenerated {
+        reason: String,
+    },
+    TpmStateChanged {
+        change: String,
+    },
+    BootChanged {
+        epoch: u64,
+        log_changed: bool,
+        pcrs: Vec<u32>,
+    },
+    KernelRestarted {
+        kind: String,
+        boot_id: String,
+    },
+    SelfAttestationFailed {
+        inconsistency: String,
+    },
+    DiskUnlock {
+        device: String,
+        status: &'static str,
+        error: Option<String>,
+    },
+    FileChanged {
+        path: String,
+        digest: String,
+        access: String,
+    },
+}
+
+impl AgentEvent {
+    fn event_type(&self) -> &'static str {
+        match self {
+            AgentEvent::Registered { .. } => "org.keylime.agent.registered",
+            AgentEvent::AttestationServed { .. } => {
+                "org.keylime.agent.attestation.served"
+            }
+            AgentEvent::KeysDelivered => "org.keylime.agent.keys.delivered",
+            AgentEvent::KeysWiped => "org.keylime.agent.keys.wiped",
+            AgentEvent::KeysExpired => "org.keylime.agent.keys.expired",
+            AgentEvent::PayloadExecuted => {
+                "org.keylime.agent.payload.executed"
+            }
+            AgentEvent::PayloadRejected { .. } => {
+                "org.keylime.agent.payload.rejected"
+            }
+            AgentEvent::RevocationReceived { .. } => {
+                "org.keylime.agent.revocation.received"
+            }
+            AgentEvent::AkRegenerated { .. } => {
+                "org.keylime.agent.ak.regenerated"
+            }
+            AgentEvent::TpmStateChanged { .. } => {
+                "org.keylime.agent.tpm.changed"
+            }
+            AgentEvent::BootChanged { .. } => {
+                "org.keylime.agent.boot.changed"
+            }
+            AgentEvent::KernelRestarted { .. } => {
+                "org.keylime.agent.kernel.restarted"
+            }
+            AgentEvent::SelfAttestationFailed { .. } => {
+                "org.keylime.agent.selfattestation.failed"
+            }
+            AgentEvent::DiskUnlock { .. } => "org.keylime.agent.disk.unlock",
+            AgentEvent::FileChanged { .. } => {
+                "org.keylime.agent.file.changed"
+            }
+        }
+    }
+
+    fn data(&self) -> Value {
+        match self {
+            AgentEvent::Registered { registrar } => {
+                json!({ "registrar": registrar })
+            }
+            AgentEvent::AttestationServed { quote } => {
+                json!({ "quote": quote })
+            }
+            AgentEvent::KeysDelivered
+            | AgentEvent::KeysWiped
+            | AgentEvent::KeysExpired
+            | AgentEvent::PayloadExecuted => {
+                json!({})
+            }
+            AgentEvent::RevocationReceived { processed } => {
+                json!({ "processed": processed })
+            }
+            AgentEvent::PayloadRejected { reason }
+            | AgentEvent::AkRegenerated { reason } => {
+                json!({ "reason": reason })
+            }
+            AgentEvent::TpmStateChanged { change } => {
+                json!({ "change": change })
+            }
+            AgentEvent::BootChanged {
+                epoch,
+                log_changed,
+                pcrs,
+            } => {
+                json!({ "epoch": epoch, "log_changed": log_changed, "pcrs": pcrs })
+            }
+            AgentEvent::KernelRestarted { kind, boot_id } => {
+                json!({ "kind": kind, "boot_id": boot_id })
+            }
+            AgentEvent::SelfAttestationFailed { inconsistency } => {
+                json!({ "inconsistency": inconsistency })
+            }
+            AgentEvent::DiskUnlock {
+                device,
+                status,
+                error,
+            } => match error {
+                Some(error) => {
+                    json!({ "device": device, "status": status, "error": error })
+                }
+                None => json!({ "device": device, "status": status }),
+            },
+            AgentEvent::FileChanged {
+                path,
+                digest,
+                access,
+            } => {
+                json!({ "path": path, "digest": digest, "access": access })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum EventMessage {
+    Event(Box<CloudEvent>),
+    Shutdown,
+}
+
+/// Queues the agent events for the workers subscribed to them. The default
+/// publisher has no subscribers and discards all events.
+#[derive(Debug, Clone)]
+pub(crate) struct EventPublisher {
+    source: String,
+    subscribers: Vec<Sender<EventMessage>>,
+    clock: SharedClock,
+}
+
+impl Default for EventPublisher {
+    fn default() -> Self {
+        EventPublisher {
+            source: String::new(),
+            subscribers: Vec::new(),
+            clock: clock::system(),
+        }
+    }
+}
+
+impl EventPublisher {
+    pub(crate) fn new(agent_uuid: &str, clock: SharedClock) -> Self {
+        EventPublisher {
+            source: format!("urn:keylime:agent:{agent_uuid}"),
+            subscribers: Vec::new(),
+            clock,
+        }
+    }
+
+    /// The clock timestamping the events
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Add a worker receiving the events published from now on
+    pub(crate) fn subscribe(&mut self, events_tx: Sender<EventMessage>) {
+        self.subscribers.push(events_tx);
+    }
+
+    pub(crate) fn cloud_event(&self, event: &AgentEvent) -> CloudEvent {
+        CloudEvent {
+            specversion: "1.0".to_string(),
+            id: Uuid::new_v4().to_string(),
+            source: self.source.clone(),
+            event_type: event.event_type().to_string(),
+            time: self.clock.rfc3339(SecondsFormat::Millis),
+            datacontenttype: "application/json".to_string(),
+            data: event.data(),
+        }
+    }
+
+    /// Queue the event without waiting. The event is dropped if the queue
+    /// is full, as the agent operations must not be delayed by the sink.
+    pub(crate) fn publish(&self, event: AgentEvent) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+
+        let cloud_event = self.cloud_event(&event);
+        for events_tx in &self.subscribers {
+            let message = EventMessage::Event(Box::new(cloud_event.clone()));
+            match events_tx.try_send(message) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("Events queue is full, dropping {:?} event", event);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    debug!(
+                        "Events worker stopped, dropping {:?} event",
+                        event
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Destination of the events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Sink {
+    Http {
+        url: String,
+        auth_token: Option<String>,
+    },
+    #[cfg(feature = "cloudevents-kafka")]
+    Kafka { hosts: Vec<String>, topic: String },
+}
+
+impl Sink {
+    /// Parse the sink from the configuration. An HTTP(S) URL is used as a
+    /// webhook, while "kafka://host:port[,host:port]/topic" selects a Kafka
+    /// topic. An empty sink disables the events.
+    pub(crate) fn parse(
+        sink: &str,
+        auth_token: &str,
+    ) -> Result<Option<Sink>> {
+        let auth_token = match auth_token {
+            "" => None,
+            t => Some(t.to_string()),
+        };
+
+        if sink.is_empty() {
+            return Ok(None);
+        }
+
+        if sink.starts_with("http://") || sink.starts_with("https://") {
+            return Ok(Some(Sink::Http {
+                url: sink.to_string(),
+                auth_token,
+            }));
+        }
+
+        if let Some(kafka) = sink.strip_prefix("kafka://") {
+            #[cfg(feature = "cloudevents-kafka")]
+            {
+                let Some((hosts, topic)) = kafka.split_once('/') else {
+                    return Err(Error::Configuration(format!(
+                        "Kafka events sink {sink} does not specify a topic"
+                    )));
+                };
+                if auth_token.is_some() {
+                    warn!("The events authentication token is not supported for Kafka sinks");
+                }
+                return Ok(Some(Sink::Kafka {
+                    hosts: hosts.split(',').map(str::to_string).collect(),
+                    topic: topic.to_string(),
+                }));
+            }
+
+            #[cfg(not(feature = "cloudevents-kafka"))]
+            return Err(Error::Configuration(format!(
+                "Kafka events sink {sink} requires the agent to be built with the 'cloudevents-kafka' feature"
+            )));
+        }
+
+        Err(Error::Configuration(format!(
+            "Invalid events sink {sink}: expected an HTTP(S) or Kafka URL"
+        )))
+    }
+}
+
+async fn send_http(
+    client: &HttpClient,
+    url: &str,
+    auth_token: Option<&str>,
+    event: &CloudEvent,
+) -> Result<()> {
+    let body = serde_json::to_vec(event)?;
+    client
+        .retry(url, || async {
+            let mut request = client
+                .request(reqwest::Method::POST, url)
+                .header(
+                    reqwest::header::CONTENT_TYPE,
+                    CLOUDEVENTS_CONTENT_TYPE,
+                )
+                .body(body.clone());
+            if let Some(token) = auth_token {
+                request = request.bearer_auth(token);
+            }
+
+            let response = request.send().await?;
+            http_client::check_status(url, response.status().as_u16())
+        })
+        .await
+}
+
+#[cfg(feature = "cloudevents-kafka")]
+async fn send_kafka(
+    producer: kafka::producer::Producer,
+    topic: &str,
+    event: &CloudEvent,
+) -> (Option<kafka::producer::Producer>, Result<()>) {
+    let topic = topic.to_string();
+    let value = match serde_json::to_vec(event) {
+        Ok(v) => v,
+        Err(e) => return (Some(producer), Err(e.into())),
+    };
+
+    // The Kafka producer is blocking
+    let result = tokio::task::spawn_blocking(move || {
+        let mut producer = producer;
+        let result = producer
+            .send(&kafka::producer::Record::from_value(&topic, value))
+            .map_err(|e| format!("Kafka error: {e}"));
+        (producer, result)
+    })
+    .await;
+
+    match result {
+        Ok((producer, result)) => {
+            (Some(producer), result.map_err(Error::Other))
+        }
+        Err(e) => (None, Err(e.into())),
+    }
+}
+
+pub(crate) async fn worker(
+    sink: Sink,
+    client: HttpClient,
+    clock: SharedClock,
+    mut events_rx: Receiver<EventMessage>,
+) -> Result<()> {
+    debug!("Starting events worker");
+
+    #[cfg(feature = "cloudevents-kafka")]
+    let mut producer = match &sink {
+        Sink::Kafka { hosts, .. } => Some(
+            kafka::producer::Producer::from_hosts(hosts.clone())
+                .with_required_acks(kafka::producer::RequiredAcks::One)
+                .create()
+                .map_err(|e| Error::Other(format!("Kafka error: {e}")))?,
+        ),
+        _ => None,
+    };
+
+    while let Some(message) = events_rx.recv().await {
+        let event = match message {
+            EventMessage::Shutdown => {
+                events_rx.close();
+                continue;
+            }
+            EventMessage::Event(event) => event,
+        };
+
+        let result = match &sink {
+            Sink::Http { url, auth_token } => {
+                send_http(&client, url, auth_token.as_deref(), &event).await
+            }
+            #[cfg(feature = "cloudevents-kafka")]
+            Sink::Kafka { topic, .. } => match producer.take() {
+                Some(p) => {
+                    let (p, result) = send_kafka(p, topic, &event).await;
+                    producer = p;
+                    result
+                }
+                None => Err(Error::Other(
+                    "Kafka producer not available".to_string(),
+                )),
+            },
+        };
+
+        match result {
+            Ok(()) => debug!("Sent {} event {}", event.event_type, event.id),
+            Err(e) => {
+                warn!("Failed to send {} event: {}", event.event_type, e);
+                clock::warn_on_skew(&e, "the events sink", clock.as_ref());
+            }
+        }
+    }
+
+    debug!("Shutting down events worker");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::FixedClock;
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn test_parse_sink() {
+        assert_eq!(Sink::parse("", "").unwrap(), None); //#[allow_ci]
+        assert_eq!(
+            Sink::parse("https://soc.example.com/events", "token").unwrap(), //#[allow_ci]
+            Some(Sink::Http {
+                url: "https://soc.example.com/events".to_string(),
+                auth_token: Some("token".to_string()),
+            })
+        );
+        assert!(Sink::parse("ftp://soc.example.com", "").is_err());
+
+        #[cfg(feature = "cloudevents-kafka")]
+        assert_eq!(
+            Sink::parse("kafka://k1:9092,k2:9092/keylime", "").unwrap(), //#[allow_ci]
+            Some(Sink::Kafka {
+                hosts: vec!["k1:9092".to_string(), "k2:9092".to_string()],
+                topic: "keylime".to_string(),
+            })
+        );
+        #[cfg(not(feature = "cloudevents-kafka"))]
+        assert!(Sink::parse("kafka://k1:9092/keylime", "").is_err());
+    }
+
+    #[test]
+    fn test_publish() {
+        // The default publisher discards the events
+        EventPublisher::default().publish(AgentEvent::PayloadExecuted);
+
+        let (events_tx, mut events_rx) = mpsc::channel(1);
+        let mut publisher = EventPublisher::new(
+            "agent-uuid",
+            Arc::new(FixedClock::at(1_700_000_000)),
+        );
+        publisher.subscribe(events_tx);
+        publisher.publish(AgentEvent::RevocationReceived { processed: true });
+        // The queue is full, so this one is dropped without blocking
+        publisher.publish(AgentEvent::PayloadExecuted);
+
+        let Ok(EventMessage::Event(event)) = events_rx.try_recv() else {
+            panic!("expected an event"); //#[allow_ci]
+        };
+        assert_eq!(event.specversion, "1.0");
+        assert_eq!(event.source, "urn:keylime:agent:agent-uuid");
+        assert_eq!(event.time, "2023-11-14T22:13:20.000Z");
+        assert_eq!(event.event_type, "org.keylime.agent.revocation.received");
+        assert_eq!(event.data, json!({"processed": true}));
+        assert!(events_rx.try_recv().is_err());
+
+        let serialized = serde_json::to_value(&*event).unwrap(); //#[allow_ci]
+        assert_eq!(
+            serialized["type"],
+            "org.keylime.agent.revocation.received"
+        );
+    }
+}
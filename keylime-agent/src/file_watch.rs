@@ -0,0 +1,546 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Runtime integrity of a watch list of files
+//!
+//! The verifier or the tenant pushes a list of critical files and
+//! directories, which the agent watches with fanotify. The files are hashed
+//! when they are executed or written, and each new content of a file is
+//! recorded in the journal of the watch list and, if configured, extended
+//! into a PCR. This covers the files the IMA policy misses, without changing
+//! the kernel policy.
+//!
+//! The PCR is extended with the digest of "<hash_alg>:<hex digest> <path>",
+//! so that the verifier can replay the journal against the quoted PCR.
+
+use crate::{
+    common::JsonWrapper,
+    error::{Error, Result},
+    events::AgentEvent,
+    service::ServiceError,
+    QuoteData,
+};
+use actix_web::{web, HttpResponse, Responder};
+use keylime::{algorithms::HashAlgorithm, hashing};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashSet, VecDeque},
+    ffi::CString,
+    fs, io,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd},
+        unix::ffi::OsStrExt,
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::{io::unix::AsyncFd, sync::mpsc::Receiver};
+
+/// Maximum number of paths in the watch list
+pub(crate) const MAX_WATCH_PATHS: usize = 256;
+
+/// Maximum number of measurements kept in the journal. The older
+/// measurements are dropped, and counted, when the journal is full.
+const JOURNAL_SIZE: usize = 4096;
+
+/// The accesses reported for the watched files, and the files in the
+/// watched directories
+const WATCH_MASK: u64 =
+    libc::FAN_OPEN_EXEC | libc::FAN_CLOSE_WRITE | libc::FAN_EVENT_ON_CHILD;
+
+/// A fanotify group, which has to be created while the agent still has the
+/// CAP_SYS_ADMIN capability
+#[derive(Debug)]
+pub(crate) struct Fanotify(OwnedFd);
+
+impl Fanotify {
+    pub(crate) fn init() -> Result<Self> {
+        let fd = unsafe {
+            libc::fanotify_init(
+                libc::FAN_CLASS_NOTIF
+                    | libc::FAN_CLOEXEC
+                    | libc::FAN_NONBLOCK,
+                (libc::O_RDONLY | libc::O_LARGEFILE | libc::O_CLOEXEC)
+                    as libc::c_uint,
+            )
+        };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            error!("Unable to create the fanotify group for the file watch list, which requires CAP_SYS_ADMIN: {e}");
+            return Err(Error::Io(e));
+        }
+        Ok(Fanotify(unsafe { OwnedFd::from_raw_fd(fd) }))
+    }
+}
+
+/// The content of a watched file at the time of an access
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct FileMeasurement {
+    pub path: String,
+    pub hash_alg: String,
+    pub digest: String,
+    /// "exec" or "write"
+    pub access: String,
+    pub timestamp: String,
+}
+
+impl FileMeasurement {
+    /// The data whose digest is extended into the PCR
+    fn event_data(&self) -> Vec<u8> {
+        format!("{}:{} {}", self.hash_alg, self.digest, self.path)
+            .into_bytes()
+    }
+}
+
+/// The watch list and the journal of its measurements
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct WatchReport {
+    pub paths: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pcr: Option<u32>,
+    pub dropped: u64,
+    pub journal: Vec<FileMeasurement>,
+}
+
+/// The watch list pushed by the verifier or the tenant
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct WatchList {
+    pub paths: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct WatchState {
+    paths: Vec<PathBuf>,
+    journal: VecDeque<FileMeasurement>,
+    dropped: u64,
+    // The contents already measured, as (path, digest)
+    measured: HashSet<(String, String)>,
+}
+
+impl WatchState {
+    /// Record the measurement, returning whether the content of the file
+    /// was not measured before
+    fn record(&mut self, measurement: FileMeasurement) -> bool {
+        let key = (measurement.path.clone(), measurement.digest.clone());
+        if !self.measured.insert(key) {
+            return false;
+        }
+        if self.journal.len() == JOURNAL_SIZE {
+            _ = self.journal.pop_front();
+            self.dropped += 1;
+        }
+        self.journal.push_back(measurement);
+        true
+    }
+
+    /// Whether another content of the file was measured before
+    fn changed(&self, measurement: &FileMeasurement) -> bool {
+        self.measured
+            .iter()
+            .any(|(p, d)| *p == measurement.path && *d != measurement.digest)
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FileWatch {
+    fanotify: AsyncFd<OwnedFd>,
+    pcr: Option<u32>,
+    state: Mutex<WatchState>,
+}
+
+#[derive(Debug)]
+pub(crate) enum FileWatchMessage {
+    Shutdown,
+}
+
+/// Validate the paths of the watch list, which have to be absolute
+fn parse_paths(
+    paths: &[String],
+) -> std::result::Result<Vec<PathBuf>, String> {
+    if paths.len() > MAX_WATCH_PATHS {
+        return Err(format!(
+            "the watch list can have up to {MAX_WATCH_PATHS} paths"
+        ));
+    }
+    paths
+        .iter()
+        .map(|p| {
+            let path = Path::new(p);
+            if path.is_absolute() && !p.contains('\0') {
+                Ok(path.to_path_buf())
+            } else {
+                Err(format!("the path should be absolute: {p}"))
+            }
+        })
+        .collect()
+}
+
+/// A fanotify event, with the descriptor of the accessed file
+#[derive(Debug)]
+struct RawEvent {
+    mask: u64,
+    fd: Option<OwnedFd>,
+}
+
+/// Parse the events read from the fanotify group, taking the ownership of
+/// their file descriptors
+fn parse_events(buf: &[u8]) -> Vec<RawEvent> {
+    let header = size_of::<libc::fanotify_event_metadata>();
+    let mut events = Vec::new();
+    let mut offset = 0;
+    while buf.len() - offset >= header {
+        // The events are not aligned in the buffer
+        let metadata = unsafe {
+            std::ptr::read_unaligned(buf[offset..].as_ptr()
+                as *const libc::fanotify_event_metadata)
+        };
+        let len = metadata.event_len as usize;
+        if metadata.vers != libc::FANOTIFY_METADATA_VERSION
+            || len < header
+            || len > buf.len() - offset
+        {
+            break;
+        }
+        let fd = (metadata.fd >= 0)
+            .then(|| unsafe { OwnedFd::from_raw_fd(metadata.fd) });
+        events.push(RawEvent {
+            mask: metadata.mask,
+            fd,
+        });
+        offset += len;
+    }
+    events
+}
+
+/// The path and the digest of the content of the accessed file
+fn measure(fd: OwnedFd, hash_alg: HashAlgorithm) -> Result<(String, String)> {
+    let path = fs::read_link(format!("/proc/self/fd/{}", fd.as_raw_fd()))?;
+    let digest = hashing::digest_reader(
+        hashing::default_backend(),
+        hash_alg,
+        fs::File::from(fd),
+    )?;
+    Ok((path.to_string_lossy().into_owned(), hex::encode(digest)))
+}
+
+impl FileWatch {
+    pub(crate) fn new(fanotify: Fanotify, pcr: Option<u32>) -> Result<Self> {
+        Ok(FileWatch {
+            fanotify: AsyncFd::new(fanotify.0)?,
+            pcr,
+            state: Mutex::new(WatchState::default()),
+        })
+    }
+
+    fn mark(
+        &self,
+        flags: libc::c_uint,
+        path: Option<&Path>,
+    ) -> io::Result<()> {
+        let path = path
+            .map(|p| CString::new(p.as_os_str().as_bytes()))
+            .transpose()?;
+        let rc = unsafe {
+            libc::fanotify_mark(
+                self.fanotify.as_raw_fd(),
+                flags,
+                WATCH_MASK,
+                libc::AT_FDCWD,
+                path.as_ref().map_or(std::ptr::null(), |p| p.as_ptr()),
+            )
+        };
+        if rc < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Replace the watch list. The journal of the previous measurements is
+    /// kept.
+    pub(crate) fn set_paths(
+        &self,
+        paths: &[String],
+    ) -> std::result::Result<(), ServiceError> {
+        let paths = parse_paths(paths).map_err(ServiceError::BadRequest)?;
+        let mut state = self.state.lock().unwrap(); //#[allow_ci]
+        self.mark(libc::FAN_MARK_FLUSH, None).map_err(|e| {
+            ServiceError::internal(e, "Failed to clear the file watch list")
+        })?;
+        state.paths.clear();
+        for path in paths {
+            self.mark(libc::FAN_MARK_ADD, Some(&path)).map_err(|e| {
+                ServiceError::BadRequest(format!(
+                    "unable to watch {}: {e}",
+                    path.display()
+                ))
+            })?;
+            state.paths.push(path);
+        }
+        info!("Watching {} paths", state.paths.len());
+        Ok(())
+    }
+
+    pub(crate) fn report(&self) -> WatchReport {
+        let state = self.state.lock().unwrap(); //#[allow_ci]
+        WatchReport {
+            paths: state
+                .paths
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect(),
+            pcr: self.pcr,
+            dropped: state.dropped,
+            journal: state.journal.iter().cloned().collect(),
+        }
+    }
+
+    /// Record the measurement in the journal and the PCR, if the content of
+    /// the file was not measured before
+    fn process(&self, data: &QuoteData, measurement: FileMeasurement) {
+        let (new, changed) = {
+            let mut state = self.state.lock().unwrap(); //#[allow_ci]
+            let changed = state.changed(&measurement);
+            (state.record(measurement.clone()), changed)
+        };
+        if !new {
+            return;
+        }
+        debug!(
+            "Measured {} on {}: {}:{}",
+            measurement.path,
+            measurement.access,
+            measurement.hash_alg,
+            measurement.digest
+        );
+
+        if let Some(pcr) = self.pcr {
+            if let Err(e) = data.tpm().extend_pcr(
+                pcr,
+                data.hash_alg,
+                &measurement.event_data(),
+            ) {
+                error!(
+                    "Failed to record the measurement of {} in PCR {pcr}: {e}",
+                    measurement.path
+                );
+            }
+        }
+
+        if changed {
+            warn!(
+                "The content of the watched file {} changed",
+                measurement.path
+            );
+            data.events.publish(AgentEvent::FileChanged {
+                path: measurement.path,
+                digest: format!(
+                    "{}:{}",
+                    measurement.hash_alg, measurement.digest
+                ),
+                access: measurement.access,
+            });
+        }
+    }
+
+    /// Read and process the pending events of the fanotify group
+    fn read_events(&self, data: &QuoteData) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        loop {
+            let len = unsafe {
+                libc::read(
+                    self.fanotify.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            for event in parse_events(&buf[..len as usize]) {
+                if event.mask & libc::FAN_Q_OVERFLOW != 0 {
+                    warn!("The file watch events queue overflowed, some accesses were not measured");
+                    continue;
+                }
+                let Some(fd) = event.fd else {
+                    continue;
+                };
+                let access = if event.mask & libc::FAN_OPEN_EXEC != 0 {
+                    "exec"
+                } else {
+                    "write"
+                };
+                match measure(fd, data.hash_alg) {
+                    Ok((path, digest)) => self.process(
+                        data,
+                        FileMeasurement {
+                            path,
+                            hash_alg: data.hash_alg.to_string(),
+                            digest,
+                            access: access.to_string(),
+                            timestamp: data
+                                .clock
+                                .rfc3339(chrono::SecondsFormat::Secs),
+                        },
+                    ),
+                    Err(e) => warn!("Unable to measure a watched file: {e}"),
+                }
+            }
+        }
+    }
+}
+
+/// Measure the watched files when they are executed or written
+pub(crate) async fn worker(
+    watch: web::Data<FileWatch>,
+    data: web::Data<QuoteData>,
+    mut file_watch_rx: Receiver<FileWatchMessage>,
+) -> Result<()> {
+    debug!("Starting file watch worker");
+
+    loop {
+        tokio::select! {
+            message = file_watch_rx.recv() => {
+                match message {
+                    Some(FileWatchMessage::Shutdown) | None => {
+                        file_watch_rx.close();
+                        break;
+                    }
+                }
+            }
+            guard = watch.fanotify.readable() => {
+                let mut guard = guard?;
+                match watch.read_events(&data) {
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        guard.clear_ready();
+                    }
+                    Err(e) => {
+                        error!("Failed to read the file watch events: {e}");
+                        guard.clear_ready();
+                    }
+                    Ok(()) => {}
+                }
+            }
+        }
+    }
+
+    debug!("Shutting down file watch worker");
+    Ok(())
+}
+
+/// Register the file watch endpoints in the API version scope
+pub(crate) fn configure(
+    cfg: &mut web::ServiceConfig,
+    watch: web::Data<FileWatch>,
+) {
+    _ = cfg.service(
+        web::scope("/files").app_data(watch).service(
+            web::resource("/watch")
+                .route(web::get().to(report))
+                .route(web::put().to(set_paths)),
+        ),
+    );
+}
+
+/// Handles the requests for the watch list and its journal
+pub(crate) async fn report(watch: web::Data<FileWatch>) -> impl Responder {
+    info!("GET file watch list returning 200 response.");
+    HttpResponse::Ok().json(JsonWrapper::success(watch.report()))
+}
+
+/// Handles the requests replacing the watch list
+pub(crate) async fn set_paths(
+    body: web::Json<WatchList>,
+    watch: web::Data<FileWatch>,
+) -> impl Responder {
+    match watch.set_paths(&body.paths) {
+        Ok(()) => {
+            info!("PUT file watch list returning 200 response.");
+            HttpResponse::Ok().json(JsonWrapper::success(watch.report()))
+        }
+        Err(e) => {
+            warn!(
+                "PUT file watch list returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measurement(path: &str, digest: &str) -> FileMeasurement {
+        FileMeasurement {
+            path: path.to_string(),
+            hash_alg: "sha256".to_string(),
+            digest: digest.to_string(),
+            access: "exec".to_string(),
+            timestamp: "2023-01-01T00:00:00Z".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_parse_paths() {
+        assert_eq!(
+            parse_paths(&["/usr/bin/sshd".to_string()]).unwrap(), //#[allow_ci]
+            vec![PathBuf::from("/usr/bin/sshd")]
+        );
+        assert!(parse_paths(&["usr/bin".to_string()]).is_err());
+        assert!(parse_paths(&vec!["/a".to_string(); MAX_WATCH_PATHS + 1])
+            .is_err());
+    }
+
+    #[test]
+    fn test_record() {
+        let mut state = WatchState::default();
+        assert!(state.record(measurement("/usr/bin/a", "00")));
+        assert!(!state.record(measurement("/usr/bin/a", "00")));
+        assert!(!state.changed(&measurement("/usr/bin/a", "00")));
+        assert!(state.changed(&measurement("/usr/bin/a", "01")));
+        assert!(state.record(measurement("/usr/bin/a", "01")));
+        assert!(!state.changed(&measurement("/usr/bin/b", "01")));
+        assert_eq!(state.journal.len(), 2);
+        assert_eq!(
+            measurement("/usr/bin/a", "00").event_data(),
+            b"sha256:00 /usr/bin/a".to_vec()
+        );
+
+        for i in 0..JOURNAL_SIZE {
+            _ = state.record(measurement("/usr/bin/c", &i.to_string()));
+        }
+        assert_eq!(state.journal.len(), JOURNAL_SIZE);
+        assert_eq!(state.dropped, 2);
+    }
+
+    #[test]
+    fn test_parse_events() {
+        let metadata = libc::fanotify_event_metadata {
+            event_len: size_of::<libc::fanotify_event_metadata>() as u32,
+            vers: libc::FANOTIFY_METADATA_VERSION,
+            reserved: 0,
+            metadata_len: size_of::<libc::fanotify_event_metadata>() as u16,
+            mask: libc::FAN_Q_OVERFLOW,
+            fd: libc::FAN_NOFD,
+            pid: 0,
+        };
+        let ptr: *const libc::fanotify_event_metadata = &metadata;
+        let bytes = unsafe {
+            std::slice::from_raw_parts(
+                ptr.cast::<u8>(),
+                size_of_val(&metadata),
+            )
+        };
+        let mut buf = bytes.to_vec();
+        buf.extend_from_slice(bytes);
+        buf.extend_from_slice(&bytes[..4]);
+
+        let events = parse_events(&buf);
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|e| e.fd.is_none() && e.mask == libc::FAN_Q_OVERFLOW));
+    }
+}
@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2022 Keylime Authors
+
+//! Restriction of the agent to FIPS 140 approved algorithms
+//!
+//! When `fips_mode` is enabled the agent refuses to start unless the
+//! OpenSSL provider it is linked against runs in FIPS mode and every
+//! configured algorithm is approved. The TLS contexts are also limited to
+//! TLS 1.2 and later with AES-GCM cipher suites.
+
+use crate::{
+    config::KeylimeConfig,
+    error::{Error, Result},
+};
+use keylime::algorithms::{
+    EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
+};
+use log::*;
+use openssl::ssl::{SslContextBuilder, SslVersion};
+use serde::{Deserialize, Serialize};
+use std::{ffi::CStr, fs};
+
+/// TLS 1.2 cipher suites used in FIPS mode
+const TLS12_CIPHERS: &str = "ECDHE+AESGCM:DHE+AESGCM";
+
+/// TLS 1.3 cipher suites used in FIPS mode
+const TLS13_CIPHERSUITES: &str =
+    "TLS_AES_256_GCM_SHA384:TLS_AES_128_GCM_SHA256";
+
+const KERNEL_FIPS_ENABLED: &str = "/proc/sys/crypto/fips_enabled";
+
+/// The FIPS state reported by the info endpoint
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub(crate) struct FipsStatus {
+    /// Whether the agent was configured to run in FIPS mode
+    pub enabled: bool,
+    /// Whether the OpenSSL provider runs in FIPS mode
+    pub provider: bool,
+    /// Whether the kernel runs in FIPS mode
+    pub kernel: bool,
+}
+
+impl FipsStatus {
+    /// Probe the state of the crypto provider and of the kernel
+    pub(crate) fn probe(enabled: bool) -> Self {
+        FipsStatus {
+            enabled,
+            provider: provider_enabled(),
+            kernel: kernel_enabled(),
+        }
+    }
+}
+
+/// Check whether the default library context of OpenSSL fetches
+/// algorithms from the FIPS provider
+///
+/// The symbols are resolved at runtime because the `openssl` crate does not
+/// expose this check for OpenSSL 3
+fn provider_enabled() -> bool {
+    type IsFipsEnabled =
+        unsafe extern "C" fn(*mut libc::c_void) -> libc::c_int;
+    type FipsMode = unsafe extern "C" fn() -> libc::c_int;
+
+    // Make sure the library is loaded and initialized before probing it
+    openssl::init();
+
+    let lookup = |name: &CStr| {
+        // SAFETY: the name is a valid C string and RTLD_DEFAULT only
+        // searches the objects already loaded in the process
+        unsafe { libc::dlsym(libc::RTLD_DEFAULT, name.as_ptr()) }
+    };
+
+    let sym = lookup(c"EVP_default_properties_is_fips_enabled");
+    if !sym.is_null() {
+        // SAFETY: the symbol has this signature since OpenSSL 3.0, and a
+        // null pointer selects the default library context
+        let f: IsFipsEnabled = unsafe { std::mem::transmute(sym) };
+        return unsafe { f(std::ptr::null_mut()) } == 1;
+    }
+
+    let sym = lookup(c"FIPS_mode");
+    if !sym.is_null() {
+        // SAFETY: the symbol has this signature up to OpenSSL 1.1.1
+        let f: FipsMode = unsafe { std::mem::transmute(sym) };
+        return unsafe { f() } == 1;
+    }
+
+    false
+}
+
+fn kernel_enabled() -> bool {
+    fs::read_to_string(KERNEL_FIPS_ENABLED)
+        .map(|s| s.trim() == "1")
+        .unwrap_or(false)
+}
+
+fn hash_approved(alg: HashAlgorithm) -> bool {
+    matches!(
+        alg,
+        HashAlgorithm::Sha256 | HashAlgorithm::Sha384 | HashAlgorithm::Sha512
+    )
+}
+
+fn sign_approved(alg: SignAlgorithm) -> bool {
+    matches!(
+        alg,
+        SignAlgorithm::RsaSsa | SignAlgorithm::RsaPss | SignAlgorithm::EcDsa
+    )
+}
+
+fn encryption_approved(alg: EncryptionAlgorithm) -> bool {
+    matches!(alg, EncryptionAlgorithm::Rsa | EncryptionAlgorithm::Ecc)
+}
+
+/// Collect the configured algorithms that are not FIPS approved
+fn violations(
+    config: &KeylimeConfig,
+    hash_alg: HashAlgorithm,
+    enc_alg: EncryptionAlgorithm,
+    sign_alg: SignAlgorithm,
+    auth_tag_algs: &[HashAlgorithm],
+) -> Vec<String> {
+    let mut found = Vec::new();
+
+    if !hash_approved(hash_alg) {
+        found.push(format!("tpm_hash_alg = {hash_alg}"));
+    }
+    if !encryption_approved(enc_alg) {
+        found.push(format!("tpm_encryption_alg = {enc_alg}"));
+    }
+    if !sign_approved(sign_alg) {
+        found.push(format!("tpm_signing_alg = {sign_alg}"));
+    }
+    for alg in auth_tag_algs {
+        if !hash_approved(*alg) {
+            found.push(format!("auth_tag_algorithms contains {alg}"));
+        }
+    }
+    if config.agent.enable_iak_idevid {
+        match HashAlgorithm::try_from(
+            config.agent.iak_idevid_name_alg.as_ref(),
+        ) {
+            Ok(alg) if hash_approved(alg) => {}
+            _ => found.push(format!(
+                "iak_idevid_name_alg = {}",
+                config.agent.iak_idevid_name_alg
+            )),
+        }
+    }
+    // Only the OpenSSL hashing backend goes through the FIPS provider
+    if config.agent.hash_backend != "openssl" {
+        found.push(format!("hash_backend = {}", config.agent.hash_backend));
+    }
+    // The gRPC server TLS stack does not use the OpenSSL provider
+    if config.agent.enable_grpc && config.agent.enable_agent_mtls {
+        found.push("enable_grpc with mTLS".to_string());
+    }
+
+    found
+}
+
+/// Verify the agent can run in FIPS mode with the given configuration
+///
+/// Returns an error, which prevents the agent from starting, if FIPS mode
+/// is enabled but the crypto provider is not in FIPS mode or any configured
+/// algorithm is not approved.
+pub(crate) fn enforce(
+    config: &KeylimeConfig,
+    hash_alg: HashAlgorithm,
+    enc_alg: EncryptionAlgorithm,
+    sign_alg: SignAlgorithm,
+    auth_tag_algs: &[HashAlgorithm],
+) -> Result<FipsStatus> {
+    let status = FipsStatus::probe(config.agent.fips_mode);
+    if !status.enabled {
+        return Ok(status);
+    }
+
+    if !status.provider {
+        error!("FIPS mode is enabled, but the OpenSSL provider is not in FIPS mode");
+        return Err(Error::Configuration(
+            "FIPS mode is enabled, but the OpenSSL provider is not in FIPS mode"
+                .to_string(),
+        ));
+    }
+    if !status.kernel {
+        warn!("FIPS mode is enabled, but the kernel is not in FIPS mode");
+    }
+
+    let found =
+        violations(config, hash_alg, enc_alg, sign_alg, auth_tag_algs);
+    if !found.is_empty() {
+        let message = format!(
+            "FIPS mode is enabled, but these settings are not FIPS approved: {}",
+            found.join(", ")
+        );
+        error!("{message}");
+        return Err(Error::Configuration(message));
+    }
+
+    info!("Running in FIPS mode");
+    Ok(status)
+}
+
+/// Limit a TLS context to the protocol versions and cipher suites allowed
+/// in FIPS mode
+pub(crate) fn restrict_tls(builder: &mut SslContextBuilder) -> Result<()> {
+    builder.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+    builder.set_cipher_list(TLS12_CIPHERS)?;
+    builder.set_ciphersuites(TLS13_CIPHERSUITES)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::ssl::{SslConnector, SslMethod};
+
+    fn check(
+        config: &KeylimeConfig,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+        auth_tag_algs: &[HashAlgorithm],
+    ) -> Vec<String> {
+        violations(
+            config,
+            hash_alg,
+            EncryptionAlgorithm::Rsa,
+            sign_alg,
+            auth_tag_algs,
+        )
+    }
+
+    #[test]
+    fn test_violations() {
+        let mut config = KeylimeConfig::default();
+        config.agent.enable_grpc = false;
+
+        assert!(check(
+            &config,
+            HashAlgorithm::Sha256,
+            SignAlgorithm::RsaSsa,
+            &[HashAlgorithm::Sha384],
+        )
+        .is_empty());
+
+        let found = check(
+            &config,
+            HashAlgorithm::Sha1,
+            SignAlgorithm::EcSchnorr,
+            &[HashAlgorithm::Sha256, HashAlgorithm::Sm3_256],
+        );
+        assert_eq!(found.len(), 3);
+        assert!(found[0].starts_with("tpm_hash_alg"));
+        assert!(found[1].starts_with("tpm_signing_alg"));
+        assert!(found[2].starts_with("auth_tag_algorithms"));
+
+        config.agent.enable_grpc = true;
+        config.agent.enable_agent_mtls = true;
+        assert_eq!(
+            check(
+                &config,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaPss,
+                &[HashAlgorithm::Sha256],
+            ),
+            vec!["enable_grpc with mTLS".to_string()]
+        );
+
+        config.agent.enable_grpc = false;
+        config.agent.hash_backend = "ring".to_string();
+        assert_eq!(
+            check(
+                &config,
+                HashAlgorithm::Sha256,
+                SignAlgorithm::RsaPss,
+                &[HashAlgorithm::Sha256],
+            ),
+            vec!["hash_backend = ring".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_enforce_disabled() {
+        let mut config = KeylimeConfig::default();
+        config.agent.fips_mode = false;
+        let status = enforce(
+            &config,
+            HashAlgorithm::Sha1,
+            EncryptionAlgorithm::Rsa,
+            SignAlgorithm::EcSchnorr,
+            &[],
+        )
+        .unwrap(); //#[allow_ci]
+        assert!(!status.enabled);
+    }
+
+    #[test]
+    fn test_enforce_unapproved() {
+        let mut config = KeylimeConfig::default();
+        config.agent.fips_mode = true;
+        // Fails either on the provider check or on the algorithms
+        assert!(enforce(
+            &config,
+            HashAlgorithm::Sha1,
+            EncryptionAlgorithm::Rsa,
+            SignAlgorithm::RsaSsa,
+            &[],
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_restrict_tls() {
+        let mut builder = SslConnector::builder(SslMethod::tls()).unwrap(); //#[allow_ci]
+        assert!(restrict_tls(&mut builder).is_ok());
+        assert_eq!(builder.min_proto_version(), Some(SslVersion::TLS1_2));
+    }
+}
@@ -0,0 +1,495 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! gRPC frontend for the agent operations
+//!
+//! The RPCs defined in proto/keylime_agent.proto are implemented on top of
+//! the same service layer used by the REST API.
+//!
+//! The verifier identities configured in 'verifier_identities' apply to the
+//! gRPC clients as they do to the REST API clients. Each RPC is checked
+//! against the allowed endpoints of the verifier as the REST endpoint it
+//! corresponds to, e.g. GetIntegrityQuote as /quotes/integrity, and the
+//! evidence returned is limited to the evidence scope of the verifier.
+
+use crate::{
+    common::LATEST_API_VERSION,
+    keys_handler::{KeylimeKeys, KeylimeUKey, KeylimeVKey},
+    quotes_handler::KeylimeQuote,
+    service::{self, IntegrityQuote, PcrRequest, ServiceError},
+    verifiers::{EvidenceScope, PeerCertificate, Verifiers},
+    Error, QuoteData, Result,
+};
+use actix_web::web;
+use base64::{engine::general_purpose, Engine as _};
+use log::*;
+use openssl::{
+    pkey::{PKey, Private},
+    x509::X509,
+};
+use proto::{
+    agent_server::{Agent, AgentServer},
+    AssetTag, BootInfo, Continuity, Empty, Evidence, EvidenceRequest,
+    IdentityQuoteRequest, IntegrityQuoteRequest, KeysRequest, PcrSelection,
+    Quote, Status as AgentStatus, UKeyRequest, VKeyRequest, VerifyKeyRequest,
+    VerifyKeyResponse,
+};
+use std::{net::SocketAddr, sync::Arc};
+use tokio::sync::mpsc::Receiver;
+use tonic::{
+    transport::{Certificate, Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+
+// The generated code does not follow the lints enforced in the crate
+#[allow(unused_qualifications, unused_results, clippy::all)]
+pub(crate) mod proto {
+    tonic::include_proto!("keylime.agent.v1");
+}
+
+#[derive(Debug)]
+pub(crate) enum GrpcMessage {
+    Shutdown,
+}
+
+pub(crate) struct AgentService {
+    data: web::Data<QuoteData>,
+    verifiers: Option<Arc<Verifiers>>,
+}
+
+impl AgentService {
+    /// Check that the client is allowed to call the RPC, corresponding to
+    /// the REST endpoint, and return its evidence scope
+    // The Status is returned as is by the RPCs
+    #[allow(clippy::result_large_err)]
+    fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        endpoint: &str,
+    ) -> std::result::Result<EvidenceScope, Status> {
+        let Some(verifiers) = &self.verifiers else {
+            return Ok(EvidenceScope::ALL);
+        };
+
+        let peer = request
+            .peer_certs()
+            .ok_or_else(|| "Client certificate not available".to_string())
+            .and_then(|certs| {
+                PeerCertificate::from_der(&certs).map_err(|e| e.to_string())
+            });
+        let path = format!("/{LATEST_API_VERSION}{endpoint}");
+        match peer.and_then(|peer| verifiers.check(&peer, &path)) {
+            Ok(identity) => {
+                Ok(identity.map_or(EvidenceScope::ALL, |i| i.evidence))
+            }
+            Err(e) => {
+                warn!("gRPC {endpoint} denied: {e}");
+                Err(Status::permission_denied(e))
+            }
+        }
+    }
+}
+
+/// Convert the error from the service layer into the gRPC status returned
+/// to the client
+fn to_status(rpc: &str, e: ServiceError) -> Status {
+    warn!("gRPC {rpc} failed: {e}");
+    match e {
+        ServiceError::BadRequest(m) => Status::invalid_argument(m),
+        ServiceError::Conflict(m) => Status::aborted(m),
+        ServiceError::Unavailable(m) => Status::unavailable(m),
+        ServiceError::Internal(m) => Status::internal(m),
+    }
+}
+
+impl From<KeylimeQuote> for Quote {
+    fn from(quote: KeylimeQuote) -> Self {
+        Quote {
+            quote: quote.quote,
+            hash_alg: quote.hash_alg,
+            enc_alg: quote.enc_alg,
+            sign_alg: quote.sign_alg,
+            pubkey: quote.pubkey,
+            ima_measurement_list: quote.ima_measurement_list,
+            mb_measurement_list: quote
+                .mb_measurement_list
+                .and_then(|ml| general_purpose::STANDARD.decode(ml).ok()),
+            mb_measurement_list_etag: quote.mb_measurement_list_etag,
+            ima_measurement_list_entry: quote.ima_measurement_list_entry,
+            pcr_selection: quote.pcr_selection.map(|s| PcrSelection {
+                hash_alg: s.hash_alg,
+                pcrs: s.pcrs,
+            }),
+            boot_epoch: quote.boot_epoch,
+            boot: quote.boot.map(|b| BootInfo {
+                boot_id: b.boot_id,
+                kernel_build_id: b.kernel_build_id,
+                kernel_version: b.kernel_version,
+                boot_kind: b.boot_kind.to_string(),
+            }),
+            asset_tag: quote.asset_tag.map(|t| AssetTag {
+                nv_index: t.nv_index,
+                data: t.data,
+                signature: t.signature,
+            }),
+            agent_nonce: quote.agent_nonce,
+            continuity: quote.continuity.map(|c| Continuity {
+                version: c.version,
+                agent_starts: c.agent_starts,
+                tpm_reset_count: c.tpm_reset_count,
+                tpm_restart_count: c.tpm_restart_count,
+            }),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Agent for AgentService {
+    async fn get_identity_quote(
+        &self,
+        request: Request<IdentityQuoteRequest>,
+    ) -> std::result::Result<Response<Quote>, Status> {
+        _ = self.authorize(&request, "/quotes/identity")?;
+        let req = request.get_ref();
+        let agent_nonce = req.agent_nonce.then_some("1");
+        let quote =
+            service::identity_quote(&self.data, &req.nonce, agent_nonce)
+                .map_err(|e| to_status("GetIdentityQuote", e))?;
+        Ok(Response::new(quote.into()))
+    }
+
+    async fn get_integrity_quote(
+        &self,
+        request: Request<IntegrityQuoteRequest>,
+    ) -> std::result::Result<Response<Quote>, Status> {
+        let scope = self.authorize(&request, "/quotes/integrity")?;
+        let req = request.into_inner();
+        let partial = if req.partial { "1" } else { "0" };
+        let ima_ml_entry = req.ima_ml_entry.map(|n| n.to_string());
+
+        let result = service::integrity_quote(
+            &self.data,
+            &req.nonce,
+            req.agent_nonce.then_some("1"),
+            &PcrRequest {
+                mask: (!req.mask.is_empty()).then_some(req.mask.as_str()),
+                pcrs: req.pcrs.as_deref(),
+                pcr_bank: req.pcr_bank.as_deref(),
+            },
+            partial,
+            ima_ml_entry.as_deref(),
+            req.mb_etag.as_deref(),
+            scope,
+        )
+        .and_then(|q| {
+            let IntegrityQuote {
+                mut quote,
+                ima_ml,
+                mut reservations,
+            } = q;
+            if let Some((file, range)) = ima_ml {
+                quote.ima_measurement_list = Some(service::read_ima_ml(
+                    &self.data,
+                    &file,
+                    &range,
+                    &mut reservations,
+                )?);
+            }
+            Ok(quote)
+        });
+
+        let quote = result.map_err(|e| to_status("GetIntegrityQuote", e))?;
+        Ok(Response::new(quote.into()))
+    }
+
+    async fn deliver_u_key(
+        &self,
+        request: Request<UKeyRequest>,
+    ) -> std::result::Result<Response<Empty>, Status> {
+        _ = self.authorize(&request, "/keys/ukey")?;
+        let req = request.into_inner();
+        let ukey = KeylimeUKey {
+            auth_tag: req.auth_tag,
+            auth_tag_alg: req.auth_tag_alg,
+            encrypted_key: req.encrypted_key,
+            payload: req.payload,
+            session_id: req.session_id,
+            sequence: req.sequence,
+            payload_delivery: req.payload_delivery,
+            ttl: req.ttl,
+        };
+        service::deliver_u_key(&self.data, &ukey)
+            .await
+            .map_err(|e| to_status("DeliverUKey", e))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn deliver_v_key(
+        &self,
+        request: Request<VKeyRequest>,
+    ) -> std::result::Result<Response<Empty>, Status> {
+        _ = self.authorize(&request, "/keys/vkey")?;
+        let req = request.into_inner();
+        let vkey = KeylimeVKey {
+            encrypted_key: req.encrypted_key,
+            session_id: req.session_id,
+            sequence: req.sequence,
+        };
+        service::deliver_v_key(&self.data, &vkey)
+            .await
+            .map_err(|e| to_status("DeliverVKey", e))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn deliver_keys(
+        &self,
+        request: Request<KeysRequest>,
+    ) -> std::result::Result<Response<Empty>, Status> {
+        _ = self.authorize(&request, "/keys/batch")?;
+        let req = request.into_inner();
+        let keys = KeylimeKeys {
+            encrypted_u_key: req.encrypted_u_key,
+            encrypted_v_key: req.encrypted_v_key,
+            auth_tag: req.auth_tag,
+            auth_tag_alg: req.auth_tag_alg,
+            payload: req.payload,
+            payload_delivery: req.payload_delivery,
+            session_id: req.session_id,
+            sequence: req.sequence,
+            ttl: req.ttl,
+        };
+        service::deliver_keys(&self.data, &keys)
+            .await
+            .map_err(|e| to_status("DeliverKeys", e))?;
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn verify_key(
+        &self,
+        request: Request<VerifyKeyRequest>,
+    ) -> std::result::Result<Response<VerifyKeyResponse>, Status> {
+        _ = self.authorize(&request, "/keys/verify")?;
+        let hmac =
+            service::verify_key(&self.data, &request.get_ref().challenge)
+                .await
+                .map_err(|e| to_status("VerifyKey", e))?;
+        Ok(Response::new(VerifyKeyResponse { hmac }))
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<Empty>,
+    ) -> std::result::Result<Response<AgentStatus>, Status> {
+        _ = self.authorize(&request, "/agent/info")?;
+        Ok(Response::new(AgentStatus {
+            agent_uuid: self.data.agent_uuid.clone(),
+            api_version: LATEST_API_VERSION.to_string(),
+            hash_alg: self.data.hash_alg.to_string(),
+            enc_alg: self.data.enc_alg.to_string(),
+            sign_alg: self.data.sign_alg.to_string(),
+            ima_ml_available: self.data.ima_ml_file.is_some(),
+            mb_ml_available: self.data.measuredboot_ml_file.is_some(),
+            fips_mode: self.data.fips.enabled,
+        }))
+    }
+
+    async fn get_evidence(
+        &self,
+        request: Request<EvidenceRequest>,
+    ) -> std::result::Result<Response<Evidence>, Status> {
+        let scope = self.authorize(&request, "/evidence")?;
+        let nth_entry = request.get_ref().ima_ml_entry.unwrap_or(0);
+        let mut reservations = Vec::new();
+
+        // Only the logs in the evidence scope of the verifier are returned
+        let result = (|| {
+            let mut evidence = Evidence::default();
+            if scope.measured_boot {
+                evidence.mb_measurement_list =
+                    service::read_measured_boot_log(
+                        &self.data,
+                        &mut reservations,
+                    )?;
+            }
+            if scope.ima {
+                if let Some((file, range)) =
+                    service::locate_ima_ml(&self.data, nth_entry)?
+                {
+                    evidence.ima_measurement_list =
+                        Some(service::read_ima_ml(
+                            &self.data,
+                            &file,
+                            &range,
+                            &mut reservations,
+                        )?);
+                    evidence.ima_measurement_list_entry =
+                        Some(range.nth_entry);
+                }
+            }
+            Ok(evidence)
+        })();
+
+        let evidence = result.map_err(|e| to_status("GetEvidence", e))?;
+        Ok(Response::new(evidence))
+    }
+}
+
+/// Build the TLS configuration for the gRPC server from the same identity
+/// and trusted CA certificates used for the REST API
+pub(crate) fn tls_config(
+    cert: &X509,
+    key: &PKey<Private>,
+    ca_certs: &[X509],
+) -> Result<ServerTlsConfig> {
+    let mut ca_pem = Vec::new();
+    for ca in ca_certs {
+        ca_pem.extend(ca.to_pem()?);
+    }
+
+    Ok(ServerTlsConfig::new()
+        .identity(Identity::from_pem(
+            cert.to_pem()?,
+            key.private_key_to_pem_pkcs8()?,
+        ))
+        .client_ca_root(Certificate::from_pem(ca_pem)))
+}
+
+pub(crate) async fn worker(
+    data: web::Data<QuoteData>,
+    verifiers: Option<Arc<Verifiers>>,
+    addr: SocketAddr,
+    tls: Option<ServerTlsConfig>,
+    mut grpc_rx: Receiver<GrpcMessage>,
+) -> Result<()> {
+    debug!("Starting gRPC server");
+
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        builder = builder.tls_config(tls)?;
+    }
+
+    builder
+        .add_service(AgentServer::new(AgentService { data, verifiers }))
+        .serve_with_shutdown(addr, async move {
+            // The server is stopped when the shutdown message is received or
+            // the channel is closed
+            let _ = grpc_rx.recv().await;
+        })
+        .await?;
+
+    debug!("Shutting down gRPC server");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_status() {
+        let status = to_status(
+            "Test",
+            ServiceError::BadRequest("invalid nonce".to_string()),
+        );
+        assert_eq!(status.code(), tonic::Code::InvalidArgument);
+        assert_eq!(status.message(), "invalid nonce");
+
+        let status =
+            to_status("Test", ServiceError::Unavailable(String::new()));
+        assert_eq!(status.code(), tonic::Code::Unavailable);
+
+        let status = to_status("Test", ServiceError::Internal(String::new()));
+        assert_eq!(status.code(), tonic::Code::Internal);
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_authorize() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("verifiers.toml");
+        std::fs::write(
+            &path,
+            format!(
+                r#"
+                [verifier.tenant]
+                ca_certs = ["{}/test-data/test-cert.pem"]
+                allowed_endpoints = ["/quotes/integrity"]
+                "#,
+                env!("CARGO_MANIFEST_DIR")
+            ),
+        )
+        .unwrap(); //#[allow_ci]
+        let verifiers = Verifiers::load(&path, &[]).unwrap(); //#[allow_ci]
+        let data = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let service = AgentService {
+            data,
+            verifiers: Some(Arc::new(verifiers)),
+        };
+
+        // Without a client certificate matching a verifier identity, no RPC
+        // is allowed
+        let result = service.get_status(Request::new(Empty {})).await;
+        assert_eq!(
+            result.unwrap_err().code(), //#[allow_ci]
+            tonic::Code::PermissionDenied
+        );
+        let result = service
+            .get_evidence(Request::new(EvidenceRequest::default()))
+            .await;
+        assert_eq!(
+            result.unwrap_err().code(), //#[allow_ci]
+            tonic::Code::PermissionDenied
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_get_status() {
+        let data = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let service = AgentService {
+            data,
+            verifiers: None,
+        };
+
+        let status = service
+            .get_status(Request::new(Empty {}))
+            .await
+            .unwrap() //#[allow_ci]
+            .into_inner();
+        assert_eq!(status.api_version, LATEST_API_VERSION);
+        assert!(status.ima_ml_available);
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_get_identity_quote() {
+        let data = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let service = AgentService {
+            data,
+            verifiers: None,
+        };
+
+        let result = service
+            .get_identity_quote(Request::new(IdentityQuoteRequest {
+                nonce: "not alphanumeric!".to_string(),
+                agent_nonce: false,
+            }))
+            .await;
+        assert_eq!(
+            result.unwrap_err().code(), //#[allow_ci]
+            tonic::Code::InvalidArgument
+        );
+
+        let quote = service
+            .get_identity_quote(Request::new(IdentityQuoteRequest {
+                nonce: "1234567890ABCDEFHIJ".to_string(),
+                agent_nonce: true,
+            }))
+            .await
+            .unwrap() //#[allow_ci]
+            .into_inner();
+        assert!(quote.pubkey.is_some());
+        assert!(quote.ima_measurement_list.is_none());
+        assert_eq!(quote.agent_nonce.map(|n| n.len()), Some(64));
+    }
+}
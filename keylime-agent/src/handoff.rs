@@ -0,0 +1,282 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Handoff of the decrypted payload to its consumer without any file
+//!
+//! Instead of writing the payload to the secure mount, the agent connects
+//! to the abstract unix socket set in 'payload_handoff_socket' and either:
+//!
+//! - writes the payload to the socket and closes it (socket mode), or
+//! - sends a single message with the payload size as a little endian u64,
+//!   carrying a sealed memfd holding the payload (memfd mode).
+//!
+//! Abstract sockets have no file permissions, so the credentials of the
+//! peer are checked before sending anything: it must run as root, as the
+//! user of the agent, or as the user set in 'payload_handoff_peer_uid'.
+
+use crate::{
+    error::{Error, Result},
+    permissions,
+};
+use keylime::payload::PayloadDelivery;
+use log::*;
+use std::{
+    ffi::CString,
+    fs::File,
+    io::{self, Seek, Write},
+    mem,
+    os::{
+        fd::{AsRawFd, FromRawFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixStream},
+    },
+    time::Duration,
+};
+
+/// Time allowed to the consumer to read the payload
+const HANDOFF_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether the payload can be handed to a peer running as the user
+fn peer_allowed(
+    uid: u32,
+    agent_uid: u32,
+    configured_uid: Option<u32>,
+) -> bool {
+    uid == 0 || uid == agent_uid || Some(uid) == configured_uid
+}
+
+fn peer_uid(stream: &UnixStream) -> io::Result<u32> {
+    let mut cred: libc::ucred = unsafe { mem::zeroed() };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    if unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            std::ptr::addr_of_mut!(cred).cast(),
+            &mut len,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(cred.uid)
+}
+
+fn connect(socket: &str, configured_uid: Option<u32>) -> Result<UnixStream> {
+    let addr = SocketAddr::from_abstract_name(socket.as_bytes())?;
+    let stream = UnixStream::connect_addr(&addr).map_err(|e| {
+        Error::Other(format!(
+            "unable to connect to payload handoff socket @{socket}: {e}"
+        ))
+    })?;
+    stream.set_write_timeout(Some(HANDOFF_TIMEOUT))?;
+
+    let uid = peer_uid(&stream)?;
+    if !peer_allowed(uid, permissions::get_euid(), configured_uid) {
+        return Err(Error::Other(format!(
+            "the process listening on payload handoff socket @{socket} runs as user {uid}, which is not allowed to receive the payload"
+        )));
+    }
+    Ok(stream)
+}
+
+/// A memfd holding the data, sealed so that the data cannot be changed
+fn sealed_memfd(data: &[u8]) -> io::Result<File> {
+    let name = CString::new("keylime-payload")?;
+    let fd = unsafe {
+        libc::memfd_create(
+            name.as_ptr(),
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(data)?;
+    file.rewind()?;
+
+    let seals = libc::F_SEAL_SHRINK
+        | libc::F_SEAL_GROW
+        | libc::F_SEAL_WRITE
+        | libc::F_SEAL_SEAL;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(file)
+}
+
+/// Send the data with the file descriptor attached
+fn send_fd(stream: &UnixStream, data: &[u8], fd: RawFd) -> io::Result<()> {
+    let mut iov = libc::iovec {
+        iov_base: data.as_ptr() as *mut libc::c_void,
+        iov_len: data.len(),
+    };
+    // Aligned buffer large enough for a single file descriptor
+    let mut control = [0u64; 4];
+    let space =
+        unsafe { libc::CMSG_SPACE(size_of::<RawFd>() as u32) } as usize;
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space;
+
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(size_of::<RawFd>() as u32) as _;
+        std::ptr::write_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>(), fd);
+    }
+
+    let sent = unsafe {
+        libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL)
+    };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if sent as usize != data.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::WriteZero,
+            "partial write of the payload handoff message",
+        ));
+    }
+    Ok(())
+}
+
+/// Hand the decrypted payload to the process listening on the abstract
+/// socket
+pub(crate) fn hand_off(
+    delivery: PayloadDelivery,
+    socket: &str,
+    peer_uid: &str,
+    payload: &[u8],
+) -> Result<()> {
+    if socket.is_empty() {
+        return Err(Error::Configuration(format!(
+            "payload delivery mode '{delivery}' requires 'payload_handoff_socket' to be set"
+        )));
+    }
+    let configured_uid = match peer_uid {
+        "" => None,
+        uid => Some(uid.parse::<u32>()?),
+    };
+
+    let mut stream = connect(socket, configured_uid)?;
+    match delivery {
+        PayloadDelivery::Files => {
+            return Err(Error::Other(
+                "payloads delivered as files are not handed off".to_string(),
+            ))
+        }
+        PayloadDelivery::Socket => {
+            stream.write_all(payload)?;
+            stream.shutdown(std::net::Shutdown::Write)?;
+        }
+        PayloadDelivery::Memfd => {
+            let memfd = sealed_memfd(payload)?;
+            let size = (payload.len() as u64).to_le_bytes();
+            send_fd(&stream, &size, memfd.as_raw_fd())?;
+        }
+    }
+    info!("Handed off payload to socket @{socket} ({delivery})");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::Read, os::unix::net::UnixListener, thread};
+
+    fn listen(name: &str) -> UnixListener {
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap(); //#[allow_ci]
+        UnixListener::bind_addr(&addr).unwrap() //#[allow_ci]
+    }
+
+    fn socket_name(mode: &str) -> String {
+        format!("keylime-test-{mode}-{}", std::process::id())
+    }
+
+    fn recv_fd(stream: &UnixStream) -> (Vec<u8>, File) {
+        let mut data = [0u8; 8];
+        let mut iov = libc::iovec {
+            iov_base: data.as_mut_ptr().cast(),
+            iov_len: data.len(),
+        };
+        let mut control = [0u64; 4];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = size_of_val(&control);
+
+        let received =
+            unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, 0) };
+        assert_eq!(received, 8);
+        let fd = unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            assert_eq!((*cmsg).cmsg_type, libc::SCM_RIGHTS);
+            std::ptr::read_unaligned(libc::CMSG_DATA(cmsg).cast::<RawFd>())
+        };
+        (data.to_vec(), unsafe { File::from_raw_fd(fd) })
+    }
+
+    #[test]
+    fn test_peer_allowed() {
+        assert!(peer_allowed(0, 1000, None));
+        assert!(peer_allowed(1000, 1000, None));
+        assert!(peer_allowed(2000, 1000, Some(2000)));
+        assert!(!peer_allowed(2000, 1000, None));
+        assert!(!peer_allowed(3000, 1000, Some(2000)));
+    }
+
+    #[test]
+    fn test_hand_off_socket() {
+        let name = socket_name("socket");
+        let listener = listen(&name);
+        let sender = thread::spawn(move || {
+            hand_off(PayloadDelivery::Socket, &name, "", b"secret").is_ok()
+        });
+
+        let (mut stream, _) = listener.accept().unwrap(); //#[allow_ci]
+        let mut received = Vec::new();
+        let _ = stream.read_to_end(&mut received).unwrap(); //#[allow_ci]
+        assert_eq!(received, b"secret");
+        assert!(sender.join().unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_hand_off_memfd() {
+        let name = socket_name("memfd");
+        let listener = listen(&name);
+        let sender = thread::spawn(move || {
+            hand_off(PayloadDelivery::Memfd, &name, "", b"secret").is_ok()
+        });
+
+        let (stream, _) = listener.accept().unwrap(); //#[allow_ci]
+        let (size, mut memfd) = recv_fd(&stream);
+        assert_eq!(size, 6u64.to_le_bytes());
+        let mut received = Vec::new();
+        let _ = memfd.read_to_end(&mut received).unwrap(); //#[allow_ci]
+        assert_eq!(received, b"secret");
+        // The payload cannot be changed by the consumer
+        assert!(memfd.write_all(b"x").is_err());
+        assert!(sender.join().unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_hand_off_errors() {
+        assert!(hand_off(PayloadDelivery::Socket, "", "", b"secret").is_err());
+        assert!(hand_off(
+            PayloadDelivery::Socket,
+            &socket_name("missing"),
+            "",
+            b"secret"
+        )
+        .is_err());
+    }
+}
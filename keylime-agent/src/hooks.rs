@@ -0,0 +1,341 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Local hooks on the agent lifecycle events
+//!
+//! The host orchestration can react to the agent events, e.g. marking the
+//! node schedulable once it is attested, without polling the verifier. The
+//! hook set in 'event_hook' is either a program, executed with the event
+//! type as argument and the event on the standard input, or a local URL
+//! receiving the event as a POST request. The event is the same CloudEvent
+//! sent to the events sink, and the hook is given 'event_hook_timeout'
+//! seconds to complete.
+
+use crate::{
+    events::{CloudEvent, EventMessage, CLOUDEVENTS_CONTENT_TYPE},
+    http_client::{self, HttpClient},
+    Error, Result,
+};
+use log::*;
+use std::{
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc::Receiver;
+
+/// Prefix of the type of the agent events, omitted in 'event_hook_events'
+const EVENT_TYPE_PREFIX: &str = "org.keylime.agent.";
+
+/// Interval at which a running hook program is checked for completion
+const EXEC_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// What is run on the events
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Hook {
+    Exec { program: PathBuf },
+    Http { url: String },
+}
+
+impl Hook {
+    /// Parse the hook from the configuration, as "exec:/path/to/program" or
+    /// as an HTTP URL on the loopback interface. An empty hook disables the
+    /// hooks.
+    pub(crate) fn parse(hook: &str) -> Result<Option<Hook>> {
+        if hook.is_empty() {
+            return Ok(None);
+        }
+
+        if let Some(program) = hook.strip_prefix("exec:") {
+            let program = PathBuf::from(program.trim());
+            if !program.is_absolute() {
+                return Err(Error::Configuration(format!(
+                    "Invalid event hook {hook}: the program path must be absolute"
+                )));
+            }
+            return Ok(Some(Hook::Exec { program }));
+        }
+
+        let local = reqwest::Url::parse(hook).ok().is_some_and(|url| {
+            let host = url
+                .host_str()
+                .unwrap_or_default()
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            url.scheme() == "http"
+                && (host == "localhost"
+                    || host
+                        .parse::<IpAddr>()
+                        .is_ok_and(|ip| ip.is_loopback()))
+        });
+        if local {
+            return Ok(Some(Hook::Http {
+                url: hook.to_string(),
+            }));
+        }
+
+        Err(Error::Configuration(format!(
+            "Invalid event hook {hook}: expected exec:/path/to/program or an HTTP URL on localhost"
+        )))
+    }
+}
+
+/// The hook and the events it is run on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Hooks {
+    hook: Hook,
+    events: Vec<String>,
+    timeout: Duration,
+}
+
+impl Hooks {
+    pub(crate) fn new(
+        hook: &str,
+        events: &str,
+        timeout: u64,
+    ) -> Result<Option<Self>> {
+        let Some(hook) = Hook::parse(hook)? else {
+            return Ok(None);
+        };
+        let events: Vec<String> = events
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(|e| format!("{EVENT_TYPE_PREFIX}{e}"))
+            .collect();
+        if events.is_empty() {
+            warn!("The event hook is set, but 'event_hook_events' is empty");
+        }
+        Ok(Some(Hooks {
+            hook,
+            events,
+            timeout: Duration::from_secs(timeout),
+        }))
+    }
+
+    fn wants(&self, event: &CloudEvent) -> bool {
+        self.events.contains(&event.event_type)
+    }
+}
+
+/// Run the program with the event type as argument and the event on the
+/// standard input, killing it if it does not exit before the timeout
+fn exec(
+    program: &Path,
+    event_type: &str,
+    body: &[u8],
+    timeout: Duration,
+) -> io::Result<()> {
+    let mut child = Command::new(program)
+        .arg(event_type)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // The program is not required to read the event
+        match stdin.write_all(body) {
+            Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                _ = child.kill();
+                _ = child.wait();
+                return Err(e);
+            }
+            _ => {}
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() {
+                return Ok(());
+            }
+            return Err(io::Error::other(format!(
+                "event hook {} failed: {status}",
+                program.display()
+            )));
+        }
+        if Instant::now() >= deadline {
+            _ = child.kill();
+            _ = child.wait();
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "event hook {} did not complete in {:?}, killed",
+                    program.display(),
+                    timeout
+                ),
+            ));
+        }
+        thread::sleep(EXEC_POLL_INTERVAL);
+    }
+}
+
+async fn post(
+    client: &HttpClient,
+    url: &str,
+    event: &CloudEvent,
+    timeout: Duration,
+) -> Result<()> {
+    let response = client
+        .request(reqwest::Method::POST, url)
+        .header(reqwest::header::CONTENT_TYPE, CLOUDEVENTS_CONTENT_TYPE)
+        .timeout(timeout)
+        .body(serde_json::to_vec(event)?)
+        .send()
+        .await?;
+    http_client::check_status(url, response.status().as_u16())
+}
+
+async fn run(
+    hooks: &Hooks,
+    client: &HttpClient,
+    event: CloudEvent,
+) -> Result<()> {
+    match &hooks.hook {
+        Hook::Exec { program } => {
+            let program = program.clone();
+            let timeout = hooks.timeout;
+            let body = serde_json::to_vec(&event)?;
+            Ok(tokio::task::spawn_blocking(move || {
+                exec(&program, &event.event_type, &body, timeout)
+            })
+            .await??)
+        }
+        Hook::Http { url } => post(client, url, &event, hooks.timeout).await,
+    }
+}
+
+pub(crate) async fn worker(
+    hooks: Hooks,
+    client: HttpClient,
+    mut events_rx: Receiver<EventMessage>,
+) -> Result<()> {
+    debug!("Starting event hooks worker");
+
+    while let Some(message) = events_rx.recv().await {
+        let event = match message {
+            EventMessage::Shutdown => {
+                events_rx.close();
+                continue;
+            }
+            EventMessage::Event(event) => event,
+        };
+        if !hooks.wants(&event) {
+            continue;
+        }
+
+        let (event_type, id) = (event.event_type.clone(), event.id.clone());
+        match run(&hooks, &client, *event).await {
+            Ok(()) => debug!("Ran the hook on {event_type} event {id}"),
+            Err(e) => {
+                warn!("Failed to run the hook on {event_type} event: {e}")
+            }
+        }
+    }
+
+    debug!("Shutting down event hooks worker");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{AgentEvent, EventPublisher};
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    fn hook_script(dir: &Path, script: &str) -> PathBuf {
+        let path = dir.join("hook.sh");
+        fs::write(&path, format!("#!/bin/sh\n{script}\n")).unwrap(); //#[allow_ci]
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o700))
+            .unwrap(); //#[allow_ci]
+        path
+    }
+
+    #[test]
+    fn test_parse_hook() {
+        assert_eq!(Hook::parse("").unwrap(), None); //#[allow_ci]
+        assert_eq!(
+            Hook::parse("exec:/usr/libexec/node-ready").unwrap(), //#[allow_ci]
+            Some(Hook::Exec {
+                program: PathBuf::from("/usr/libexec/node-ready"),
+            })
+        );
+        for url in [
+            "http://localhost:8080/hook",
+            "http://127.0.0.1:8080/hook",
+            "http://[::1]:8080/",
+        ] {
+            assert_eq!(
+                Hook::parse(url).unwrap(), //#[allow_ci]
+                Some(Hook::Http {
+                    url: url.to_string()
+                })
+            );
+        }
+        for hook in [
+            "exec:node-ready",
+            "http://orchestrator.example.com/hook",
+            "https://localhost/hook",
+            "/usr/libexec/node-ready",
+        ] {
+            assert!(Hook::parse(hook).is_err());
+        }
+    }
+
+    #[test]
+    fn test_wants() {
+        let hooks = Hooks::new(
+            "exec:/usr/libexec/node-ready",
+            "registered, payload.executed",
+            5,
+        )
+        .unwrap() //#[allow_ci]
+        .unwrap(); //#[allow_ci]
+        let publisher = EventPublisher::default();
+        assert!(hooks.wants(&publisher.cloud_event(
+            &AgentEvent::Registered {
+                registrar: "127.0.0.1:8890".to_string()
+            }
+        )));
+        assert!(
+            hooks.wants(&publisher.cloud_event(&AgentEvent::PayloadExecuted))
+        );
+        assert!(!hooks.wants(&publisher.cloud_event(&AgentEvent::KeysWiped)));
+        assert_eq!(Hooks::new("", "registered", 5).unwrap(), None); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_exec() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let out = dir.path().join("out");
+        let event = EventPublisher::default()
+            .cloud_event(&AgentEvent::RevocationReceived { processed: true });
+
+        let program = hook_script(
+            dir.path(),
+            &format!("echo \"$1\" > {0}; cat >> {0}", out.display()),
+        );
+        let body = serde_json::to_vec(&event).unwrap(); //#[allow_ci]
+        let run = |program: &Path, timeout| {
+            exec(program, &event.event_type, &body, timeout)
+        };
+        run(&program, Duration::from_secs(5)).unwrap(); //#[allow_ci]
+        let output = fs::read_to_string(&out).unwrap(); //#[allow_ci]
+        let (event_type, body) = output.split_once('\n').unwrap(); //#[allow_ci]
+        assert_eq!(event_type, "org.keylime.agent.revocation.received");
+        let received: CloudEvent = serde_json::from_str(body).unwrap(); //#[allow_ci]
+        assert_eq!(received, event);
+
+        let program = hook_script(dir.path(), "exit 3");
+        assert!(run(&program, Duration::from_secs(5)).is_err());
+
+        let program = hook_script(dir.path(), "exec sleep 10");
+        let start = Instant::now();
+        let e = run(&program, Duration::from_millis(100)).unwrap_err(); //#[allow_ci]
+        assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,504 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Client for the requests sent by the agent to other services
+//!
+//! The registrar, the events sink and the Kubernetes API are all contacted
+//! through an [`HttpClient`], so that they share the same behavior when the
+//! network is unreliable:
+//!
+//! - the connections are pooled and reused across the requests;
+//! - the connection and the whole request are bounded by timeouts;
+//! - the requests failing with a retryable error (connection failures,
+//!   timeouts, 429 and 5xx responses) are retried with an exponential
+//!   backoff, with a random jitter so that the agents of a fleet do not all
+//!   retry at the same time;
+//! - the requests go through the configured HTTP proxy, using CONNECT for
+//!   the TLS connections;
+//! - the requests identify the agent version and UUID in the User-Agent.
+
+use crate::{
+    config::KeylimeConfig, endpoint::Endpoint, peer_tls::PeerTls, Error,
+    Result,
+};
+use hyper::{body::Body, header, Method, Request};
+use log::*;
+use openssl::rand::rand_bytes;
+use serde::Serialize;
+use std::{future::Future, io, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Idle pooled connections are closed after this time
+const POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Maximum size of the response of a proxy to a CONNECT request
+const MAX_PROXY_RESPONSE: usize = 8192;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HttpClientOptions {
+    pub user_agent: String,
+    pub timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Number of retries after the first attempt
+    pub retries: u32,
+    /// Delay before the first retry, doubled on each retry
+    pub retry_delay: Duration,
+    pub max_retry_delay: Duration,
+    pub proxy: Option<Endpoint>,
+}
+
+impl Default for HttpClientOptions {
+    fn default() -> Self {
+        use crate::config::*;
+
+        HttpClientOptions {
+            user_agent: user_agent(""),
+            timeout: Duration::from_millis(DEFAULT_OUTBOUND_TIMEOUT),
+            connect_timeout: Duration::from_millis(
+                DEFAULT_OUTBOUND_CONNECT_TIMEOUT,
+            ),
+            retries: DEFAULT_OUTBOUND_RETRIES,
+            retry_delay: Duration::from_millis(DEFAULT_OUTBOUND_RETRY_DELAY),
+            max_retry_delay: Duration::from_millis(
+                DEFAULT_OUTBOUND_MAX_RETRY_DELAY,
+            ),
+            proxy: None,
+        }
+    }
+}
+
+impl HttpClientOptions {
+    /// The options of the logical agent with the given UUID
+    pub(crate) fn new(
+        config: &KeylimeConfig,
+        agent_uuid: &str,
+    ) -> Result<Self> {
+        Ok(HttpClientOptions {
+            user_agent: user_agent(agent_uuid),
+            timeout: Duration::from_millis(config.agent.outbound_timeout),
+            connect_timeout: Duration::from_millis(
+                config.agent.outbound_connect_timeout,
+            ),
+            retries: config.agent.outbound_retries,
+            retry_delay: Duration::from_millis(
+                config.agent.outbound_retry_delay,
+            ),
+            max_retry_delay: Duration::from_millis(
+                config.agent.outbound_max_retry_delay,
+            ),
+            proxy: parse_proxy(&config.agent.outbound_proxy)?,
+        })
+    }
+}
+
+fn user_agent(agent_uuid: &str) -> String {
+    match agent_uuid {
+        "" => format!("keylime-agent/{}", env!("CARGO_PKG_VERSION")),
+        uuid => {
+            format!("keylime-agent/{} ({uuid})", env!("CARGO_PKG_VERSION"))
+        }
+    }
+}
+
+/// Parse the HTTP proxy, given as "host:port" or "http://host:port"
+pub(crate) fn parse_proxy(proxy: &str) -> Result<Option<Endpoint>> {
+    let proxy = proxy.trim();
+    if proxy.is_empty() {
+        return Ok(None);
+    }
+    let url = match proxy.contains("://") {
+        true => proxy.to_string(),
+        false => format!("http://{proxy}"),
+    };
+    let url = reqwest::Url::parse(&url).map_err(|e| {
+        Error::Configuration(format!("Invalid proxy {proxy}: {e}"))
+    })?;
+    match (url.scheme(), url.host_str(), url.port_or_known_default()) {
+        ("http", Some(host), Some(port)) => {
+            Ok(Some(Endpoint::new(host, port.into())))
+        }
+        _ => Err(Error::Configuration(format!(
+            "Invalid proxy {proxy}: expected an HTTP proxy as host:port"
+        ))),
+    }
+}
+
+/// Check the status of a response, returning an error for the failures
+pub(crate) fn check_status(url: &str, status: u16) -> Result<()> {
+    if (200..300).contains(&status) {
+        return Ok(());
+    }
+    Err(Error::Http {
+        url: url.to_string(),
+        code: status,
+    })
+}
+
+fn timed_out(what: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::TimedOut, what))
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct HttpClient {
+    client: reqwest::Client,
+    options: Arc<HttpClientOptions>,
+}
+
+impl HttpClient {
+    pub(crate) fn new(options: HttpClientOptions) -> Result<Self> {
+        Self::build(reqwest::Client::builder(), options)
+    }
+
+    /// A client trusting the given CA certificate, for the services with a
+    /// private CA
+    #[cfg(feature = "kubernetes")]
+    pub(crate) fn with_root_certificate(
+        options: HttpClientOptions,
+        ca: reqwest::Certificate,
+    ) -> Result<Self> {
+        Self::build(
+            reqwest::Client::builder().add_root_certificate(ca),
+            options,
+        )
+    }
+
+    fn build(
+        builder: reqwest::ClientBuilder,
+        options: HttpClientOptions,
+    ) -> Result<Self> {
+        let builder = builder
+            .user_agent(&options.user_agent)
+            .timeout(options.timeout)
+            .connect_timeout(options.connect_timeout)
+            .pool_idle_timeout(POOL_IDLE_TIMEOUT);
+        // The proxy variables of the environment are ignored, as they are
+        // not used for the TLS connections either
+        let builder = match &options.proxy {
+            Some(proxy) => {
+                builder.proxy(reqwest::Proxy::all(proxy.url("http", ""))?)
+            }
+            None => builder.no_proxy(),
+        };
+
+        Ok(HttpClient {
+            client: builder.build()?,
+            options: Arc::new(options),
+        })
+    }
+
+    /// Start a request sent over the pooled connections
+    pub(crate) fn request(
+        &self,
+        method: Method,
+        url: &str,
+    ) -> reqwest::RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    /// Run the request, retrying it while it fails with a retryable error
+    /// up to the configured number of retries
+    pub(crate) async fn retry<T, F, Fut>(
+        &self,
+        target: &str,
+        mut request: F,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            match request().await {
+                Err(e)
+                    if e.is_retryable() && attempt < self.options.retries =>
+                {
+                    let delay = self.backoff(attempt);
+                    attempt += 1;
+                    warn!(
+                        "Request to {target} failed, retrying in {} ms (retry {attempt} of {}): {e}",
+                        delay.as_millis(),
+                        self.options.retries
+                    );
+                    tokio::time::sleep(delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// The delay before the retry, between half and the whole of the
+    /// exponentially increasing delay
+    fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .options
+            .retry_delay
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.options.max_retry_delay);
+        let mut random = [0u8; 4];
+        if rand_bytes(&mut random).is_err() {
+            return delay;
+        }
+        let jitter =
+            f64::from(u32::from_ne_bytes(random)) / f64::from(u32::MAX);
+        delay / 2 + delay.mul_f64(jitter / 2.0)
+    }
+
+    /// Open a connection to the server, through the proxy if configured
+    async fn connect(&self, endpoint: &Endpoint) -> Result<TcpStream> {
+        let connect = async {
+            let Some(proxy) = &self.options.proxy else {
+                let port = u16::try_from(endpoint.port())?;
+                return Ok(TcpStream::connect((endpoint.host(), port)).await?);
+            };
+
+            let port = u16::try_from(proxy.port())?;
+            let mut tcp = TcpStream::connect((proxy.host(), port)).await?;
+            tcp.write_all(
+                format!(
+                    "CONNECT {endpoint} HTTP/1.1\r\nHost: {endpoint}\r\nUser-Agent: {}\r\n\r\n",
+                    self.options.user_agent
+                )
+                .as_bytes(),
+            )
+            .await?;
+
+            // Read the response byte by byte, so that nothing sent by the
+            // server after the end of the headers is consumed
+            let mut response = Vec::new();
+            while !response.ends_with(b"\r\n\r\n") {
+                if response.len() >= MAX_PROXY_RESPONSE {
+                    return Err(Error::Other(format!(
+                        "Invalid response from proxy {proxy}"
+                    )));
+                }
+                response.push(tcp.read_u8().await?);
+            }
+            let status = String::from_utf8_lossy(&response)
+                .split_whitespace()
+                .nth(1)
+                .and_then(|s| s.parse::<u16>().ok())
+                .unwrap_or_default();
+            check_status(&format!("http://{proxy}"), status)?;
+            Ok(tcp)
+        };
+
+        tokio::time::timeout(self.options.connect_timeout, connect)
+            .await
+            .map_err(|_| timed_out(format!("connecting to {endpoint}")))?
+    }
+
+    /// Send the JSON request to the server, returning the status code and
+    /// the body of the response. The certificate of the server is validated
+    /// according to the TLS settings, or plain HTTP is used if TLS is
+    /// disabled.
+    pub(crate) async fn send_json<T: Serialize>(
+        &self,
+        method: Method,
+        endpoint: &Endpoint,
+        path: &str,
+        body: &T,
+        tls: &PeerTls,
+    ) -> Result<(u16, Vec<u8>)> {
+        self.send(
+            method,
+            endpoint,
+            path,
+            Some(serde_json::to_vec(body)?),
+            tls,
+        )
+        .await
+    }
+
+    /// Send a GET request to the server, with the path including the query
+    /// string, returning the status code and the body of the response
+    pub(crate) async fn get(
+        &self,
+        endpoint: &Endpoint,
+        path: &str,
+        tls: &PeerTls,
+    ) -> Result<(u16, Vec<u8>)> {
+        self.send(Method::GET, endpoint, path, None, tls).await
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        endpoint: &Endpoint,
+        path: &str,
+        body: Option<Vec<u8>>,
+        tls: &PeerTls,
+    ) -> Result<(u16, Vec<u8>)> {
+        if !tls.is_enabled() {
+            let mut request =
+                self.request(method, &endpoint.url(tls.scheme(), path));
+            if let Some(body) = body {
+                request = request
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .body(body);
+            }
+            let response = request.send().await?;
+            let status = response.status().as_u16();
+            return Ok((status, response.bytes().await?.to_vec()));
+        }
+
+        let tcp = self.connect(endpoint).await?;
+        let send = async {
+            let stream = tls.handshake(tcp, endpoint).await?;
+            let (mut sender, connection) =
+                hyper::client::conn::handshake(stream).await?;
+            let connection = tokio::spawn(async move {
+                if let Err(e) = connection.await {
+                    debug!("HTTPS connection closed: {e}");
+                }
+            });
+
+            let mut request = Request::builder()
+                .method(method)
+                .uri(format!("/{}", path.trim_start_matches('/')))
+                .header(header::HOST, endpoint.to_string())
+                .header(header::USER_AGENT, &self.options.user_agent);
+            if body.is_some() {
+                request =
+                    request.header(header::CONTENT_TYPE, "application/json");
+            }
+            let request = request
+                .body(body.map_or_else(Body::empty, Body::from))
+                .map_err(|e| Error::Other(format!("invalid request: {e}")))?;
+            let response = sender.send_request(request).await?;
+            let status = response.status().as_u16();
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+            connection.abort();
+            Ok((status, body.to_vec()))
+        };
+
+        tokio::time::timeout(self.options.timeout, send)
+            .await
+            .map_err(|_| {
+                timed_out(format!(
+                    "request to {}",
+                    endpoint.url(tls.scheme(), path)
+                ))
+            })?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use tokio::net::TcpListener;
+
+    fn client(retries: u32) -> HttpClient {
+        HttpClient::new(HttpClientOptions {
+            retries,
+            retry_delay: Duration::from_millis(1),
+            max_retry_delay: Duration::from_millis(4),
+            ..Default::default()
+        })
+        .unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn test_parse_proxy() {
+        assert_eq!(parse_proxy("").unwrap(), None); //#[allow_ci]
+        assert_eq!(
+            parse_proxy("proxy.example.com:3128").unwrap(), //#[allow_ci]
+            Some(Endpoint::new("proxy.example.com", 3128))
+        );
+        assert_eq!(
+            parse_proxy("http://[::1]:8080").unwrap(), //#[allow_ci]
+            Some(Endpoint::new("::1", 8080))
+        );
+        assert_eq!(
+            parse_proxy("http://proxy").unwrap(), //#[allow_ci]
+            Some(Endpoint::new("proxy", 80))
+        );
+        assert!(parse_proxy("https://proxy:443").is_err());
+        assert!(parse_proxy("http://").is_err());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let client = HttpClient::new(HttpClientOptions {
+            retry_delay: Duration::from_millis(100),
+            max_retry_delay: Duration::from_millis(1000),
+            ..Default::default()
+        })
+        .unwrap(); //#[allow_ci]
+
+        for (attempt, max) in [(0, 100), (1, 200), (3, 800), (10, 1000)] {
+            let delay = client.backoff(attempt);
+            assert!(delay >= Duration::from_millis(max / 2));
+            assert!(delay <= Duration::from_millis(max));
+        }
+    }
+
+    #[actix_rt::test]
+    async fn test_retry() {
+        let attempts = AtomicU32::new(0);
+        let result = client(2)
+            .retry("server", || async {
+                let _ = attempts.fetch_add(1, Ordering::SeqCst);
+                check_status("http://server", 503)
+            })
+            .await;
+        assert!(matches!(result, Err(Error::Http { code: 503, .. })));
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // The errors which are not retryable are returned immediately
+        attempts.store(0, Ordering::SeqCst);
+        let result = client(2)
+            .retry("server", || async {
+                let _ = attempts.fetch_add(1, Ordering::SeqCst);
+                check_status("http://server", 404)
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        attempts.store(0, Ordering::SeqCst);
+        let result = client(2)
+            .retry("server", || async {
+                match attempts.fetch_add(1, Ordering::SeqCst) {
+                    0 => check_status("http://server", 429),
+                    _ => Ok(()),
+                }
+            })
+            .await;
+        assert!(result.is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+
+    #[actix_rt::test]
+    async fn test_proxy_connect() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap(); //#[allow_ci]
+        let port = listener.local_addr().unwrap().port(); //#[allow_ci]
+        let proxy = tokio::spawn(async move {
+            let (mut tcp, _) = listener.accept().await.unwrap(); //#[allow_ci]
+            let mut request = [0u8; 1024];
+            let n = tcp.read(&mut request).await.unwrap(); //#[allow_ci]
+            tcp.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                .await
+                .unwrap(); //#[allow_ci]
+            String::from_utf8_lossy(&request[..n]).to_string()
+        });
+
+        let client = HttpClient::new(HttpClientOptions {
+            proxy: Some(Endpoint::new("127.0.0.1", port.into())),
+            ..Default::default()
+        })
+        .unwrap(); //#[allow_ci]
+        assert!(client
+            .connect(&Endpoint::new("registrar.example.com", 8891))
+            .await
+            .is_ok());
+
+        let request = proxy.await.unwrap(); //#[allow_ci]
+        assert!(request
+            .starts_with("CONNECT registrar.example.com:8891 HTTP/1.1\r\n"));
+        assert!(request.contains("User-Agent: keylime-agent/"));
+    }
+}
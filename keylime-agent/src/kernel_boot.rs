@@ -0,0 +1,253 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Detection of kexec reboots and systemd soft-reboots
+//!
+//! A kexec starts a new kernel without resetting the platform, so the PCRs
+//! keep the measurements of the boot chain of the previous kernel, and a
+//! soft-reboot restarts the userspace without starting a new kernel. In both
+//! cases the boot time evidence does not describe what is running anymore.
+//!
+//! The kernel boot ID, which changes on every kernel start, is stored with
+//! the agent data together with the TPM reset counter, which only changes on
+//! a platform reset. A new boot ID with the same reset counter shows a
+//! kexec. The soft-reboots are counted by systemd.
+
+use crate::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, path::Path, process::Command};
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const KERNEL_VERSION_PATH: &str = "/proc/version";
+const KERNEL_NOTES_PATH: &str = "/sys/kernel/notes";
+
+/// The ELF note type of the GNU build ID
+const NT_GNU_BUILD_ID: u32 = 3;
+
+/// How the system was started since the previous run of the agent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum BootKind {
+    /// The agent did not run before on this system
+    Unknown,
+    /// Only the agent was restarted
+    Restart,
+    /// The platform was reset
+    Reboot,
+    /// A new kernel was started without a platform reset
+    Kexec,
+    /// The userspace was restarted on the same kernel
+    SoftReboot,
+}
+
+impl fmt::Display for BootKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self {
+            BootKind::Unknown => "unknown",
+            BootKind::Restart => "restart",
+            BootKind::Reboot => "reboot",
+            BootKind::Kexec => "kexec",
+            BootKind::SoftReboot => "soft_reboot",
+        };
+        write!(f, "{kind}")
+    }
+}
+
+/// The identity of the running kernel, compared across the agent runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct KernelBoot {
+    pub boot_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_build_id: Option<String>,
+    pub kernel_version: String,
+    /// Number of systemd soft-reboots since the kernel started, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub soft_reboots: Option<u64>,
+    pub tpm_reset_count: u32,
+}
+
+/// The kernel boot information included in the quotes
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct BootInfo {
+    pub boot_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kernel_build_id: Option<String>,
+    pub kernel_version: String,
+    pub boot_kind: BootKind,
+}
+
+impl KernelBoot {
+    /// Read the identity of the running kernel. The TPM reset counter is
+    /// the one read for the TPM state.
+    pub(crate) fn read(tpm_reset_count: u32) -> Result<Self> {
+        let boot_id = fs::read_to_string(BOOT_ID_PATH)?.trim().to_string();
+        let kernel_version =
+            fs::read_to_string(KERNEL_VERSION_PATH)?.trim().to_string();
+        let kernel_build_id = match fs::read(KERNEL_NOTES_PATH) {
+            Ok(notes) => build_id(&notes),
+            Err(e) => {
+                debug!("Unable to read {KERNEL_NOTES_PATH}: {e}");
+                None
+            }
+        };
+
+        Ok(KernelBoot {
+            boot_id,
+            kernel_build_id,
+            kernel_version,
+            soft_reboots: soft_reboots(),
+            tpm_reset_count,
+        })
+    }
+
+    /// Classify the start of the system since the previous state was read
+    pub(crate) fn kind(&self, previous: Option<&KernelBoot>) -> BootKind {
+        let Some(previous) = previous else {
+            return BootKind::Unknown;
+        };
+
+        if self.boot_id != previous.boot_id {
+            // The reset counter only changes on a platform reset, and goes
+            // backwards if the TPM was cleared
+            return match self.tpm_reset_count == previous.tpm_reset_count {
+                true => BootKind::Kexec,
+                false => BootKind::Reboot,
+            };
+        }
+
+        match (self.soft_reboots, previous.soft_reboots) {
+            (Some(current), Some(previous)) if current > previous => {
+                BootKind::SoftReboot
+            }
+            _ => BootKind::Restart,
+        }
+    }
+
+    pub(crate) fn info(&self, boot_kind: BootKind) -> BootInfo {
+        BootInfo {
+            boot_id: self.boot_id.clone(),
+            kernel_build_id: self.kernel_build_id.clone(),
+            kernel_version: self.kernel_version.clone(),
+            boot_kind,
+        }
+    }
+}
+
+/// Find the GNU build ID in the ELF notes of the kernel
+fn build_id(notes: &[u8]) -> Option<String> {
+    let word = |offset: usize| -> Option<u32> {
+        let bytes = notes.get(offset..offset + 4)?;
+        Some(u32::from_ne_bytes(bytes.try_into().ok()?))
+    };
+    let align = |size: usize| size.div_ceil(4) * 4;
+
+    let mut offset = 0;
+    while offset + 12 <= notes.len() {
+        let name_size = word(offset)? as usize;
+        let desc_size = word(offset + 4)? as usize;
+        let note_type = word(offset + 8)?;
+        let name_start = offset + 12;
+        let desc_start = name_start + align(name_size);
+        let desc = notes.get(desc_start..desc_start + desc_size)?;
+
+        if note_type == NT_GNU_BUILD_ID
+            && notes.get(name_start..name_start + name_size)
+                == Some(b"GNU\0".as_slice())
+        {
+            return Some(hex::encode(desc));
+        }
+        offset = desc_start + align(desc_size);
+    }
+
+    None
+}
+
+/// The number of soft-reboots counted by systemd, which is only available
+/// since systemd 256
+fn soft_reboots() -> Option<u64> {
+    if !Path::new("/run/systemd/system").exists() {
+        return None;
+    }
+    let output = Command::new("systemctl")
+        .args(["show", "--property=SoftRebootsCount", "--value"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+}
+
+/// Record the kexec and soft-reboots prominently, as the boot time evidence
+/// does not describe the running kernel or userspace
+pub(crate) fn report(kind: BootKind, current: &KernelBoot) {
+    match kind {
+        BootKind::Kexec => warn!(
+            "The kernel was started with kexec (boot ID {}): the PCRs still hold the measurements of the boot chain of the previous kernel",
+            current.boot_id
+        ),
+        BootKind::SoftReboot => warn!(
+            "The system was soft-rebooted (boot ID {}): the userspace was restarted on the same kernel",
+            current.boot_id
+        ),
+        kind => debug!("System start since the previous run: {kind}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot(boot_id: &str, reset_count: u32) -> KernelBoot {
+        KernelBoot {
+            boot_id: boot_id.to_string(),
+            kernel_build_id: None,
+            kernel_version: "Linux version 6.5.0".to_string(),
+            soft_reboots: Some(0),
+            tpm_reset_count: reset_count,
+        }
+    }
+
+    #[test]
+    fn test_kind() {
+        let previous = boot("a", 5);
+
+        assert_eq!(boot("a", 5).kind(None), BootKind::Unknown);
+        assert_eq!(boot("a", 5).kind(Some(&previous)), BootKind::Restart);
+        assert_eq!(boot("b", 6).kind(Some(&previous)), BootKind::Reboot);
+        assert_eq!(boot("b", 5).kind(Some(&previous)), BootKind::Kexec);
+
+        let mut current = boot("a", 5);
+        current.soft_reboots = Some(1);
+        assert_eq!(current.kind(Some(&previous)), BootKind::SoftReboot);
+
+        // Without the count from systemd a soft-reboot is a restart
+        current.soft_reboots = None;
+        assert_eq!(current.kind(Some(&previous)), BootKind::Restart);
+    }
+
+    #[test]
+    fn test_build_id() {
+        let mut notes = Vec::new();
+        // A note of another type, with a name needing padding
+        for word in [5u32, 2, 1] {
+            notes.extend_from_slice(&word.to_ne_bytes());
+        }
+        notes.extend_from_slice(b"Linux\0\0\0\x01\x02\0\0");
+        for word in [4u32, 4, NT_GNU_BUILD_ID] {
+            notes.extend_from_slice(&word.to_ne_bytes());
+        }
+        notes.extend_from_slice(b"GNU\0\xde\xad\xbe\xef");
+
+        assert_eq!(build_id(&notes), Some("deadbeef".to_string()));
+        assert_eq!(build_id(&notes[..20]), None);
+    }
+
+    #[test]
+    fn test_read() {
+        let current = KernelBoot::read(1).unwrap(); //#[allow_ci]
+        assert_eq!(current.boot_id.len(), 36);
+        assert_eq!(current.kind(Some(&current)), BootKind::Restart);
+    }
+}
@@ -3,37 +3,37 @@
 
 use crate::crypto;
 use crate::{
+    clock::Clock,
     common::{
         AuthTag, EncryptedData, JsonWrapper, KeySet, SymmKey, AES_BLOCK_SIZE,
-        AGENT_UUID_LEN, AUTH_TAG_LEN,
+        AGENT_UUID_LEN,
     },
     config::KeylimeConfig,
+    events::{AgentEvent, EventPublisher},
+    luks::LuksDevice,
     payloads::{Payload, PayloadMessage},
-    Error, QuoteData, Result,
+    resources::Reservation,
+    service, spire, Error, QuoteData, Result,
 };
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use actix_web::{web, HttpResponse, Responder};
 use base64::{engine::general_purpose, Engine as _};
+pub(crate) use keylime::key_delivery::{
+    DeliverySession, KeylimeKeys, KeylimeUKey, KeylimeVKey,
+};
+use keylime::payload::PayloadDelivery;
 use log::*;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::convert::TryInto;
-use tokio::sync::{
-    mpsc::{Receiver, Sender},
-    oneshot,
+use std::{
+    collections::HashMap, convert::TryInto, path::PathBuf, time::Duration,
+};
+use tokio::{
+    sync::{
+        mpsc::{Receiver, Sender},
+        oneshot,
+    },
+    time::Instant,
 };
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct KeylimeUKey {
-    auth_tag: String,
-    encrypted_key: String,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    payload: Option<String>,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-pub struct KeylimeVKey {
-    encrypted_key: String,
-}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct KeylimePubkey {
@@ -47,32 +47,452 @@ pub struct KeylimeChallenge {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct KeylimeHMAC {
-    hmac: String,
+    pub(crate) hmac: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeylimeKeyCertify {
+    handle: String,
+    name: Option<String>,
+    nonce: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimeKeyCertification {
+    pub(crate) name: String,
+    pub(crate) public: String,
+    pub(crate) certify_info: String,
+    pub(crate) signature: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct UKey {
-    decrypted_key: SymmKey,
-    auth_tag: AuthTag,
-    payload: Option<EncryptedData>,
+    pub(crate) decrypted_key: SymmKey,
+    pub(crate) auth_tag: AuthTag,
+    pub(crate) payload: Option<EncryptedData>,
+    pub(crate) delivery: Option<PayloadDelivery>,
+    pub(crate) session: Option<DeliverySession>,
+    // Seconds the combined key and the payload are kept, if limited
+    pub(crate) ttl: Option<u64>,
+    // Memory reserved for the payload while the key is kept
+    #[serde(skip)]
+    pub(crate) reservation: Option<Reservation>,
+}
+
+impl UKey {
+    // A retransmitted U key carries the same data
+    fn same_key(&self, other: &UKey) -> bool {
+        self.decrypted_key == other.decrypted_key
+            && self.auth_tag == other.auth_tag
+            && self.payload == other.payload
+            && self.delivery == other.delivery
+            && self.ttl == other.ttl
+    }
+
+    // Identifies the key without keeping it once the delivery completed
+    fn digest(&self) -> [u8; 32] {
+        let mut hasher = openssl::sha::Sha256::new();
+        hasher.update(self.decrypted_key.as_ref());
+        hasher.update(self.auth_tag.as_ref());
+        hasher.finish()
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) struct VKey {
-    decrypted_key: SymmKey,
+    pub(crate) decrypted_key: SymmKey,
+    pub(crate) session: Option<DeliverySession>,
+}
+
+impl VKey {
+    // Identifies the key without keeping it once the delivery completed
+    fn digest(&self) -> [u8; 32] {
+        openssl::sha::sha256(self.decrypted_key.as_ref())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) enum KeyMessage {
     UKey(UKey),
     VKey(VKey),
+    // Both halves, combined without waiting for other keys
+    Keys(Box<(UKey, VKey)>),
     Shutdown,
     GetSymmKey,
+    ListKeys,
+    WipeKeys,
+}
+
+/// Error rejecting a U or V key delivered in a session
+#[derive(
+    Debug, Clone, PartialEq, Eq, Deserialize, Serialize, thiserror::Error,
+)]
+pub(crate) enum DeliveryError {
+    #[error("Session {id} was started with sequence {expected}, not {got}")]
+    SequenceMismatch { id: String, expected: u64, got: u64 },
+    #[error("A different {half} key was already delivered in session {id}")]
+    ConflictingKey { id: String, half: String },
+    #[error("Session {id} with sequence {sequence} is older than the last completed delivery with sequence {completed}")]
+    Stale {
+        id: String,
+        sequence: u64,
+        completed: u64,
+    },
+    #[error("The U and V keys delivered in session {id} do not match the auth tag")]
+    InvalidAuthTag { id: String },
+    #[error("The U and V keys do not match the auth tag")]
+    InvalidKeys,
+    #[error("Too many delivery sessions waiting for the other key")]
+    TooManySessions,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
 pub(crate) enum SymmKeyMessage {
     SymmKey(Option<SymmKey>),
+    Delivery(std::result::Result<(), DeliveryError>),
+    Keys(KeyInventory),
+}
+
+/// A delivery session waiting for one of its key halves
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct SessionInventory {
+    pub(crate) id: String,
+    pub(crate) sequence: u64,
+    pub(crate) ukey: bool,
+    pub(crate) vkey: bool,
+}
+
+/// Metadata of the keys held by the keys worker. The keys themselves are
+/// never included.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct KeyInventory {
+    /// Length in bytes of the bootstrap key, if derived
+    pub(crate) bootstrap_key_len: Option<usize>,
+    /// The delivery session the bootstrap key was derived from, if any
+    pub(crate) completed_session: Option<DeliverySession>,
+    /// U and V keys delivered without a session, waiting to be combined
+    pub(crate) ukeys: usize,
+    pub(crate) vkeys: usize,
+    pub(crate) sessions: Vec<SessionInventory>,
+    /// When the bootstrap key and the payload expire, in seconds since the
+    /// UNIX epoch, if their time to live is limited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expires_at: Option<u64>,
+    /// When the last bootstrap key expired, until a new one is delivered
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) expired_at: Option<u64>,
+}
+
+/// The time to live of the bootstrap key and the payload derived from it
+#[derive(Debug, Default)]
+struct KeyLifetime {
+    expires: Option<(Instant, u64)>,
+    expired_at: Option<u64>,
+}
+
+impl KeyLifetime {
+    /// Start the lifetime of a newly derived key, replacing the previous one
+    fn start(&mut self, ttl: Option<u64>, clock: &dyn Clock) {
+        self.expires = ttl.map(|ttl| {
+            (
+                Instant::now() + Duration::from_secs(ttl),
+                clock.unix_time().saturating_add(ttl),
+            )
+        });
+        self.expired_at = None;
+    }
+
+    fn expire(&mut self, clock: &dyn Clock) {
+        self.expires = None;
+        self.expired_at = Some(clock.unix_time());
+    }
+
+    fn inventory(&self, inventory: KeyInventory) -> KeyInventory {
+        KeyInventory {
+            expires_at: self.expires.map(|(_, at)| at),
+            expired_at: self.expired_at,
+            ..inventory
+        }
+    }
+}
+
+/// Maximum number of delivery sessions waiting for the other key half
+const MAX_PENDING_SESSIONS: usize = 16;
+
+#[derive(Debug)]
+struct PendingDelivery {
+    sequence: u64,
+    ukey: Option<UKey>,
+    vkey: Option<VKey>,
+}
+
+/// The halves of a session that were already waiting when the last one
+/// was received
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+struct Waiting {
+    ukey: bool,
+    vkey: bool,
+}
+
+/// The state of a delivery session after receiving one of its halves
+#[derive(Debug)]
+enum Delivery {
+    Pending,
+    Complete(DeliverySession, Box<(UKey, VKey)>, Waiting),
+    // Both halves delivered together without a session
+    Batch(Box<(UKey, VKey)>),
+}
+
+/// The last completed delivery, with digests of its halves to tell
+/// retransmits from conflicting keys
+#[derive(Debug)]
+struct CompletedDelivery {
+    session: DeliverySession,
+    ukey: [u8; 32],
+    vkey: [u8; 32],
+}
+
+impl CompletedDelivery {
+    fn new(session: DeliverySession, ukey: &UKey, vkey: &VKey) -> Self {
+        CompletedDelivery {
+            session,
+            ukey: ukey.digest(),
+            vkey: vkey.digest(),
+        }
+    }
+}
+
+/// Tracks the U and V keys delivered in sessions until both halves of a
+/// session are received
+#[derive(Debug, Default)]
+struct DeliverySessions {
+    pending: HashMap<String, PendingDelivery>,
+    completed: Option<CompletedDelivery>,
+}
+
+impl DeliverySessions {
+    fn add(
+        &mut self,
+        session: &DeliverySession,
+        ukey: Option<UKey>,
+        vkey: Option<VKey>,
+    ) -> std::result::Result<Delivery, DeliveryError> {
+        if let Some(CompletedDelivery {
+            session: completed,
+            ukey: ukey_digest,
+            vkey: vkey_digest,
+        }) = &self.completed
+        {
+            if completed.id == session.id {
+                if completed.sequence != session.sequence {
+                    return Err(DeliveryError::SequenceMismatch {
+                        id: session.id.clone(),
+                        expected: completed.sequence,
+                        got: session.sequence,
+                    });
+                }
+                if ukey.is_some_and(|u| u.digest() != *ukey_digest) {
+                    return Err(DeliveryError::ConflictingKey {
+                        id: session.id.clone(),
+                        half: "U".to_string(),
+                    });
+                }
+                if vkey.is_some_and(|v| v.digest() != *vkey_digest) {
+                    return Err(DeliveryError::ConflictingKey {
+                        id: session.id.clone(),
+                        half: "V".to_string(),
+                    });
+                }
+                // Retransmit of a key of the completed delivery
+                return Ok(Delivery::Pending);
+            }
+            if session.sequence <= completed.sequence {
+                return Err(DeliveryError::Stale {
+                    id: session.id.clone(),
+                    sequence: session.sequence,
+                    completed: completed.sequence,
+                });
+            }
+        }
+
+        if !self.pending.contains_key(&session.id)
+            && self.pending.len() >= MAX_PENDING_SESSIONS
+        {
+            return Err(DeliveryError::TooManySessions);
+        }
+
+        let pending =
+            self.pending.entry(session.id.clone()).or_insert_with(|| {
+                PendingDelivery {
+                    sequence: session.sequence,
+                    ukey: None,
+                    vkey: None,
+                }
+            });
+
+        if pending.sequence != session.sequence {
+            return Err(DeliveryError::SequenceMismatch {
+                id: session.id.clone(),
+                expected: pending.sequence,
+                got: session.sequence,
+            });
+        }
+
+        let waiting = Waiting {
+            ukey: pending.ukey.is_some(),
+            vkey: pending.vkey.is_some(),
+        };
+
+        if let Some(ukey) = ukey {
+            match &pending.ukey {
+                Some(u) if !u.same_key(&ukey) => {
+                    return Err(DeliveryError::ConflictingKey {
+                        id: session.id.clone(),
+                        half: "U".to_string(),
+                    });
+                }
+                Some(_) => debug!("Ignoring retransmitted U key"),
+                None => pending.ukey = Some(ukey),
+            }
+        }
+
+        if let Some(vkey) = vkey {
+            match &pending.vkey {
+                Some(v) if v.decrypted_key != vkey.decrypted_key => {
+                    return Err(DeliveryError::ConflictingKey {
+                        id: session.id.clone(),
+                        half: "V".to_string(),
+                    });
+                }
+                Some(_) => debug!("Ignoring retransmitted V key"),
+                None => pending.vkey = Some(vkey),
+            }
+        }
+
+        if pending.ukey.is_none() || pending.vkey.is_none() {
+            return Ok(Delivery::Pending);
+        }
+
+        match self.pending.remove(&session.id) {
+            Some(PendingDelivery {
+                ukey: Some(u),
+                vkey: Some(v),
+                ..
+            }) => Ok(Delivery::Complete(
+                session.clone(),
+                Box::new((u, v)),
+                waiting,
+            )),
+            _ => Ok(Delivery::Pending),
+        }
+    }
+
+    fn complete(&mut self, completed: CompletedDelivery) {
+        // Older deliveries can not complete anymore
+        self.pending
+            .retain(|_, p| p.sequence > completed.session.sequence);
+        self.completed = Some(completed);
+    }
+
+    /// Put back the halves that were waiting before a delivery whose
+    /// combined key failed the auth tag check, so that a bad half does not
+    /// discard the session. The half just received is dropped.
+    fn restore(
+        &mut self,
+        session: &DeliverySession,
+        mut ukey: Option<UKey>,
+        mut vkey: Option<VKey>,
+        waiting: Waiting,
+    ) {
+        if !waiting.ukey {
+            if let Some(mut u) = ukey.take() {
+                u.decrypted_key.zeroize();
+            }
+        }
+        if !waiting.vkey {
+            if let Some(mut v) = vkey.take() {
+                v.decrypted_key.zeroize();
+            }
+        }
+        if ukey.is_some() || vkey.is_some() {
+            let _ = self.pending.insert(
+                session.id.clone(),
+                PendingDelivery {
+                    sequence: session.sequence,
+                    ukey,
+                    vkey,
+                },
+            );
+        }
+    }
+
+    fn inventory(&self) -> Vec<SessionInventory> {
+        let mut sessions: Vec<SessionInventory> = self
+            .pending
+            .iter()
+            .map(|(id, p)| SessionInventory {
+                id: id.clone(),
+                sequence: p.sequence,
+                ukey: p.ukey.is_some(),
+                vkey: p.vkey.is_some(),
+            })
+            .collect();
+        sessions.sort_by(|a, b| a.id.cmp(&b.id));
+        sessions
+    }
+
+    /// Discard the pending halves. The completed session is kept, so that
+    /// the keys of that delivery are still rejected if replayed.
+    fn wipe(&mut self) {
+        for (_, pending) in self.pending.drain() {
+            if let Some(mut ukey) = pending.ukey {
+                ukey.decrypted_key.zeroize();
+            }
+            if let Some(mut vkey) = pending.vkey {
+                vkey.decrypted_key.zeroize();
+            }
+        }
+    }
+}
+
+fn key_inventory(
+    ukeys: &[UKey],
+    vkeys: &[VKey],
+    sessions: &DeliverySessions,
+    symm_key: &Option<SymmKey>,
+) -> KeyInventory {
+    KeyInventory {
+        bootstrap_key_len: symm_key.as_ref().map(|k| k.as_ref().len()),
+        completed_session: sessions
+            .completed
+            .as_ref()
+            .map(|c| c.session.clone()),
+        ukeys: ukeys.len(),
+        vkeys: vkeys.len(),
+        sessions: sessions.inventory(),
+        ..Default::default()
+    }
+}
+
+/// Overwrite and discard all the keys held by the keys worker
+fn wipe_keys(
+    ukeys: &mut Vec<UKey>,
+    vkeys: &mut Vec<VKey>,
+    sessions: &mut DeliverySessions,
+    symm_key: &mut Option<SymmKey>,
+) {
+    for ukey in ukeys.iter_mut() {
+        ukey.decrypted_key.zeroize();
+    }
+    ukeys.clear();
+    for vkey in vkeys.iter_mut() {
+        vkey.decrypted_key.zeroize();
+    }
+    vkeys.clear();
+    sessions.wipe();
+    if let Some(mut key) = symm_key.take() {
+        key.zeroize();
+    }
 }
 
 // Attempt to combine U and V keys into the payload decryption key. An HMAC over
@@ -83,7 +503,7 @@ fn try_combine_keys(
     ukeys: &mut Vec<UKey>,
     vkeys: &mut Vec<VKey>,
     uuid: &[u8],
-) -> Option<(SymmKey, Option<Payload>)> {
+) -> Option<(SymmKey, Option<Payload>, Option<u64>)> {
     // U, V keys and auth_tag must be present for this to succeed
     if ukeys.is_empty() || vkeys.is_empty() {
         debug!("Still waiting on u or v key");
@@ -101,7 +521,8 @@ fn try_combine_keys(
 
             // Computes HMAC over agent UUID with provided key (payload decryption key) and
             // checks that this matches the provided auth_tag.
-            if crypto::verify_hmac(
+            if crypto::verify_hmac_with_digest(
+                ukey.auth_tag.alg().into(),
                 symm_key.as_ref(),
                 uuid,
                 ukey.auth_tag.as_ref(),
@@ -116,12 +537,14 @@ fn try_combine_keys(
                     ukey.payload.as_ref().map(|encrypted_payload| Payload {
                         symm_key: symm_key.clone(),
                         encrypted_payload: encrypted_payload.clone(),
+                        delivery: ukey.delivery,
                     });
+                let ttl = ukey.ttl;
 
                 ukeys.clear();
                 vkeys.clear();
 
-                return Some((symm_key, payload));
+                return Some((symm_key, payload, ttl));
             }
         }
     }
@@ -132,211 +555,101 @@ fn try_combine_keys(
 
 pub(crate) async fn u_key(
     body: web::Json<KeylimeUKey>,
-    req: HttpRequest,
     quote_data: web::Data<QuoteData>,
 ) -> impl Responder {
-    debug!("Received ukey");
-
-    // get key and decode it from web data
-    let encrypted_key = match general_purpose::STANDARD
-        .decode(&body.encrypted_key)
-        .map_err(Error::from)
-    {
-        Ok(k) => k,
+    match service::deliver_u_key(&quote_data, &body).await {
+        Ok(()) => HttpResponse::Ok().json(JsonWrapper::success(())),
         Err(e) => {
             warn!(
-                    "POST u_key returning 400 response. Invalid base64 encoding in encrypted_key: {e}"
-                );
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                format!("Invalid base64 encoding in encrypted_key: {e}"),
-            ));
-        }
-    };
-
-    // Uses NK (key for encrypting data from verifier or tenant to agent in transit) to
-    // decrypt U and V keys, which will be combined into one key that can decrypt the
-    // payload.
-    //
-    // Reference:
-    // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
-    // keylime/crypto.py#L118
-    let decrypted_key = match crypto::rsa_oaep_decrypt(
-        &quote_data.priv_key,
-        &encrypted_key,
-    )
-    .map_err(Error::from)
-    {
-        Ok(k) => k,
-        Err(e) => {
-            warn!("POST u_key returning 400 response. Failed to decrypt encrypted_key: {e}");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                format!("Failed to decrypt encrypted_key: {e}"),
-            ));
-        }
-    };
-
-    let decrypted_key: SymmKey = match decrypted_key.as_slice().try_into() {
-        Ok(k) => k,
-        Err(e) => {
-            warn!("POST u_key returning 400 response. Invalid decrypted key: {e}");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                format!("Invalid decrypted key: {e}"),
-            ));
-        }
-    };
-
-    let auth_tag = match hex::decode(&body.auth_tag).map_err(Error::from) {
-        Ok(t) => t,
-        Err(e) => {
-            warn!("POST u_key returning 400 response: Invalid hex encoding in auth_tag: {e}");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                format!("Invalid hex encoding in auth_tag: {e}"),
-            ));
-        }
-    };
-
-    let auth_tag = match auth_tag.as_slice().try_into() {
-        Ok(t) => t,
-        Err(e) => {
-            warn!("POST u_key returning 400 response: {e}");
-            return HttpResponse::BadRequest()
-                .json(JsonWrapper::error(400, e));
+                "POST u_key returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
         }
-    };
-
-    let payload = match &body.payload {
-        Some(data) => match general_purpose::STANDARD
-            .decode(data)
-            .map_err(Error::from)
-        {
-            Ok(d) => Some(d.into()),
-            Err(e) => {
-                warn!("POST u_key returning 400 response. Invalid base64 encoding in payload: {e}");
-                return HttpResponse::BadRequest().json(JsonWrapper::error(
-                    400,
-                    format!("Invalid base64 encoding in payload: {e}"),
-                ));
-            }
-        },
-        None => None,
-    };
-
-    let m = KeyMessage::UKey(UKey {
-        decrypted_key,
-        auth_tag,
-        payload,
-    });
-
-    debug!("Sending UKey message to keys worker");
-
-    if let Err(e) = quote_data.keys_tx.send((m, None)).await {
-        warn!("Failed to send UKey message to keys worker");
-        return HttpResponse::InternalServerError().json(JsonWrapper::error(
-            500,
-            "Failed to send UKey message to keys worker".to_string(),
-        ));
     }
-
-    HttpResponse::Ok().json(JsonWrapper::success(()))
 }
 
 pub(crate) async fn v_key(
     body: web::Json<KeylimeVKey>,
-    req: HttpRequest,
     quote_data: web::Data<QuoteData>,
 ) -> impl Responder {
-    debug!("Received vkey");
-
-    // get key and decode it from web data
-    let encrypted_key = match general_purpose::STANDARD
-        .decode(&body.encrypted_key)
-        .map_err(Error::from)
-    {
-        Ok(k) => k,
+    match service::deliver_v_key(&quote_data, &body).await {
+        Ok(()) => HttpResponse::Ok().json(JsonWrapper::success(())),
         Err(e) => {
-            warn!("POST v_key returning 400 response. Invalid base64 encoding in encrypted_key: {e}");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                format!("Invalid base64 encoding in encrypted_key: {e}"),
-            ));
+            warn!(
+                "POST v_key returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
         }
-    };
+    }
+}
 
-    // Uses NK (key for encrypting data from verifier or tenant to agent in transit) to
-    // decrypt U and V keys, which will be combined into one key that can decrypt the
-    // payload.
-    //
-    // Reference:
-    // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
-    // keylime/crypto.py#L118
-    let decrypted_key = match crypto::rsa_oaep_decrypt(
-        &quote_data.priv_key,
-        &encrypted_key,
-    )
-    .map_err(Error::from)
-    {
-        Ok(k) => k,
+pub(crate) async fn keys(
+    body: web::Json<KeylimeKeys>,
+    quote_data: web::Data<QuoteData>,
+) -> impl Responder {
+    match service::deliver_keys(&quote_data, &body).await {
+        Ok(()) => HttpResponse::Ok().json(JsonWrapper::success(())),
         Err(e) => {
-            warn!("POST v_key returning 400 response. Failed to decrypt encrypted_key: {e}");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                format!("Failed to decrypt encrypted_key: {e}"),
-            ));
+            warn!(
+                "POST keys returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
         }
-    };
+    }
+}
 
-    let decrypted_key: SymmKey = match decrypted_key.as_slice().try_into() {
-        Ok(k) => k,
+pub(crate) async fn delivered_keys(
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    match service::list_keys(&data).await {
+        Ok(inventory) => {
+            info!("GET delivered keys returning 200 response.");
+            HttpResponse::Ok().json(JsonWrapper::success(inventory))
+        }
         Err(e) => {
-            warn!("POST v_key returning 400 response. Decrypted key is invalid: {e}");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                format!("Decrypted key is invalid: {e}"),
-            ));
+            warn!(
+                "GET delivered keys returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
         }
-    };
-
-    let m = KeyMessage::VKey(VKey { decrypted_key });
-
-    debug!("Sending VKey message to keys worker");
-
-    if let Err(e) = quote_data.keys_tx.send((m, None)).await {
-        warn!("Failed to send VKey message to keys worker");
-        return HttpResponse::InternalServerError().json(JsonWrapper::error(
-            500,
-            "Failed to send VKey message to keys worker".to_string(),
-        ));
     }
-
-    HttpResponse::Ok().json(JsonWrapper::success(()))
 }
 
-pub(crate) async fn pubkey(
-    req: HttpRequest,
+pub(crate) async fn wipe_delivered_keys(
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    match crypto::pkey_pub_to_pem(&data.pub_key) {
+    match service::wipe_keys(&data).await {
+        Ok(wipe) => {
+            info!("DELETE delivered keys returning 200 response.");
+            HttpResponse::Ok().json(JsonWrapper::success(wipe))
+        }
+        Err(e) => {
+            warn!(
+                "DELETE delivered keys returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
+        }
+    }
+}
+
+pub(crate) async fn pubkey(data: web::Data<QuoteData>) -> impl Responder {
+    match service::pubkey(&data) {
         Ok(pubkey) => {
             let response = JsonWrapper::success(KeylimePubkey { pubkey });
             info!("GET pubkey returning 200 response.");
 
             HttpResponse::Ok().json(response)
         }
-        Err(e) => {
-            debug!("Unable to retrieve public key: {:?}", e);
-            HttpResponse::InternalServerError().json(JsonWrapper::error(
-                500,
-                "Unable to retrieve public key".to_string(),
-            ))
-        }
+        Err(e) => e.to_response(),
     }
 }
 
-async fn get_symm_key(
+pub(crate) async fn get_symm_key(
     keys_tx: Sender<(KeyMessage, Option<oneshot::Sender<SymmKeyMessage>>)>,
 ) -> Result<Option<SymmKey>> {
     let (resp_tx, resp_rx) = oneshot::channel::<SymmKeyMessage>();
@@ -366,62 +679,48 @@ async fn get_symm_key(
 
 pub(crate) async fn verify(
     param: web::Query<KeylimeChallenge>,
-    req: HttpRequest,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    if param.challenge.is_empty() {
-        warn!(
-            "GET key challenge returning 400 response. No challenge provided"
-        );
-        return HttpResponse::BadRequest()
-            .json(JsonWrapper::error(400, "No challenge provided."));
-    }
-
-    if !param.challenge.chars().all(char::is_alphanumeric) {
-        warn!("GET key challenge returning 400 response. Parameters should be strictly alphanumeric: {}", param.challenge);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Parameters should be strictly alphanumeric: {}",
-                param.challenge
-            ),
-        ));
+    match service::verify_key(&data, &param.challenge).await {
+        Ok(hmac) => {
+            info!("GET key challenge returning 200 response.");
+            HttpResponse::Ok()
+                .json(JsonWrapper::success(KeylimeHMAC { hmac }))
+        }
+        Err(e) => {
+            warn!(
+                "GET key challenge returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
+        }
     }
+}
 
-    // Send a message requesting the symmetric key
-    if let Ok(key) = get_symm_key(data.keys_tx.clone()).await {
-        let k = match key {
-            Some(k) => k,
-            None => {
-                warn!("GET key challenge returning 400 response. Bootstrap key not available");
-                return HttpResponse::BadRequest().json(JsonWrapper::error(
-                    400,
-                    "Bootstrap key not yet available.",
-                ));
-            }
-        };
-
-        match crypto::compute_hmac(k.as_ref(), param.challenge.as_bytes()) {
-            Ok(hmac) => {
-                let response = JsonWrapper::success(KeylimeHMAC {
-                    hmac: hex::encode(hmac),
-                });
-
-                info!("GET key challenge returning 200 response.");
-                HttpResponse::Ok().json(response)
-            }
-            Err(e) => {
-                warn!("GET key challenge failed: {:?}", e);
-                HttpResponse::InternalServerError().json(JsonWrapper::error(
-                    500,
-                    "GET key challenge failed".to_string(),
-                ))
-            }
+pub(crate) async fn certify(
+    body: web::Json<KeylimeKeyCertify>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    match service::certify_key(
+        &data,
+        &body.handle,
+        body.name.as_deref(),
+        &body.nonce,
+    ) {
+        Ok(certification) => {
+            info!(
+                "POST key certification for {} returning 200 response.",
+                body.handle
+            );
+            HttpResponse::Ok().json(JsonWrapper::success(certification))
+        }
+        Err(e) => {
+            warn!(
+                "POST key certification returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
         }
-    } else {
-        warn!("GET key challenge returning 500 response. Failed to get bootstrap key.");
-        HttpResponse::InternalServerError()
-            .json(JsonWrapper::error(500, "Failed to get bootstrap key."))
     }
 }
 
@@ -447,9 +746,9 @@ async fn process_keys(
     uuid: String,
     payloads_tx: Sender<PayloadMessage>,
     run_payload: bool,
-) -> Option<SymmKey> {
+) -> Option<(SymmKey, Option<u64>)> {
     match try_combine_keys(ukeys, vkeys, uuid.as_bytes()) {
-        Some((key, p)) => {
+        Some((key, p, ttl)) => {
             if run_payload {
                 if let Some(payload) = p {
                     match request_run_payload(payloads_tx.clone(), payload)
@@ -469,12 +768,74 @@ async fn process_keys(
                 warn!("agent mTLS is disabled, and unless 'enable_insecure_payload' is set to 'True', payloads cannot be deployed'");
             }
 
-            Some(key)
+            Some((key, ttl))
         }
         None => None,
     }
 }
 
+/// Discard the bootstrap key and the payload decrypted with it at the end of
+/// their time to live. A new delivery of the keys renews them.
+async fn expire_keys(
+    symm_key: &mut Option<SymmKey>,
+    payloads_tx: &Sender<PayloadMessage>,
+    spire_attestation_path: Option<&PathBuf>,
+    events: &EventPublisher,
+) {
+    if let Some(mut key) = symm_key.take() {
+        key.zeroize();
+    }
+    if let Some(path) = spire_attestation_path {
+        spire::remove(path);
+    }
+    if payloads_tx.send(PayloadMessage::Expire).await.is_err() {
+        warn!("Failed to send Expire message to payloads worker");
+    }
+    info!("The delivered keys expired and were discarded");
+    events.publish(AgentEvent::KeysExpired);
+}
+
+/// Unlock the LUKS device with the passphrase derived from the bootstrap key
+/// and report the outcome
+async fn unlock_device(
+    luks: &LuksDevice,
+    uuid: &str,
+    key: &SymmKey,
+    events: &EventPublisher,
+) {
+    let device = luks.device.display().to_string();
+    // The error is not Send, so only its description leaves the thread
+    let result = {
+        let (luks, uuid, key) = (luks.clone(), uuid.to_string(), key.clone());
+        tokio::task::spawn_blocking(move || {
+            luks.unlock(&key, &uuid).map_err(|e| e.to_string())
+        })
+        .await
+        .unwrap_or_else(|e| Err(e.to_string()))
+    };
+
+    let event = match result {
+        Ok(status) => {
+            info!("LUKS device {device} unlocked ({status})");
+            AgentEvent::DiskUnlock {
+                device,
+                status: status.as_str(),
+                error: None,
+            }
+        }
+        Err(e) => {
+            error!("Failed to unlock LUKS device {device}: {e}");
+            AgentEvent::DiskUnlock {
+                device,
+                status: "failed",
+                error: Some(e),
+            }
+        }
+    };
+    events.publish(event);
+}
+
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn worker(
     run_payload: bool,
     uuid: String,
@@ -483,16 +844,59 @@ pub(crate) async fn worker(
         Option<oneshot::Sender<SymmKeyMessage>>,
     )>,
     mut payloads_tx: Sender<PayloadMessage>,
+    spire_attestation_path: Option<PathBuf>,
+    luks: Option<LuksDevice>,
+    key_ttl: Option<u64>,
+    events: EventPublisher,
 ) -> Result<()> {
     let mut ukeys: Vec<UKey> = Vec::new();
     let mut vkeys: Vec<VKey> = Vec::new();
+    let mut sessions = DeliverySessions::default();
     let mut symm_key: Option<SymmKey> = None;
+    let mut lifetime = KeyLifetime::default();
+    // Time of the next attestation result for SPIRE, while the key is held
+    let mut spire_refresh: Option<Instant> = None;
 
     debug!("Starting keys worker");
 
-    // Receive message
-    while let Some((message, resp_tx)) = keys_rx.recv().await {
-        match message {
+    loop {
+        // Receive message, until the delivered keys expire or the
+        // attestation result for SPIRE has to be refreshed
+        let now = Instant::now();
+        let expires = lifetime.expires.map(|(deadline, _)| deadline);
+        let received = tokio::select! {
+            received = keys_rx.recv() => received,
+            _ = tokio::time::sleep_until(expires.unwrap_or(now)),
+                if expires.is_some() =>
+            {
+                expire_keys(
+                    &mut symm_key,
+                    &payloads_tx,
+                    spire_attestation_path.as_ref(),
+                    &events,
+                )
+                .await;
+                lifetime.expire(events.clock());
+                spire_refresh = None;
+                continue;
+            }
+            _ = tokio::time::sleep_until(spire_refresh.unwrap_or(now)),
+                if spire_refresh.is_some() =>
+            {
+                if let (Some(path), Some(key)) =
+                    (&spire_attestation_path, &symm_key)
+                {
+                    spire::export(path, &uuid, key, events.clock());
+                }
+                spire_refresh = Some(Instant::now() + spire::REFRESH_INTERVAL);
+                continue;
+            }
+        };
+        let Some((message, resp_tx)) = received else {
+            break;
+        };
+
+        let delivery = match message {
             KeyMessage::GetSymmKey => {
                 if let Some(r) = resp_tx {
                     if let Err(e) =
@@ -503,40 +907,157 @@ pub(crate) async fn worker(
                 } else {
                     debug!("Empty receiver in GetSymmKey message");
                 }
+                continue;
             }
             KeyMessage::Shutdown => {
                 keys_rx.close();
+                continue;
             }
-            KeyMessage::UKey(ukey) => {
-                // Store received data
-                ukeys.push(ukey);
-                if let Some(key) = process_keys(
-                    &mut ukeys,
-                    &mut vkeys,
-                    uuid.clone(),
-                    payloads_tx.clone(),
-                    run_payload,
-                )
-                .await
-                {
-                    symm_key = Some(key);
+            KeyMessage::ListKeys => {
+                let inventory = lifetime.inventory(key_inventory(
+                    &ukeys, &vkeys, &sessions, &symm_key,
+                ));
+                if let Some(r) = resp_tx {
+                    if r.send(SymmKeyMessage::Keys(inventory)).is_err() {
+                        debug!("Failed to send Keys message");
+                    }
                 }
+                continue;
             }
-            KeyMessage::VKey(vkey) => {
-                // Store received data
-                vkeys.push(vkey);
-                if let Some(key) = process_keys(
+            KeyMessage::WipeKeys => {
+                let inventory = lifetime.inventory(key_inventory(
+                    &ukeys, &vkeys, &sessions, &symm_key,
+                ));
+                wipe_keys(
                     &mut ukeys,
                     &mut vkeys,
+                    &mut sessions,
+                    &mut symm_key,
+                );
+                lifetime = KeyLifetime::default();
+                if let Some(path) = &spire_attestation_path {
+                    spire::remove(path);
+                }
+                spire_refresh = None;
+                info!("Wiped the delivered keys");
+                events.publish(AgentEvent::KeysWiped);
+                // Reply with what was wiped
+                if let Some(r) = resp_tx {
+                    if r.send(SymmKeyMessage::Keys(inventory)).is_err() {
+                        debug!("Failed to send Keys message");
+                    }
+                }
+                continue;
+            }
+            KeyMessage::UKey(ukey) => match ukey.session.clone() {
+                Some(session) => sessions.add(&session, Some(ukey), None),
+                None => {
+                    // Store received data, ignoring retransmits
+                    if !ukeys.iter().any(|u| u.same_key(&ukey)) {
+                        ukeys.push(ukey);
+                    }
+                    Ok(Delivery::Pending)
+                }
+            },
+            KeyMessage::VKey(vkey) => match vkey.session.clone() {
+                Some(session) => sessions.add(&session, None, Some(vkey)),
+                None => {
+                    // Store received data, ignoring retransmits
+                    if !vkeys
+                        .iter()
+                        .any(|v| v.decrypted_key == vkey.decrypted_key)
+                    {
+                        vkeys.push(vkey);
+                    }
+                    Ok(Delivery::Pending)
+                }
+            },
+            KeyMessage::Keys(keys) => match keys.0.session.clone() {
+                Some(session) => {
+                    let (ukey, vkey) = *keys;
+                    sessions.add(&session, Some(ukey), Some(vkey))
+                }
+                None => Ok(Delivery::Batch(keys)),
+            },
+        };
+
+        let result = match delivery {
+            Ok(Delivery::Complete(session, keys, waiting)) => {
+                let (ukey, vkey) = *keys;
+                let completed =
+                    CompletedDelivery::new(session.clone(), &ukey, &vkey);
+                let mut session_ukeys = vec![ukey];
+                let mut session_vkeys = vec![vkey];
+                match process_keys(
+                    &mut session_ukeys,
+                    &mut session_vkeys,
                     uuid.clone(),
                     payloads_tx.clone(),
                     run_payload,
                 )
                 .await
                 {
-                    symm_key = Some(key);
+                    Some(key) => {
+                        sessions.complete(completed);
+                        Ok(Some(key))
+                    }
+                    None => {
+                        sessions.restore(
+                            &session,
+                            session_ukeys.pop(),
+                            session_vkeys.pop(),
+                            waiting,
+                        );
+                        Err(DeliveryError::InvalidAuthTag { id: session.id })
+                    }
                 }
             }
+            // The keys delivered together are not combined with the ones
+            // waiting for their other half
+            Ok(Delivery::Batch(keys)) => match process_keys(
+                &mut vec![keys.0],
+                &mut vec![keys.1],
+                uuid.clone(),
+                payloads_tx.clone(),
+                run_payload,
+            )
+            .await
+            {
+                Some(key) => Ok(Some(key)),
+                None => Err(DeliveryError::InvalidKeys),
+            },
+            // Keys delivered without a session are combined with any
+            // other key delivered without a session
+            Ok(Delivery::Pending) => Ok(process_keys(
+                &mut ukeys,
+                &mut vkeys,
+                uuid.clone(),
+                payloads_tx.clone(),
+                run_payload,
+            )
+            .await),
+            Err(e) => Err(e),
+        };
+
+        if let Some(r) = resp_tx {
+            let reply = result.as_ref().map(|_| ()).map_err(Clone::clone);
+            if r.send(SymmKeyMessage::Delivery(reply)).is_err() {
+                debug!("Failed to send Delivery message");
+            }
+        }
+
+        if let Ok(Some((key, ttl))) = result {
+            if let Some(path) = &spire_attestation_path {
+                spire::export(path, &uuid, &key, events.clock());
+                spire_refresh =
+                    Some(Instant::now() + spire::REFRESH_INTERVAL);
+            }
+            if let Some(luks) = &luks {
+                unlock_device(luks, &uuid, &key, &events).await;
+            }
+            events.publish(AgentEvent::KeysDelivered);
+            symm_key = Some(key);
+            lifetime.start(ttl.or(key_ttl), events.clock());
         }
     }
 
@@ -599,8 +1120,15 @@ mod tests {
             decrypted_key: u,
             auth_tag,
             payload,
+            delivery: None,
+            reservation: None,
+            session: None,
+            ttl: None,
+        };
+        let vkey = VKey {
+            decrypted_key: v,
+            session: None,
         };
-        let vkey = VKey { decrypted_key: v };
 
         (ukey, vkey, k)
     }
@@ -626,10 +1154,17 @@ mod tests {
             payload: ukey
                 .payload
                 .map(|p| general_purpose::STANDARD.encode(p.as_ref())),
+            session_id: None,
+            sequence: None,
+            auth_tag_alg: None,
+            payload_delivery: None,
+            ttl: None,
         };
 
         let enc_v = KeylimeVKey {
             encrypted_key: general_purpose::STANDARD.encode(encrypted_v),
+            session_id: None,
+            sequence: None,
         };
 
         (enc_u, enc_v, k)
@@ -691,7 +1226,7 @@ mod tests {
         assert!(ukeys.is_empty());
         assert!(vkeys.is_empty());
 
-        if let Some((k, p)) = result {
+        if let Some((k, p, _)) = result {
             assert!(k == k2);
         }
     }
@@ -706,6 +1241,181 @@ mod tests {
         test_combine_keys(AES_256_KEY_LEN);
     }
 
+    #[test]
+    async fn test_delivery_sessions() {
+        let mut sessions = DeliverySessions::default();
+        let session = |id: &str, sequence| DeliverySession {
+            id: id.to_string(),
+            sequence,
+        };
+
+        let (u, v, _) = prepare_keys(AES_256_KEY_LEN, None, "uuid".into());
+        let (u2, v2, _) = prepare_keys(AES_256_KEY_LEN, None, "uuid".into());
+        let copy_u = UKey {
+            decrypted_key: u.decrypted_key.clone(),
+            auth_tag: u.auth_tag.clone(),
+            payload: None,
+            delivery: None,
+            reservation: None,
+            session: None,
+            ttl: None,
+        };
+
+        // Retransmitted U key is accepted, a different one is rejected
+        let s1 = session("s1", 1);
+        assert!(matches!(
+            sessions.add(&s1, Some(u), None),
+            Ok(Delivery::Pending)
+        ));
+        assert!(matches!(
+            sessions.add(&s1, Some(copy_u), None),
+            Ok(Delivery::Pending)
+        ));
+        assert!(matches!(
+            sessions.add(&s1, Some(u2), None),
+            Err(DeliveryError::ConflictingKey { .. })
+        ));
+        assert!(matches!(
+            sessions.add(&session("s1", 2), None, Some(v2)),
+            Err(DeliveryError::SequenceMismatch { .. })
+        ));
+
+        // The V key completes the delivery
+        let copy_v = VKey {
+            decrypted_key: v.decrypted_key.clone(),
+            session: None,
+        };
+        let Ok(Delivery::Complete(completed, keys, waiting)) =
+            sessions.add(&s1, None, Some(v))
+        else {
+            panic!("Delivery not complete"); //#[allow_ci]
+        };
+        assert_eq!(completed, s1);
+        assert_eq!(
+            waiting,
+            Waiting {
+                ukey: true,
+                vkey: false
+            }
+        );
+        sessions
+            .complete(CompletedDelivery::new(completed, &keys.0, &keys.1));
+
+        // Late retransmits of the completed delivery are ignored, different
+        // keys for it and older deliveries are rejected
+        assert!(matches!(
+            sessions.add(&s1, None, Some(copy_v)),
+            Ok(Delivery::Pending)
+        ));
+        let (_, v, _) = prepare_keys(AES_256_KEY_LEN, None, "uuid".into());
+        assert!(matches!(
+            sessions.add(&s1, None, Some(v)),
+            Err(DeliveryError::ConflictingKey { .. })
+        ));
+        let (_, v, _) = prepare_keys(AES_256_KEY_LEN, None, "uuid".into());
+        assert!(matches!(
+            sessions.add(&session("s0", 0), None, Some(v)),
+            Err(DeliveryError::Stale { .. })
+        ));
+        assert!(sessions.pending.is_empty());
+
+        // A half failing the auth tag check does not discard the one
+        // already waiting
+        let s2 = session("s2", 2);
+        let (u, _, _) = prepare_keys(AES_256_KEY_LEN, None, "uuid".into());
+        let (_, v, _) = prepare_keys(AES_256_KEY_LEN, None, "uuid".into());
+        assert!(matches!(
+            sessions.add(&s2, Some(u), None),
+            Ok(Delivery::Pending)
+        ));
+        let Ok(Delivery::Complete(completed, keys, waiting)) =
+            sessions.add(&s2, None, Some(v))
+        else {
+            panic!("Delivery not complete"); //#[allow_ci]
+        };
+        let (u, v) = *keys;
+        sessions.restore(&completed, Some(u), Some(v), waiting);
+        assert_eq!(
+            sessions.inventory(),
+            vec![SessionInventory {
+                id: "s2".to_string(),
+                sequence: 2,
+                ukey: true,
+                vkey: false,
+            }]
+        );
+    }
+
+    #[test]
+    async fn test_wipe_keys() {
+        let (u, v, k) = prepare_keys(AES_128_KEY_LEN, None, "uuid".into());
+        let (u2, _, _) = prepare_keys(AES_256_KEY_LEN, None, "uuid".into());
+        let mut ukeys = vec![u];
+        let mut vkeys = vec![v];
+        let mut symm_key = Some(k);
+        let mut sessions = DeliverySessions {
+            completed: Some(CompletedDelivery {
+                session: DeliverySession {
+                    id: "s1".to_string(),
+                    sequence: 1,
+                },
+                ukey: [0; 32],
+                vkey: [0; 32],
+            }),
+            ..Default::default()
+        };
+        let s2 = DeliverySession {
+            id: "s2".to_string(),
+            sequence: 2,
+        };
+        assert!(sessions.add(&s2, Some(u2), None).is_ok());
+
+        let inventory = key_inventory(&ukeys, &vkeys, &sessions, &symm_key);
+        assert_eq!(inventory.bootstrap_key_len, Some(AES_128_KEY_LEN));
+        assert_eq!(inventory.ukeys, 1);
+        assert_eq!(inventory.vkeys, 1);
+        assert_eq!(
+            inventory.sessions,
+            vec![SessionInventory {
+                id: "s2".to_string(),
+                sequence: 2,
+                ukey: true,
+                vkey: false,
+            }]
+        );
+        // Only the metadata is listed
+        let listed = serde_json::to_string(&inventory).unwrap(); //#[allow_ci]
+        assert!(!listed.contains("decrypted_key"));
+
+        wipe_keys(&mut ukeys, &mut vkeys, &mut sessions, &mut symm_key);
+        let inventory = key_inventory(&ukeys, &vkeys, &sessions, &symm_key);
+        assert_eq!(
+            inventory,
+            KeyInventory {
+                completed_session: sessions
+                    .completed
+                    .as_ref()
+                    .map(|c| c.session.clone()),
+                ..Default::default()
+            }
+        );
+        assert!(symm_key.is_none());
+
+        // The wiped delivery is still rejected if replayed
+        let (u, _, _) = prepare_keys(AES_128_KEY_LEN, None, "uuid".into());
+        assert!(matches!(
+            sessions.add(
+                &DeliverySession {
+                    id: "s0".to_string(),
+                    sequence: 0
+                },
+                Some(u),
+                None
+            ),
+            Err(DeliveryError::Stale { .. })
+        ));
+    }
+
     #[actix_rt::test]
     async fn test_process_keys() {
         let mut ukeys = Vec::new();
@@ -735,6 +1445,7 @@ mod tests {
                     m == PayloadMessage::RunPayload(Payload {
                         symm_key: k_clone,
                         encrypted_payload: data.as_bytes().into(),
+                        delivery: None,
                     })
                 );
             };
@@ -766,11 +1477,75 @@ mod tests {
         )
         .await;
         assert!(result.is_some());
-        if let Some(key) = result {
+        if let Some((key, _)) = result {
             assert!(key == k);
         }
     }
 
+    #[actix_rt::test]
+    async fn test_key_expiry() {
+        let uuid = "test-uuid";
+        let (keys_tx, keys_rx) = mpsc::channel::<(
+            KeyMessage,
+            Option<oneshot::Sender<SymmKeyMessage>>,
+        )>(1);
+        let (payload_tx, mut payload_rx) = mpsc::channel::<PayloadMessage>(1);
+        _ = actix_rt::spawn(worker(
+            true,
+            uuid.to_string(),
+            keys_rx,
+            payload_tx,
+            None,
+            None,
+            None,
+            EventPublisher::default(),
+        ));
+
+        let list = || async {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            keys_tx
+                .send((KeyMessage::ListKeys, Some(resp_tx)))
+                .await
+                .unwrap(); //#[allow_ci]
+            match resp_rx.await {
+                Ok(SymmKeyMessage::Keys(inventory)) => inventory,
+                m => panic!("unexpected message {m:?}"), //#[allow_ci]
+            }
+        };
+
+        // Without a time to live the key is kept
+        let (u, v, _) = prepare_keys(AES_128_KEY_LEN, None, uuid.into());
+        keys_tx.send((KeyMessage::UKey(u), None)).await.unwrap(); //#[allow_ci]
+        keys_tx.send((KeyMessage::VKey(v), None)).await.unwrap(); //#[allow_ci]
+        let inventory = list().await;
+        assert_eq!(inventory.bootstrap_key_len, Some(AES_128_KEY_LEN));
+        assert_eq!(inventory.expires_at, None);
+
+        // The key expires, and the payload worker is asked to remove the
+        // payload
+        let (mut u, v, _) = prepare_keys(AES_128_KEY_LEN, None, uuid.into());
+        u.ttl = Some(0);
+        keys_tx.send((KeyMessage::UKey(u), None)).await.unwrap(); //#[allow_ci]
+        keys_tx.send((KeyMessage::VKey(v), None)).await.unwrap(); //#[allow_ci]
+        assert_eq!(payload_rx.recv().await, Some(PayloadMessage::Expire));
+        let inventory = list().await;
+        assert_eq!(inventory.bootstrap_key_len, None);
+        assert!(inventory.expired_at.is_some());
+        assert!(get_symm_key(keys_tx.clone()).await.unwrap().is_none()); //#[allow_ci]
+
+        // A new delivery renews the key
+        let (mut u, v, k) = prepare_keys(AES_128_KEY_LEN, None, uuid.into());
+        u.ttl = Some(3600);
+        keys_tx.send((KeyMessage::UKey(u), None)).await.unwrap(); //#[allow_ci]
+        keys_tx.send((KeyMessage::VKey(v), None)).await.unwrap(); //#[allow_ci]
+        let inventory = list().await;
+        assert!(inventory.expires_at.is_some());
+        assert_eq!(inventory.expired_at, None);
+        assert_eq!(get_symm_key(keys_tx.clone()).await.unwrap(), Some(k)); //#[allow_ci]
+
+        keys_tx.send((KeyMessage::Shutdown, None)).await.unwrap(); //#[allow_ci]
+    }
+
     #[cfg(feature = "testing")]
     async fn test_u_or_v_key(key_len: usize, payload: Option<&[u8]>) {
         let test_config = KeylimeConfig::default();
@@ -833,7 +1608,17 @@ mod tests {
         let uuid_clone = uuid.clone();
         // Run keys worker
         assert!(arbiter.spawn(Box::pin(async move {
-            let result = worker(true, uuid_clone, keys_rx, p_tx).await;
+            let result = worker(
+                true,
+                uuid_clone,
+                keys_rx,
+                p_tx,
+                None,
+                None,
+                None,
+                EventPublisher::default(),
+            )
+            .await;
 
             if result.is_err() {
                 debug!("keys worker failed: {:?}", result);
@@ -861,6 +1646,9 @@ mod tests {
                             );
                         }
                     }
+                    PayloadMessage::Expire => {
+                        panic!("the keys expired"); //#[allow_ci]
+                    }
                 }
             }
 
@@ -876,6 +1664,11 @@ mod tests {
             encrypted_key: general_purpose::STANDARD.encode(&encrypted_key),
             auth_tag: hex::encode(auth_tag),
             payload: payload.map(|p| general_purpose::STANDARD.encode(p)),
+            session_id: None,
+            sequence: None,
+            auth_tag_alg: None,
+            payload_delivery: None,
+            ttl: None,
         };
 
         let req = test::TestRequest::post()
@@ -891,6 +1684,8 @@ mod tests {
 
         let vkey = KeylimeVKey {
             encrypted_key: general_purpose::STANDARD.encode(&encrypted_key),
+            session_id: None,
+            sequence: None,
         };
 
         let req = test::TestRequest::post()
@@ -989,6 +1784,102 @@ mod tests {
         test_u_or_v_key(AES_256_KEY_LEN, None).await;
     }
 
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_keys() {
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let (payload_tx, _payload_rx) = mpsc::channel::<PayloadMessage>(1);
+        let (keys_tx, keys_rx) = mpsc::channel::<(
+            KeyMessage,
+            Option<oneshot::Sender<SymmKeyMessage>>,
+        )>(1);
+        fixture.keys_tx = keys_tx.clone();
+        let quotedata = web::Data::new(fixture);
+        let uuid = KeylimeConfig::default().agent.uuid;
+
+        let arbiter = Arbiter::new();
+        let uuid_clone = uuid.clone();
+        assert!(arbiter.spawn(Box::pin(async move {
+            let result = worker(
+                true,
+                uuid_clone,
+                keys_rx,
+                payload_tx,
+                None,
+                None,
+                None,
+                EventPublisher::default(),
+            )
+            .await;
+            if result.is_err() {
+                debug!("keys worker failed: {:?}", result);
+            }
+        })));
+
+        let mut app = test::init_service(
+            App::new()
+                .app_data(quotedata.clone())
+                .route("/keys/ukey", web::post().to(u_key))
+                .route("/keys/batch", web::post().to(keys)),
+        )
+        .await;
+
+        let batch = |u: &KeylimeUKey, v: &KeylimeVKey| KeylimeKeys {
+            encrypted_u_key: u.encrypted_key.clone(),
+            encrypted_v_key: v.encrypted_key.clone(),
+            auth_tag: u.auth_tag.clone(),
+            auth_tag_alg: None,
+            payload: None,
+            payload_delivery: None,
+            session_id: None,
+            sequence: None,
+            ttl: None,
+        };
+        let (u1, v1, k1) = prepare_encrypted_keys(
+            AES_256_KEY_LEN,
+            None,
+            uuid.clone(),
+            &quotedata.pub_key,
+        );
+        let (u2, v2, k2) = prepare_encrypted_keys(
+            AES_256_KEY_LEN,
+            None,
+            uuid,
+            &quotedata.pub_key,
+        );
+
+        // A U key waiting for its V key is not combined with a batch
+        let req = test::TestRequest::post()
+            .uri("/keys/ukey")
+            .set_json(&u1)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::post()
+            .uri("/keys/batch")
+            .set_json(batch(&u2, &v1))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), actix_web::http::StatusCode::BAD_REQUEST);
+        let key = get_symm_key(keys_tx.clone()).await.unwrap(); //#[allow_ci]
+        assert!(key.is_none());
+
+        let req = test::TestRequest::post()
+            .uri("/keys/batch")
+            .set_json(batch(&u2, &v2))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let key = get_symm_key(keys_tx.clone()).await.unwrap(); //#[allow_ci]
+        assert_eq!(key.unwrap().as_ref(), k2.as_ref()); //#[allow_ci]
+        assert_ne!(k1.as_ref(), k2.as_ref());
+
+        keys_tx.send((KeyMessage::Shutdown, None)).await.unwrap(); //#[allow_ci]
+        assert!(arbiter.stop());
+        arbiter.join().unwrap(); //#[allow_ci]
+    }
+
     #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn test_pubkey() {
@@ -1013,4 +1904,39 @@ mod tests {
             .unwrap() //#[allow_ci]
             .public_eq(&quotedata.pub_key));
     }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_certify_invalid() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/keys/certify"),
+                web::post().to(certify),
+            ))
+            .await;
+
+        for (handle, nonce) in [
+            // Not a persistent handle
+            ("0x40000001", "1234567890"),
+            // Nonce not alphanumeric
+            ("0x81000001", "12345-67890"),
+            // No key under the handle
+            ("0x81fffff0", "1234567890"),
+        ] {
+            let req = test::TestRequest::post()
+                .uri(&format!("/{API_VERSION}/keys/certify"))
+                .set_json(serde_json::json!({
+                    "handle": handle,
+                    "nonce": nonce,
+                }))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(
+                resp.status(),
+                actix_web::http::StatusCode::BAD_REQUEST
+            );
+        }
+    }
 }
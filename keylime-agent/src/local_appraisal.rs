@@ -0,0 +1,494 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Local appraisal of the IMA measurement list
+//!
+//! The allowlist and exclude list pushed by the verifier are cached in a
+//! SQLite database, so that they survive agent restarts. A worker
+//! periodically checks the new entries of the IMA measurement list against
+//! the cached lists and records the violations found.
+
+use crate::{common::JsonWrapper, Error, Result};
+use actix_web::{web, HttpResponse, Responder};
+use keylime::ima::Entry;
+use log::*;
+use regex::Regex;
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, Seek, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::sync::mpsc::Receiver;
+
+/// Maximum number of violations kept in the appraisal status
+const MAX_VIOLATIONS: usize = 1024;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS allowlist (
+        path TEXT NOT NULL,
+        digest TEXT NOT NULL,
+        PRIMARY KEY (path, digest)
+    );
+    CREATE TABLE IF NOT EXISTS excludelist (
+        pattern TEXT PRIMARY KEY
+    );
+";
+
+#[derive(Debug)]
+pub(crate) enum AppraisalMessage {
+    Shutdown,
+}
+
+/// The allowlist in the format used by the runtime policies: a map from
+/// file paths to the list of accepted digests, and a list of regular
+/// expressions matching the paths to be ignored.
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Allowlist {
+    #[serde(default)]
+    pub digests: HashMap<String, Vec<String>>,
+    #[serde(default)]
+    pub excludes: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Violation {
+    pub entry: u64,
+    pub path: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub(crate) struct AppraisalStatus {
+    pub allowlist_loaded: bool,
+    pub entries_checked: u64,
+    pub violations: Vec<Violation>,
+    #[serde(skip)]
+    offset: u64,
+}
+
+impl AppraisalStatus {
+    fn add_violation(&mut self, path: &str, reason: &str) {
+        warn!(
+            "Local appraisal failed for entry {} ({path}): {reason}",
+            self.entries_checked
+        );
+        if self.violations.len() < MAX_VIOLATIONS {
+            self.violations.push(Violation {
+                entry: self.entries_checked,
+                path: path.to_string(),
+                reason: reason.to_string(),
+            });
+        }
+    }
+}
+
+/// Allowlist and exclude list cached in a SQLite database
+pub(crate) struct AllowlistStore {
+    conn: Connection,
+    excludes: Vec<Regex>,
+}
+
+impl AllowlistStore {
+    pub(crate) fn open(path: &Path) -> Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    fn from_connection(conn: Connection) -> Result<Self> {
+        conn.execute_batch(SCHEMA)?;
+
+        let mut store = AllowlistStore {
+            conn,
+            excludes: Vec::new(),
+        };
+        store.excludes = store.load_excludes()?;
+        Ok(store)
+    }
+
+    fn load_excludes(&self) -> Result<Vec<Regex>> {
+        let mut stmt =
+            self.conn.prepare("SELECT pattern FROM excludelist")?;
+        let patterns = stmt
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        patterns
+            .iter()
+            .map(|p| Regex::new(p).map_err(Error::from))
+            .collect()
+    }
+
+    /// Whether an allowlist was stored
+    pub(crate) fn is_loaded(&self) -> Result<bool> {
+        let mut stmt = self.conn.prepare_cached("SELECT 1 FROM allowlist")?;
+        Ok(stmt.exists([])?)
+    }
+
+    /// Replace the stored lists. The regular expressions are validated
+    /// before anything is written.
+    pub(crate) fn replace(&mut self, allowlist: &Allowlist) -> Result<()> {
+        let excludes = allowlist
+            .excludes
+            .iter()
+            .map(|p| Regex::new(p))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let tx = self.conn.transaction()?;
+        _ = tx.execute("DELETE FROM allowlist", [])?;
+        _ = tx.execute("DELETE FROM excludelist", [])?;
+        {
+            let mut insert = tx.prepare(
+                "INSERT OR IGNORE INTO allowlist (path, digest) VALUES (?1, ?2)",
+            )?;
+            for (path, digests) in &allowlist.digests {
+                for digest in digests {
+                    _ = insert
+                        .execute(params![path, digest.to_lowercase()])?;
+                }
+            }
+            let mut insert = tx.prepare(
+                "INSERT OR IGNORE INTO excludelist (pattern) VALUES (?1)",
+            )?;
+            for pattern in &allowlist.excludes {
+                _ = insert.execute(params![pattern])?;
+            }
+        }
+        tx.commit()?;
+
+        self.excludes = excludes;
+        Ok(())
+    }
+
+    /// Check a single entry of the measurement list. Returns the reason of
+    /// the failure, if any.
+    pub(crate) fn check(&self, entry: &Entry) -> Result<Option<String>> {
+        // The allowlist holds the names as text, without the escaping used
+        // to send the measurement list
+        let path = String::from_utf8_lossy(entry.event_data.raw_path());
+        let path = path.as_ref();
+        if path == "boot_aggregate"
+            || self.excludes.iter().any(|r| r.is_match(path))
+        {
+            return Ok(None);
+        }
+
+        let digest = hex::encode(entry.event_data.digest().value());
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT 1 FROM allowlist WHERE path = ?1 AND digest = ?2",
+        )?;
+        if stmt.exists(params![path, digest])? {
+            return Ok(None);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare_cached("SELECT 1 FROM allowlist WHERE path = ?1")?;
+        if stmt.exists(params![path])? {
+            Ok(Some(format!("digest {digest} not in allowlist")))
+        } else {
+            Ok(Some("path not in allowlist".to_string()))
+        }
+    }
+}
+
+/// State shared between the appraisal worker and the handlers
+pub(crate) struct LocalAppraisal {
+    store: Mutex<AllowlistStore>,
+    status: Mutex<AppraisalStatus>,
+}
+
+impl LocalAppraisal {
+    pub(crate) fn new(store: AllowlistStore) -> Result<Self> {
+        let status = AppraisalStatus {
+            allowlist_loaded: store.is_loaded()?,
+            ..Default::default()
+        };
+        Ok(LocalAppraisal {
+            store: Mutex::new(store),
+            status: Mutex::new(status),
+        })
+    }
+
+    /// Replace the allowlist and restart the appraisal from the beginning
+    /// of the measurement list
+    pub(crate) fn set_allowlist(&self, allowlist: &Allowlist) -> Result<()> {
+        let mut store = self.store.lock().map_err(|_| {
+            Error::Other("Failed to lock allowlist store".to_string())
+        })?;
+        let mut status = self.status.lock().map_err(|_| {
+            Error::Other("Failed to lock appraisal status".to_string())
+        })?;
+
+        store.replace(allowlist)?;
+        *status = AppraisalStatus {
+            allowlist_loaded: true,
+            ..Default::default()
+        };
+        Ok(())
+    }
+
+    pub(crate) fn status(&self) -> Result<AppraisalStatus> {
+        self.status.lock().map(|s| s.clone()).map_err(|_| {
+            Error::Other("Failed to lock appraisal status".to_string())
+        })
+    }
+
+    /// Check the entries added to the measurement list since the last call
+    pub(crate) fn check_new_entries(&self, ima_ml: &mut File) -> Result<()> {
+        let store = self.store.lock().map_err(|_| {
+            Error::Other("Failed to lock allowlist store".to_string())
+        })?;
+        let mut status = self.status.lock().map_err(|_| {
+            Error::Other("Failed to lock appraisal status".to_string())
+        })?;
+
+        if !status.allowlist_loaded {
+            return Ok(());
+        }
+
+        _ = ima_ml.seek(SeekFrom::Start(status.offset))?;
+        let mut reader = BufReader::new(ima_ml);
+        // The file names are not necessarily valid UTF-8, so the lines are
+        // read as bytes
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let read = reader.read_until(b'\n', &mut line)?;
+            // Only complete lines are checked, a partial line is read again
+            // on the next call
+            if read == 0 || line.pop() != Some(b'\n') {
+                break;
+            }
+            status.offset += read as u64;
+            status.entries_checked += 1;
+
+            match Entry::try_from(line.as_slice()) {
+                Ok(entry) => {
+                    if let Some(reason) = store.check(&entry)? {
+                        status
+                            .add_violation(&entry.event_data.path(), &reason);
+                    }
+                }
+                Err(e) => status.add_violation(
+                    "",
+                    &format!("failed to parse entry: {e}"),
+                ),
+            }
+        }
+        Ok(())
+    }
+}
+
+// This is the allowlist request from the verifier via REST API
+pub(crate) async fn allowlist(
+    body: web::Json<Allowlist>,
+    data: web::Data<LocalAppraisal>,
+) -> impl Responder {
+    info!("Received allowlist for local appraisal");
+
+    match data.set_allowlist(&body) {
+        Ok(_) => HttpResponse::Ok().json(JsonWrapper::success(())),
+        Err(Error::Regex(e)) => {
+            warn!("POST allowlist returning 400 response. Invalid exclude list: {e}");
+            HttpResponse::BadRequest().json(JsonWrapper::error(
+                400,
+                format!("Invalid exclude list: {e}"),
+            ))
+        }
+        Err(e) => {
+            warn!("POST allowlist returning 500 response. Failed to store allowlist: {e}");
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                format!("Failed to store allowlist: {e}"),
+            ))
+        }
+    }
+}
+
+pub(crate) async fn status(
+    data: web::Data<LocalAppraisal>,
+) -> impl Responder {
+    match data.status() {
+        Ok(status) => HttpResponse::Ok().json(JsonWrapper::success(status)),
+        Err(e) => {
+            warn!("GET appraisal status returning 500 response. {e}");
+            HttpResponse::InternalServerError()
+                .json(JsonWrapper::error(500, e.to_string()))
+        }
+    }
+}
+
+/// Register the local appraisal endpoints in the API version scope
+pub(crate) fn configure(
+    cfg: &mut web::ServiceConfig,
+    data: web::Data<LocalAppraisal>,
+) {
+    _ = cfg.service(
+        web::scope("/appraisal")
+            .app_data(data)
+            .service(
+                web::resource("/allowlist").route(web::post().to(allowlist)),
+            )
+            .service(web::resource("/status").route(web::get().to(status))),
+    );
+}
+
+pub(crate) async fn worker(
+    appraisal: web::Data<LocalAppraisal>,
+    ima_ml_path: PathBuf,
+    interval: Duration,
+    mut appraisal_rx: Receiver<AppraisalMessage>,
+) -> Result<()> {
+    debug!("Starting local appraisal worker");
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            message = appraisal_rx.recv() => {
+                match message {
+                    Some(AppraisalMessage::Shutdown) | None => {
+                        appraisal_rx.close();
+                        break;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                let result = File::open(&ima_ml_path)
+                    .map_err(Error::from)
+                    .and_then(|mut f| appraisal.check_new_entries(&mut f));
+                if let Err(e) = result {
+                    warn!("Failed to appraise IMA measurement list: {e}");
+                }
+            }
+        }
+    }
+
+    debug!("Shutting down local appraisal worker");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::API_VERSION;
+    use serde_json::json;
+    use std::io::Write;
+
+    const BOOT_AGGREGATE: &str = "10 0000000000000000000000000000000000000000 ima-ng sha1:0000000000000000000000000000000000000000 boot_aggregate\n";
+    const ENTRY: &str = "10 d0ec6e1ea2ee32a3b9bc73bf0a33dcb3bc8f7b1a ima-ng sha1:bc026ae66d81713e4e852465e980784dc96651f8 /usr/lib/systemd/systemd\n";
+
+    fn allowlist(digest: &str, excludes: &[&str]) -> Allowlist {
+        let mut digests = HashMap::new();
+        _ = digests.insert(
+            "/usr/lib/systemd/systemd".to_string(),
+            vec![digest.to_string()],
+        );
+        Allowlist {
+            digests,
+            excludes: excludes.iter().map(|s| s.to_string()).collect(),
+        }
+    }
+
+    fn appraisal() -> LocalAppraisal {
+        let store = AllowlistStore::from_connection(
+            Connection::open_in_memory().unwrap(), //#[allow_ci]
+        )
+        .unwrap(); //#[allow_ci]
+        LocalAppraisal::new(store).unwrap() //#[allow_ci]
+    }
+
+    #[test]
+    fn test_check_entries() {
+        let appraisal = appraisal();
+        let mut ml = tempfile::tempfile().unwrap(); //#[allow_ci]
+        ml.write_all(BOOT_AGGREGATE.as_bytes()).unwrap(); //#[allow_ci]
+
+        // Nothing is checked before an allowlist is available
+        appraisal.check_new_entries(&mut ml).unwrap(); //#[allow_ci]
+        assert_eq!(appraisal.status().unwrap().entries_checked, 0); //#[allow_ci]
+
+        appraisal
+            .set_allowlist(&allowlist(
+                "BC026AE66D81713E4E852465E980784DC96651F8",
+                &[],
+            ))
+            .unwrap(); //#[allow_ci]
+        ml.write_all(ENTRY.as_bytes()).unwrap(); //#[allow_ci]
+        appraisal.check_new_entries(&mut ml).unwrap(); //#[allow_ci]
+        let status = appraisal.status().unwrap(); //#[allow_ci]
+        assert!(status.allowlist_loaded);
+        assert_eq!(status.entries_checked, 2);
+        assert!(status.violations.is_empty());
+
+        // A digest not in the allowlist is reported, partial lines are not
+        ml.write_all(ENTRY.replace("bc02", "ffff").as_bytes())
+            .unwrap(); //#[allow_ci]
+        ml.write_all(b"10 d0ec6e").unwrap(); //#[allow_ci]
+        appraisal.check_new_entries(&mut ml).unwrap(); //#[allow_ci]
+        let status = appraisal.status().unwrap(); //#[allow_ci]
+        assert_eq!(status.entries_checked, 3);
+        assert_eq!(status.violations.len(), 1);
+        assert_eq!(status.violations[0].entry, 3);
+        assert_eq!(status.violations[0].path, "/usr/lib/systemd/systemd");
+
+        // Replacing the allowlist restarts the appraisal
+        appraisal
+            .set_allowlist(&allowlist("00", &["^/usr/lib/"]))
+            .unwrap(); //#[allow_ci]
+        appraisal.check_new_entries(&mut ml).unwrap(); //#[allow_ci]
+        let status = appraisal.status().unwrap(); //#[allow_ci]
+        assert_eq!(status.entries_checked, 3);
+        assert!(status.violations.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_excludes() {
+        let appraisal = appraisal();
+        let result = appraisal.set_allowlist(&allowlist("00", &["("]));
+        assert!(matches!(result, Err(Error::Regex(_))));
+        assert!(!appraisal.status().unwrap().allowlist_loaded); //#[allow_ci]
+    }
+
+    #[actix_rt::test]
+    async fn test_allowlist_endpoints() {
+        use actix_web::{test, App};
+
+        let data = web::Data::new(appraisal());
+        let mut app = test::init_service(
+            App::new().service(
+                web::scope(&format!("/{API_VERSION}"))
+                    .configure(|cfg| configure(cfg, data.clone())),
+            ),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{API_VERSION}/appraisal/allowlist"))
+            .set_json(
+                json!({"digests": {"/bin/sh": ["00"]}, "excludes": ["("]}),
+            )
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/{API_VERSION}/appraisal/allowlist"))
+            .set_json(json!({"digests": {"/bin/sh": ["00"]}}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/{API_VERSION}/appraisal/status"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let result: JsonWrapper<serde_json::Value> =
+            test::read_body_json(resp).await;
+        assert_eq!(result.results["allowlist_loaded"], true);
+    }
+}
@@ -0,0 +1,213 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! SELinux and AppArmor awareness
+//!
+//! On hosts where the agent and the services consuming the payload are
+//! confined, the files created by the agent need the right SELinux context
+//! to be readable by those services. The secure mount is labeled with the
+//! 'secure_mount_context' mount option, and the work directory with the
+//! 'work_dir_context' extended attribute, when set.
+//!
+//! When an operation fails with a permission error while a security module
+//! is enforcing, the error points at the policy instead of being reported as
+//! a plain I/O error.
+
+use log::*;
+use std::{
+    ffi::CString,
+    fmt, fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+static SELINUX_ENFORCE: &str = "/sys/fs/selinux/enforce";
+static APPARMOR_ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+static APPARMOR_CURRENT: &str = "/proc/self/attr/apparmor/current";
+// Kernels before 5.8 only provide the attribute shared by all the modules
+static APPARMOR_CURRENT_LEGACY: &str = "/proc/self/attr/current";
+static SELINUX_XATTR: &str = "security.selinux";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SelinuxMode {
+    Disabled,
+    Permissive,
+    Enforcing,
+}
+
+impl fmt::Display for SelinuxMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SelinuxMode::Disabled => write!(f, "disabled"),
+            SelinuxMode::Permissive => write!(f, "permissive"),
+            SelinuxMode::Enforcing => write!(f, "enforcing"),
+        }
+    }
+}
+
+fn parse_enforce(value: &str) -> SelinuxMode {
+    match value.trim() {
+        "1" => SelinuxMode::Enforcing,
+        _ => SelinuxMode::Permissive,
+    }
+}
+
+pub(crate) fn selinux_mode() -> SelinuxMode {
+    match fs::read_to_string(SELINUX_ENFORCE) {
+        Ok(value) => parse_enforce(&value),
+        Err(_) => SelinuxMode::Disabled,
+    }
+}
+
+/// The AppArmor profile of a label such as "keylime_agent (enforce)", if
+/// the label is confined
+fn parse_apparmor_label(label: &str) -> Option<String> {
+    let label = label.trim_end_matches(['\n', '\0']).trim();
+    let profile = match label.rsplit_once(' ') {
+        Some((profile, "(enforce)")) => profile,
+        // Complaining profiles only log the denials
+        Some((_, _)) | None => return None,
+    };
+    Some(profile.to_string())
+}
+
+/// The AppArmor profile enforced on the agent, if any
+pub(crate) fn apparmor_profile() -> Option<String> {
+    match fs::read_to_string(APPARMOR_ENABLED) {
+        Ok(enabled) if enabled.trim() == "Y" => (),
+        _ => return None,
+    }
+    fs::read_to_string(APPARMOR_CURRENT)
+        .or_else(|_| fs::read_to_string(APPARMOR_CURRENT_LEGACY))
+        .ok()
+        .and_then(|label| parse_apparmor_label(&label))
+}
+
+/// Log the status of the security modules at startup, warning when the
+/// files in the secure mount would get the default label of tmpfs
+pub(crate) fn log_status(secure_mount_context: &str) {
+    let mode = selinux_mode();
+    info!("SELinux is {mode}");
+    if mode != SelinuxMode::Disabled && secure_mount_context.is_empty() {
+        warn!("SELinux is {mode} but 'secure_mount_context' is not set: the files of the delivered payloads get the default label of tmpfs, which confined services may not be allowed to read");
+    }
+    if let Some(profile) = apparmor_profile() {
+        info!("The agent is confined by the AppArmor profile {profile}");
+    }
+}
+
+/// Set the SELinux context of the file, without following symlinks
+pub(crate) fn set_file_context(path: &Path, context: &str) -> io::Result<()> {
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let c_name = CString::new(SELINUX_XATTR)?;
+    let c_value = CString::new(context)?;
+    let bytes = c_value.as_bytes_with_nul();
+    if unsafe {
+        libc::lsetxattr(
+            c_path.as_ptr(),
+            c_name.as_ptr(),
+            bytes.as_ptr().cast(),
+            bytes.len(),
+            0,
+        )
+    } != 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the SELinux context of the directory and of all the files in it
+pub(crate) fn label_tree(path: &Path, context: &str) -> io::Result<()> {
+    set_file_context(path, context)?;
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            label_tree(&entry?.path(), context)?;
+        }
+    }
+    Ok(())
+}
+
+/// Explain a permission error on the path as a possible denial by the
+/// enforcing security modules, if any
+pub(crate) fn explain_denial(err: &io::Error, path: &Path) -> Option<String> {
+    if err.kind() != io::ErrorKind::PermissionDenied {
+        return None;
+    }
+
+    let mut modules = Vec::new();
+    if selinux_mode() == SelinuxMode::Enforcing {
+        modules.push("SELinux is enforcing: check the AVC denials with 'ausearch -m AVC -ts recent' and the 'secure_mount_context' and 'work_dir_context' options".to_string());
+    }
+    if let Some(profile) = apparmor_profile() {
+        modules.push(format!("the agent is confined by the AppArmor profile {profile}: check the kernel log for 'apparmor=\"DENIED\"'"));
+    }
+    if modules.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "access to {} was denied ({err}) and may be blocked by the security policy; {}",
+        path.display(),
+        modules.join("; ")
+    ))
+}
+
+/// Convert a failed operation on the path to an access denial error if it
+/// may have been denied by a security module
+pub(crate) fn check_denial<T>(
+    result: io::Result<T>,
+    path: impl Into<PathBuf>,
+) -> crate::error::Result<T> {
+    result.map_err(|e| match explain_denial(&e, &path.into()) {
+        Some(message) => {
+            error!("{message}");
+            crate::error::Error::AccessDenied(message)
+        }
+        None => e.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_enforce() {
+        assert_eq!(parse_enforce("1\n"), SelinuxMode::Enforcing);
+        assert_eq!(parse_enforce("0\n"), SelinuxMode::Permissive);
+    }
+
+    #[test]
+    fn test_parse_apparmor_label() {
+        assert_eq!(
+            parse_apparmor_label("keylime_agent (enforce)\n"),
+            Some("keylime_agent".to_string())
+        );
+        assert_eq!(
+            parse_apparmor_label("/usr/bin/keylime_agent (enforce)\0"),
+            Some("/usr/bin/keylime_agent".to_string())
+        );
+        assert_eq!(parse_apparmor_label("keylime_agent (complain)"), None);
+        assert_eq!(parse_apparmor_label("unconfined\n"), None);
+        // SELinux label read from the legacy attribute
+        assert_eq!(
+            parse_apparmor_label("system_u:system_r:keylime_agent_t:s0\0"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_explain_denial() {
+        let path = Path::new("/var/lib/keylime");
+        assert_eq!(
+            explain_denial(&io::Error::from(io::ErrorKind::NotFound), path),
+            None
+        );
+        let denied = io::Error::from(io::ErrorKind::PermissionDenied);
+        if selinux_mode() != SelinuxMode::Enforcing
+            && apparmor_profile().is_none()
+        {
+            assert_eq!(explain_denial(&denied, path), None);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Attestation-gated unlock of a LUKS encrypted device
+//!
+//! Once the U and V keys are combined, a passphrase for the configured LUKS
+//! device is derived from the bootstrap key, so that the device can only be
+//! unlocked after the tenant and the verifier delivered their key shares.
+//! The passphrase is the hex encoded HMAC of "keylime-luks:<agent_uuid>"
+//! using the bootstrap key, which the tenant can compute to enroll it in a
+//! key slot in advance.
+//!
+//! If the passphrase does not open the device and an enrollment key file is
+//! configured, the passphrase is first added to a free key slot using the
+//! existing key. The passphrase is passed to cryptsetup through a key file in
+//! the secure mount, which is removed as soon as the commands complete.
+
+use crate::{common::SymmKey, crypto, Error, Result};
+use log::*;
+use std::{
+    ffi::OsStr,
+    fmt,
+    fs::{self, Permissions},
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+use tempfile::NamedTempFile;
+
+const CRYPTSETUP: &str = "cryptsetup";
+const DEVICE_MAPPER_DIR: &str = "/dev/mapper";
+
+/// The outcome of a successful unlock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum UnlockStatus {
+    /// The mapping already existed, the device was not touched
+    AlreadyOpen,
+    /// The device was opened with the derived passphrase
+    Opened,
+    /// The passphrase was enrolled in a key slot and the device opened
+    Enrolled,
+}
+
+impl UnlockStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            UnlockStatus::AlreadyOpen => "already_open",
+            UnlockStatus::Opened => "opened",
+            UnlockStatus::Enrolled => "enrolled",
+        }
+    }
+}
+
+impl fmt::Display for UnlockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A LUKS device unlocked when the bootstrap key is derived
+#[derive(Debug, Clone)]
+pub(crate) struct LuksDevice {
+    pub device: PathBuf,
+    pub name: String,
+    pub enroll_key_file: Option<PathBuf>,
+    /// Directory where the key file is written, expected to be a tmpfs
+    pub key_dir: PathBuf,
+}
+
+/// Derive the LUKS passphrase from the bootstrap key
+pub(crate) fn passphrase(key: &SymmKey, agent_uuid: &str) -> Result<String> {
+    let mac = crypto::compute_hmac(
+        key.as_ref(),
+        format!("keylime-luks:{agent_uuid}").as_bytes(),
+    )?;
+    Ok(hex::encode(mac))
+}
+
+/// Write the passphrase to a file readable only by the owner. The file is
+/// removed when the returned handle is dropped.
+fn write_key_file(dir: &Path, passphrase: &str) -> Result<NamedTempFile> {
+    let mut file = NamedTempFile::new_in(dir)?;
+    fs::set_permissions(file.path(), Permissions::from_mode(0o400))?;
+    file.write_all(passphrase.as_bytes())?;
+    file.flush()?;
+    Ok(file)
+}
+
+fn cryptsetup<I, S>(args: I) -> Result<()>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let output = Command::new(CRYPTSETUP).args(args).output()?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(Error::Other(format!(
+            "{CRYPTSETUP} failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )))
+    }
+}
+
+impl LuksDevice {
+    fn open(&self, key_file: &Path) -> Result<()> {
+        cryptsetup([
+            OsStr::new("open"),
+            OsStr::new("--type"),
+            OsStr::new("luks"),
+            OsStr::new("--key-file"),
+            key_file.as_os_str(),
+            self.device.as_os_str(),
+            OsStr::new(&self.name),
+        ])
+    }
+
+    fn enroll(&self, enroll_key_file: &Path, key_file: &Path) -> Result<()> {
+        cryptsetup([
+            OsStr::new("luksAddKey"),
+            OsStr::new("--key-file"),
+            enroll_key_file.as_os_str(),
+            self.device.as_os_str(),
+            key_file.as_os_str(),
+        ])
+    }
+
+    /// Open the device with the passphrase derived from the bootstrap key,
+    /// enrolling the passphrase first if needed and allowed
+    pub(crate) fn unlock(
+        &self,
+        key: &SymmKey,
+        agent_uuid: &str,
+    ) -> Result<UnlockStatus> {
+        if Path::new(DEVICE_MAPPER_DIR).join(&self.name).exists() {
+            return Ok(UnlockStatus::AlreadyOpen);
+        }
+
+        let key_file =
+            write_key_file(&self.key_dir, &passphrase(key, agent_uuid)?)?;
+
+        let e = match self.open(key_file.path()) {
+            Ok(()) => return Ok(UnlockStatus::Opened),
+            Err(e) => e,
+        };
+
+        let Some(enroll_key_file) = &self.enroll_key_file else {
+            return Err(e);
+        };
+
+        debug!(
+            "Unable to open {} with the derived passphrase, enrolling it: {e}",
+            self.device.display()
+        );
+        self.enroll(enroll_key_file, key_file.path())?;
+        self.open(key_file.path())?;
+        Ok(UnlockStatus::Enrolled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_passphrase() {
+        let key = SymmKey::try_from(&[0x42u8; 32][..]).unwrap(); //#[allow_ci]
+        let p =
+            passphrase(&key, "d432fbb3-d2f1-4a97-9ef7-75bd81c00000").unwrap(); //#[allow_ci]
+
+        // Hex encoded HMAC-SHA384
+        assert_eq!(p.len(), 96);
+        assert_eq!(
+            p,
+            passphrase(&key, "d432fbb3-d2f1-4a97-9ef7-75bd81c00000").unwrap() //#[allow_ci]
+        );
+        assert_ne!(p, passphrase(&key, "another-agent").unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_write_key_file() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let file = write_key_file(dir.path(), "secret").unwrap(); //#[allow_ci]
+        let path = file.path().to_path_buf();
+
+        let metadata = fs::metadata(&path).unwrap(); //#[allow_ci]
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o400);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret"); //#[allow_ci]
+
+        drop(file);
+        assert!(!path.exists());
+    }
+}
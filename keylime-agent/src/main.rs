@@ -31,32 +31,78 @@
 //  missing_docs: there is many functions missing documentations for now
 #![allow(unused, missing_docs)]
 
+mod agent_handler;
+mod asset_tag;
+mod boot_state;
+mod certificates;
+mod clock;
 mod common;
 mod config;
+mod connections;
+mod cose;
 mod crypto;
+mod eat;
+mod endpoint;
+mod enrollment;
 mod error;
 mod errors_handler;
+mod events;
+mod file_watch;
+mod fips;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handoff;
+mod hooks;
+mod http_client;
+mod kernel_boot;
 mod keys_handler;
+#[cfg(feature = "local-appraisal")]
+mod local_appraisal;
+mod lsm;
+mod luks;
+mod measured_boot;
+mod node_status;
 mod notifications_handler;
 mod payloads;
+mod pcr_forecast;
+mod peer_tls;
 mod permissions;
 mod quotes_handler;
 mod registrar_agent;
+mod relay;
+mod resources;
+mod response_signing;
 mod revocation;
+mod sealed_config;
 mod secure_mount;
+mod self_attestation;
 mod serialization;
+mod service;
+#[cfg(feature = "testing")]
+mod simulation;
+mod spire;
+mod tpm_sharing;
+mod tpm_state;
+mod verifiers;
 mod version_handler;
+mod work_dir;
 
-use actix_web::{dev::Service, http, middleware, rt, web, App, HttpServer};
+use actix_web::{
+    dev::Service, http, middleware, rt, web, App, HttpMessage, HttpResponse,
+    HttpServer,
+};
 use base64::{engine::general_purpose, Engine as _};
 use clap::{Arg, Command as ClapApp};
 use common::*;
-use error::{Error, Result};
+use endpoint::Endpoint;
+use error::{Context as _, Error, Result};
 use futures::{
-    future::{ok, TryFutureExt},
+    future::{
+        ok, try_join_all, Either, FutureExt, LocalBoxFuture, TryFutureExt,
+    },
     try_join,
 };
-use keylime::{ima::MeasurementList, list_parser::parse_list, tpm};
+use keylime::{identity, ima::MeasurementList, list_parser::parse_list, tpm};
 use log::*;
 use openssl::{
     pkey::{PKey, Private, Public},
@@ -65,10 +111,10 @@ use openssl::{
 use std::{
     convert::TryFrom,
     fs,
-    io::{BufReader, Read, Write},
+    io::{BufReader, Read, Seek, Write},
     path::{Path, PathBuf},
     str::FromStr,
-    sync::Mutex,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 use tokio::{
@@ -79,7 +125,9 @@ use tss_esapi::{
     handles::KeyHandle,
     interface_types::algorithm::{AsymmetricAlgorithm, HashingAlgorithm},
     interface_types::resource_handles::Hierarchy,
-    structures::{Auth, Data, Digest, MaxBuffer, PublicBuffer},
+    structures::{
+        Attest, Auth, Data, Digest, MaxBuffer, PublicBuffer, Signature,
+    },
     traits::Marshall,
     Context,
 };
@@ -94,7 +142,7 @@ static NOTFOUND: &[u8] = b"Not Found";
 // handle quotes.
 #[derive(Debug)]
 pub struct QuoteData {
-    tpmcontext: Mutex<tpm::Context>,
+    tpmcontext: Arc<Mutex<tpm::Context>>,
     priv_key: PKey<Private>,
     pub_key: PKey<Public>,
     ak_handle: KeyHandle,
@@ -105,6 +153,8 @@ pub struct QuoteData {
         Option<oneshot::Sender<keys_handler::SymmKeyMessage>>,
     )>,
     hash_alg: keylime::algorithms::HashAlgorithm,
+    pcr_policy: service::PcrPolicy,
+    auth_tag_algs: Vec<keylime::algorithms::HashAlgorithm>,
     enc_alg: keylime::algorithms::EncryptionAlgorithm,
     sign_alg: keylime::algorithms::SignAlgorithm,
     agent_uuid: String,
@@ -115,6 +165,24 @@ pub struct QuoteData {
     measuredboot_ml_file: Option<Mutex<fs::File>>,
     ima_ml: Mutex<MeasurementList>,
     secure_mount: PathBuf,
+    key_wipe_pcr: Option<u32>,
+    fips: fips::FipsStatus,
+    boot_epoch: Option<u64>,
+    agent_starts: Option<u64>,
+    boot: Option<kernel_boot::BootInfo>,
+    tpm_lock: tpm_sharing::TpmLock,
+    asset_tag: Option<asset_tag::AssetTag>,
+    enable_agent_nonce: bool,
+    memory_budget: Arc<resources::MemoryBudget>,
+    events: events::EventPublisher,
+    clock: clock::SharedClock,
+}
+
+impl QuoteData {
+    /// Lock the TPM context for a sequence of TPM commands
+    pub(crate) fn tpm(&self) -> tpm_sharing::TpmGuard<'_> {
+        tpm_sharing::lock(&self.tpmcontext, &self.tpm_lock)
+    }
 }
 
 #[actix_web::main]
@@ -125,113 +193,71 @@ async fn main() -> Result<()> {
         .override_usage(
             "sudo RUST_LOG=keylime_agent=trace ./target/debug/keylime_agent",
         )
+        .arg(
+            Arg::new("export_enrollment_bundle")
+                .long("export-enrollment-bundle")
+                .value_name("DIR")
+                .help("Write the enrollment bundle of each agent to DIR for the offline provisioning of verifiers, instead of registering the agents"),
+        )
+        .arg(
+            Arg::new("seal_config_value")
+                .long("seal-config-value")
+                .value_name("FILE")
+                .help("Seal the secret read from the standard input to the TPM, under a policy on the PCRs of 'sealed_config_pcrs', and write it to FILE, to be referenced as \"tpm-sealed:FILE\" in the configuration"),
+        )
         .get_matches();
 
     pretty_env_logger::init();
 
     // Load config
-    let mut config = config::KeylimeConfig::new()?;
-
-    // load path for IMA logfile
-    #[cfg(test)]
-    fn ima_ml_path_get(_: &String) -> PathBuf {
-        Path::new(env!("CARGO_MANIFEST_DIR"))
-            .join("test-data")
-            .join("ima")
-            .join("ascii_runtime_measurements")
-    }
-
-    #[cfg(not(test))]
-    fn ima_ml_path_get(s: &String) -> PathBuf {
-        Path::new(&s).to_path_buf()
-    }
-
-    let ima_ml_path = ima_ml_path_get(&config.agent.ima_ml_path);
-
-    // check whether anyone has overridden the default
-    if ima_ml_path.as_os_str() != config::DEFAULT_IMA_ML_PATH {
-        warn!(
-            "IMA measurement list location override: {}",
-            ima_ml_path.display()
+    let config = config::KeylimeConfig::new()?;
+
+    if let Some(path) = matches.get_one::<String>("seal_config_value") {
+        let mut ctx = tpm::Context::new()?;
+        return sealed_config::seal_to_file(
+            &mut ctx,
+            &config,
+            std::io::stdin().lock(),
+            Path::new(path),
         );
     }
 
-    // check IMA logfile exists & accessible
-    let ima_ml_file = if ima_ml_path.exists() {
-        match fs::File::open(&ima_ml_path) {
-            Ok(file) => Some(Mutex::new(file)),
-            Err(e) => {
-                warn!(
-                    "IMA measurement list not accessible: {}",
-                    ima_ml_path.display()
-                );
-                None
-            }
-        }
-    } else {
-        warn!(
-            "IMA measurement list not available: {}",
-            ima_ml_path.display()
-        );
-        None
-    };
+    let additional_agents = config.additional_agents()?;
 
-    // load path for MBA logfile
-    let mut measuredboot_ml_path =
-        Path::new(&config.agent.measuredboot_ml_path);
-    let env_mb_path: String;
-    #[cfg(feature = "testing")]
-    if let Ok(v) = std::env::var("TPM_BINARY_MEASUREMENTS") {
-        env_mb_path = v;
-        measuredboot_ml_path = Path::new(&env_mb_path);
+    // Apply the resource limits while the process still has the privileges
+    // to raise them
+    if config.agent.rlimit_nofile != 0 {
+        resources::set_rlimit(
+            resources::ResourceLimit::OpenFiles,
+            config.agent.rlimit_nofile,
+        )?;
     }
-
-    // check whether anyone has overridden the default MBA logfile
-    if measuredboot_ml_path.as_os_str()
-        != config::DEFAULT_MEASUREDBOOT_ML_PATH
-    {
-        warn!(
-            "Measured boot measurement list location override: {}",
-            measuredboot_ml_path.display()
-        );
+    let rlimit_as = resources::parse_size(&config.agent.rlimit_as)?;
+    if rlimit_as != 0 {
+        resources::set_rlimit(
+            resources::ResourceLimit::AddressSpace,
+            rlimit_as as libc::rlim_t,
+        )?;
     }
 
-    // check MBA logfile exists & accessible
-    let measuredboot_ml_file = if measuredboot_ml_path.exists() {
-        match fs::File::open(measuredboot_ml_path) {
-            Ok(file) => Some(Mutex::new(file)),
-            Err(e) => {
-                warn!(
-                    "Measured boot measurement list not accessible: {}",
-                    measuredboot_ml_path.display()
-                );
-                None
-            }
-        }
-    } else {
-        warn!(
-            "Measured boot measurement list not available: {}",
-            measuredboot_ml_path.display()
-        );
-        None
-    };
-
-    // The agent cannot run when a payload script is defined, but mTLS is disabled and insecure
-    // payloads are not explicitly enabled
-    if !config.agent.enable_agent_mtls
-        && !config.agent.enable_insecure_payload
-        && !config.agent.payload_script.is_empty()
-    {
-        let message = "The agent mTLS is disabled and 'payload_script' is not empty. To allow the agent to run, 'enable_insecure_payload' has to be set to 'True'".to_string();
-
-        error!("Configuration error: {}", &message);
-        return Err(Error::Configuration(message));
+    // Devices without a working RTC may start with the clock unset, which
+    // breaks the TLS certificate validation
+    let clock = clock::system();
+    _ = clock::check_plausible(clock.as_ref());
+    lsm::log_status(&config.agent.secure_mount_context);
+
+    // The memory budget is shared by all the logical agents
+    let memory_budget = Arc::new(resources::MemoryBudget::new(
+        resources::parse_size(&config.agent.memory_budget)?,
+    ));
+
+    // The measurement lists are opened and the secure mounts created for
+    // each logical agent while the process still has the privileges
+    let mut agents = Vec::new();
+    for agent_config in std::iter::once(&config).chain(&additional_agents) {
+        agents.push((agent_config.clone(), AgentFiles::open(agent_config)?));
     }
 
-    let secure_size = config.agent.secure_size.clone();
-    let work_dir = PathBuf::from(&config.agent.keylime_dir);
-    let mount = secure_mount::mount(&work_dir, &config.agent.secure_size)?;
-
     let run_as = if permissions::get_euid() == 0 {
         if (config.agent.run_as).is_empty() {
             warn!("Cannot drop privileges since 'run_as' is empty in 'agent' section of 'keylime-agent.conf'.");
@@ -248,7 +274,10 @@ async fn main() -> Result<()> {
 
     // Drop privileges
     if let Some(user_group) = run_as {
-        permissions::chown(user_group, &mount)?;
+        for (_, files) in &agents {
+            files.layout.chown(user_group)?;
+            permissions::chown(user_group, &files.mount)?;
+        }
         if let Err(e) = permissions::run_as(user_group) {
             let message = "The user running the Keylime agent should be set in keylime-agent.conf, using the parameter `run_as`, with the format `user:group`".to_string();
 
@@ -260,6 +289,10 @@ async fn main() -> Result<()> {
 
     info!("Starting server with API version {}...", API_VERSION);
 
+    // The TPM is shared with the other TPM users of the system
+    tpm_sharing::check(&config)?;
+    let tpm_lock = tpm_sharing::TpmLock::open(&config.agent.tpm_lock_path)?;
+
     let mut ctx = tpm::Context::new()?;
 
     //  Retrieve the TPM Vendor, this allows us to warn if someone is using a
@@ -290,7 +323,11 @@ async fn main() -> Result<()> {
     // When the tpm_ownerpassword is given, set auth for the Endorsement hierarchy.
     // Note in the Python implementation, tpm_ownerpassword option is also used for claiming
     // ownership of TPM access, which will not be implemented here.
-    let tpm_ownerpassword = &config.agent.tpm_ownerpassword;
+    let tpm_ownerpassword = sealed_config::resolve(
+        &mut ctx,
+        "tpm_ownerpassword",
+        &config.agent.tpm_ownerpassword,
+    )?;
     if !tpm_ownerpassword.is_empty() {
         let auth = Auth::try_from(tpm_ownerpassword.as_bytes())?;
         ctx.as_mut().tr_set_auth(Hierarchy::Endorsement.into(), auth)
@@ -301,6 +338,208 @@ async fn main() -> Result<()> {
             })?;
     };
 
+    // The sealed secrets are unsealed before the TPM context is shared
+    for (agent_config, _) in &mut agents {
+        agent_config.agent.cloudevents_auth_token = sealed_config::resolve(
+            &mut ctx,
+            "cloudevents_auth_token",
+            &agent_config.agent.cloudevents_auth_token,
+        )?;
+    }
+
+    let bundle_dir = matches
+        .get_one::<String>("export_enrollment_bundle")
+        .map(PathBuf::from);
+
+    // A single TPM context is shared by all the logical agents, which are
+    // registered one after the other
+    let ctx = Arc::new(Mutex::new(ctx));
+    let mut runs = Vec::new();
+    for (agent_config, files) in agents {
+        runs.push(
+            start_agent(
+                agent_config,
+                files,
+                ctx.clone(),
+                tpm_lock.clone(),
+                memory_budget.clone(),
+                clock.clone(),
+                bundle_dir.as_deref(),
+            )
+            .await?,
+        );
+    }
+
+    try_join_all(runs).await.map(|_| ())
+}
+
+/// The files of a logical agent opened before the privileges are dropped
+#[derive(Debug)]
+struct AgentFiles {
+    layout: work_dir::Layout,
+    mount: PathBuf,
+    ima_ml_path: PathBuf,
+    ima_ml_file: Option<Mutex<fs::File>>,
+    measuredboot_ml_file: Option<Mutex<fs::File>>,
+    fanotify: Option<file_watch::Fanotify>,
+}
+
+impl AgentFiles {
+    fn open(config: &config::KeylimeConfig) -> Result<Self> {
+        // load path for IMA logfile
+        #[cfg(test)]
+        fn ima_ml_path_get(_: &String) -> PathBuf {
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data")
+                .join("ima")
+                .join("ascii_runtime_measurements")
+        }
+
+        #[cfg(not(test))]
+        fn ima_ml_path_get(s: &String) -> PathBuf {
+            Path::new(&s).to_path_buf()
+        }
+
+        let ima_ml_path = ima_ml_path_get(&config.agent.ima_ml_path);
+
+        // check whether anyone has overridden the default
+        if ima_ml_path.as_os_str() != config::DEFAULT_IMA_ML_PATH {
+            warn!(
+                "IMA measurement list location override: {}",
+                ima_ml_path.display()
+            );
+        }
+
+        // check IMA logfile exists & accessible
+        let ima_ml_file = if ima_ml_path.exists() {
+            match fs::File::open(&ima_ml_path) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    warn!(
+                        "IMA measurement list not accessible: {}",
+                        ima_ml_path.display()
+                    );
+                    None
+                }
+            }
+        } else {
+            warn!(
+                "IMA measurement list not available: {}",
+                ima_ml_path.display()
+            );
+            None
+        };
+
+        // load path for MBA logfile
+        let mut measuredboot_ml_path =
+            Path::new(&config.agent.measuredboot_ml_path);
+        let env_mb_path: String;
+        #[cfg(feature = "testing")]
+        if let Ok(v) = std::env::var("TPM_BINARY_MEASUREMENTS") {
+            env_mb_path = v;
+            measuredboot_ml_path = Path::new(&env_mb_path);
+        }
+
+        // check whether anyone has overridden the default MBA logfile
+        if measuredboot_ml_path.as_os_str()
+            != config::DEFAULT_MEASUREDBOOT_ML_PATH
+        {
+            warn!(
+                "Measured boot measurement list location override: {}",
+                measuredboot_ml_path.display()
+            );
+        }
+
+        // check MBA logfile exists & accessible
+        let measuredboot_ml_file = if measuredboot_ml_path.exists() {
+            match fs::File::open(measuredboot_ml_path) {
+                Ok(file) => Some(Mutex::new(file)),
+                Err(e) => {
+                    warn!(
+                        "Measured boot measurement list not accessible: {}",
+                        measuredboot_ml_path.display()
+                    );
+                    None
+                }
+            }
+        } else {
+            warn!(
+                "Measured boot measurement list not available: {}",
+                measuredboot_ml_path.display()
+            );
+            None
+        };
+
+        // The agent cannot run when a payload script is defined, but mTLS is disabled and insecure
+        // payloads are not explicitly enabled
+        if !config.agent.enable_agent_mtls
+            && !config.agent.enable_insecure_payload
+            && !config.agent.payload_script.is_empty()
+        {
+            let message = "The agent mTLS is disabled and 'payload_script' is not empty. To allow the agent to run, 'enable_insecure_payload' has to be set to 'True'".to_string();
+
+            error!("Configuration error: {}", &message);
+            return Err(Error::Configuration(message));
+        }
+
+        let layout = work_dir::Layout::new(config);
+        layout.create()?;
+        layout.migrate(config)?;
+        let mount = secure_mount::mount(
+            &layout.secure_dir,
+            &config.agent.secure_size,
+            &config.agent.secure_mount_context,
+        )?;
+
+        let fanotify = if config.agent.enable_file_watch {
+            Some(file_watch::Fanotify::init()?)
+        } else {
+            None
+        };
+
+        Ok(AgentFiles {
+            layout,
+            mount,
+            ima_ml_path,
+            ima_ml_file,
+            measuredboot_ml_file,
+            fanotify,
+        })
+    }
+}
+
+/// Set up a logical agent with its configuration, and start its servers
+/// and workers. The returned future completes when the agent is stopped.
+// The TPM context guard is explicitly dropped before the registration
+#[allow(clippy::await_holding_lock)]
+async fn start_agent(
+    mut config: config::KeylimeConfig,
+    files: AgentFiles,
+    tpm: Arc<Mutex<tpm::Context>>,
+    tpm_lock: tpm_sharing::TpmLock,
+    memory_budget: Arc<resources::MemoryBudget>,
+    clock: clock::SharedClock,
+    bundle_dir: Option<&Path>,
+) -> Result<LocalBoxFuture<'static, Result<()>>> {
+    let AgentFiles {
+        layout: _,
+        mount,
+        ima_ml_path,
+        ima_ml_file,
+        measuredboot_ml_file,
+        fanotify,
+    } = files;
+    let secure_size = config.agent.secure_size.clone();
+    let work_dir = PathBuf::from(&config.agent.keylime_dir);
+
+    // The TPM context is released while waiting for the registrar
+    let mut ctx = tpm_sharing::lock(&tpm, &tpm_lock);
+
+    let persistent_handles = tpm_sharing::HandleRange::parse(
+        &config.agent.tpm_persistent_handles,
+    )?;
+    tpm_sharing::report_usage(&mut ctx, &persistent_handles);
+
     let tpm_encryption_alg =
         keylime::algorithms::EncryptionAlgorithm::try_from(
             config.agent.tpm_encryption_alg.as_ref(),
@@ -311,6 +550,18 @@ async fn main() -> Result<()> {
     let tpm_signing_alg = keylime::algorithms::SignAlgorithm::try_from(
         config.agent.tpm_signing_alg.as_ref(),
     )?;
+    let auth_tag_algs =
+        auth_tag_algorithms(&config.agent.auth_tag_algorithms)?;
+    let pcr_policy = pcr_policy(&config, &mut ctx)?;
+    let fips = fips::enforce(
+        &config,
+        tpm_hash_alg,
+        tpm_encryption_alg,
+        tpm_signing_alg,
+        &auth_tag_algs,
+    )?;
+    keylime::hashing::set_default(&config.agent.hash_backend)?;
+    info!("Using the {} hashing backend", config.agent.hash_backend);
 
     let iak_cert: Option<X509>;
     let idevid_cert: Option<X509>;
@@ -415,27 +666,38 @@ async fn main() -> Result<()> {
         (None, None)
     };
 
-    // Gather EK values and certs
-    let ek_result = match config.agent.ek_handle.as_ref() {
-        "" => ctx.create_ek(tpm_encryption_alg, None)?,
-        s => ctx.create_ek(tpm_encryption_alg, Some(s))?,
+    // Gather EK values. The EK certificate is read later, only if it is not
+    // cached in the agent data
+    let mut ek_result = match config.agent.ek_handle.as_ref() {
+        "" => ctx.create_ek_object(tpm_encryption_alg, None)?,
+        s => ctx.create_ek_object(tpm_encryption_alg, Some(s))?,
     };
 
     // Calculate the SHA-256 hash of the public key in PEM format
     let ek_hash = hash_ek_pubkey(ek_result.public.clone())?;
 
-    // Replace the uuid with the actual EK hash if the option was set.
-    // We cannot do that when the configuration is loaded initially,
-    // because only have later access to the the TPM.
-    config.agent.uuid = match config.agent.uuid.as_ref() {
-        "hash_ek" => ek_hash.clone(),
-        s => s.to_string(),
-    };
+    // Obtain the UUID from the configured identity source. We cannot do
+    // that when the configuration is loaded initially, because some sources
+    // need the TPM.
+    let identity = identity::from_config(
+        &config.agent.uuid,
+        Path::new(&config.agent.uuid_path),
+    );
+    config.agent.uuid = identity
+        .uuid(&identity::IdentityContext {
+            ek_hash: Some(ek_hash.clone()),
+        })
+        .map_err(|e| {
+            Error::Configuration(format!(
+                "Unable to get the UUID from the {} identity: {e}",
+                identity.name()
+            ))
+        })?;
 
     let agent_uuid = config.agent.uuid.clone();
 
     // Try to load persistent Agent data
-    let old_ak = match config.agent.agent_data_path.as_ref() {
+    let agent_data = match config.agent.agent_data_path.as_ref() {
         "" => {
             info!("Agent Data path not set in the configuration file");
             None
@@ -444,43 +706,7 @@ async fn main() -> Result<()> {
             let path = Path::new(&path);
             if path.exists() {
                 match AgentData::load(path) {
-                    Ok(data) => {
-                        match data.valid(
-                            tpm_hash_alg,
-                            tpm_signing_alg,
-                            ek_hash.as_bytes(),
-                        ) {
-                            true => {
-                                let ak_result = data.get_ak()?;
-                                match ctx
-                                    .load_ak(ek_result.key_handle, &ak_result)
-                                {
-                                    Ok(ak_handle) => {
-                                        info!(
-                                            "Loaded old AK key from {}",
-                                            path.display()
-                                        );
-                                        Some((ak_handle, ak_result))
-                                    }
-                                    Err(e) => {
-                                        warn!(
-                                            "Loading old AK key from {} failed: {}",
-                                            path.display(),
-                                            e
-                                        );
-                                        None
-                                    }
-                                }
-                            }
-                            false => {
-                                warn!(
-                                    "Not using old {} because it is not valid with current configuration",
-                                    path.display()
-                                );
-                                None
-                            }
-                        }
-                    }
+                    Ok(data) => Some(data),
                     Err(e) => {
                         warn!("Could not load agent data: {}", e);
                         None
@@ -493,8 +719,76 @@ async fn main() -> Result<()> {
         }
     };
 
+    // Use the cached EK certificate if it was read for the same EK,
+    // otherwise read it again, as it may have been provisioned since
+    let cached_ek_cert = match &agent_data {
+        Some(data) => data.cached_ek_cert(&ek_result.public)?,
+        None => None,
+    };
+    ek_result.ek_cert = match cached_ek_cert {
+        Some(ek_cert) => {
+            info!("Using EK certificate cached in agent data");
+            Some(ek_cert)
+        }
+        None => ctx.read_ek_cert(tpm_encryption_alg),
+    };
+
+    let previous_tpm_state =
+        agent_data.as_ref().and_then(|data| data.tpm_state.clone());
+    let previous_boot_state =
+        agent_data.as_ref().and_then(|data| data.boot_state.clone());
+    let previous_kernel_boot = agent_data
+        .as_ref()
+        .and_then(|data| data.kernel_boot.clone());
+    let agent_starts = agent_data
+        .as_ref()
+        .and_then(|data| data.agent_starts)
+        .unwrap_or(0)
+        + 1;
+
+    // Try to load the AK from the persistent Agent data
+    let old_ak = match agent_data {
+        Some(data) => {
+            let path = Path::new(&config.agent.agent_data_path);
+            match data.valid(
+                tpm_hash_alg,
+                tpm_signing_alg,
+                ek_hash.as_bytes(),
+            ) {
+                true => {
+                    let ak_result = data.get_ak()?;
+                    match ctx.load_ak(ek_result.key_handle, &ak_result) {
+                        Ok(ak_handle) => {
+                            info!(
+                                "Loaded old AK key from {}",
+                                path.display()
+                            );
+                            Some((ak_handle, ak_result))
+                        }
+                        Err(e) => {
+                            warn!(
+                                "Loading old AK key from {} failed: {}",
+                                path.display(),
+                                e
+                            );
+                            None
+                        }
+                    }
+                }
+                false => {
+                    warn!(
+                        "Not using old {} because it is not valid with current configuration",
+                        path.display()
+                    );
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+
     // Use old AK or generate a new one and update the AgentData
-    let (ak_handle, ak) = match old_ak {
+    let (mut ak_handle, mut ak) = match old_ak {
         Some((ak_handle, ak)) => (ak_handle, ak),
         None => {
             let new_ak = ctx.create_ak(
@@ -507,22 +801,196 @@ async fn main() -> Result<()> {
         }
     };
 
+    // Detect whether the TPM was cleared or the PCR banks reallocated since
+    // the agent data was stored, in which case the stored AK and EK
+    // certificate are replaced
+    let tpm_state = tpm_state::TpmState::read(&mut ctx, ak_handle)?;
+    let tpm_changes = match &previous_tpm_state {
+        Some(previous) => tpm_state.changes(previous),
+        None => Vec::new(),
+    };
+
+    // Detect whether the kernel was started with kexec or the system
+    // soft-rebooted, in which case the boot time evidence is stale
+    let kernel_boot =
+        match kernel_boot::KernelBoot::read(tpm_state.reset_count) {
+            Ok(kernel_boot) => Some(kernel_boot),
+            Err(e) => {
+                warn!("Unable to read the kernel boot information: {e}");
+                None
+            }
+        };
+    let boot_kind = kernel_boot.as_ref().map(|current| {
+        (current.kind(previous_kernel_boot.as_ref()), current)
+    });
+    let kexec_reregister = config.agent.refuse_quotes_after_kexec
+        && matches!(boot_kind, Some((kernel_boot::BootKind::Kexec, _)));
+    if kexec_reregister {
+        warn!("Discarding the AK after kexec, the agent has to be enrolled again with the new AK");
+    }
+
+    if !tpm_changes.is_empty() || kexec_reregister {
+        warn!(
+            "Discarding the TPM data stored in {}",
+            config.agent.agent_data_path
+        );
+        ctx.as_mut().flush_context(ak_handle.into())?;
+        ak = ctx.create_ak(
+            ek_result.key_handle,
+            tpm_hash_alg,
+            tpm_signing_alg,
+        )?;
+        ak_handle = ctx.load_ak(ek_result.key_handle, &ak)?;
+        ek_result.ek_cert = ctx.read_ek_cert(tpm_encryption_alg);
+    }
+
+    // Detect whether the firmware or the booted kernel changed since the
+    // previous run, by comparing the boot log and the boot PCRs
+    let mb_log = match &measuredboot_ml_file {
+        Some(file) => {
+            let mut f = file.lock().unwrap(); //#[allow_ci]
+            let mut buf = Vec::new();
+            match f.rewind().and_then(|_| f.read_to_end(&mut buf)) {
+                Ok(_) => Some(buf),
+                Err(e) => {
+                    warn!("Could not read the measured boot log: {e}");
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let mut boot_state = boot_state::BootState::read(
+        &mut ctx,
+        tpm_hash_alg,
+        mb_log.as_deref(),
+    )?;
+    let boot_change = match &previous_boot_state {
+        Some(previous) => boot_state.follow(previous),
+        None => None,
+    };
+
+    // The asset tag is read before the TPM is released for the registration
+    let asset_tag = asset_tag::read(&mut ctx, &config)?;
+
     // Store new AgentData
-    let agent_data_new = AgentData::create(
+    let mut agent_data_new = AgentData::create(
         tpm_hash_alg,
         tpm_signing_alg,
         &ak,
         ek_hash.as_bytes(),
     )?;
+    agent_data_new
+        .cache_ek_cert(&ek_result.public, ek_result.ek_cert.clone())?;
+    agent_data_new.tpm_state = Some(tpm_state.clone());
+    agent_data_new.boot_state = Some(boot_state.clone());
+    agent_data_new.kernel_boot = kernel_boot.clone();
+    agent_data_new.agent_starts = Some(agent_starts);
+
+    // The boot epoch and the agent starts are only meaningful if they are
+    // kept across the restarts
+    let (boot_epoch, agent_starts) =
+        match config.agent.agent_data_path.as_ref() {
+            "" => {
+                info!("Agent Data not stored");
+                (None, None)
+            }
+            path => {
+                agent_data_new.store(Path::new(&path))?;
+                info!(
+                    "Agent started {agent_starts} times with this agent data"
+                );
+                (Some(boot_state.epoch), Some(agent_starts))
+            }
+        };
 
-    match config.agent.agent_data_path.as_ref() {
-        "" => info!("Agent Data not stored"),
-        path => agent_data_new.store(Path::new(&path))?,
+    info!("Agent UUID: {}", agent_uuid);
+
+    // The requests to the registrar, the events sink and the Kubernetes API
+    // share the connection pool and the retry policy
+    let http_options =
+        http_client::HttpClientOptions::new(&config, &agent_uuid)?;
+    let http_client = http_client::HttpClient::new(http_options.clone())?;
+
+    // Events are queued from now on, and sent once the events worker starts
+    let events_sink = events::Sink::parse(
+        &config.agent.cloudevents_sink,
+        &config.agent.cloudevents_auth_token,
+    )?;
+    let (mut events_tx, mut events_rx) =
+        mpsc::channel::<events::EventMessage>(events::EVENTS_QUEUE_SIZE);
+    let mut events = events::EventPublisher::new(&agent_uuid, clock.clone());
+    if events_sink.is_some() {
+        events.subscribe(events_tx.clone());
     }
 
-    info!("Agent UUID: {}", agent_uuid);
+    // The local hooks are run on the events selected in 'event_hook_events'
+    let event_hooks = hooks::Hooks::new(
+        &config.agent.event_hook,
+        &config.agent.event_hook_events,
+        config.agent.event_hook_timeout,
+    )?;
+    let (mut hooks_tx, mut hooks_rx) =
+        mpsc::channel::<events::EventMessage>(events::EVENTS_QUEUE_SIZE);
+    if event_hooks.is_some() {
+        events.subscribe(hooks_tx.clone());
+    }
+
+    // The node status is derived from the events
+    let node_status_path = match config.agent.attestation_status_path.as_ref()
+    {
+        "" => None,
+        path => Some(PathBuf::from(path)),
+    };
+    #[cfg(feature = "kubernetes")]
+    let node_client = match config.agent.kubernetes_node_name.as_ref() {
+        "" => None,
+        name => {
+            Some(node_status::NodeClient::in_cluster(name, &http_options)?)
+        }
+    };
+    #[cfg(not(feature = "kubernetes"))]
+    if !config.agent.kubernetes_node_name.is_empty() {
+        warn!("The Kubernetes node name is set, but the agent was built without the 'kubernetes' feature");
+    }
+    let (mut node_status_tx, mut node_status_rx) =
+        mpsc::channel::<events::EventMessage>(events::EVENTS_QUEUE_SIZE);
+    let run_node_status = node_status_path.is_some()
+        || (cfg!(feature = "kubernetes")
+            && !config.agent.kubernetes_node_name.is_empty());
+    if run_node_status {
+        events.subscribe(node_status_tx.clone());
+    }
+
+    tpm_state::report(&tpm_changes, &events);
+
+    if let Some((kind, current)) = boot_kind {
+        kernel_boot::report(kind, current);
+        if matches!(
+            kind,
+            kernel_boot::BootKind::Kexec | kernel_boot::BootKind::SoftReboot
+        ) {
+            events.publish(events::AgentEvent::KernelRestarted {
+                kind: kind.to_string(),
+                boot_id: current.boot_id.clone(),
+            });
+        }
+    }
 
-    let (attest, signature) = if config.agent.enable_iak_idevid {
+    if let Some(change) = &boot_change {
+        boot_state::report(change);
+        let event = change.event();
+        if !config.agent.boot_change_notify_url.is_empty() {
+            drop(rt::spawn(boot_state::notify(
+                http_client.clone(),
+                config.agent.boot_change_notify_url.clone(),
+                events.cloud_event(&event),
+            )));
+        }
+        events.publish(event);
+    }
+
+    let (mut attest, mut signature) = if config.agent.enable_iak_idevid {
         let qualifying_data = config.agent.uuid.as_bytes();
         let (attest, signature) = ctx.certify_credential_with_iak(
             Data::try_from(qualifying_data).unwrap(), //#[allow_ci]
@@ -586,6 +1054,13 @@ async fn main() -> Result<()> {
     let cert: X509;
     let mtls_cert;
     let ssl_context;
+    let mut verifiers = None;
+    #[cfg(feature = "grpc")]
+    let mut grpc_tls = None;
+    let mut certificates = certificates::Inventory::new(
+        clock.clone(),
+        config.agent.cert_expiry_warning_days,
+    );
     if config.agent.enable_agent_mtls {
         cert = match config.agent.server_cert.as_ref() {
             "" => {
@@ -640,103 +1115,189 @@ async fn main() -> Result<()> {
             }
         }?;
 
+        // The CA certificates of the additional verifiers are also trusted
+        // in the handshake, and each request is checked against the
+        // identity of the verifier
+        let mut trusted_ca_certs = keylime_ca_certs.clone();
+        if !config.agent.verifier_identities.is_empty() {
+            let v = verifiers::Verifiers::load(
+                Path::new(&config.agent.verifier_identities),
+                &keylime_ca_certs,
+            )?;
+            trusted_ca_certs.extend(v.ca_certs());
+            verifiers = Some(Arc::new(v));
+        }
+
+        // The gRPC server uses the same identity and trusted CAs as the REST
+        // API, and checks the verifier identities for each RPC
+        #[cfg(feature = "grpc")]
+        if config.agent.enable_grpc {
+            grpc_tls =
+                Some(grpc::tls_config(&cert, &nk_priv, &trusted_ca_certs)?);
+        }
+
+        _ = clock::check_certificate(
+            &cert,
+            "agent mTLS certificate",
+            clock.as_ref(),
+        );
+        for ca_cert in &keylime_ca_certs {
+            _ = clock::check_certificate(
+                ca_cert,
+                "trusted client CA certificate",
+                clock.as_ref(),
+            );
+        }
+
+        certificates
+            .add_loaded(certificates::CertificateKind::Server, cert.clone());
+        for path in &certs_list {
+            certificates
+                .add_file(certificates::CertificateKind::KeylimeCa, path);
+        }
+
         mtls_cert = Some(&cert);
         ssl_context = Some(crypto::generate_mtls_context(
             &cert,
             &nk_priv,
-            keylime_ca_certs,
+            trusted_ca_certs,
+            config.agent.fips_mode,
         )?);
     } else {
+        if !config.agent.verifier_identities.is_empty() {
+            error!("The verifier identities require mTLS to be enabled");
+            return Err(Error::Configuration(
+                "The verifier identities require mTLS to be enabled"
+                    .to_string(),
+            ));
+        }
         mtls_cert = None;
         ssl_context = None;
         warn!("mTLS disabled, Tenant and Verifier will reach out to agent via HTTP");
     }
 
-    {
-        // Request keyblob material
-        let keyblob = if config.agent.enable_iak_idevid {
-            let (Some(iak), Some(idevid), Some(attest), Some(signature)) =
-                (iak, idevid, attest, signature)
-            else {
-                error!(
-                    "IDevID and IAK are enabled but could not be generated"
-                );
-                return Err(Error::Configuration(
-                    "IDevID and IAK are enabled but could not be generated"
-                        .to_string(),
-                ));
-            };
-            registrar_agent::do_register_agent(
-                config.agent.registrar_ip.as_ref(),
-                config.agent.registrar_port,
-                &agent_uuid,
-                &PublicBuffer::try_from(ek_result.public.clone())?
-                    .marshall()?,
-                ek_result.ek_cert,
-                &PublicBuffer::try_from(ak.public)?.marshall()?,
-                Some(
-                    &PublicBuffer::try_from(iak.public.clone())?
-                        .marshall()?,
-                ),
-                Some(
-                    &PublicBuffer::try_from(idevid.public.clone())?
-                        .marshall()?,
-                ),
-                idevid_cert,
-                iak_cert,
-                Some(attest.marshall()?),
-                Some(signature.marshall()?),
-                mtls_cert,
-                config.agent.contact_ip.as_ref(),
-                config.agent.contact_port,
-            )
-            .await?
-        } else {
-            registrar_agent::do_register_agent(
-                config.agent.registrar_ip.as_ref(),
-                config.agent.registrar_port,
-                &agent_uuid,
-                &PublicBuffer::try_from(ek_result.public.clone())?
-                    .marshall()?,
-                ek_result.ek_cert,
-                &PublicBuffer::try_from(ak.public)?.marshall()?,
-                None,
-                None,
-                None,
-                None,
-                None,
-                None,
-                mtls_cert,
-                config.agent.contact_ip.as_ref(),
-                config.agent.contact_port,
-            )
-            .await?
+    // The enrollment bundle replaces the registration
+    if let Some(dir) = bundle_dir {
+        let evidence = enrollment::Evidence {
+            agent_uuid: agent_uuid.clone(),
+            ek_tpm: PublicBuffer::try_from(ek_result.public.clone())?
+                .marshall()?,
+            ek_cert: ek_result.ek_cert.clone(),
+            aik_tpm: PublicBuffer::try_from(ak.public.clone())?.marshall()?,
+            mtls_cert: mtls_cert
+                .map(|c| c.to_pem())
+                .transpose()?
+                .map(|pem| String::from_utf8_lossy(&pem).to_string()),
+            hash_alg: tpm_hash_alg.to_string(),
+            sign_alg: tpm_signing_alg.to_string(),
+            mb_measurement_list: match &measuredboot_ml_file {
+                Some(file) => {
+                    let mut buf = Vec::new();
+                    let _ = file
+                        .lock()
+                        .unwrap() //#[allow_ci]
+                        .read_to_end(&mut buf)?;
+                    Some(buf)
+                }
+                None => None,
+            },
+            ima_measurement_list: match &ima_ml_file {
+                Some(file) => {
+                    let mut ml = String::new();
+                    let _ = file
+                        .lock()
+                        .unwrap() //#[allow_ci]
+                        .read_to_string(&mut ml)?;
+                    Some(ml)
+                }
+                None => None,
+            },
+            created: clock.unix_time(),
         };
-
-        info!("SUCCESS: Agent {} registered", &agent_uuid);
-
-        let key = ctx.activate_credential(
-            keyblob,
-            ak_handle,
-            ek_result.key_handle,
+        let bundle = enrollment::create(
+            &mut ctx, ak_handle, &evidence, &nk_pub, &nk_priv,
         )?;
-        // Flush EK if we created it
+        let _ = enrollment::export(dir, &agent_uuid, &bundle)?;
+
         if config.agent.ek_handle.is_empty() {
             ctx.as_mut().flush_context(ek_result.key_handle.into())?;
         }
-        let mackey = general_purpose::STANDARD.encode(key.value());
-        let auth_tag =
-            crypto::compute_hmac(mackey.as_bytes(), agent_uuid.as_bytes())?;
-        let auth_tag = hex::encode(&auth_tag);
-
-        registrar_agent::do_activate_agent(
-            config.agent.registrar_ip.as_ref(),
-            config.agent.registrar_port,
+        return Ok(ok(()).boxed_local());
+    }
+
+    drop(ctx);
+
+    // If the activation fails because the registrar or the TPM do not
+    // accept the AK anymore, a new AK is generated and the agent registered
+    // again, up to the configured number of attempts
+    let mut reactivations = 0;
+    loop {
+        let result = register_and_activate(
+            &config,
+            &tpm,
+            &tpm_lock,
             &agent_uuid,
-            &auth_tag,
+            &ek_result,
+            ak_handle,
+            &ak,
+            iak.as_ref().zip(idevid.as_ref()),
+            iak_cert.clone(),
+            idevid_cert.clone(),
+            attest.as_ref().zip(signature.as_ref()),
+            mtls_cert,
+            asset_tag.as_ref(),
+            &events,
+            &http_client,
         )
-        .await?;
-        info!("SUCCESS: Agent {} activated", &agent_uuid);
+        .await;
+
+        let e = match result {
+            Ok(()) => break,
+            Err(e) if registrar_agent::is_stale_ak(&e) => e,
+            Err(e) => return Err(e),
+        };
+
+        if reactivations >= config.agent.ak_reactivation_attempts {
+            error!("Agent {agent_uuid} activation failed after {reactivations} AK regenerations, manual intervention is required: {e}");
+            return Err(e);
+        }
+        reactivations += 1;
+
+        error!("Agent {agent_uuid} activation failed, regenerating the AK and registering again (attempt {reactivations} of {}): {e}", config.agent.ak_reactivation_attempts);
+        let mut ctx = tpm_sharing::lock(&tpm, &tpm_lock);
+        ctx.as_mut().flush_context(ak_handle.into())?;
+        ak = ctx.create_ak(
+            ek_result.key_handle,
+            tpm_hash_alg,
+            tpm_signing_alg,
+        )?;
+        ak_handle = ctx.load_ak(ek_result.key_handle, &ak)?;
+
+        if let Some(iak) = &iak {
+            let (a, s) = ctx.certify_credential_with_iak(
+                Data::try_from(config.agent.uuid.as_bytes())?,
+                ak_handle,
+                iak.handle,
+            )?;
+            attest = Some(a);
+            signature = Some(s);
+        }
+
+        agent_data_new.set_ak(&ak)?;
+        match config.agent.agent_data_path.as_ref() {
+            "" => {}
+            path => agent_data_new.store(Path::new(&path))?,
+        }
+        events.publish(events::AgentEvent::AkRegenerated {
+            reason: e.to_string(),
+        });
+    }
+
+    // Flush EK if we created it
+    if config.agent.ek_handle.is_empty() {
+        tpm_sharing::lock(&tpm, &tpm_lock)
+            .as_mut()
+            .flush_context(ek_result.key_handle.into())?;
     }
 
     let (mut payload_tx, mut payload_rx) =
@@ -763,6 +1324,18 @@ async fn main() -> Result<()> {
         }
         s => PathBuf::from(s),
     };
+    certificates.add_file(
+        certificates::CertificateKind::Revocation,
+        &revocation_cert,
+    );
+    if !config.agent.registrar_tls_ca.is_empty() {
+        certificates.add_file(
+            certificates::CertificateKind::RegistrarCa,
+            &config.agent.registrar_tls_ca,
+        );
+    }
+    let certificates = web::Data::new(certificates);
+    certificates::report(&certificates.check());
 
     let revocation_actions_dir = config.agent.revocation_actions_dir.clone();
 
@@ -782,11 +1355,17 @@ async fn main() -> Result<()> {
         allow_payload_revocation_actions,
         work_dir.clone(),
         mount.clone(),
+        events.clone(),
     ))
     .map_err(Error::from);
 
+    // The requests are refused early when the file descriptors or the
+    // memory budget are exhausted
+    let admission =
+        Arc::new(resources::Admission::new(memory_budget.clone()));
+
     let quotedata = web::Data::new(QuoteData {
-        tpmcontext: Mutex::new(ctx),
+        tpmcontext: tpm,
         priv_key: nk_priv,
         pub_key: nk_pub,
         ak_handle,
@@ -794,6 +1373,8 @@ async fn main() -> Result<()> {
         payload_tx: payload_tx.clone(),
         revocation_tx: revocation_tx.clone(),
         hash_alg: tpm_hash_alg,
+        pcr_policy,
+        auth_tag_algs,
         enc_alg: tpm_encryption_alg,
         sign_alg: tpm_signing_alg,
         agent_uuid: agent_uuid.clone(),
@@ -804,116 +1385,456 @@ async fn main() -> Result<()> {
         measuredboot_ml_file,
         ima_ml: Mutex::new(MeasurementList::new()),
         secure_mount: PathBuf::from(&mount),
+        key_wipe_pcr: match config.agent.key_wipe_pcr.as_ref() {
+            "" => None,
+            pcr => Some(pcr.parse()?),
+        },
+        fips,
+        boot_epoch,
+        agent_starts,
+        boot: boot_kind.map(|(kind, current)| current.info(kind)),
+        tpm_lock,
+        asset_tag,
+        enable_agent_nonce: config.agent.enable_agent_nonce,
+        memory_budget,
+        events: events.clone(),
+        clock: clock.clone(),
     });
 
-    let actix_server =
-        HttpServer::new(move || {
-            App::new()
-                .wrap(middleware::ErrorHandlers::new().handler(
-                    http::StatusCode::NOT_FOUND,
-                    errors_handler::wrap_404,
-                ))
-                .wrap(middleware::Logger::new(
-                    "%r from %a result %s (took %D ms)",
-                ))
-                .wrap_fn(|req, srv| {
-                    info!(
-                        "{} invoked from {:?} with uri {}",
-                        req.head().method,
-                        req.connection_info().peer_addr().unwrap(), //#[allow_ci]
-                        req.uri()
+    #[cfg(feature = "grpc")]
+    let (mut grpc_tx, mut grpc_rx) = mpsc::channel::<grpc::GrpcMessage>(1);
+
+    #[cfg(not(feature = "grpc"))]
+    if config.agent.enable_grpc {
+        warn!("The gRPC server is enabled, but the agent was built without the 'grpc' feature");
+    }
+
+    #[cfg(feature = "local-appraisal")]
+    let (mut appraisal_tx, mut appraisal_rx) =
+        mpsc::channel::<local_appraisal::AppraisalMessage>(1);
+
+    #[cfg(feature = "local-appraisal")]
+    let local_appraisal = if config.agent.enable_local_appraisal {
+        let store = local_appraisal::AllowlistStore::open(Path::new(
+            &config.agent.local_appraisal_db,
+        ))?;
+        Some(web::Data::new(local_appraisal::LocalAppraisal::new(store)?))
+    } else {
+        None
+    };
+
+    #[cfg(not(feature = "local-appraisal"))]
+    if config.agent.enable_local_appraisal {
+        warn!("The local appraisal is enabled, but the agent was built without the 'local-appraisal' feature");
+    }
+
+    #[cfg(feature = "local-appraisal")]
+    let appraisal_data = local_appraisal.clone();
+
+    #[cfg(feature = "grpc")]
+    let grpc_data = quotedata.clone();
+    #[cfg(feature = "grpc")]
+    let grpc_verifiers = verifiers.clone();
+
+    let tpm_state_data = quotedata.clone();
+    let self_attestation_data = quotedata.clone();
+
+    let file_watch = match fanotify {
+        Some(fanotify) => {
+            let pcr = match config.agent.file_watch_pcr.as_ref() {
+                "" => None,
+                pcr => Some(pcr.parse()?),
+            };
+            Some(web::Data::new(file_watch::FileWatch::new(fanotify, pcr)?))
+        }
+        None => None,
+    };
+    let file_watch_data = file_watch.clone();
+
+    // Quotes of the guests relayed with the host attestation
+    let relay_data =
+        relay::Relay::new(&config, http_client.clone())?.map(web::Data::new);
+    let file_watch_quote_data = quotedata.clone();
+
+    let response_signing_data = if config.agent.enable_response_signing {
+        info!("The API responses are signed with the AK");
+        Some(quotedata.clone())
+    } else {
+        None
+    };
+
+    let connection_stats = Arc::new(connections::ConnectionStats::default());
+    let stats_data = web::Data::from(connection_stats.clone());
+    let certificates_data = certificates.clone();
+
+    let identify_verifiers = verifiers.is_some();
+    let enable_key_certification = config.agent.enable_key_certification;
+    let enable_delivered_keys = config.agent.enable_agent_mtls;
+    let enable_pcr_forecast = config.agent.enable_pcr_forecast;
+    let actix_server = HttpServer::new(move || {
+        let verifiers = verifiers.clone();
+        let response_signing_data = response_signing_data.clone();
+        let request_stats = stats_data.clone();
+        let admission = admission.clone();
+        App::new()
+            .wrap_fn(move |req, srv| {
+                if let Some(verifiers) = &verifiers {
+                    if let Err(e) = verifiers.authorize(&req) {
+                        warn!(
+                            "{} {} returning 403 response. {e}",
+                            req.method(),
+                            req.path()
+                        );
+                        let response = HttpResponse::Forbidden()
+                            .json(JsonWrapper::error(403, e));
+                        return Either::Left(ok(req.into_response(response)));
+                    }
+                }
+                Either::Right(srv.call(req))
+            })
+            .wrap(middleware::ErrorHandlers::new().handler(
+                http::StatusCode::NOT_FOUND,
+                errors_handler::wrap_404,
+            ))
+            .wrap(middleware::Logger::new(
+                "%r from %a result %s (took %D ms)",
+            ))
+            .wrap_fn(move |req, srv| {
+                info!(
+                    "{} invoked from {:?} with uri {}",
+                    req.head().method,
+                    req.connection_info().peer_addr().unwrap(), //#[allow_ci]
+                    req.uri()
+                );
+                request_stats.on_request(&req);
+                srv.call(req)
+            })
+            .wrap_fn(move |req, srv| {
+                let signing_data = response_signing_data.clone();
+                let nonce = signing_data
+                    .as_ref()
+                    .and_then(|_| response_signing::request_nonce(&req));
+                let fut = srv.call(req);
+                async move {
+                    let res = fut.await?;
+                    match signing_data {
+                        Some(data) => {
+                            response_signing::sign(res, &data, nonce).await
+                        }
+                        None => Ok(res.map_into_boxed_body()),
+                    }
+                }
+            })
+            // Outermost, so that the refused requests are not processed
+            .wrap_fn(move |req, srv| match admission.admit(&req) {
+                Ok(reservation) => {
+                    // Held until the request is dropped with its response
+                    if let Some(reservation) = reservation {
+                        _ = req.extensions_mut().insert(reservation);
+                    }
+                    Either::Right(srv.call(req))
+                }
+                Err(e) => {
+                    warn!(
+                        "{} {} returning 503 response. {e}",
+                        req.method(),
+                        req.path()
                     );
-                    srv.call(req)
-                })
-                .app_data(quotedata.clone())
-                .app_data(
-                    web::JsonConfig::default()
-                        .error_handler(errors_handler::json_parser_error),
-                )
-                .app_data(
-                    web::QueryConfig::default()
-                        .error_handler(errors_handler::query_parser_error),
-                )
-                .app_data(
-                    web::PathConfig::default()
-                        .error_handler(errors_handler::path_parser_error),
-                )
-                .service(
-                    web::scope(&format!("/{API_VERSION}"))
-                        .service(
-                            web::scope("/keys")
-                                .service(web::resource("/pubkey").route(
-                                    web::get().to(keys_handler::pubkey),
-                                ))
-                                .service(web::resource("/ukey").route(
-                                    web::post().to(keys_handler::u_key),
-                                ))
-                                .service(web::resource("/verify").route(
-                                    web::get().to(keys_handler::verify),
-                                ))
-                                .service(web::resource("/vkey").route(
-                                    web::post().to(keys_handler::v_key),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::keys_default,
-                                )),
-                        )
-                        .service(
-                            web::scope("/notifications")
-                                .service(web::resource("/revocation").route(
-                                    web::post().to(
-                                        notifications_handler::revocation,
-                                    ),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::notifications_default,
-                                )),
-                        )
-                        .service(
-                            web::scope("/quotes")
-                                .service(web::resource("/identity").route(
-                                    web::get().to(quotes_handler::identity),
-                                ))
-                                .service(web::resource("/integrity").route(
-                                    web::get().to(quotes_handler::integrity),
-                                ))
-                                .default_service(web::to(
-                                    errors_handler::quotes_default,
-                                )),
-                        )
-                        .default_service(web::to(
-                            errors_handler::api_default,
-                        )),
-                )
-                .service(
-                    web::resource("/version")
-                        .route(web::get().to(version_handler::version)),
-                )
-                .service(
-                    web::resource(r"/v{major:\d+}.{minor:\d+}{tail}*")
-                        .to(errors_handler::version_not_supported),
-                )
-                .default_service(web::to(errors_handler::app_default))
-        })
-        // Disable default signal handlers.  See:
-        // https://github.com/actix/actix-web/issues/2739
-        // for details.
-        .disable_signals();
+                    let response = HttpResponse::ServiceUnavailable().json(
+                        JsonWrapper::error(
+                            503,
+                            format!("{e}, try again later"),
+                        ),
+                    );
+                    Either::Left(ok(req.into_response(response)))
+                }
+            })
+            .app_data(quotedata.clone())
+            .app_data(stats_data.clone())
+            .app_data(certificates_data.clone())
+            .app_data(
+                web::JsonConfig::default()
+                    .limit(resources::MAX_REQUEST_BODY)
+                    .error_handler(errors_handler::json_parser_error),
+            )
+            .app_data(
+                web::QueryConfig::default()
+                    .error_handler(errors_handler::query_parser_error),
+            )
+            .app_data(
+                web::PathConfig::default()
+                    .error_handler(errors_handler::path_parser_error),
+            )
+            .configure(|cfg| {
+                // The older versions are served without the later
+                // additions
+                for version in SUPPORTED_API_VERSIONS {
+                    _ = cfg.service(
+                        web::scope(&format!("/{version}"))
+                            .configure(|cfg| {
+                                #[cfg(feature = "local-appraisal")]
+                                if let Some(data) = &appraisal_data {
+                                    if api_version_at_least(
+                                        version,
+                                        LOCAL_APPRAISAL_API_VERSION,
+                                    ) {
+                                        local_appraisal::configure(
+                                            cfg,
+                                            data.clone(),
+                                        );
+                                    }
+                                }
+                                if let Some(data) = &file_watch_data {
+                                    if api_version_at_least(
+                                        version,
+                                        FILE_WATCH_API_VERSION,
+                                    ) {
+                                        file_watch::configure(cfg, data.clone());
+                                    }
+                                }
+                                if let Some(data) = &relay_data {
+                                    if api_version_at_least(
+                                        version,
+                                        RELAY_API_VERSION,
+                                    ) {
+                                        relay::configure(cfg, data.clone());
+                                    }
+                                }
+                                if enable_pcr_forecast
+                                    && api_version_at_least(
+                                        version,
+                                        PCR_FORECAST_API_VERSION,
+                                    )
+                                {
+                                    _ = cfg.service(
+                                        web::resource("/boot/forecast").route(
+                                            web::post()
+                                                .to(pcr_forecast::pcr_forecast),
+                                        ),
+                                    );
+                                }
+                            })
+                            .service(
+                                web::scope("/keys")
+                                    .configure(|cfg| {
+                                        if api_version_at_least(
+                                            version,
+                                            KEYS_BATCH_API_VERSION,
+                                        ) {
+                                            _ = cfg.service(
+                                                web::resource("/batch").route(
+                                                    web::post()
+                                                        .to(keys_handler::keys),
+                                                ),
+                                            );
+                                        }
+                                        // Only the clients authenticated with
+                                        // mTLS can list and wipe the
+                                        // delivered keys
+                                        if enable_delivered_keys
+                                            && api_version_at_least(
+                                                version,
+                                                DELIVERED_KEYS_API_VERSION,
+                                            )
+                                        {
+                                            _ = cfg.service(
+                                                web::resource("/delivered")
+                                                    .route(web::get().to(
+                                                    keys_handler::delivered_keys,
+                                                ))
+                                                    .route(web::delete().to(
+                                                    keys_handler::wipe_delivered_keys,
+                                                )),
+                                            );
+                                        }
+                                    })
+                                    .service(web::resource("/pubkey").route(
+                                        web::get().to(keys_handler::pubkey),
+                                    ))
+                                    .service(web::resource("/ukey").route(
+                                        web::post().to(keys_handler::u_key),
+                                    ))
+                                    .service(web::resource("/verify").route(
+                                        web::get().to(keys_handler::verify),
+                                    ))
+                                    .service(web::resource("/vkey").route(
+                                        web::post().to(keys_handler::v_key),
+                                    ))
+                                    .configure(|cfg| {
+                                        if enable_key_certification
+                                            && api_version_at_least(
+                                                version,
+                                                CERTIFY_API_VERSION,
+                                            )
+                                        {
+                                            _ = cfg.service(
+                                                web::resource("/certify")
+                                                    .route(web::post().to(
+                                                        keys_handler::certify,
+                                                    )),
+                                            );
+                                        }
+                                    })
+                                    .default_service(web::to(
+                                        errors_handler::keys_default,
+                                    )),
+                            )
+                            .configure(|cfg| {
+                                if api_version_at_least(
+                                    version,
+                                    AGENT_INFO_API_VERSION,
+                                ) {
+                                    _ = cfg.service(
+                                        web::resource("/agent/info").route(
+                                            web::get().to(agent_handler::info),
+                                        ),
+                                    );
+                                }
+                                if api_version_at_least(
+                                    version,
+                                    CERTIFICATES_API_VERSION,
+                                ) {
+                                    _ = cfg.service(
+                                        web::resource("/agent/certificates")
+                                            .route(web::get().to(
+                                                certificates::inventory,
+                                            )),
+                                    );
+                                }
+                                if api_version_at_least(
+                                    version,
+                                    CONNECTIONS_API_VERSION,
+                                ) {
+                                    _ = cfg.service(
+                                        web::resource("/connections").route(
+                                            web::get().to(connections::stats),
+                                        ),
+                                    );
+                                }
+                            })
+                            .service(
+                                web::scope("/evidence")
+                                    .configure(|cfg| {
+                                        if api_version_at_least(
+                                            version,
+                                            EAT_API_VERSION,
+                                        ) {
+                                            _ = cfg.service(
+                                                web::resource("/eat").route(
+                                                    web::get().to(eat::eat),
+                                                ),
+                                            );
+                                        }
+                                    })
+                                    .configure(|cfg| {
+                                        if api_version_at_least(
+                                            version,
+                                            MEASURED_BOOT_API_VERSION,
+                                        ) {
+                                            _ = cfg.service(
+                                                web::resource("/measured_boot")
+                                                    .route(web::get().to(
+                                                    measured_boot::measured_boot_log,
+                                                )),
+                                            );
+                                        }
+                                    })
+                                    .default_service(web::to(
+                                        errors_handler::evidence_default,
+                                    )),
+                            )
+                            .service(
+                                web::scope("/notifications")
+                                    .service(
+                                        web::resource("/revocation")
+                                            .route(web::post().to(
+                                            notifications_handler::revocation,
+                                        )),
+                                    )
+                                    .default_service(web::to(
+                                        errors_handler::notifications_default,
+                                    )),
+                            )
+                            .service(
+                                web::scope("/quotes")
+                                    .service(
+                                        web::resource("/identity")
+                                            .route(web::get().to(
+                                                quotes_handler::identity,
+                                            )),
+                                    )
+                                    .service(
+                                        web::resource("/integrity").route(
+                                            web::get().to(
+                                                quotes_handler::integrity,
+                                            ),
+                                        ),
+                                    )
+                                    .default_service(web::to(
+                                        errors_handler::quotes_default,
+                                    )),
+                            )
+                            .default_service(web::to(
+                                errors_handler::api_default,
+                            )),
+                    );
+                }
+            })
+            .service(
+                web::resource("/version")
+                    .route(web::get().to(version_handler::version)),
+            )
+            .service(
+                web::resource(r"/v{major:\d+}.{minor:\d+}{tail}*")
+                    .to(errors_handler::version_not_supported),
+            )
+            .default_service(web::to(errors_handler::app_default))
+    })
+    // Disable default signal handlers.  See:
+    // https://github.com/actix/actix-web/issues/2739
+    // for details.
+    .disable_signals()
+    .workers(http_workers(config.agent.http_workers))
+    .client_request_timeout(Duration::from_millis(
+        config.agent.http_client_request_timeout,
+    ))
+    .keep_alive(match config.agent.http_keep_alive {
+        0 => http::KeepAlive::Disabled,
+        secs => http::KeepAlive::Timeout(Duration::from_secs(secs)),
+    })
+    .max_connections(config.agent.http_max_connections as usize)
+    .tls_handshake_timeout(Duration::from_millis(
+        config.agent.tls_handshake_timeout,
+    ));
+
+    // The client certificate is needed to identify the verifier
+    let actix_server = actix_server.on_connect(move |conn, ext| {
+        if identify_verifiers {
+            verifiers::on_connect(conn, ext);
+        }
+        connection_stats.on_connect(conn, ext);
+    });
 
     let server;
-    let ip = &config.agent.ip;
-    let port = config.agent.port;
-    if config.agent.enable_agent_mtls && ssl_context.is_some() {
-        server = actix_server
-            .bind_openssl(
-                format!("{ip}:{port}"),
-                ssl_context.unwrap(), //#[allow_ci]
-            )?
-            .run();
-        info!("Listening on https://{ip}:{port}");
-    } else {
-        server = actix_server.bind(format!("{ip}:{port}"))?.run();
-        info!("Listening on http://{ip}:{port}");
+    let listen = Endpoint::new(&config.agent.ip, config.agent.port);
+    match ssl_context {
+        Some(ssl_context) if config.agent.enable_agent_mtls => {
+            server = actix_server
+                .bind_openssl(listen.to_string(), ssl_context)?
+                .run();
+            info!("Listening on {}", listen.url("https", ""));
+        }
+        _ if config.agent.enable_h2c => {
+            server = actix_server.bind_auto_h2c(listen.to_string())?.run();
+            info!(
+                "Listening on {} (HTTP/1.1 and HTTP/2 with prior knowledge)",
+                listen.url("http", "")
+            );
+        }
+        _ => {
+            server = actix_server.bind(listen.to_string())?.run();
+            info!("Listening on {}", listen.url("http", ""));
+        }
     };
 
     let server_handle = server.handle();
@@ -928,16 +1849,44 @@ async fn main() -> Result<()> {
         PathBuf::from(&mount),
         payload_rx,
         revocation_tx.clone(),
+        events.clone(),
         #[cfg(feature = "with-zmq")]
         zmq_tx.clone(),
     ))
     .map_err(Error::from);
 
+    let spire_attestation_path =
+        match config.agent.spire_attestation_path.as_ref() {
+            "" => None,
+            path => Some(PathBuf::from(path)),
+        };
+
+    let luks_device = match config.agent.luks_device.as_ref() {
+        "" => None,
+        device => Some(luks::LuksDevice {
+            device: PathBuf::from(device),
+            name: config.agent.luks_name.clone(),
+            enroll_key_file: match config.agent.luks_enroll_key_file.as_ref()
+            {
+                "" => None,
+                path => Some(PathBuf::from(path)),
+            },
+            key_dir: PathBuf::from(&mount),
+        }),
+    };
+
     let key_task = rt::spawn(keys_handler::worker(
         run_payload,
         agent_uuid,
         keys_rx,
         payload_tx.clone(),
+        spire_attestation_path,
+        luks_device,
+        match config.agent.key_ttl {
+            0 => None,
+            ttl => Some(ttl),
+        },
+        events.clone(),
     ))
     .map_err(Error::from);
 
@@ -946,14 +1895,150 @@ async fn main() -> Result<()> {
     let zmq_task = if config.agent.enable_revocation_notifications {
         warn!("The support for ZeroMQ revocation notifications is deprecated and will be removed on next major release");
 
-        let zmq_ip = config.agent.revocation_notification_ip;
-        let zmq_port = config.agent.revocation_notification_port;
+        let notifier = Endpoint::new(
+            &config.agent.revocation_notification_ip,
+            config.agent.revocation_notification_port,
+        );
 
         rt::spawn(revocation::zmq_worker(
             zmq_rx,
             revocation_tx.clone(),
-            zmq_ip,
-            zmq_port,
+            notifier,
+        ))
+        .map_err(Error::from)
+    } else {
+        rt::spawn(ok(())).map_err(Error::from)
+    };
+
+    // If local-appraisal feature is enabled, run the local appraisal of the
+    // IMA measurement list
+    #[cfg(feature = "local-appraisal")]
+    let appraisal_task = match local_appraisal {
+        Some(appraisal) => rt::spawn(local_appraisal::worker(
+            appraisal,
+            ima_ml_path.clone(),
+            Duration::from_secs(config.agent.local_appraisal_interval.into()),
+            appraisal_rx,
+        ))
+        .map_err(Error::from),
+        None => rt::spawn(ok(())).map_err(Error::from),
+    };
+
+    let events_task = match events_sink {
+        Some(sink) => rt::spawn(events::worker(
+            sink,
+            http_client.clone(),
+            clock.clone(),
+            events_rx,
+        ))
+        .map_err(Error::from),
+        None => rt::spawn(ok(())).map_err(Error::from),
+    };
+
+    let hooks_task = match event_hooks {
+        Some(event_hooks) => rt::spawn(hooks::worker(
+            event_hooks,
+            http_client.clone(),
+            hooks_rx,
+        ))
+        .map_err(Error::from),
+        None => rt::spawn(ok(())).map_err(Error::from),
+    };
+
+    let node_status_task = if run_node_status {
+        rt::spawn(node_status::worker(
+            node_status_path,
+            #[cfg(feature = "kubernetes")]
+            node_client,
+            clock.clone(),
+            node_status_rx,
+        ))
+        .map_err(Error::from)
+    } else {
+        rt::spawn(ok(())).map_err(Error::from)
+    };
+
+    let (mut tpm_state_tx, mut tpm_state_rx) =
+        mpsc::channel::<tpm_state::TpmStateMessage>(1);
+    let tpm_state_task = if config.agent.tpm_state_check_interval > 0 {
+        rt::spawn(tpm_state::worker(
+            tpm_state_data,
+            tpm_state,
+            Duration::from_secs(config.agent.tpm_state_check_interval.into()),
+            events.clone(),
+            tpm_state_rx,
+        ))
+        .map_err(Error::from)
+    } else {
+        rt::spawn(ok(())).map_err(Error::from)
+    };
+
+    let (mut certificates_tx, mut certificates_rx) =
+        mpsc::channel::<certificates::CertificatesMessage>(1);
+    let certificates_task = if config.agent.cert_expiry_check_interval > 0 {
+        rt::spawn(certificates::worker(
+            certificates,
+            Duration::from_secs(
+                config.agent.cert_expiry_check_interval.into(),
+            ),
+            certificates_rx,
+        ))
+        .map_err(Error::from)
+    } else {
+        rt::spawn(ok(())).map_err(Error::from)
+    };
+
+    let (mut self_attestation_tx, mut self_attestation_rx) =
+        mpsc::channel::<self_attestation::SelfAttestationMessage>(1);
+    let self_attestation_task = if config.agent.self_attestation_interval > 0
+    {
+        rt::spawn(self_attestation::worker(
+            self_attestation_data,
+            Duration::from_secs(
+                config.agent.self_attestation_interval.into(),
+            ),
+            events.clone(),
+            self_attestation_rx,
+        ))
+        .map_err(Error::from)
+    } else {
+        rt::spawn(ok(())).map_err(Error::from)
+    };
+
+    let (mut file_watch_tx, mut file_watch_rx) =
+        mpsc::channel::<file_watch::FileWatchMessage>(1);
+    let file_watch_task = match file_watch {
+        Some(watch) => rt::spawn(file_watch::worker(
+            watch,
+            file_watch_quote_data,
+            file_watch_rx,
+        ))
+        .map_err(Error::from),
+        None => rt::spawn(ok(())).map_err(Error::from),
+    };
+
+    // If grpc feature is enabled, run the gRPC server
+    #[cfg(feature = "grpc")]
+    let grpc_task = if config.agent.enable_grpc {
+        let grpc_listen =
+            Endpoint::new(&config.agent.ip, config.agent.grpc_port);
+        let addr = grpc_listen.to_string().parse().map_err(|e| {
+            Error::Configuration(format!(
+                "Invalid gRPC address {grpc_listen}: {e}"
+            ))
+        })?;
+        if grpc_tls.is_some() {
+            info!("Listening for gRPC on {}", grpc_listen.url("https", ""));
+        } else {
+            info!("Listening for gRPC on {}", grpc_listen.url("http", ""));
+        }
+
+        rt::spawn(grpc::worker(
+            grpc_data,
+            grpc_verifiers,
+            addr,
+            grpc_tls,
+            grpc_rx,
         ))
         .map_err(Error::from)
     } else {
@@ -983,25 +2068,228 @@ async fn main() -> Result<()> {
         #[cfg(feature = "with-zmq")]
         zmq_tx.send(revocation::ZmqMessage::Shutdown);
 
+        #[cfg(feature = "grpc")]
+        grpc_tx.send(grpc::GrpcMessage::Shutdown);
+
+        #[cfg(feature = "local-appraisal")]
+        appraisal_tx.send(local_appraisal::AppraisalMessage::Shutdown);
+
         revocation_tx.send(revocation::RevocationMessage::Shutdown);
+        events_tx.send(events::EventMessage::Shutdown);
+        hooks_tx.send(events::EventMessage::Shutdown);
+        node_status_tx.send(events::EventMessage::Shutdown);
+        tpm_state_tx.send(tpm_state::TpmStateMessage::Shutdown);
+        certificates_tx.send(certificates::CertificatesMessage::Shutdown);
+        self_attestation_tx
+            .send(self_attestation::SelfAttestationMessage::Shutdown);
+        file_watch_tx.send(file_watch::FileWatchMessage::Shutdown);
 
         // Await tasks shutdown
         server_stop.await;
     })
     .map_err(Error::from);
 
-    // If with-zmq feature is enabled, wait for the service listening for ZeroMQ messages
-    #[cfg(feature = "with-zmq")]
-    try_join!(zmq_task)?;
-
-    let result = try_join!(
-        server_task,
-        payload_task,
-        key_task,
-        revocation_task,
-        shutdown_task,
+    Ok(async move {
+        // If with-zmq feature is enabled, wait for the service listening for ZeroMQ messages
+        #[cfg(feature = "with-zmq")]
+        try_join!(zmq_task)?;
+
+        // If grpc feature is enabled, wait for the gRPC server
+        #[cfg(feature = "grpc")]
+        try_join!(grpc_task)?;
+
+        // If local-appraisal feature is enabled, wait for the local appraisal
+        #[cfg(feature = "local-appraisal")]
+        try_join!(appraisal_task)?;
+
+        let result = try_join!(
+            server_task,
+            payload_task,
+            key_task,
+            revocation_task,
+            events_task,
+            hooks_task,
+            node_status_task,
+            tpm_state_task,
+            certificates_task,
+            self_attestation_task,
+            file_watch_task,
+            shutdown_task,
+        );
+        result.map(|_| ())
+    }
+    .boxed_local())
+}
+
+// Maximum number of HTTP workers started when the count is derived from the
+// CPUs. The requests are serialized on the TPM, so more workers only use
+// more memory.
+const MAX_DEFAULT_HTTP_WORKERS: usize = 8;
+
+/// The number of HTTP workers, derived from the available CPUs if not
+/// configured. At least two workers are started, so that a request waiting
+/// for the TPM does not block the others.
+fn http_workers(configured: u32) -> usize {
+    match configured as usize {
+        0 => std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .clamp(2, MAX_DEFAULT_HTTP_WORKERS),
+        n => n,
+    }
+}
+
+/// The hash algorithms accepted for the auth tag of the delivered U keys
+fn auth_tag_algorithms(
+    configured: &str,
+) -> Result<Vec<keylime::algorithms::HashAlgorithm>> {
+    let mut algs = Vec::new();
+    for alg in parse_list(configured)? {
+        let alg = keylime::algorithms::HashAlgorithm::try_from(alg)?;
+        if !AUTH_TAG_ALGORITHMS.contains(&alg) {
+            return Err(Error::Configuration(format!(
+                "auth_tag_algorithms: {alg} can not be used for the auth tag HMAC"
+            )));
+        }
+        algs.push(alg);
+    }
+    if algs.is_empty() {
+        return Err(Error::Configuration(
+            "auth_tag_algorithms should not be empty".to_string(),
+        ));
+    }
+    Ok(algs)
+}
+
+/// The PCRs and banks the verifiers can select in the integrity quotes
+fn pcr_policy(
+    config: &config::KeylimeConfig,
+    ctx: &mut tpm::Context,
+) -> Result<service::PcrPolicy> {
+    let allocated = ctx
+        .get_pcr_banks()?
+        .into_iter()
+        .filter_map(|bank| {
+            keylime::algorithms::HashAlgorithm::try_from(bank).ok()
+        })
+        .collect::<Vec<_>>();
+    service::PcrPolicy::new(
+        &parse_list(&config.agent.quote_allowed_pcrs)?,
+        &parse_list(&config.agent.quote_pcr_banks)?,
+        &allocated,
+    )
+}
+
+/// Register the agent with the AK and activate it by decrypting the
+/// credential with the TPM
+#[allow(clippy::too_many_arguments)]
+async fn register_and_activate(
+    config: &config::KeylimeConfig,
+    tpm: &Mutex<tpm::Context>,
+    tpm_lock: &tpm_sharing::TpmLock,
+    agent_uuid: &str,
+    ek_result: &tpm::EKResult,
+    ak_handle: KeyHandle,
+    ak: &tpm::AKResult,
+    iak_idevid: Option<(&tpm::IAKResult, &tpm::IDevIDResult)>,
+    iak_cert: Option<X509>,
+    idevid_cert: Option<X509>,
+    certification: Option<(&Attest, &Signature)>,
+    mtls_cert: Option<&X509>,
+    asset_tag: Option<&asset_tag::AssetTag>,
+    events: &events::EventPublisher,
+    http_client: &http_client::HttpClient,
+) -> Result<()> {
+    let registrar = Endpoint::new(
+        &config.agent.registrar_ip,
+        config.agent.registrar_port,
     );
-    result.map(|_| ())
+    let contact =
+        Endpoint::new(&config.agent.contact_ip, config.agent.contact_port);
+    let registrar_tls = peer_tls::PeerTls::new(
+        &config.agent.registrar_tls,
+        &config.agent.registrar_tls_ca,
+        &config.agent.registrar_tls_pins,
+        &config.agent.registrar_tls_server_name,
+        config.agent.fips_mode,
+    )?;
+
+    // Request keyblob material
+    let keyblob = if config.agent.enable_iak_idevid {
+        let (Some((iak, idevid)), Some((attest, signature))) =
+            (iak_idevid, certification)
+        else {
+            error!("IDevID and IAK are enabled but could not be generated");
+            return Err(Error::Configuration(
+                "IDevID and IAK are enabled but could not be generated"
+                    .to_string(),
+            ));
+        };
+        registrar_agent::do_register_agent(
+            &registrar,
+            http_client,
+            &registrar_tls,
+            agent_uuid,
+            &PublicBuffer::try_from(ek_result.public.clone())?.marshall()?,
+            ek_result.ek_cert.clone(),
+            &PublicBuffer::try_from(ak.public.clone())?.marshall()?,
+            Some(&PublicBuffer::try_from(iak.public.clone())?.marshall()?),
+            Some(&PublicBuffer::try_from(idevid.public.clone())?.marshall()?),
+            idevid_cert,
+            iak_cert,
+            Some(attest.marshall()?),
+            Some(signature.marshall()?),
+            mtls_cert,
+            &contact,
+            asset_tag,
+        )
+        .await?
+    } else {
+        registrar_agent::do_register_agent(
+            &registrar,
+            http_client,
+            &registrar_tls,
+            agent_uuid,
+            &PublicBuffer::try_from(ek_result.public.clone())?.marshall()?,
+            ek_result.ek_cert.clone(),
+            &PublicBuffer::try_from(ak.public.clone())?.marshall()?,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            mtls_cert,
+            &contact,
+            asset_tag,
+        )
+        .await?
+    };
+
+    info!("SUCCESS: Agent {} registered", agent_uuid);
+    events.publish(events::AgentEvent::Registered {
+        registrar: registrar.to_string(),
+    });
+
+    let key = tpm_sharing::lock(tpm, tpm_lock)
+        .activate_credential(keyblob, ak_handle, ek_result.key_handle)
+        .context("Unable to activate the credential with the AK")?;
+    let mackey = general_purpose::STANDARD.encode(key.value());
+    let auth_tag =
+        crypto::compute_hmac(mackey.as_bytes(), agent_uuid.as_bytes())?;
+    let auth_tag = hex::encode(&auth_tag);
+
+    registrar_agent::do_activate_agent(
+        &registrar,
+        http_client,
+        &registrar_tls,
+        agent_uuid,
+        &auth_tag,
+    )
+    .await
+    .with_context(|| format!("Unable to activate agent {agent_uuid}"))?;
+    info!("SUCCESS: Agent {} activated", agent_uuid);
+    Ok(())
 }
 
 /*
@@ -1111,7 +2399,7 @@ mod testing {
                 };
 
             Ok(QuoteData {
-                tpmcontext: Mutex::new(ctx),
+                tpmcontext: Arc::new(Mutex::new(ctx)),
                 priv_key: nk_priv,
                 pub_key: nk_pub,
                 ak_handle,
@@ -1119,6 +2407,8 @@ mod testing {
                 payload_tx,
                 revocation_tx,
                 hash_alg: keylime::algorithms::HashAlgorithm::Sha256,
+                pcr_policy: service::PcrPolicy::default(),
+                auth_tag_algs: AUTH_TAG_ALGORITHMS.to_vec(),
                 enc_alg: keylime::algorithms::EncryptionAlgorithm::Rsa,
                 sign_alg: keylime::algorithms::SignAlgorithm::RsaSsa,
                 agent_uuid: test_config.agent.uuid,
@@ -1131,6 +2421,17 @@ mod testing {
                 measuredboot_ml_file,
                 ima_ml: Mutex::new(MeasurementList::new()),
                 secure_mount,
+                key_wipe_pcr: None,
+                fips: fips::FipsStatus::default(),
+                boot_epoch: None,
+                agent_starts: None,
+                boot: None,
+                tpm_lock: tpm_sharing::TpmLock::default(),
+                asset_tag: None,
+                enable_agent_nonce: true,
+                memory_budget: Arc::new(resources::MemoryBudget::default()),
+                events: events::EventPublisher::default(),
+                clock: clock::system(),
             })
         }
     }
@@ -1154,4 +2455,24 @@ mod tests {
             String::from("Hello World!\n")
         );
     }
+
+    #[test]
+    fn test_auth_tag_algorithms() {
+        use keylime::algorithms::HashAlgorithm;
+
+        assert_eq!(
+            auth_tag_algorithms("sha384, sha512").unwrap(), //#[allow_ci]
+            [HashAlgorithm::Sha384, HashAlgorithm::Sha512]
+        );
+        assert!(auth_tag_algorithms("sha1").is_err());
+        assert!(auth_tag_algorithms("md5").is_err());
+        assert!(auth_tag_algorithms("").is_err());
+    }
+
+    #[test]
+    fn test_http_workers() {
+        assert_eq!(http_workers(3), 3);
+        let workers = http_workers(0);
+        assert!((2..=MAX_DEFAULT_HTTP_WORKERS).contains(&workers));
+    }
 }
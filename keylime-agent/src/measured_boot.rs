@@ -0,0 +1,320 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Ranged retrieval of the measured boot event log
+//!
+//! The UEFI event log of servers with many option ROMs can be several
+//! megabytes, but only grows after the boot. The log is identified by a
+//! strong ETag, the digest of its contents, so that verifiers can keep the
+//! log they already fetched and request only:
+//!
+//! - nothing, if the ETag sent in If-None-Match still matches;
+//! - the bytes from a given offset, or the events from a given index, with
+//!   If-Match set to the ETag of the log they have, to be sure that the
+//!   part they already fetched was not changed.
+//!
+//! The integrity quotes include the ETag of the log, and omit the log if
+//! the verifier sends the ETag of the current one.
+
+use crate::{
+    common::JsonWrapper,
+    service::{self, ServiceError},
+    verifiers, QuoteData,
+};
+use actix_web::{
+    http::header::{self, ContentRangeSpec, ContentType, EntityTag},
+    web, HttpRequest, HttpResponse, Responder,
+};
+use keylime::event_log;
+use log::*;
+use openssl::hash::{hash, MessageDigest};
+use serde::Deserialize;
+use std::ops::Range;
+
+/// Part of the log requested by the verifier. The range is given either in
+/// bytes, or in events.
+#[derive(Debug, Default, Deserialize)]
+pub struct MbLogParams {
+    /// First byte of the range
+    offset: Option<usize>,
+    /// Number of bytes of the range, up to the end of the log if not given
+    length: Option<usize>,
+    /// Index of the first event of the range
+    event: Option<usize>,
+    /// Number of events of the range, up to the end of the log if not given
+    count: Option<usize>,
+}
+
+/// The strong entity tag of the log, the SHA-256 digest of its contents
+pub(crate) fn etag(log: &[u8]) -> Result<String, openssl::error::ErrorStack> {
+    Ok(hex::encode(hash(MessageDigest::sha256(), log)?))
+}
+
+/// The byte range of the requested part of the log
+fn select_range(
+    log: &[u8],
+    params: &MbLogParams,
+) -> Result<Range<usize>, String> {
+    let (start, end) = match params {
+        MbLogParams {
+            event: None,
+            count: None,
+            offset,
+            length,
+        } => {
+            let start = offset.unwrap_or(0);
+            let end = match length {
+                Some(length) => start.saturating_add(*length),
+                None => log.len(),
+            };
+            (start, end.min(log.len()))
+        }
+        MbLogParams {
+            offset: None,
+            length: None,
+            event,
+            count,
+        } => {
+            let offsets: Vec<usize> = event_log::parse_with_offsets(log)
+                .map_err(|e| format!("Unable to parse the event log: {e}"))?
+                .into_iter()
+                .map(|(offset, _)| offset)
+                .collect();
+            let first = event.unwrap_or(0);
+            let last = match count {
+                Some(count) => first.saturating_add(*count),
+                None => offsets.len(),
+            };
+            let start = match offsets.get(first) {
+                Some(start) => *start,
+                None if first == offsets.len() => log.len(),
+                None => {
+                    return Err(format!(
+                        "event {first} is beyond the {} events of the log",
+                        offsets.len()
+                    ))
+                }
+            };
+            (start, offsets.get(last).copied().unwrap_or(log.len()))
+        }
+        _ => {
+            return Err(
+                "the range is given either by 'offset' and 'length', or by 'event' and 'count'"
+                    .to_string(),
+            )
+        }
+    };
+
+    if start > log.len() {
+        return Err(format!(
+            "offset {start} is beyond the {} bytes of the log",
+            log.len()
+        ));
+    }
+    Ok(start..end)
+}
+
+fn error_response(status: u16, message: &str) -> HttpResponse {
+    warn!("GET measured boot log returning {status} response. {message}");
+    let mut response = match status {
+        400 => HttpResponse::BadRequest(),
+        403 => HttpResponse::Forbidden(),
+        404 => HttpResponse::NotFound(),
+        _ => HttpResponse::PreconditionFailed(),
+    };
+    response.json(JsonWrapper::error(status, message))
+}
+
+pub async fn measured_boot_log(
+    req: HttpRequest,
+    params: web::Query<MbLogParams>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    if !verifiers::evidence_scope(&req).measured_boot {
+        return error_response(
+            403,
+            "The measured boot log is not in the evidence scope of the verifier",
+        );
+    }
+
+    let mut reservations = Vec::new();
+    let log = match service::read_measured_boot_log(&data, &mut reservations)
+    {
+        Ok(Some(log)) => log,
+        Ok(None) => {
+            return error_response(
+                404,
+                "The measured boot log is not available",
+            )
+        }
+        Err(e) => {
+            warn!(
+                "GET measured boot log returning {} response. {e}",
+                e.status().as_u16()
+            );
+            return e.to_response();
+        }
+    };
+
+    let tag = match etag(&log) {
+        Ok(tag) => EntityTag::new_strong(tag),
+        Err(e) => {
+            return ServiceError::internal(
+                e,
+                "Unable to retrieve the measured boot log",
+            )
+            .to_response()
+        }
+    };
+
+    let request = req.headers();
+    if let Some(Ok(header::IfNoneMatch::Items(tags))) = request
+        .get(header::IF_NONE_MATCH)
+        .map(|_| header::Header::parse(&req))
+    {
+        if tags.iter().any(|t| t.strong_eq(&tag)) {
+            info!("GET measured boot log returning 304 response");
+            return HttpResponse::NotModified()
+                .insert_header(header::ETag(tag))
+                .finish();
+        }
+    }
+    if let Some(Ok(header::IfMatch::Items(tags))) = request
+        .get(header::IF_MATCH)
+        .map(|_| header::Header::parse(&req))
+    {
+        // The part of the log the verifier already has was changed
+        if !tags.iter().any(|t| t.strong_eq(&tag)) {
+            return error_response(
+                412,
+                "The measured boot log does not match the ETag",
+            );
+        }
+    }
+
+    let range = match select_range(&log, &params) {
+        Ok(range) => range,
+        Err(e) => return error_response(400, &e),
+    };
+
+    let partial = range.len() != log.len();
+    info!(
+        "GET measured boot log returning {} response",
+        if partial { 206 } else { 200 }
+    );
+    let mut response = if !partial {
+        HttpResponse::Ok()
+    } else {
+        let mut response = HttpResponse::PartialContent();
+        _ = response.insert_header(header::ContentRange(
+            ContentRangeSpec::Bytes {
+                // An empty range has no last byte
+                range: (!range.is_empty())
+                    .then(|| (range.start as u64, range.end as u64 - 1)),
+                instance_length: Some(log.len() as u64),
+            },
+        ));
+        response
+    };
+    response
+        .insert_header(header::ETag(tag))
+        .insert_header(ContentType::octet_stream())
+        .body(log[range].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log() -> Vec<u8> {
+        // Legacy SHA-1 events, of 32 bytes each plus the event data
+        let mut log = Vec::new();
+        for (pcr, data) in [(0u32, &b"a"[..]), (1, b"bb"), (7, b"ccc")] {
+            log.extend(pcr.to_le_bytes());
+            log.extend(8u32.to_le_bytes());
+            log.extend([0u8; 20]);
+            log.extend((data.len() as u32).to_le_bytes());
+            log.extend(data);
+        }
+        log
+    }
+
+    #[test]
+    fn test_select_range() {
+        let log = log();
+        let range = |params| select_range(&log, &params);
+
+        assert_eq!(range(MbLogParams::default()), Ok(0..log.len()));
+        assert_eq!(
+            range(MbLogParams {
+                offset: Some(10),
+                length: Some(5),
+                ..Default::default()
+            }),
+            Ok(10..15)
+        );
+        assert_eq!(
+            range(MbLogParams {
+                offset: Some(10),
+                length: Some(1000),
+                ..Default::default()
+            }),
+            Ok(10..log.len())
+        );
+        assert_eq!(
+            range(MbLogParams {
+                offset: Some(log.len()),
+                ..Default::default()
+            }),
+            Ok(log.len()..log.len())
+        );
+        assert_eq!(
+            range(MbLogParams {
+                event: Some(1),
+                count: Some(1),
+                ..Default::default()
+            }),
+            Ok(33..67)
+        );
+        assert_eq!(
+            range(MbLogParams {
+                event: Some(2),
+                ..Default::default()
+            }),
+            Ok(67..log.len())
+        );
+        assert_eq!(
+            range(MbLogParams {
+                event: Some(3),
+                ..Default::default()
+            }),
+            Ok(log.len()..log.len())
+        );
+
+        for params in [
+            MbLogParams {
+                offset: Some(log.len() + 1),
+                ..Default::default()
+            },
+            MbLogParams {
+                event: Some(4),
+                ..Default::default()
+            },
+            MbLogParams {
+                offset: Some(0),
+                event: Some(0),
+                ..Default::default()
+            },
+        ] {
+            assert!(range(params).is_err());
+        }
+    }
+
+    #[test]
+    fn test_etag() {
+        assert_eq!(
+            etag(b"").unwrap(), //#[allow_ci]
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
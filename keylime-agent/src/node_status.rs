@@ -0,0 +1,439 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Attestation status of the node for schedulers
+//!
+//! The status is derived from the agent lifecycle events: it becomes
+//! attested when the keys are delivered or the verifier requests integrity
+//! quotes, and failed when a revocation is received. The agent does not see
+//! the verdict of the verifier, so attested only means that the verifier is
+//! polling the agent and did not revoke it. It is written to a file in the
+//! format used by the Kubernetes downward API volumes and, when built with
+//! the 'kubernetes' feature, reported as a condition of the node.
+//!
+//! The status is published as soon as its state changes. The time of the
+//! last update, refreshed by every quote served, is only published with the
+//! heartbeats, at most every HEARTBEAT_INTERVAL, so that the verifier polls
+//! do not each rewrite the file and patch the node.
+
+use crate::{
+    clock::{self, Clock, SharedClock},
+    endpoint::Endpoint,
+    events::{CloudEvent, EventMessage},
+    Error, Result,
+};
+#[cfg(feature = "kubernetes")]
+use crate::{
+    error::Context,
+    http_client::{self, HttpClient, HttpClientOptions},
+};
+use chrono::SecondsFormat;
+use log::*;
+use serde::Serialize;
+use std::{
+    fmt::Write as _,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::sync::mpsc::Receiver;
+
+/// Type of the node condition reported to Kubernetes
+pub(crate) const CONDITION_TYPE: &str = "KeylimeAttested";
+
+/// Minimum interval between the publications of the status without a change
+/// of its state
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AttestationState {
+    Unknown,
+    /// The keys were delivered or an integrity quote was served, not a
+    /// verdict of the verifier
+    Attested,
+    Failed,
+}
+
+impl AttestationState {
+    /// The status of the node condition
+    fn condition_status(&self) -> &'static str {
+        match self {
+            AttestationState::Unknown => "Unknown",
+            AttestationState::Attested => "True",
+            AttestationState::Failed => "False",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct NodeStatus {
+    pub state: AttestationState,
+    pub reason: &'static str,
+    pub last_update: String,
+    pub last_transition: String,
+}
+
+impl NodeStatus {
+    /// The status before the first attestation
+    pub(crate) fn new(clock: &dyn Clock) -> Self {
+        let now = clock.rfc3339(SecondsFormat::Secs);
+        NodeStatus {
+            state: AttestationState::Unknown,
+            reason: "AwaitingAttestation",
+            last_update: now.clone(),
+            last_transition: now,
+        }
+    }
+
+    /// Update the status from the event. Returns whether the state changed,
+    /// in which case the status is to be published immediately. The other
+    /// relevant events only refresh the reason and the time of the update.
+    pub(crate) fn update(&mut self, event: &CloudEvent) -> bool {
+        let (state, reason) = match event.event_type.as_str() {
+            "org.keylime.agent.registered" => {
+                // A new registration restarts the attestation
+                (AttestationState::Unknown, "Registered")
+            }
+            "org.keylime.agent.keys.delivered" => {
+                (AttestationState::Attested, "KeysDelivered")
+            }
+            "org.keylime.agent.keys.wiped" => {
+                // Decommissioned until keys are delivered again
+                (AttestationState::Unknown, "KeysWiped")
+            }
+            "org.keylime.agent.attestation.served"
+                if event.data["quote"] == "integrity" =>
+            {
+                // The verifier stops polling after a failure, so a quote
+                // after a revocation does not change the failed state
+                if self.state == AttestationState::Failed {
+                    return false;
+                }
+                (AttestationState::Attested, "IntegrityQuoteServed")
+            }
+            "org.keylime.agent.revocation.received" => {
+                (AttestationState::Failed, "RevocationReceived")
+            }
+            "org.keylime.agent.tpm.changed" => {
+                (AttestationState::Failed, "TpmStateChanged")
+            }
+            _ => return false,
+        };
+
+        let changed = state != self.state;
+        if changed {
+            self.last_transition = event.time.clone();
+        }
+        self.state = state;
+        self.reason = reason;
+        self.last_update = event.time.clone();
+        changed
+    }
+
+    /// The status in the "key=\"value\"" format of the downward API files
+    pub(crate) fn to_labels(&self) -> String {
+        let mut labels = String::new();
+        for (key, value) in [
+            (
+                "keylime.dev/attestation-status",
+                self.state.condition_status(),
+            ),
+            ("keylime.dev/attestation-reason", self.reason),
+            ("keylime.dev/attestation-last-update", &self.last_update),
+        ] {
+            let _ = writeln!(labels, "{key}=\"{value}\"");
+        }
+        labels
+    }
+
+    fn store(&self, path: &Path) -> Result<()> {
+        let dir = path.parent().ok_or_else(|| {
+            Error::Configuration(format!(
+                "Invalid attestation status path {}",
+                path.display()
+            ))
+        })?;
+
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        file.write_all(self.to_labels().as_bytes())?;
+        let _ = file.persist(path)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct NodeCondition<'a> {
+    #[serde(rename = "type")]
+    condition_type: &'a str,
+    status: &'a str,
+    reason: &'a str,
+    message: String,
+    last_heartbeat_time: &'a str,
+    last_transition_time: &'a str,
+}
+
+/// Minimal client for the Kubernetes API, using the service account of the
+/// pod running the agent
+#[cfg(feature = "kubernetes")]
+pub(crate) struct NodeClient {
+    client: HttpClient,
+    url: String,
+    token: String,
+}
+
+#[cfg(feature = "kubernetes")]
+impl NodeClient {
+    const SERVICE_ACCOUNT_DIR: &'static str =
+        "/var/run/secrets/kubernetes.io/serviceaccount";
+
+    pub(crate) fn in_cluster(
+        node_name: &str,
+        options: &HttpClientOptions,
+    ) -> Result<Self> {
+        let host = std::env::var("KUBERNETES_SERVICE_HOST").map_err(|_| {
+            Error::Configuration(
+                "KUBERNETES_SERVICE_HOST is not set: not running in a Kubernetes cluster".to_string(),
+            )
+        })?;
+        let port = match std::env::var("KUBERNETES_SERVICE_PORT") {
+            Ok(port) => port.parse().map_err(|e| {
+                Error::Configuration(format!(
+                    "Invalid KUBERNETES_SERVICE_PORT {port}: {e}"
+                ))
+            })?,
+            Err(_) => 443,
+        };
+        let api_server = Endpoint::new(&host, port);
+
+        let dir = Path::new(Self::SERVICE_ACCOUNT_DIR);
+        let token = std::fs::read_to_string(dir.join("token"))?
+            .trim()
+            .to_string();
+        let ca = reqwest::Certificate::from_pem(&std::fs::read(
+            dir.join("ca.crt"),
+        )?)?;
+        // The API server is reached directly from the pod
+        let client = HttpClient::with_root_certificate(
+            HttpClientOptions {
+                proxy: None,
+                ..options.clone()
+            },
+            ca,
+        )?;
+
+        Ok(NodeClient {
+            client,
+            url: api_server
+                .url("https", &format!("api/v1/nodes/{node_name}/status")),
+            token,
+        })
+    }
+
+    pub(crate) async fn patch_condition(
+        &self,
+        status: &NodeStatus,
+    ) -> Result<()> {
+        let patch = serde_json::json!({
+            "status": { "conditions": [ condition(status) ] }
+        });
+
+        let body = serde_json::to_vec(&patch)?;
+        self.client
+            .retry(&self.url, || async {
+                let response = self
+                    .client
+                    .request(reqwest::Method::PATCH, &self.url)
+                    .bearer_auth(&self.token)
+                    .header(
+                        reqwest::header::CONTENT_TYPE,
+                        "application/strategic-merge-patch+json",
+                    )
+                    .body(body.clone())
+                    .send()
+                    .await?;
+                http_client::check_status(
+                    &self.url,
+                    response.status().as_u16(),
+                )
+            })
+            .await
+            .context("patching the node status")
+    }
+}
+
+fn condition(status: &NodeStatus) -> NodeCondition<'_> {
+    NodeCondition {
+        condition_type: CONDITION_TYPE,
+        status: status.state.condition_status(),
+        reason: status.reason,
+        message: format!("Keylime attestation status: {}", status.reason),
+        last_heartbeat_time: &status.last_update,
+        last_transition_time: &status.last_transition,
+    }
+}
+
+/// Write the status file and report the node condition
+async fn publish(
+    status: &NodeStatus,
+    status_path: Option<&Path>,
+    #[cfg(feature = "kubernetes")] node_client: Option<&NodeClient>,
+    clock: &dyn Clock,
+) {
+    if let Some(path) = status_path {
+        if let Err(e) = status.store(path) {
+            warn!("Failed to write attestation status: {}", e);
+        }
+    }
+
+    #[cfg(feature = "kubernetes")]
+    if let Some(client) = node_client {
+        if let Err(e) = client.patch_condition(status).await {
+            warn!("Failed to update the node condition: {}", e);
+            clock::warn_on_skew(&e, "the Kubernetes API server", clock);
+        }
+    }
+    #[cfg(not(feature = "kubernetes"))]
+    let _ = clock;
+}
+
+pub(crate) async fn worker(
+    status_path: Option<PathBuf>,
+    #[cfg(feature = "kubernetes")] node_client: Option<NodeClient>,
+    clock: SharedClock,
+    mut events_rx: Receiver<EventMessage>,
+) -> Result<()> {
+    debug!("Starting node status worker");
+
+    let mut status = NodeStatus::new(clock.as_ref());
+    if let Some(path) = &status_path {
+        if let Err(e) = status.store(path) {
+            warn!("Failed to write attestation status: {}", e);
+        }
+    }
+    let mut published = status.clone();
+
+    let mut ticker = tokio::time::interval(HEARTBEAT_INTERVAL);
+    _ = ticker.tick().await;
+    loop {
+        let changed = tokio::select! {
+            message = events_rx.recv() => match message {
+                None => break,
+                Some(EventMessage::Shutdown) => {
+                    events_rx.close();
+                    continue;
+                }
+                Some(EventMessage::Event(event)) => status.update(&event),
+            },
+            // Heartbeat, only if the status was refreshed since published
+            _ = ticker.tick() => status != published,
+        };
+        if !changed {
+            continue;
+        }
+
+        publish(
+            &status,
+            status_path.as_deref(),
+            #[cfg(feature = "kubernetes")]
+            node_client.as_ref(),
+            clock.as_ref(),
+        )
+        .await;
+        published = status.clone();
+    }
+
+    debug!("Shutting down node status worker");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{AgentEvent, EventPublisher};
+    use tokio::sync::mpsc;
+
+    fn events(published: &[AgentEvent]) -> Vec<CloudEvent> {
+        let (events_tx, mut events_rx) = mpsc::channel(published.len());
+        let mut publisher =
+            EventPublisher::new("agent-uuid", clock::system());
+        publisher.subscribe(events_tx);
+        for event in published {
+            publisher.publish(event.clone());
+        }
+
+        let mut result = Vec::new();
+        while let Ok(EventMessage::Event(event)) = events_rx.try_recv() {
+            result.push(*event);
+        }
+        result
+    }
+
+    #[test]
+    fn test_status_update() {
+        let events = events(&[
+            AgentEvent::AttestationServed { quote: "identity" },
+            AgentEvent::KeysDelivered,
+            AgentEvent::RevocationReceived { processed: true },
+            AgentEvent::AttestationServed { quote: "integrity" },
+        ]);
+        let mut status = NodeStatus::new(&clock::FixedClock::at(0));
+        assert_eq!(status.last_update, "1970-01-01T00:00:00Z");
+
+        // Identity quotes are requested by the tenant before the attestation
+        assert!(!status.update(&events[0]));
+        assert_eq!(status.state, AttestationState::Unknown);
+
+        assert!(status.update(&events[1]));
+        assert_eq!(status.state, AttestationState::Attested);
+        assert_eq!(status.last_transition, events[1].time);
+
+        assert!(status.update(&events[2]));
+        assert_eq!(status.state, AttestationState::Failed);
+        assert_eq!(status.reason, "RevocationReceived");
+
+        assert!(!status.update(&events[3]));
+        assert_eq!(status.state, AttestationState::Failed);
+    }
+
+    #[test]
+    fn test_status_heartbeat() {
+        let events = events(&[
+            AgentEvent::AttestationServed { quote: "integrity" },
+            AgentEvent::AttestationServed { quote: "integrity" },
+        ]);
+        let mut status = NodeStatus::new(&clock::FixedClock::at(0));
+        assert!(status.update(&events[0]));
+        assert_eq!(status.state, AttestationState::Attested);
+
+        // Further quotes only refresh the time of the update
+        let published = status.clone();
+        let mut later = events[1].clone();
+        later.time = "2030-01-01T00:00:00Z".to_string();
+        assert!(!status.update(&later));
+        assert_eq!(status.last_update, later.time);
+        assert_eq!(status.last_transition, published.last_transition);
+        assert_ne!(status, published);
+    }
+
+    #[test]
+    fn test_status_labels() {
+        let status = NodeStatus {
+            state: AttestationState::Attested,
+            reason: "KeysDelivered",
+            last_update: "2023-01-01T00:00:00Z".to_string(),
+            last_transition: "2023-01-01T00:00:00Z".to_string(),
+        };
+        assert_eq!(
+            status.to_labels(),
+            "keylime.dev/attestation-status=\"True\"\n\
+             keylime.dev/attestation-reason=\"KeysDelivered\"\n\
+             keylime.dev/attestation-last-update=\"2023-01-01T00:00:00Z\"\n"
+        );
+
+        let condition = serde_json::to_value(condition(&status)).unwrap(); //#[allow_ci]
+        assert_eq!(condition["type"], CONDITION_TYPE);
+        assert_eq!(condition["status"], "True");
+        assert_eq!(condition["lastTransitionTime"], "2023-01-01T00:00:00Z");
+    }
+}
@@ -4,6 +4,8 @@
 use crate::{
     common::{EncryptedData, SymmKey},
     config, crypto,
+    events::{AgentEvent, EventPublisher},
+    handoff, lsm,
     revocation::{Revocation, RevocationMessage},
     Error, Result,
 };
@@ -11,14 +13,20 @@ use crate::{
 #[cfg(feature = "with-zmq")]
 use crate::revocation::ZmqMessage;
 
+use keylime::{
+    algorithms::HashAlgorithm,
+    hashing,
+    payload::{parse_action_list, PayloadDelivery},
+};
 use log::*;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::{
+    ffi::CString,
     fmt::Display,
     fs,
-    io::{BufReader, Read, Write},
-    os::unix::fs::PermissionsExt,
+    io::{self, BufReader, Read, Write},
+    os::unix::{ffi::OsStrExt, fs::PermissionsExt},
     path::{Path, PathBuf},
     process::{Command, Stdio},
     sync::{Arc, Condvar, Mutex},
@@ -30,11 +38,15 @@ use zip::ZipArchive;
 pub(crate) struct Payload {
     pub symm_key: SymmKey,
     pub encrypted_payload: EncryptedData,
+    // Requested with the U key, the configured default if not set
+    pub delivery: Option<PayloadDelivery>,
 }
 
 #[derive(Debug, Deserialize, Serialize, PartialEq)]
 pub(crate) enum PayloadMessage {
     RunPayload(Payload),
+    // Remove the decrypted payload, as its key expired
+    Expire,
     Shutdown,
 }
 
@@ -42,6 +54,7 @@ impl Display for PayloadMessage {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             PayloadMessage::RunPayload(_) => write!(f, "RunPayload"),
+            PayloadMessage::Expire => write!(f, "Expire"),
             PayloadMessage::Shutdown => write!(f, "Shutdown"),
         }
     }
@@ -61,14 +74,20 @@ fn decrypt_payload(
     Ok(decrypted)
 }
 
-// sets up unzipped directory in secure mount location in preparation for
+/// Directory in the secure mount holding the deployed payload
+const UNZIPPED_DIR: &str = "unzipped";
+/// Directory where a new payload is prepared before replacing the deployed
+/// one
+const STAGED_DIR: &str = "unzipped.new";
+
+// sets up the staging directory in secure mount location in preparation for
 // writing out symmetric key and encrypted payload. returns file paths for
 // both.
 fn setup_unzipped(
     config: &config::KeylimeConfig,
     mount: &Path,
 ) -> Result<(PathBuf, PathBuf, PathBuf)> {
-    let unzipped = mount.join("unzipped");
+    let unzipped = mount.join(STAGED_DIR);
 
     // clear any old data
     if Path::new(&unzipped).exists() {
@@ -87,7 +106,7 @@ fn setup_unzipped(
                 )),
                 k => {
                     let key_path = unzipped.join(k);
-                    fs::create_dir(&unzipped)?;
+                    lsm::check_denial(fs::create_dir(&unzipped), &unzipped)?;
                     Ok((unzipped, dec_payload_path, key_path))
                 }
             }
@@ -95,6 +114,41 @@ fn setup_unzipped(
     }
 }
 
+// replaces the deployed payload with the staged one. The directories are
+// exchanged atomically, so that the deployed payload is never missing or
+// partial. returns whether a previous payload was replaced.
+fn swap_unzipped(mount: &Path) -> Result<bool> {
+    let staged = mount.join(STAGED_DIR);
+    let unzipped = mount.join(UNZIPPED_DIR);
+
+    if !unzipped.exists() {
+        fs::rename(&staged, &unzipped)?;
+        return Ok(false);
+    }
+
+    let staged_c = CString::new(staged.as_os_str().as_bytes())
+        .map_err(|e| Error::Other(e.to_string()))?;
+    let unzipped_c = CString::new(unzipped.as_os_str().as_bytes())
+        .map_err(|e| Error::Other(e.to_string()))?;
+
+    if unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            staged_c.as_ptr(),
+            libc::AT_FDCWD,
+            unzipped_c.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    } != 0
+    {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+
+    // The previous payload is now in the staging directory
+    fs::remove_dir_all(&staged)?;
+    Ok(true)
+}
+
 // write symm key data and decrypted payload data out to specified files
 fn write_out_key_and_payload(
     dec_payload: &[u8],
@@ -102,14 +156,18 @@ fn write_out_key_and_payload(
     key: &SymmKey,
     key_path: &Path,
 ) -> Result<()> {
-    let mut key_file = fs::File::create(key_path)?;
+    let mut key_file =
+        lsm::check_denial(fs::File::create(key_path), key_path)?;
     let bytes = key_file.write(key.as_ref())?;
     if bytes != key.as_ref().len() {
         return Err(Error::Other(format!("Error writing symm key to {:?}: key len is {}, but {bytes} bytes were written", key_path, key.as_ref().len())));
     }
     info!("Wrote payload decryption key to {:?}", key_path);
 
-    let mut dec_payload_file = fs::File::create(dec_payload_path)?;
+    let mut dec_payload_file = lsm::check_denial(
+        fs::File::create(dec_payload_path),
+        dec_payload_path,
+    )?;
     let bytes = dec_payload_file.write(dec_payload)?;
     if bytes != dec_payload.len() {
         return Err(Error::Other(format!("Error writing decrypted payload to {:?}: payload len is {}, but {bytes} bytes were written", dec_payload_path, dec_payload.len())));
@@ -119,8 +177,53 @@ fn write_out_key_and_payload(
     Ok(())
 }
 
-// run a script (such as the init script, if any) and check the status
-fn run(dir: &Path, script: &str) -> Result<()> {
+// overwrite the file with zeros before removing it, so that the decrypted
+// data does not remain in the pages freed by tmpfs
+fn shred_file(path: &Path) -> io::Result<()> {
+    let len = fs::metadata(path)?.len();
+    let mut file = fs::OpenOptions::new().write(true).open(path)?;
+    _ = io::copy(&mut io::repeat(0).take(len), &mut file)?;
+    file.sync_all()?;
+    fs::remove_file(path)?;
+    Ok(())
+}
+
+fn shred_tree(path: &Path) -> io::Result<usize> {
+    let mut removed = 0;
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            removed += shred_tree(&entry.path())?;
+        } else if file_type.is_file() {
+            shred_file(&entry.path())?;
+            removed += 1;
+        } else {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    fs::remove_dir(path)?;
+    Ok(removed)
+}
+
+/// Overwrite and remove the deployed and staged payloads, including the
+/// payload decryption key written with them. Returns the number of files
+/// removed.
+pub(crate) fn wipe(mount: &Path) -> Result<usize> {
+    let mut removed = 0;
+    for dir in [UNZIPPED_DIR, STAGED_DIR] {
+        let path = mount.join(dir);
+        if path.exists() {
+            removed += lsm::check_denial(shred_tree(&path), &path)?;
+        }
+    }
+    info!("Wiped {removed} payload files from {}", mount.display());
+    Ok(removed)
+}
+
+// run a script (such as the init script, if any) and check the status. the
+// script is confined by the AppArmor profile, if set.
+fn run(dir: &Path, script: &str, apparmor_profile: &str) -> Result<()> {
     let script_path = dir.join(script);
     info!("Running script: {:?}", script_path);
 
@@ -140,7 +243,17 @@ fn run(dir: &Path, script: &str) -> Result<()> {
 
     info!("Executing payload script: {}", script_path.display());
 
-    match Command::new("sh")
+    let mut command = match apparmor_profile {
+        "" => Command::new("sh"),
+        profile => {
+            info!("Confining payload script with AppArmor profile {profile}");
+            let mut command = Command::new("aa-exec");
+            _ = command.args(["-p", profile, "--", "sh"]);
+            command
+        }
+    };
+
+    match command
         .arg("-c")
         .arg(script_path.to_str().unwrap()) //#[allow_ci]
         .current_dir(dir)
@@ -149,10 +262,27 @@ fn run(dir: &Path, script: &str) -> Result<()> {
         .stderr(Stdio::piped())
         .status()
     {
-        Ok(_) => {
+        Ok(status) if status.success() => {
             info!("{:?} ran successfully", &script_path);
             Ok(())
         }
+        // The shell reports that the script could not be executed
+        Ok(status) if status.code() == Some(126) => lsm::check_denial(
+            Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            &script_path,
+        ),
+        Ok(status) => Err(Error::Other(format!(
+            "{:?} failed with {status}",
+            &script_path
+        ))),
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound
+                && !apparmor_profile.is_empty() =>
+        {
+            Err(Error::Configuration(
+                "aa-exec was not found, but is required to run the payload script with the AppArmor profile set in 'payload_apparmor_profile'".to_string(),
+            ))
+        }
         Err(e) => Err(Error::Other(format!(
             "{:?} failed during run: {}",
             &script_path, e
@@ -178,7 +308,12 @@ fn optional_unzip_payload(
 
                 let mut source = fs::File::open(zipped_payload_path)?;
                 let mut zip = ZipArchive::new(source)?;
-                zip.extract(unzipped)?;
+                match zip.extract(unzipped) {
+                    Err(zip::result::ZipError::Io(e)) => {
+                        lsm::check_denial(Err(e), unzipped)?
+                    }
+                    result => result?,
+                }
             }
         }
     }
@@ -186,15 +321,58 @@ fn optional_unzip_payload(
     Ok(())
 }
 
-async fn run_encrypted_payload(
-    symm_key: SymmKey,
+// collects the regular files under the directory, recursively
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+// computes the digests of the staged payload files, other than the payload
+// decryption key, in the algorithm of 'tpm_hash_alg'. The files are hashed
+// in parallel, as large payloads are common on edge devices.
+fn payload_digests(
+    unzipped: &Path,
+    key_path: &Path,
+    config: &config::KeylimeConfig,
+) -> Result<Vec<(PathBuf, String)>> {
+    let alg = HashAlgorithm::try_from(config.agent.tpm_hash_alg.as_str())?;
+    let mut files = Vec::new();
+    collect_files(unzipped, &mut files)?;
+    files.retain(|file| file != key_path);
+    files.sort();
+    let digests =
+        hashing::digest_files(hashing::default_backend(), alg, &files)?;
+    Ok(files
+        .into_iter()
+        .zip(digests)
+        .map(|(file, digest)| {
+            let file = file
+                .strip_prefix(unzipped)
+                .map(Path::to_path_buf)
+                .unwrap_or(file);
+            (file, format!("{alg}:{}", hex::encode(digest)))
+        })
+        .collect())
+}
+
+// prepares the payload in the staging directory, running the init script
+// and setting the permissions of the revocation actions. Any failure leaves
+// the deployed payload untouched.
+fn stage_payload(
+    symm_key: &SymmKey,
     payload: EncryptedData,
     config: &config::KeylimeConfig,
     mount: &Path,
-    revocation_tx: Sender<RevocationMessage>,
-    #[cfg(feature = "with-zmq")] zmq_tx: Sender<ZmqMessage>,
 ) -> Result<()> {
-    let dec_payload = decrypt_payload(&symm_key, payload)?;
+    let dec_payload = decrypt_payload(symm_key, payload)?;
 
     let (unzipped, dec_payload_path, key_path) =
         setup_unzipped(config, mount)?;
@@ -202,11 +380,18 @@ async fn run_encrypted_payload(
     write_out_key_and_payload(
         &dec_payload,
         &dec_payload_path,
-        &symm_key,
+        symm_key,
         &key_path,
     )?;
 
     optional_unzip_payload(&unzipped, config)?;
+    // log the digests so that the deployed content can be audited
+    for (file, digest) in payload_digests(&unzipped, &key_path, config)? {
+        info!(
+            "Staged payload file {} with digest {digest}",
+            file.display()
+        );
+    }
     // there may also be also a separate init script
     match config.agent.payload_script.as_ref() {
         "" => {
@@ -214,7 +399,7 @@ async fn run_encrypted_payload(
         }
         script => {
             info!("Payload init script indicated: {}", script);
-            run(&unzipped, script)?;
+            run(&unzipped, script, &config.agent.payload_apparmor_profile)?;
         }
     }
 
@@ -222,13 +407,10 @@ async fn run_encrypted_payload(
     let action_file = unzipped.join("action_list");
 
     if action_file.exists() {
-        let action_data = std::fs::read_to_string(&action_file)
-            .expect("unable to read action_list");
+        let action_data = fs::read(&action_file)?;
 
-        action_data
-            .split('\n')
-            .filter(|&script| !script.is_empty())
-            .map(|script| script.trim())
+        parse_action_list(&action_data)?
+            .into_iter()
             .map(|script| unzipped.join(script))
             .filter(|script| script.exists())
             .try_for_each(|script| {
@@ -250,6 +432,50 @@ async fn run_encrypted_payload(
             })?
     }
 
+    Ok(())
+}
+
+// deploys the payload, replacing the one deployed previously, if any. If the
+// new payload fails to decrypt, to extract, or its init script fails, it is
+// discarded and the previous payload is kept. payloads not delivered as
+// files are handed off to their consumer, without writing anything to the
+// secure mount. returns whether a previous payload was replaced.
+async fn run_encrypted_payload(
+    symm_key: SymmKey,
+    payload: EncryptedData,
+    delivery: Option<PayloadDelivery>,
+    config: &config::KeylimeConfig,
+    mount: &Path,
+    revocation_tx: Sender<RevocationMessage>,
+    #[cfg(feature = "with-zmq")] zmq_tx: Sender<ZmqMessage>,
+) -> Result<bool> {
+    let delivery = match delivery {
+        Some(delivery) => delivery,
+        None => {
+            PayloadDelivery::try_from(config.agent.payload_delivery.as_ref())?
+        }
+    };
+    if delivery != PayloadDelivery::Files {
+        let dec_payload = decrypt_payload(&symm_key, payload)?;
+        handoff::hand_off(
+            delivery,
+            &config.agent.payload_handoff_socket,
+            &config.agent.payload_handoff_peer_uid,
+            &dec_payload,
+        )?;
+        return Ok(false);
+    }
+
+    if let Err(e) = stage_payload(&symm_key, payload, config, mount) {
+        let staged = mount.join(STAGED_DIR);
+        if staged.exists() {
+            fs::remove_dir_all(&staged)?;
+        }
+        return Err(e);
+    }
+
+    let renewed = swap_unzipped(mount)?;
+
     debug!("Sending PayloadDecrypted message to revocation worker");
     if let Err(e) = revocation_tx
         .send(RevocationMessage::PayloadDecrypted)
@@ -266,7 +492,28 @@ async fn run_encrypted_payload(
         };
     }
 
-    Ok(())
+    Ok(renewed)
+}
+
+// runs the renewal hook, if configured, notifying the outcome of the payload
+// deployment
+fn notify_hook(hook: &str, mount: &Path, status: &str) {
+    if hook.is_empty() {
+        return;
+    }
+
+    match Command::new(hook)
+        .env("KEYLIME_PAYLOAD_DIR", mount.join(UNZIPPED_DIR))
+        .env("KEYLIME_PAYLOAD_STATUS", status)
+        .stdin(Stdio::null())
+        .status()
+    {
+        Ok(s) if s.success() => {
+            debug!("Payload hook {hook} notified ({status})");
+        }
+        Ok(s) => warn!("Payload hook {hook} failed with {s}"),
+        Err(e) => warn!("Failed to run payload hook {hook}: {e}"),
+    }
 }
 
 pub(crate) async fn worker(
@@ -274,6 +521,7 @@ pub(crate) async fn worker(
     mount: impl AsRef<Path>,
     mut payload_rx: Receiver<PayloadMessage>,
     mut revocation_tx: Sender<RevocationMessage>,
+    events: EventPublisher,
     #[cfg(feature = "with-zmq")] mut zmq_tx: Sender<ZmqMessage>,
 ) -> Result<()> {
     debug!("Starting payloads worker");
@@ -284,12 +532,23 @@ pub(crate) async fn worker(
             PayloadMessage::Shutdown => {
                 payload_rx.close();
             }
+            PayloadMessage::Expire => {
+                if let Err(e) = wipe(mount.as_ref()) {
+                    warn!("Failed to remove the expired payload: {e}");
+                }
+                notify_hook(
+                    &config.agent.payload_hook,
+                    mount.as_ref(),
+                    "expired",
+                );
+            }
             PayloadMessage::RunPayload(run_payload) => {
                 // The keys worker will send this message only if mTLS is enabled or
                 // 'enable_insecure_payload' configuration option is set
                 match run_encrypted_payload(
                     run_payload.symm_key,
                     run_payload.encrypted_payload,
+                    run_payload.delivery,
                     &config,
                     mount.as_ref(),
                     revocation_tx.clone(),
@@ -298,11 +557,25 @@ pub(crate) async fn worker(
                 )
                 .await
                 {
-                    Ok(_) => {
+                    Ok(renewed) => {
                         info!("Successfully executed encrypted payload");
+                        events.publish(AgentEvent::PayloadExecuted);
+                        notify_hook(
+                            &config.agent.payload_hook,
+                            mount.as_ref(),
+                            if renewed { "renewed" } else { "deployed" },
+                        );
                     }
                     Err(e) => {
                         warn!("Failed to run encrypted payload: {}", e);
+                        events.publish(AgentEvent::PayloadRejected {
+                            reason: e.to_string(),
+                        });
+                        notify_hook(
+                            &config.agent.payload_hook,
+                            mount.as_ref(),
+                            "rejected",
+                        );
                     }
                 }
             }
@@ -378,11 +651,98 @@ echo hello > test-output
         run(
             dir.path(),
             script_path.file_name().unwrap().to_str().unwrap(), //#[allow_ci]
+            "",
         )
         .unwrap(); //#[allow_ci]
         assert!(dir.path().join("test-output").exists());
     }
 
+    #[test]
+    fn test_run_failure() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        fs::write(dir.path().join("fail.sh"), "#!/bin/sh\nexit 1\n").unwrap(); //#[allow_ci]
+        assert!(run(dir.path(), "fail.sh", "").is_err());
+    }
+
+    #[test]
+    fn test_swap_unzipped() {
+        let mount = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let staged = mount.path().join(STAGED_DIR);
+        let unzipped = mount.path().join(UNZIPPED_DIR);
+
+        fs::create_dir(&staged).unwrap(); //#[allow_ci]
+        fs::write(staged.join("secret"), "first").unwrap(); //#[allow_ci]
+        assert!(!swap_unzipped(mount.path()).unwrap()); //#[allow_ci]
+        assert!(!staged.exists());
+        assert_eq!(
+            fs::read_to_string(unzipped.join("secret")).unwrap(), //#[allow_ci]
+            "first"
+        );
+
+        fs::create_dir(&staged).unwrap(); //#[allow_ci]
+        fs::write(staged.join("secret"), "second").unwrap(); //#[allow_ci]
+        assert!(swap_unzipped(mount.path()).unwrap()); //#[allow_ci]
+        assert!(!staged.exists());
+        assert_eq!(
+            fs::read_to_string(unzipped.join("secret")).unwrap(), //#[allow_ci]
+            "second"
+        );
+    }
+
+    #[test]
+    fn test_wipe() {
+        let mount = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let unzipped = mount.path().join(UNZIPPED_DIR);
+        let other = mount.path().join("other");
+
+        fs::create_dir_all(unzipped.join("dir")).unwrap(); //#[allow_ci]
+        fs::write(unzipped.join("key"), "key").unwrap(); //#[allow_ci]
+        fs::write(unzipped.join("dir/payload"), "payload").unwrap(); //#[allow_ci]
+        fs::write(&other, "other").unwrap(); //#[allow_ci]
+
+        assert_eq!(wipe(mount.path()).unwrap(), 2); //#[allow_ci]
+        assert!(!unzipped.exists());
+        // Files not placed by the agent are kept
+        assert!(other.exists());
+
+        // Nothing left to wipe
+        assert_eq!(wipe(mount.path()).unwrap(), 0); //#[allow_ci]
+    }
+
+    #[actix_rt::test]
+    async fn test_run_encrypted_payload_rejected() {
+        let test_config = KeylimeConfig::default();
+        let mount = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let unzipped = mount.path().join(UNZIPPED_DIR);
+        fs::create_dir(&unzipped).unwrap(); //#[allow_ci]
+        fs::write(unzipped.join("secret"), "current").unwrap(); //#[allow_ci]
+
+        let (revocation_tx, _revocation_rx) =
+            mpsc::channel::<RevocationMessage>(1);
+        #[cfg(feature = "with-zmq")]
+        let (zmq_tx, _zmq_rx) = mpsc::channel::<ZmqMessage>(1);
+
+        // The payload cannot be decrypted with the key
+        let result = run_encrypted_payload(
+            setup_key(AES_128_KEY_LEN),
+            vec![0u8; 64].into(),
+            None,
+            &test_config,
+            mount.path(),
+            revocation_tx,
+            #[cfg(feature = "with-zmq")]
+            zmq_tx,
+        )
+        .await;
+
+        assert!(result.is_err());
+        assert!(!mount.path().join(STAGED_DIR).exists());
+        assert_eq!(
+            fs::read_to_string(unzipped.join("secret")).unwrap(), //#[allow_ci]
+            "current"
+        );
+    }
+
     #[cfg(feature = "testing")]
     #[test]
     fn test_decrypt_payload() {
@@ -453,6 +813,35 @@ echo hello > test-output
         assert!(temp_workdir.path().join("autorun.sh").exists());
     }
 
+    #[test]
+    fn test_payload_digests() {
+        let test_config = KeylimeConfig::default();
+        let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let dir = temp_workdir.path();
+        fs::create_dir(dir.join("actions")).unwrap(); //#[allow_ci]
+        fs::write(dir.join("actions").join("local_action.py"), b"abc")
+            .unwrap(); //#[allow_ci]
+        fs::write(dir.join("autorun.sh"), b"").unwrap(); //#[allow_ci]
+        fs::write(dir.join("derived_tci_key"), b"key").unwrap(); //#[allow_ci]
+
+        let digests =
+            payload_digests(dir, &dir.join("derived_tci_key"), &test_config)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(
+            digests,
+            vec![
+                (
+                    PathBuf::from("actions/local_action.py"),
+                    "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string()
+                ),
+                (
+                    PathBuf::from("autorun.sh"),
+                    "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string()
+                ),
+            ]
+        );
+    }
+
     #[cfg(feature = "testing")]
     #[actix_rt::test]
     async fn test_run_encrypted_payload() {
@@ -474,6 +863,7 @@ echo hello > test-output
         run_encrypted_payload(
             k,
             payload,
+            None,
             &test_config,
             &secure_mount,
             revocation_tx,
@@ -531,6 +921,7 @@ echo hello > test-output
                 secure_mount,
                 payload_rx,
                 revocation_tx,
+                EventPublisher::default(),
                 #[cfg(feature = "with-zmq")]
                 zmq_tx,
             )
@@ -551,6 +942,7 @@ echo hello > test-output
         let run_payload = Payload {
             symm_key: k,
             encrypted_payload: payload,
+            delivery: None,
         };
 
         let result = payload_tx
@@ -0,0 +1,285 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Forecast of the PCR values after a reboot
+//!
+//! Before a maintenance window, the fleet tooling can submit the digests of
+//! the boot components about to be updated, e.g. the bootloader, the kernel
+//! or the initrd, as replacements of the digests currently in the measured
+//! boot log. The agent replays the log with the events of the replaced
+//! components carrying the new digests, which predicts the PCR values after
+//! the reboot, so that the verifier policies can be updated beforehand.
+
+use crate::{
+    common::JsonWrapper,
+    service::{self, ServiceError},
+    QuoteData,
+};
+use actix_web::{web, HttpResponse, Responder};
+use keylime::{
+    algorithms::HashAlgorithm,
+    event_log::{self, Event, EV_NO_ACTION},
+};
+use log::*;
+use openssl::hash::MessageDigest;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Maximum number of replacements in a forecast request
+const MAX_REPLACEMENTS: usize = 64;
+
+/// A boot component about to be updated, identified by the digest of its
+/// events in the measured boot log
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Replacement {
+    /// Hex encoded digest of the events of the current component
+    pub current: String,
+    /// Hex encoded digest of the component after the update
+    pub new: String,
+    /// Only replace the events extended into this PCR
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pcr: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct ForecastRequest {
+    /// The PCR bank of the forecast, the one of 'tpm_hash_alg' if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pcr_bank: Option<String>,
+    pub replacements: Vec<Replacement>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct Forecast {
+    pub pcr_bank: String,
+    /// The hex encoded values of the PCRs after the reboot
+    pub pcrs: BTreeMap<u32, String>,
+    /// The PCRs whose values change
+    pub changed: Vec<u32>,
+    pub replaced_events: usize,
+}
+
+fn decode_digest(
+    digest: &str,
+    alg: HashAlgorithm,
+) -> Result<Vec<u8>, ServiceError> {
+    let size = MessageDigest::from(alg).size();
+    match hex::decode(digest) {
+        Ok(d) if d.len() == size => Ok(d),
+        _ => Err(ServiceError::BadRequest(format!(
+            "digest should be a hex encoded {alg} digest: {digest}"
+        ))),
+    }
+}
+
+/// Replay the events with the digests of the replaced components, in the
+/// PCR bank of the algorithm. Every replacement has to match at least one
+/// event, so that a forecast is not silently made from a stale digest.
+pub(crate) fn forecast(
+    events: &[Event],
+    alg: HashAlgorithm,
+    replacements: &[Replacement],
+) -> Result<Forecast, ServiceError> {
+    if replacements.is_empty() || replacements.len() > MAX_REPLACEMENTS {
+        return Err(ServiceError::BadRequest(format!(
+            "the forecast requires from 1 to {MAX_REPLACEMENTS} replacements"
+        )));
+    }
+    if !event_log::has_bank(events, alg) {
+        return Err(ServiceError::BadRequest(format!(
+            "the measured boot log has no {alg} digests"
+        )));
+    }
+
+    let mut forecast_events = events.to_vec();
+    let mut replaced_events = 0;
+    for replacement in replacements {
+        let current = decode_digest(&replacement.current, alg)?;
+        let new = decode_digest(&replacement.new, alg)?;
+
+        let mut matched = false;
+        for event in forecast_events.iter_mut().filter(|e| {
+            e.event_type != EV_NO_ACTION
+                && replacement.pcr.is_none_or(|pcr| e.pcr_index == pcr)
+        }) {
+            for (a, digest) in event.digests.iter_mut() {
+                if *a == alg && *digest == current {
+                    digest.clone_from(&new);
+                    matched = true;
+                    replaced_events += 1;
+                }
+            }
+        }
+        if !matched {
+            return Err(ServiceError::BadRequest(format!(
+                "no event of the measured boot log has the digest {}",
+                replacement.current
+            )));
+        }
+    }
+
+    let replay = |events: &[Event]| {
+        event_log::replay(events, alg).map_err(|e| {
+            ServiceError::internal(
+                e,
+                "Unable to replay the measured boot log",
+            )
+        })
+    };
+    let before = replay(events)?;
+    let after = replay(&forecast_events)?;
+
+    Ok(Forecast {
+        pcr_bank: alg.to_string(),
+        changed: after
+            .iter()
+            .filter(|(pcr, value)| before.get(pcr) != Some(value))
+            .map(|(pcr, _)| *pcr)
+            .collect(),
+        pcrs: after
+            .into_iter()
+            .map(|(pcr, value)| (pcr, hex::encode(value)))
+            .collect(),
+        replaced_events,
+    })
+}
+
+fn forecast_request(
+    data: &QuoteData,
+    request: &ForecastRequest,
+) -> Result<Forecast, ServiceError> {
+    let alg = match request.pcr_bank.as_deref() {
+        None => data.hash_alg,
+        Some(bank) => HashAlgorithm::try_from(bank).map_err(|e| {
+            ServiceError::BadRequest(format!("pcr_bank: {e}"))
+        })?,
+    };
+
+    let mut reservations = Vec::new();
+    let Some(log) = service::read_measured_boot_log(data, &mut reservations)?
+    else {
+        return Err(ServiceError::Unavailable(
+            "the measured boot log is not available".to_string(),
+        ));
+    };
+    let events = event_log::parse(&log).map_err(|e| {
+        ServiceError::internal(e, "Unable to parse the measured boot log")
+    })?;
+
+    forecast(&events, alg, &request.replacements)
+}
+
+/// Handles the requests for the forecast of the PCR values after a reboot
+pub(crate) async fn pcr_forecast(
+    body: web::Json<ForecastRequest>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    match forecast_request(&data, &body) {
+        Ok(forecast) => {
+            info!(
+                "POST PCR forecast returning 200 response, PCRs {:?} change",
+                forecast.changed
+            );
+            HttpResponse::Ok().json(JsonWrapper::success(forecast))
+        }
+        Err(e) => {
+            warn!(
+                "POST PCR forecast returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::hash::hash;
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        hash(MessageDigest::sha256(), data).unwrap().to_vec() //#[allow_ci]
+    }
+
+    fn event(pcr_index: u32, data: &[u8]) -> Event {
+        Event {
+            pcr_index,
+            event_type: 0x80000003,
+            digests: vec![(HashAlgorithm::Sha256, sha256(data))],
+            data: data.to_vec(),
+        }
+    }
+
+    fn replacement(current: &[u8], new: &[u8]) -> Replacement {
+        Replacement {
+            current: hex::encode(sha256(current)),
+            new: hex::encode(sha256(new)),
+            pcr: None,
+        }
+    }
+
+    #[test]
+    fn test_forecast() {
+        let events = vec![
+            event(0, b"firmware"),
+            event(4, b"shim"),
+            event(4, b"grub"),
+            event(4, b"vmlinuz-6.1"),
+            event(9, b"initrd-6.1"),
+        ];
+
+        let result = forecast(
+            &events,
+            HashAlgorithm::Sha256,
+            &[
+                replacement(b"vmlinuz-6.1", b"vmlinuz-6.2"),
+                replacement(b"initrd-6.1", b"initrd-6.2"),
+            ],
+        )
+        .unwrap(); //#[allow_ci]
+        assert_eq!(result.changed, vec![4, 9]);
+        assert_eq!(result.replaced_events, 2);
+
+        let expected = event_log::replay(
+            &[
+                event(0, b"firmware"),
+                event(4, b"shim"),
+                event(4, b"grub"),
+                event(4, b"vmlinuz-6.2"),
+                event(9, b"initrd-6.2"),
+            ],
+            HashAlgorithm::Sha256,
+        )
+        .unwrap(); //#[allow_ci]
+        for (pcr, value) in expected {
+            assert_eq!(result.pcrs.get(&pcr), Some(&hex::encode(value)));
+        }
+
+        // The replacement can be restricted to a PCR
+        let mut restricted = replacement(b"grub", b"grub-2.12");
+        restricted.pcr = Some(9);
+        assert!(matches!(
+            forecast(&events, HashAlgorithm::Sha256, &[restricted]),
+            Err(ServiceError::BadRequest(_))
+        ));
+
+        // The digests have to match the PCR bank
+        assert!(forecast(
+            &events,
+            HashAlgorithm::Sha256,
+            &[Replacement {
+                current: "00".to_string(),
+                new: "01".to_string(),
+                pcr: None,
+            }]
+        )
+        .is_err());
+        assert!(forecast(
+            &events,
+            HashAlgorithm::Sha384,
+            &[replacement(b"grub", b"grub-2.12")]
+        )
+        .is_err());
+        assert!(forecast(&events, HashAlgorithm::Sha256, &[]).is_err());
+    }
+}
@@ -0,0 +1,432 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Validation of the TLS certificates of the servers contacted by the agent
+//!
+//! The registrar is contacted over plain HTTP, unless 'registrar_tls' sets
+//! how the certificate of the registrar is validated:
+//!
+//! - hostname: the certificate must chain to the system trust store and
+//!   its subject alternative names must match the server name;
+//! - ca: the certificate must chain to the CA certificates in
+//!   'registrar_tls_ca', and its subject alternative names must match the
+//!   server name;
+//! - pin: the SHA-256 hash of the public key of the certificate must be one
+//!   of 'registrar_tls_pins', in the "sha256//<base64>" format used by curl.
+//!   The chain and the names in the certificate are not checked.
+//!
+//! The server name is the address of the server, unless set explicitly,
+//! e.g. when the registrar is contacted by IP address but its certificate
+//! only includes its DNS name.
+
+use crate::{crypto, endpoint::Endpoint, fips, Error, Result};
+use base64::{engine::general_purpose, Engine as _};
+use log::*;
+use openssl::{
+    hash::{hash, MessageDigest},
+    memcmp,
+    ssl::{SslConnector, SslMethod, SslVerifyMode},
+    x509::{store::X509StoreBuilder, X509StoreContextRef},
+};
+use std::{fmt, path::Path, pin::Pin};
+use tokio::net::TcpStream;
+use tokio_openssl::SslStream;
+
+const PIN_PREFIX: &str = "sha256//";
+
+/// How the certificate of a server is validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verification {
+    /// Plain HTTP, without TLS
+    Disabled,
+    Hostname,
+    Ca,
+    Pin,
+}
+
+impl TryFrom<&str> for Verification {
+    type Error = Error;
+
+    fn try_from(value: &str) -> Result<Self> {
+        match value {
+            "disabled" => Ok(Verification::Disabled),
+            "hostname" => Ok(Verification::Hostname),
+            "ca" => Ok(Verification::Ca),
+            "pin" => Ok(Verification::Pin),
+            other => Err(Error::Configuration(format!(
+                "invalid TLS verification '{other}', expected one of 'disabled', 'hostname', 'ca' or 'pin'"
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Verification::Disabled => write!(f, "disabled"),
+            Verification::Hostname => write!(f, "hostname"),
+            Verification::Ca => write!(f, "ca"),
+            Verification::Pin => write!(f, "pin"),
+        }
+    }
+}
+
+/// Parse the comma separated list of "sha256//<base64>" public key pins
+pub(crate) fn parse_pins(pins: &str) -> Result<Vec<Vec<u8>>> {
+    pins.split(',')
+        .map(str::trim)
+        .filter(|pin| !pin.is_empty())
+        .map(|pin| {
+            let digest = pin
+                .strip_prefix(PIN_PREFIX)
+                .and_then(|b64| general_purpose::STANDARD.decode(b64).ok())
+                .filter(|digest| digest.len() == 32);
+            digest.ok_or_else(|| {
+                Error::Configuration(format!(
+                    "invalid public key pin '{pin}', expected the base64 encoded SHA-256 hash of the public key prefixed with '{PIN_PREFIX}'"
+                ))
+            })
+        })
+        .collect()
+}
+
+/// The pin of the public key of the certificate
+fn spki_pin(ctx: &X509StoreContextRef) -> Option<Vec<u8>> {
+    let der = ctx
+        .current_cert()?
+        .public_key()
+        .ok()?
+        .public_key_to_der()
+        .ok()?;
+    hash(MessageDigest::sha256(), &der).ok().map(|d| d.to_vec())
+}
+
+/// The TLS settings used to contact a server
+#[derive(Clone)]
+pub(crate) struct PeerTls {
+    verification: Verification,
+    connector: Option<SslConnector>,
+    server_name: String,
+}
+
+impl Default for PeerTls {
+    fn default() -> Self {
+        PeerTls {
+            verification: Verification::Disabled,
+            connector: None,
+            server_name: String::new(),
+        }
+    }
+}
+
+impl fmt::Debug for PeerTls {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PeerTls")
+            .field("verification", &self.verification)
+            .field("server_name", &self.server_name)
+            .finish()
+    }
+}
+
+impl PeerTls {
+    /// The TLS settings from the configuration options of the server. The
+    /// CA certificates are only used in 'ca' mode, and the pins in 'pin'
+    /// mode. In FIPS mode the connection is restricted to the approved
+    /// cipher suites.
+    pub(crate) fn new(
+        verification: &str,
+        ca: &str,
+        pins: &str,
+        server_name: &str,
+        fips_mode: bool,
+    ) -> Result<Self> {
+        let verification = Verification::try_from(verification)?;
+        if verification == Verification::Disabled {
+            return Ok(PeerTls::default());
+        }
+
+        let mut builder = SslConnector::builder(SslMethod::tls_client())?;
+        if fips_mode {
+            fips::restrict_tls(&mut builder)?;
+        }
+        match verification {
+            Verification::Disabled | Verification::Hostname => (),
+            Verification::Ca => {
+                if ca.is_empty() {
+                    return Err(Error::Configuration(
+                        "TLS verification 'ca' requires the CA certificates of the server to be set".to_string(),
+                    ));
+                }
+                let certs = crypto::load_x509_cert_list(vec![Path::new(ca)])?;
+                if certs.is_empty() {
+                    return Err(Error::Configuration(format!(
+                        "No CA certificates loaded from {ca}"
+                    )));
+                }
+                // Only the configured CAs are trusted, not the system ones
+                let mut store = X509StoreBuilder::new()?;
+                for cert in certs {
+                    store.add_cert(cert)?;
+                }
+                builder.set_verify_cert_store(store.build())?;
+            }
+            Verification::Pin => {
+                let pins = parse_pins(pins)?;
+                if pins.is_empty() {
+                    return Err(Error::Configuration(
+                        "TLS verification 'pin' requires at least one public key pin to be set".to_string(),
+                    ));
+                }
+                builder.set_verify_callback(
+                    SslVerifyMode::PEER,
+                    move |_, ctx| {
+                        // Only the server certificate itself is pinned
+                        if ctx.error_depth() != 0 {
+                            return true;
+                        }
+                        match spki_pin(ctx) {
+                            Some(pin) => pins.iter().any(|p| {
+                                p.len() == pin.len() && memcmp::eq(p, &pin)
+                            }),
+                            None => false,
+                        }
+                    },
+                );
+            }
+        }
+
+        Ok(PeerTls {
+            verification,
+            connector: Some(builder.build()),
+            server_name: server_name.to_string(),
+        })
+    }
+
+    pub(crate) fn scheme(&self) -> &'static str {
+        match self.connector {
+            Some(_) => "https",
+            None => "http",
+        }
+    }
+
+    /// Whether the server is contacted over TLS
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.connector.is_some()
+    }
+
+    /// Establish the TLS session with the server over the connection,
+    /// validating its certificate
+    pub(crate) async fn handshake(
+        &self,
+        tcp: TcpStream,
+        endpoint: &Endpoint,
+    ) -> Result<SslStream<TcpStream>> {
+        let Some(connector) = &self.connector else {
+            return Err(Error::Tls(format!(
+                "TLS is disabled for {endpoint}"
+            )));
+        };
+        let server_name = match self.server_name.as_ref() {
+            "" => endpoint.host(),
+            name => name,
+        };
+
+        let mut config = connector.configure()?;
+        if self.verification == Verification::Pin {
+            config.set_verify_hostname(false);
+        }
+        let ssl = config.into_ssl(server_name)?;
+        let mut stream = SslStream::new(ssl, tcp)?;
+        Pin::new(&mut stream).connect().await.map_err(|e| {
+            Error::Tls(format!(
+                "TLS connection to {endpoint} failed ({} verification): {e}",
+                self.verification
+            ))
+        })?;
+        Ok(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http_client::{HttpClient, HttpClientOptions};
+    use hyper::Method;
+    use openssl::{
+        asn1::Asn1Time,
+        pkey::{PKey, Private},
+        rsa::Rsa,
+        ssl::SslAcceptor,
+        x509::{extension::SubjectAlternativeName, X509NameBuilder, X509},
+    };
+    use serde_json::json;
+    use std::io::Write;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn server_cert(san: &str) -> (PKey<Private>, X509) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap(); //#[allow_ci]
+        let mut name = X509NameBuilder::new().unwrap(); //#[allow_ci]
+        name.append_entry_by_text("CN", "registrar").unwrap(); //#[allow_ci]
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap(); //#[allow_ci]
+        builder.set_version(2).unwrap(); //#[allow_ci]
+        builder.set_subject_name(&name).unwrap(); //#[allow_ci]
+        builder.set_issuer_name(&name).unwrap(); //#[allow_ci]
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder.set_pubkey(&key).unwrap(); //#[allow_ci]
+        let san = SubjectAlternativeName::new()
+            .dns(san)
+            .build(&builder.x509v3_context(None, None))
+            .unwrap(); //#[allow_ci]
+        builder.append_extension(san).unwrap(); //#[allow_ci]
+        builder.sign(&key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+        (key, builder.build())
+    }
+
+    fn pin(cert: &X509) -> String {
+        let der = cert.public_key().unwrap().public_key_to_der().unwrap(); //#[allow_ci]
+        let digest = hash(MessageDigest::sha256(), &der).unwrap(); //#[allow_ci]
+        format!("{PIN_PREFIX}{}", general_purpose::STANDARD.encode(digest))
+    }
+
+    fn client() -> HttpClient {
+        HttpClient::new(HttpClientOptions::default()).unwrap() //#[allow_ci]
+    }
+
+    /// Serve a single HTTPS request with an empty JSON object
+    async fn serve(key: PKey<Private>, cert: X509) -> Endpoint {
+        let mut acceptor =
+            SslAcceptor::mozilla_intermediate(SslMethod::tls()).unwrap(); //#[allow_ci]
+        acceptor.set_private_key(&key).unwrap(); //#[allow_ci]
+        acceptor.set_certificate(&cert).unwrap(); //#[allow_ci]
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap(); //#[allow_ci]
+        let port = listener.local_addr().unwrap().port(); //#[allow_ci]
+        drop(tokio::spawn(async move {
+            let (tcp, _) = listener.accept().await.unwrap(); //#[allow_ci]
+            let ssl = openssl::ssl::Ssl::new(acceptor.context()).unwrap(); //#[allow_ci]
+            let mut stream = SslStream::new(ssl, tcp).unwrap(); //#[allow_ci]
+            if Pin::new(&mut stream).accept().await.is_err() {
+                return;
+            }
+            let mut request = [0u8; 4096];
+            let _ = stream.read(&mut request).await.unwrap(); //#[allow_ci]
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}")
+                .await
+                .unwrap(); //#[allow_ci]
+        }));
+        Endpoint::new("127.0.0.1", port.into())
+    }
+
+    #[test]
+    fn test_parse_pins() {
+        let digest = general_purpose::STANDARD.encode([0x42; 32]);
+        let pins = parse_pins(&format!(
+            "{PIN_PREFIX}{digest}, {PIN_PREFIX}{digest}"
+        ))
+        .unwrap(); //#[allow_ci]
+        assert_eq!(pins, vec![vec![0x42; 32], vec![0x42; 32]]);
+        assert!(parse_pins("").unwrap().is_empty()); //#[allow_ci]
+        assert!(parse_pins(&digest).is_err());
+        assert!(parse_pins("sha256//AAAA").is_err());
+    }
+
+    #[test]
+    fn test_new() {
+        assert_eq!(
+            PeerTls::new("disabled", "", "", "", false)
+                .unwrap() //#[allow_ci]
+                .scheme(),
+            "http"
+        );
+        assert_eq!(
+            PeerTls::new("hostname", "", "", "", false)
+                .unwrap() //#[allow_ci]
+                .scheme(),
+            "https"
+        );
+        assert!(PeerTls::new("none", "", "", "", false).is_err());
+        assert!(PeerTls::new("ca", "", "", "", false).is_err());
+        assert!(PeerTls::new("pin", "", "", "", false).is_err());
+    }
+
+    #[actix_rt::test]
+    async fn test_pin() {
+        let (key, cert) = server_cert("registrar.example.com");
+        let (_, other) = server_cert("registrar.example.com");
+
+        let endpoint = serve(key.clone(), cert.clone()).await;
+        let tls = PeerTls::new("pin", "", &pin(&cert), "", false).unwrap(); //#[allow_ci]
+        let (status, body) = client()
+            .send_json(
+                Method::POST,
+                &endpoint,
+                "v2.1/agents/uuid",
+                &json!({}),
+                &tls,
+            )
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(status, 200);
+        assert_eq!(body, b"{}");
+
+        let endpoint = serve(key, cert).await;
+        let tls = PeerTls::new("pin", "", &pin(&other), "", false).unwrap(); //#[allow_ci]
+        assert!(matches!(
+            client()
+                .send_json(Method::POST, &endpoint, "/", &json!({}), &tls)
+                .await,
+            Err(Error::Tls(_))
+        ));
+    }
+
+    #[actix_rt::test]
+    async fn test_ca() {
+        let (key, cert) = server_cert("registrar.example.com");
+        let mut ca = tempfile::NamedTempFile::new().unwrap(); //#[allow_ci]
+        ca.write_all(&cert.to_pem().unwrap()).unwrap(); //#[allow_ci]
+        let ca = ca.path().display().to_string();
+
+        let endpoint = serve(key.clone(), cert.clone()).await;
+        let tls = PeerTls::new("ca", &ca, "", "registrar.example.com", false)
+            .unwrap(); //#[allow_ci]
+        let (status, _) = client()
+            .send_json(Method::PUT, &endpoint, "/", &json!({}), &tls)
+            .await
+            .unwrap(); //#[allow_ci]
+        assert_eq!(status, 200);
+
+        // The server name does not match the certificate
+        let endpoint = serve(key.clone(), cert.clone()).await;
+        let tls = PeerTls::new("ca", &ca, "", "", false).unwrap(); //#[allow_ci]
+        assert!(matches!(
+            client()
+                .send_json(Method::PUT, &endpoint, "/", &json!({}), &tls)
+                .await,
+            Err(Error::Tls(_))
+        ));
+
+        // The certificate is not trusted by the system
+        let endpoint = serve(key, cert).await;
+        let tls =
+            PeerTls::new("hostname", "", "", "registrar.example.com", false)
+                .unwrap(); //#[allow_ci]
+        assert!(matches!(
+            client()
+                .send_json(Method::PUT, &endpoint, "/", &json!({}), &tls)
+                .await,
+            Err(Error::Tls(_))
+        ));
+    }
+}
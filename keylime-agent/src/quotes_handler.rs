@@ -1,31 +1,48 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
-use crate::common::JsonWrapper;
-use crate::crypto;
+use crate::asset_tag::AssetTag;
+use crate::common::{
+    api_version_at_least, request_api_version, JsonWrapper,
+    QUOTE_FIELDS_API_VERSION,
+};
+use crate::cose;
+use crate::kernel_boot::BootInfo;
+use crate::resources::Reservation;
 use crate::serialization::serialize_maybe_base64;
-use crate::{tpm, Error as KeylimeError, QuoteData};
-use actix_web::{web, HttpRequest, HttpResponse, Responder};
-use base64::{engine::general_purpose, Engine as _};
+use crate::service::{self, IntegrityQuote, PcrRequest};
+use crate::verifiers;
+use crate::{Error as KeylimeError, QuoteData};
+use actix_web::{web, web::Bytes, HttpRequest, HttpResponse, Responder};
+use futures::{future::Future, stream::Stream, task::Context, task::Poll};
+use keylime::ima::{escape_non_utf8, READ_CHUNK_SIZE};
 use log::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    fs::{read, read_to_string},
+    fs::{read, read_to_string, File},
     io::{Read, Seek},
+    os::unix::fs::FileExt,
+    pin::Pin,
+    sync::Arc,
 };
-use tss_esapi::structures::PcrSlot;
+use tokio::task::{spawn_blocking, JoinHandle};
 
 #[derive(Deserialize)]
 pub struct Ident {
     nonce: String,
+    agent_nonce: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct Integ {
     nonce: String,
-    mask: String,
+    agent_nonce: Option<String>,
+    mask: Option<String>,
+    pcrs: Option<String>,
+    pcr_bank: Option<String>,
     partial: String,
     ima_ml_entry: Option<String>,
+    mb_etag: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Default)]
@@ -40,308 +57,568 @@ pub(crate) struct KeylimeQuote {
     pub ima_measurement_list: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub mb_measurement_list: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mb_measurement_list_etag: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub ima_measurement_list_entry: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pcr_selection: Option<PcrSelection>,
+    /// Number of changes of the boot chain seen by the agent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot_epoch: Option<u64>,
+    /// The running kernel and how it was started
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub boot: Option<BootInfo>,
+    /// The asset tag provisioned by the operator in the TPM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub asset_tag: Option<AssetTag>,
+    /// Hex encoded random contribution of the agent. The quote is over the
+    /// SHA-256 digest of the nonce followed by the agent nonce.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent_nonce: Option<String>,
+    /// Counters showing whether the agent or the TPM restarted since the
+    /// previous quote, only sent from API version 2.2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub continuity: Option<Continuity>,
 }
 
-// This is a Quote request from the tenant, which does not check
-// integrity measurement. It should return this data:
-// { QuoteAIK(nonce, 16:H(NK_pub)), NK_pub }
-pub async fn identity(
-    req: HttpRequest,
-    param: web::Query<Ident>,
-    data: web::Data<QuoteData>,
-) -> impl Responder {
-    // nonce can only be in alphanumerical format
-    if !param.nonce.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.nonce);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Parameters should be strictly alphanumeric: {}",
-                param.nonce
-            ),
-        ));
+/// Version of the format of the continuity information
+pub(crate) const CONTINUITY_VERSION: u32 = 1;
+
+/// The counters of the agent starts and of the TPM resets and restarts. A
+/// verifier seeing any of them change between two quotes knows that the
+/// agent or the TPM restarted in between, even if the attestation did not
+/// fail. The TPM counters are the ones signed in the clock information of
+/// the quote.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Continuity {
+    pub version: u32,
+    /// Number of times the agent was started with the same agent data
+    pub agent_starts: u64,
+    /// Number of TPM resets, i.e. reboots, since the TPM was cleared
+    pub tpm_reset_count: u32,
+    /// Number of TPM restarts, i.e. resumes from hibernation, since the last
+    /// reset
+    pub tpm_restart_count: u32,
+}
+
+/// The PCRs included in an integrity quote, echoed to the verifier. The
+/// PCR bank may differ from the hash algorithm of the quote signature.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct PcrSelection {
+    pub hash_alg: String,
+    pub pcrs: Vec<u32>,
+}
+
+/// Streams the JSON body of an integrity quote response.
+///
+/// The IMA measurement list can be very large, so instead of reading it into
+/// a String and serializing the whole response at once, the quote is
+/// serialized without the list and the list is then read from the file in
+/// chunks of READ_CHUNK_SIZE bytes, each escaped as part of a JSON string.
+/// The chunks are read on the blocking thread pool, not to block the
+/// executor on the file reads.
+/// The file names are not necessarily valid UTF-8, and their invalid bytes
+/// and backslashes are escaped as in `keylime::ima::escape_non_utf8`.
+struct QuoteBodyStream {
+    prefix: Option<Bytes>,
+    file: Arc<File>,
+    pos: u64,
+    end: u64,
+    // Bytes of an incomplete UTF-8 sequence carried over to the next chunk
+    pending: Vec<u8>,
+    // The read of the next chunk, if started
+    reading: Option<JoinHandle<std::io::Result<Vec<u8>>>>,
+    done: bool,
+    // Memory reserved for the response, released once the body is dropped
+    _reservations: Vec<Reservation>,
+}
+
+impl QuoteBodyStream {
+    fn new(
+        quote: KeylimeQuote,
+        file: File,
+        start: u64,
+        end: u64,
+        reservations: Vec<Reservation>,
+    ) -> Result<Self, KeylimeError> {
+        // The results object is the last field of the wrapper, so removing
+        // the two closing braces allows appending the measurement list field
+        let mut prefix = serde_json::to_vec(&JsonWrapper::success(quote))?;
+        if !prefix.ends_with(b"}}") {
+            return Err(KeylimeError::Other(
+                "unexpected quote serialization".to_string(),
+            ));
+        }
+        prefix.truncate(prefix.len() - 2);
+        prefix.extend_from_slice(b",\"ima_measurement_list\":\"");
+
+        Ok(Self {
+            prefix: Some(Bytes::from(prefix)),
+            file: Arc::new(file),
+            pos: start,
+            end,
+            pending: Vec::new(),
+            reading: None,
+            done: false,
+            _reservations: reservations,
+        })
     }
 
-    if param.nonce.len() > tpm::MAX_NONCE_SIZE {
-        warn!("Get quote returning 400 response. Nonce is too long (max size {}): {}",
-              tpm::MAX_NONCE_SIZE,
-              param.nonce.len()
-        );
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Nonce is too long (max size {}): {}",
-                tpm::MAX_NONCE_SIZE,
-                param.nonce
-            ),
-        ));
+    /// Start reading the next chunk, after the pending bytes
+    fn read_next_chunk(&mut self) -> JoinHandle<std::io::Result<Vec<u8>>> {
+        let len = (self.end - self.pos).min(READ_CHUNK_SIZE as u64) as usize;
+        let mut buf = std::mem::take(&mut self.pending);
+        let file = self.file.clone();
+        let pos = self.pos;
+        self.pos += len as u64;
+        spawn_blocking(move || {
+            let offset = buf.len();
+            buf.resize(offset + len, 0);
+            file.read_exact_at(&mut buf[offset..], pos)?;
+            Ok(buf)
+        })
+    }
+
+    fn next_chunk(
+        &mut self,
+        mut buf: Vec<u8>,
+    ) -> Result<Bytes, KeylimeError> {
+        // A multi-byte sequence cut at the end of the chunk is completed
+        // by the next one, unless the end of the range is reached
+        if self.pos < self.end {
+            self.pending = buf.split_off(incomplete_suffix(&buf));
+        }
+        Self::escape(&buf)
     }
 
-    debug!("Calling Identity Quote with nonce: {}", param.nonce);
+    fn escape(buf: &[u8]) -> Result<Bytes, KeylimeError> {
+        // Serializing a str produces a quoted JSON string; the quotes are
+        // removed as the chunks are parts of a single string
+        let escaped = serde_json::to_vec(&escape_non_utf8(buf))?;
+        Ok(Bytes::copy_from_slice(&escaped[1..escaped.len() - 1]))
+    }
+}
+
+/// Start of the UTF-8 sequence at the end of the buffer which may be
+/// completed by the following bytes, or the length of the buffer
+fn incomplete_suffix(buf: &[u8]) -> usize {
+    let mut start = 0;
+    loop {
+        match std::str::from_utf8(&buf[start..]) {
+            Ok(_) => return buf.len(),
+            Err(e) => match e.error_len() {
+                None => return start + e.valid_up_to(),
+                Some(len) => start += e.valid_up_to() + len,
+            },
+        }
+    }
+}
 
-    // must unwrap here due to lock mechanism
-    // https://github.com/rust-lang-nursery/failure/issues/192
-    let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
+impl Stream for QuoteBodyStream {
+    type Item = Result<Bytes, KeylimeError>;
 
-    let tpm_quote = match context.quote(
-        param.nonce.as_bytes(),
-        0,
-        &data.pub_key,
-        data.ak_handle,
-        data.hash_alg,
-        data.sign_alg,
-    ) {
-        Ok(quote) => quote,
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if let Some(prefix) = self.prefix.take() {
+            return Poll::Ready(Some(Ok(prefix)));
+        }
+        if self.done {
+            return Poll::Ready(None);
+        }
+        if self.reading.is_none() && self.pos < self.end {
+            self.reading = Some(self.read_next_chunk());
+        }
+        if let Some(reading) = self.reading.as_mut() {
+            let read = match Pin::new(reading).poll(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(read) => read,
+            };
+            self.reading = None;
+            let chunk = read
+                .map_err(KeylimeError::from)
+                .and_then(|read| read.map_err(KeylimeError::from))
+                .and_then(|buf| self.next_chunk(buf));
+            if chunk.is_err() {
+                self.done = true;
+            }
+            return Poll::Ready(Some(chunk));
+        }
+        self.done = true;
+        Poll::Ready(Some(Ok(Bytes::from_static(b"\"}}"))))
+    }
+}
+
+/// Respond with the quote encoded as a COSE_Sign1 structure
+fn cose_response(
+    kind: &str,
+    quote: KeylimeQuote,
+    data: &QuoteData,
+) -> HttpResponse {
+    match cose::sign_quote(quote, &data.priv_key) {
+        Ok(body) => {
+            info!("GET {kind} quote returning 200 response (COSE)");
+            HttpResponse::Ok()
+                .content_type(cose::COSE_CONTENT_TYPE)
+                .body(body)
+        }
         Err(e) => {
-            debug!("Unable to retrieve quote: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
-            );
+            warn!("GET {kind} quote returning 500 response. Unable to sign quote: {e}");
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                "Unable to retrieve quote".to_string(),
+            ))
         }
-    };
+    }
+}
 
-    let mut quote = KeylimeQuote {
-        quote: tpm_quote,
-        hash_alg: data.hash_alg.to_string(),
-        enc_alg: data.enc_alg.to_string(),
-        sign_alg: data.sign_alg.to_string(),
-        ..Default::default()
-    };
+/// Whether the request is for an API version serving the fields of the
+/// quotes added since v2.1. Older requests are also served without the
+/// parameters added since, so that the quote matches the returned fields.
+pub(crate) fn latest_quote_fields(req: &HttpRequest) -> bool {
+    request_api_version(req.path()).is_some_and(|version| {
+        api_version_at_least(version, QUOTE_FIELDS_API_VERSION)
+    })
+}
+
+/// Remove the fields added in later API versions than the one of the request
+pub(crate) fn for_api_version(
+    req: &HttpRequest,
+    mut quote: KeylimeQuote,
+) -> KeylimeQuote {
+    if !latest_quote_fields(req) {
+        quote.mb_measurement_list_etag = None;
+        quote.pcr_selection = None;
+        quote.boot_epoch = None;
+        quote.boot = None;
+        quote.asset_tag = None;
+        quote.agent_nonce = None;
+        quote.continuity = None;
+    }
+    quote
+}
 
-    match crypto::pkey_pub_to_pem(&data.pub_key) {
-        Ok(pubkey) => quote.pubkey = Some(pubkey),
+// This is a Quote request from the tenant, which does not check
+// integrity measurement. It should return this data:
+// { QuoteAIK(nonce, 16:H(NK_pub)), NK_pub }
+pub async fn identity(
+    req: HttpRequest,
+    param: web::Query<Ident>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let latest = latest_quote_fields(&req);
+    match service::identity_quote(
+        &data,
+        &param.nonce,
+        param.agent_nonce.as_deref().filter(|_| latest),
+    )
+    .map(|quote| for_api_version(&req, quote))
+    {
+        Ok(quote) if cose::accepts_cose(&req) => {
+            cose_response("identity", quote, &data)
+        }
+        Ok(quote) => {
+            info!("GET identity quote returning 200 response");
+            HttpResponse::Ok().json(JsonWrapper::success(quote))
+        }
         Err(e) => {
-            debug!("Unable to retrieve public key for quote: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
+            warn!(
+                "GET identity quote returning {} response. {e}",
+                e.status().as_u16()
             );
+            e.to_response()
         }
     }
-
-    let response = JsonWrapper::success(quote);
-    info!("GET identity quote returning 200 response");
-    HttpResponse::Ok().json(response)
 }
 
 // This is a Quote request from the cloud verifier, which will check
 // integrity measurement. The PCRs included in the Quote will be specified
-// by the mask. It should return this data:
+// by the mask, or by the list of PCR indexes, in the requested PCR bank.
+// It should return this data:
 // { QuoteAIK(nonce, 16:H(NK_pub), xi:yi), NK_pub}
 // where xi:yi are additional PCRs to be included in the quote.
+// The measured boot log is omitted if it still matches the 'mb_etag'
+// sent by the verifier.
 pub async fn integrity(
     req: HttpRequest,
     param: web::Query<Integ>,
     data: web::Data<QuoteData>,
 ) -> impl Responder {
-    // nonce, mask can only be in alphanumerical format
-    if !param.nonce.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.nonce);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!("nonce should be strictly alphanumeric: {}", param.nonce),
-        ));
-    }
-
-    if !param.mask.chars().all(char::is_alphanumeric) {
-        warn!("Get quote returning 400 response. Parameters should be strictly alphanumeric: {}", param.mask);
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!("mask should be strictly alphanumeric: {}", param.mask),
-        ));
-    }
-
-    let mask =
-        match u32::from_str_radix(param.mask.trim_start_matches("0x"), 16) {
-            Ok(mask) => mask,
-            Err(e) => {
-                return HttpResponse::BadRequest().json(JsonWrapper::error(
-                    400,
-                    format!(
-                        "mask should be a hex encoded 32-bit integer: {}",
-                        param.mask
-                    ),
-                ));
-            }
-        };
-
-    if param.nonce.len() > tpm::MAX_NONCE_SIZE {
-        warn!("Get quote returning 400 response. Nonce is too long (max size {}): {}",
-              tpm::MAX_NONCE_SIZE,
-              param.nonce.len()
-        );
-        return HttpResponse::BadRequest().json(JsonWrapper::error(
-            400,
-            format!(
-                "Nonce is too long (max size: {}): {}",
-                tpm::MAX_NONCE_SIZE,
-                param.nonce.len()
-            ),
-        ));
-    }
-
-    // If partial="0", include the public key in the quote
-    let pubkey = match &param.partial[..] {
-        "0" => {
-            let pubkey = match crypto::pkey_pub_to_pem(&data.pub_key) {
-                Ok(pubkey) => pubkey,
+    // Verifiers from other administrative domains may be restricted to a
+    // subset of the evidence
+    let scope = verifiers::evidence_scope(&req);
+    let latest = latest_quote_fields(&req);
+
+    let IntegrityQuote {
+        mut quote,
+        ima_ml,
+        mut reservations,
+    } = match service::integrity_quote(
+        &data,
+        &param.nonce,
+        param.agent_nonce.as_deref().filter(|_| latest),
+        &PcrRequest {
+            mask: param.mask.as_deref(),
+            pcrs: param.pcrs.as_deref().filter(|_| latest),
+            pcr_bank: param.pcr_bank.as_deref().filter(|_| latest),
+        },
+        &param.partial,
+        param.ima_ml_entry.as_deref(),
+        param.mb_etag.as_deref().filter(|_| latest),
+        scope,
+    ) {
+        Ok(q) => q,
+        Err(e) => {
+            warn!(
+                "GET integrity quote returning {} response. {e}",
+                e.status().as_u16()
+            );
+            return e.to_response();
+        }
+    };
+    quote = for_api_version(&req, quote);
+
+    // The COSE signature covers the whole payload, so the measurement list
+    // cannot be streamed
+    if cose::accepts_cose(&req) {
+        if let Some((file, range)) = ima_ml {
+            match service::read_ima_ml(
+                &data,
+                &file,
+                &range,
+                &mut reservations,
+            ) {
+                Ok(ml) => quote.ima_measurement_list = Some(ml),
                 Err(e) => {
-                    debug!("Unable to retrieve public key: {:?}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
-                            "Unable to retrieve public key".to_string(),
-                        ),
+                    warn!(
+                        "GET integrity quote returning {} response. {e}",
+                        e.status().as_u16()
                     );
+                    return e.to_response();
                 }
-            };
-            Some(pubkey)
-        }
-        "1" => None,
-        _ => {
-            warn!("Get quote returning 400 response. uri must contain key 'partial' and value '0' or '1'");
-            return HttpResponse::BadRequest().json(JsonWrapper::error(
-                400,
-                "uri must contain key 'partial' and value '0' or '1'"
-                    .to_string(),
-            ));
+            }
         }
-    };
-
-    debug!(
-        "Calling Integrity Quote with nonce: {}, mask: {}",
-        param.nonce, param.mask
-    );
+        return cose_response("integrity", quote, &data);
+    }
 
-    // If an index was provided, the request is for the entries starting from the given index
-    // (iterative attestation). Otherwise the request is for the whole list.
-    let nth_entry = match &param.ima_ml_entry {
-        None => 0,
-        Some(idx) => idx.parse::<u64>().unwrap_or(0),
+    let Some((file, range)) = ima_ml else {
+        let response = JsonWrapper::success(quote);
+        info!("GET integrity quote returning 200 response");
+        return HttpResponse::Ok().json(response);
     };
 
-    // must unwrap here due to lock mechanism
-    // https://github.com/rust-lang-nursery/failure/issues/192
-    let mut context = data.tpmcontext.lock().unwrap(); //#[allow_ci]
-
-    // Generate the ID quote.
-    let tpm_quote = match context.quote(
-        param.nonce.as_bytes(),
-        mask,
-        &data.pub_key,
-        data.ak_handle,
-        data.hash_alg,
-        data.sign_alg,
-    ) {
-        Ok(tpm_quote) => tpm_quote,
-        Err(e) => {
-            debug!("Unable to retrieve quote: {:?}", e);
-            return HttpResponse::InternalServerError().json(
+    // The measurement list is read one chunk at a time, which is escaped
+    // into a new buffer before being sent
+    match data.memory_budget.try_reserve(2 * READ_CHUNK_SIZE) {
+        Some(r) => reservations.push(r),
+        None => {
+            warn!("GET integrity quote returning 503 response. Memory budget exceeded");
+            return HttpResponse::ServiceUnavailable().json(
                 JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
+                    503,
+                    "Memory budget exceeded, try again later".to_string(),
                 ),
             );
         }
-    };
-
-    let id_quote = KeylimeQuote {
-        quote: tpm_quote,
-        hash_alg: data.hash_alg.to_string(),
-        enc_alg: data.enc_alg.to_string(),
-        sign_alg: data.sign_alg.to_string(),
-        ..Default::default()
-    };
+    }
 
-    // If PCR 0 is included in the mask, obtain the measured boot
-    let mut mb_measurement_list = None;
-    match tpm::check_mask(mask, &PcrSlot::Slot0) {
-        Ok(true) => {
-            if let Some(measuredboot_ml_file) = &data.measuredboot_ml_file {
-                let mut ml = Vec::<u8>::new();
-                let mut f = measuredboot_ml_file.lock().unwrap(); //#[allow_ci]
-                if let Err(e) = f.rewind() {
-                    debug!("Failed to rewind measured boot file: {}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
-                            "Unable to retrieve quote".to_string(),
-                        ),
-                    );
-                }
-                mb_measurement_list = match f.read_to_end(&mut ml) {
-                    Ok(_) => Some(general_purpose::STANDARD.encode(ml)),
-                    Err(e) => {
-                        warn!("Could not read TPM2 event log: {}", e);
-                        None
-                    }
-                };
-            }
+    match QuoteBodyStream::new(
+        quote,
+        file,
+        range.start,
+        range.end,
+        reservations,
+    ) {
+        Ok(body) => {
+            info!("GET integrity quote returning 200 response");
+            HttpResponse::Ok()
+                .content_type("application/json")
+                .streaming(body)
         }
         Err(e) => {
-            debug!("Unable to check PCR mask: {:?}", e);
-            return HttpResponse::InternalServerError().json(
-                JsonWrapper::error(
-                    500,
-                    "Unable to retrieve quote".to_string(),
-                ),
-            );
+            debug!("Unable to serialize quote: {:?}", e);
+            HttpResponse::InternalServerError().json(JsonWrapper::error(
+                500,
+                "Unable to retrieve quote".to_string(),
+            ))
         }
-        _ => (),
     }
+}
 
-    // Generate the measurement list
-    let (ima_measurement_list, ima_measurement_list_entry, num_entries) =
-        if let Some(ima_file) = &data.ima_ml_file {
-            let mut ima_ml = data.ima_ml.lock().unwrap(); //#[allow_ci]
-            match ima_ml.read(
-                &mut ima_file.lock().unwrap(), //#[allow_ci]
-                nth_entry,
-            ) {
-                Ok(result) => {
-                    (Some(result.0), Some(result.1), Some(result.2))
-                }
-                Err(e) => {
-                    debug!("Unable to read measurement list: {:?}", e);
-                    return HttpResponse::InternalServerError().json(
-                        JsonWrapper::error(
-                            500,
-                            "Unable to retrieve quote".to_string(),
-                        ),
-                    );
-                }
-            }
-        } else {
-            (None, None, None)
+#[cfg(test)]
+mod stream_tests {
+    use super::*;
+    use crate::kernel_boot::BootKind;
+    use futures::StreamExt;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    #[actix_rt::test]
+    async fn test_quote_body_stream() {
+        // Use a multi-byte character split across the chunk boundary and
+        // characters that need escaping
+        let mut ml = "a".repeat(READ_CHUNK_SIZE - 1);
+        ml.push_str(
+            "\u{e9}\"quoted\"\tpath\\\n10 abc ima-ng x /usr/bin/ls\n",
+        );
+
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(ml.as_bytes()).unwrap(); //#[allow_ci]
+        tf.flush().unwrap(); //#[allow_ci]
+        let file = File::open(tf.path()).unwrap(); //#[allow_ci]
+
+        let quote = KeylimeQuote {
+            quote: "rquote".to_string(),
+            hash_alg: "sha256".to_string(),
+            ima_measurement_list_entry: Some(0),
+            ..Default::default()
         };
 
-    // Generate the final quote based on the ID quote
-    let quote = KeylimeQuote {
-        pubkey,
-        ima_measurement_list,
-        mb_measurement_list,
-        ima_measurement_list_entry,
-        ..id_quote
-    };
+        let mut stream =
+            QuoteBodyStream::new(quote, file, 0, ml.len() as u64, vec![])
+                .unwrap(); //#[allow_ci]
+        let mut body = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            body.extend_from_slice(&chunk.unwrap()); //#[allow_ci]
+        }
+
+        let result: JsonWrapper<KeylimeQuote> =
+            serde_json::from_slice(&body).unwrap(); //#[allow_ci]
+        assert_eq!(result.code, 200);
+        assert_eq!(result.results.quote, "rquote");
+        assert_eq!(result.results.ima_measurement_list_entry, Some(0));
+        // The backslash is escaped
+        assert_eq!(
+            result.results.ima_measurement_list.unwrap(), //#[allow_ci]
+            ml.replace('\\', "\\\\")
+        );
+    }
+
+    #[actix_rt::test]
+    async fn test_quote_body_stream_non_utf8() {
+        // Latin-1 file names, one of them cut at the chunk boundary, and a
+        // truncated sequence at the end of the list
+        let mut ml = vec![b'a'; READ_CHUNK_SIZE - 1];
+        ml.extend_from_slice(b"\xc3\xa9\xe9t\xe9\n\xc3");
+
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(&ml).unwrap(); //#[allow_ci]
+        tf.flush().unwrap(); //#[allow_ci]
+        let file = File::open(tf.path()).unwrap(); //#[allow_ci]
+
+        let mut stream = QuoteBodyStream::new(
+            KeylimeQuote::default(),
+            file,
+            0,
+            ml.len() as u64,
+            vec![],
+        )
+        .unwrap(); //#[allow_ci]
+        let mut body = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            body.extend_from_slice(&chunk.unwrap()); //#[allow_ci]
+        }
+
+        let result: JsonWrapper<KeylimeQuote> =
+            serde_json::from_slice(&body).unwrap(); //#[allow_ci]
+        assert_eq!(
+            result.results.ima_measurement_list.unwrap(), //#[allow_ci]
+            escape_non_utf8(&ml)
+        );
+        assert!(body.ends_with(b"\\\\xe9t\\\\xe9\\n\\\\xc3\"}}"));
+    }
 
-    let response = JsonWrapper::success(quote);
-    info!("GET integrity quote returning 200 response");
-    HttpResponse::Ok().json(response)
+    #[test]
+    fn test_for_api_version() {
+        let quote = || KeylimeQuote {
+            quote: "r".to_string(),
+            hash_alg: "sha256".to_string(),
+            enc_alg: "rsa".to_string(),
+            sign_alg: "rsassa".to_string(),
+            pubkey: Some("pubkey".to_string()),
+            ima_measurement_list: Some("ml".to_string()),
+            mb_measurement_list: Some("mb".to_string()),
+            mb_measurement_list_etag: Some("etag".to_string()),
+            ima_measurement_list_entry: Some(0),
+            pcr_selection: Some(PcrSelection {
+                hash_alg: "sha384".to_string(),
+                pcrs: vec![0, 10],
+            }),
+            boot_epoch: Some(2),
+            boot: Some(BootInfo {
+                boot_id: "id".to_string(),
+                kernel_build_id: None,
+                kernel_version: "6.1".to_string(),
+                boot_kind: BootKind::Reboot,
+            }),
+            asset_tag: Some(AssetTag {
+                nv_index: "0x1500020".to_string(),
+                data: b"tag".to_vec(),
+                signature: None,
+            }),
+            agent_nonce: Some("abcd".to_string()),
+            continuity: Some(Continuity {
+                version: CONTINUITY_VERSION,
+                agent_starts: 3,
+                tpm_reset_count: 5,
+                tpm_restart_count: 1,
+            }),
+        };
+        let request = |path| {
+            actix_web::test::TestRequest::get()
+                .uri(path)
+                .to_http_request()
+        };
+        let keys = |quote: &KeylimeQuote| {
+            let json = serde_json::to_value(quote).unwrap(); //#[allow_ci]
+            let mut keys = json
+                .as_object()
+                .unwrap() //#[allow_ci]
+                .keys()
+                .cloned()
+                .collect::<Vec<String>>();
+            keys.sort();
+            keys
+        };
+
+        let served =
+            for_api_version(&request("/v2.2/quotes/identity"), quote());
+        assert_eq!(keys(&served).len(), 15);
+        let json = serde_json::to_value(&served).unwrap(); //#[allow_ci]
+        assert_eq!(json["continuity"]["version"], 1);
+        assert_eq!(json["continuity"]["agent_starts"], 3);
+        assert_eq!(json["continuity"]["tpm_reset_count"], 5);
+
+        // Older verifiers receive the fields of API version 2.1 only
+        let served =
+            for_api_version(&request("/v2.1/quotes/integrity"), quote());
+        assert_eq!(
+            keys(&served),
+            [
+                "enc_alg",
+                "hash_alg",
+                "ima_measurement_list",
+                "ima_measurement_list_entry",
+                "mb_measurement_list",
+                "pubkey",
+                "quote",
+                "sign_alg",
+            ]
+        );
+    }
 }
 
 #[cfg(feature = "testing")]
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{common::API_VERSION, crypto::testing::pkey_pub_from_pem};
+    use crate::{
+        common::API_VERSION, crypto::testing::pkey_pub_from_pem, tpm,
+    };
     use actix_web::{test, web, App};
+    use coset::TaggedCborSerializable;
 
     #[actix_rt::test]
     async fn test_identity() {
@@ -374,7 +651,7 @@ mod tests {
         );
         assert!(result.results.quote.starts_with('r'));
 
-        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let mut context = quotedata.tpm();
         tpm::testing::check_quote(
             context.as_mut(),
             quotedata.ak_handle,
@@ -384,6 +661,35 @@ mod tests {
         .expect("unable to verify quote");
     }
 
+    #[actix_rt::test]
+    async fn test_identity_cose() {
+        let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let mut app =
+            test::init_service(App::new().app_data(quotedata.clone()).route(
+                &format!("/{API_VERSION}/quotes/identity"),
+                web::get().to(identity),
+            ))
+            .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/{API_VERSION}/quotes/identity?nonce=1234567890ABCDEFHIJ",
+            ))
+            .insert_header(("Accept", cose::COSE_CONTENT_TYPE))
+            .to_request();
+
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get("Content-Type").unwrap(), //#[allow_ci]
+            cose::COSE_CONTENT_TYPE
+        );
+
+        let body = test::read_body(resp).await;
+        let sign1 = coset::CoseSign1::from_tagged_slice(&body).unwrap(); //#[allow_ci]
+        assert!(sign1.payload.is_some());
+    }
+
     #[actix_rt::test]
     async fn test_integrity_pre() {
         let quotedata = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
@@ -408,6 +714,13 @@ mod tests {
         assert_eq!(result.results.hash_alg.as_str(), "sha256");
         assert_eq!(result.results.enc_alg.as_str(), "rsa");
         assert_eq!(result.results.sign_alg.as_str(), "rsassa");
+        assert_eq!(
+            result.results.pcr_selection,
+            Some(PcrSelection {
+                hash_alg: "sha256".to_string(),
+                pcrs: vec![15, 16, 22],
+            })
+        );
         assert!(
             pkey_pub_from_pem(&result.results.pubkey.unwrap()) //#[allow_ci]
                 .unwrap() //#[allow_ci]
@@ -426,7 +739,7 @@ mod tests {
                     );
                     assert!(result.results.quote.starts_with('r'));
 
-                    let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+                    let mut context = quotedata.tpm();
                     tpm::testing::check_quote(
                         context.as_mut(),
                         quotedata.ak_handle,
@@ -485,7 +798,7 @@ mod tests {
             panic!("IMA file was None"); //#[allow_ci]
         }
 
-        let mut context = quotedata.tpmcontext.lock().unwrap(); //#[allow_ci]
+        let mut context = quotedata.tpm();
         tpm::testing::check_quote(
             context.as_mut(),
             quotedata.ak_handle,
@@ -1,11 +1,16 @@
 use crate::error::Error;
 
+use crate::asset_tag::AssetTag;
 use crate::common::API_VERSION;
+use crate::endpoint::Endpoint;
+use crate::http_client::HttpClient;
+use crate::peer_tls::PeerTls;
 use crate::serialization::*;
+use hyper::Method;
 use log::*;
 use openssl::x509::X509;
 use serde::{Deserialize, Serialize};
-use serde_json::Number;
+use serde_json::{Number, Value};
 
 fn is_empty(buf: &[u8]) -> bool {
     buf.is_empty()
@@ -52,6 +57,8 @@ struct Register<'a> {
     ip: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     port: Option<u32>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    asset_tag: Option<&'a AssetTag>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -75,45 +82,89 @@ pub struct Response<T> {
     results: T,
 }
 
+/// Send the request to the registrar and decode the results of the response
+async fn send<T: Serialize, R: serde::de::DeserializeOwned>(
+    registrar: &Endpoint,
+    client: &HttpClient,
+    tls: &PeerTls,
+    method: Method,
+    path: &str,
+    data: &T,
+) -> crate::error::Result<R> {
+    let addr = registrar.url(tls.scheme(), path);
+    let body = client
+        .retry(&addr, || async {
+            let (code, body) = client
+                .send_json(method.clone(), registrar, path, data, tls)
+                .await?;
+            if !(200..300).contains(&code) {
+                // The status describes why the request was refused
+                let status = serde_json::from_slice::<Response<Value>>(&body)
+                    .map(|r| r.status)
+                    .unwrap_or_default();
+                return Err(Error::Registrar {
+                    addr: addr.clone(),
+                    code,
+                    status,
+                });
+            }
+            Ok(body)
+        })
+        .await?;
+
+    let resp: Response<R> = serde_json::from_slice(&body)?;
+    Ok(resp.results)
+}
+
 pub(crate) async fn do_activate_agent(
-    registrar_ip: &str,
-    registrar_port: u32,
+    registrar: &Endpoint,
+    client: &HttpClient,
+    tls: &PeerTls,
     agent_uuid: &str,
     auth_tag: &str,
 ) -> crate::error::Result<()> {
     let data = Activate { auth_tag };
 
-    #[cfg(test)]
-    let addr = format!("http://{registrar_ip}:{registrar_port}");
-
-    #[cfg(not(test))]
-    let addr = format!(
-        "http://{registrar_ip}:{registrar_port}/{API_VERSION}/agents/{agent_uuid}"
-    );
+    let path = format!("{API_VERSION}/agents/{agent_uuid}");
 
     info!(
         "Requesting agent activation from {} for {}",
-        addr, agent_uuid
+        registrar.url(tls.scheme(), &path),
+        agent_uuid
     );
 
-    let resp = reqwest::Client::new().put(&addr).json(&data).send().await?;
-
-    if !resp.status().is_success() {
-        return Err(Error::Registrar {
-            addr,
-            code: resp.status().as_u16(),
-        });
-    }
-
-    let resp: Response<ActivateResponseResults> = resp.json().await?;
+    let _: ActivateResponseResults =
+        send(registrar, client, tls, Method::PUT, &path, &data).await?;
 
     Ok(())
 }
 
+/// The status of the registrar response rejecting an activation because the
+/// auth tag does not match the one expected for the registered AK
+const AUTH_TAG_MISMATCH: &str = "does not match expected value";
+
+/// Check whether the error during the registration or activation indicates
+/// that the AK is not accepted anymore, which can be fixed by registering
+/// again with a new AK. This is the case when the TPM fails to decrypt the
+/// credential, or when the registrar rejects the activation because the auth
+/// tag does not match the registered AK. Other rejections, such as an invalid
+/// EK certificate, are not fixed by a new AK.
+pub(crate) fn is_stale_ak(e: &Error) -> bool {
+    match e.root() {
+        // A busy TPM does not mean the AK is not accepted anymore
+        Error::Tpm(_) | Error::Tss2 { .. } => !e.is_retryable(),
+        Error::Registrar { code, status, .. } => {
+            *code == 400 && status.contains(AUTH_TAG_MISMATCH)
+        }
+        _ => false,
+    }
+}
+
 #[allow(clippy::too_many_arguments)]
 pub(crate) async fn do_register_agent(
-    registrar_ip: &str,
-    registrar_port: u32,
+    registrar: &Endpoint,
+    client: &HttpClient,
+    tls: &PeerTls,
     agent_uuid: &str,
     ek_tpm: &[u8],
     ekcert: Option<Vec<u8>>,
@@ -125,8 +176,8 @@ pub(crate) async fn do_register_agent(
     iak_attest: Option<Vec<u8>>,
     iak_sign: Option<Vec<u8>>,
     mtls_cert_x509: Option<&X509>,
-    ip: &str,
-    port: u32,
+    contact: &Endpoint,
+    asset_tag: Option<&AssetTag>,
 ) -> crate::error::Result<Vec<u8>> {
     let mtls_cert = match mtls_cert_x509 {
         Some(cert) => Some(String::from_utf8(cert.to_pem()?)?),
@@ -143,10 +194,11 @@ pub(crate) async fn do_register_agent(
         None => None,
     };
 
-    let ip = if ip.is_empty() {
+    // The contact address is sent without the brackets of IPv6 addresses
+    let ip = if contact.host().is_empty() {
         None
     } else {
-        Some(ip.to_string())
+        Some(contact.host().to_string())
     };
 
     let data = Register {
@@ -161,42 +213,22 @@ pub(crate) async fn do_register_agent(
         iak_sign,
         mtls_cert,
         ip,
-        port: Some(port),
+        port: Some(contact.port()),
+        asset_tag,
     };
 
-    #[cfg(test)]
-    let addr = format!("http://{registrar_ip}:{registrar_port}");
-
-    #[cfg(not(test))]
-    let addr = format!(
-        "http://{registrar_ip}:{registrar_port}/{API_VERSION}/agents/{agent_uuid}"
-    );
+    let path = format!("{API_VERSION}/agents/{agent_uuid}");
 
     info!(
         "Requesting agent registration from {} for {}",
-        addr, agent_uuid
+        registrar.url(tls.scheme(), &path),
+        agent_uuid
     );
 
-    let resp = reqwest::Client::new()
-        .post(&addr)
-        .json(&data)
-        .send()
-        .await?;
+    let results: RegisterResponseResults =
+        send(registrar, client, tls, Method::POST, &path, &data).await?;
 
-    if !resp.status().is_success() {
-        return Err(Error::Registrar {
-            addr,
-            code: resp.status().as_u16(),
-        });
-    }
-
-    let resp: Response<RegisterResponseResults> = resp.json().await?;
-
-    if resp.results.blob.is_some() {
-        Ok(resp.results.blob.unwrap()) //#[allow_ci]
-    } else {
-        Ok(Vec::new())
-    }
+    Ok(results.blob.unwrap_or_default())
 }
 
 #[cfg(feature = "testing")]
@@ -204,9 +236,14 @@ pub(crate) async fn do_register_agent(
 mod tests {
     use super::*;
     use crate::crypto;
+    use crate::http_client::HttpClientOptions;
     use wiremock::matchers::{any, method};
     use wiremock::{Mock, MockServer, ResponseTemplate};
 
+    fn client() -> HttpClient {
+        HttpClient::new(HttpClientOptions::default()).unwrap() //#[allow_ci]
+    }
+
     #[actix_rt::test]
     async fn mock_register_agent_ok() {
         let response: Response<RegisterResponseResults> = Response {
@@ -226,17 +263,15 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
-
-        let ip = uri[0];
-        let port = uri[1].parse().unwrap(); //#[allow_ci]
+        let registrar = Endpoint::new(uri[0], uri[1].parse().unwrap()); //#[allow_ci]
 
         let mock_data = [0u8; 1];
         let priv_key = crypto::testing::rsa_generate(2048).unwrap(); //#[allow_ci]
         let cert = crypto::generate_x509(&priv_key, "uuid").unwrap(); //#[allow_ci]
         let response = do_register_agent(
-            ip,
-            port,
+            &registrar,
+            &client(),
+            &PeerTls::default(),
             "uuid",
             &mock_data,
             Some(mock_data.to_vec()),
@@ -248,8 +283,8 @@ mod tests {
             None,
             None,
             Some(&cert),
-            "",
-            0,
+            &Endpoint::new("", 0),
+            None,
         )
         .await;
         assert!(response.is_ok());
@@ -274,17 +309,15 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
-
-        let ip = uri[0];
-        let port = uri[1].parse().unwrap(); //#[allow_ci]
+        let registrar = Endpoint::new(uri[0], uri[1].parse().unwrap()); //#[allow_ci]
 
         let mock_data = [0u8; 1];
         let priv_key = crypto::testing::rsa_generate(2048).unwrap(); //#[allow_ci]
         let cert = crypto::generate_x509(&priv_key, "uuid").unwrap(); //#[allow_ci]
         let response = do_register_agent(
-            ip,
-            port,
+            &registrar,
+            &client(),
+            &PeerTls::default(),
             "uuid",
             &mock_data,
             None,
@@ -296,8 +329,8 @@ mod tests {
             None,
             None,
             Some(&cert),
-            "",
-            0,
+            &Endpoint::new("", 0),
+            None,
         )
         .await;
         assert!(response.is_ok());
@@ -318,17 +351,15 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
-
-        let ip = uri[0];
-        let port = uri[1].parse().unwrap(); //#[allow_ci]
+        let registrar = Endpoint::new(uri[0], uri[1].parse().unwrap()); //#[allow_ci]
 
         let mock_data = [0u8; 1];
         let priv_key = crypto::testing::rsa_generate(2048).unwrap(); //#[allow_ci]
         let cert = crypto::generate_x509(&priv_key, "uuid").unwrap(); //#[allow_ci]
         let response = do_register_agent(
-            ip,
-            port,
+            &registrar,
+            &client(),
+            &PeerTls::default(),
             "uuid",
             &mock_data,
             Some(mock_data.to_vec()),
@@ -340,14 +371,40 @@ mod tests {
             None,
             None,
             Some(&cert),
-            "",
-            0,
+            &Endpoint::new("", 0),
+            None,
         )
         .await;
         assert!(response.is_err());
         assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
     }
 
+    #[test]
+    fn test_is_stale_ak() {
+        assert!(is_stale_ak(&Error::Registrar {
+            addr: "http://127.0.0.1:8890".to_string(),
+            code: 400,
+            status: "Auth tag 0123 for agent d432fbb3-d2f1-4a97-9ef7-75bd81c00000 does not match expected value".to_string(),
+        }));
+        // Other rejections are not fixed by a new AK
+        assert!(!is_stale_ak(&Error::Registrar {
+            addr: "http://127.0.0.1:8890".to_string(),
+            code: 400,
+            status: "Invalid EK certificate".to_string(),
+        }));
+        assert!(!is_stale_ak(&Error::Registrar {
+            addr: "http://127.0.0.1:8890".to_string(),
+            code: 404,
+            status: "agent id not found".to_string(),
+        }));
+        assert!(!is_stale_ak(&Error::Registrar {
+            addr: "http://127.0.0.1:8890".to_string(),
+            code: 500,
+            status: String::new(),
+        }));
+        assert!(!is_stale_ak(&Error::Configuration(String::new())));
+    }
+
     #[actix_rt::test]
     async fn mock_activate_agent_ok() {
         let response: Response<ActivateResponseResults> = Response {
@@ -367,12 +424,16 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
-
-        let ip = uri[0];
-        let port = uri[1].parse().unwrap(); //#[allow_ci]
+        let registrar = Endpoint::new(uri[0], uri[1].parse().unwrap()); //#[allow_ci]
 
-        let response = do_activate_agent(ip, port, "uuid", "tag").await;
+        let response = do_activate_agent(
+            &registrar,
+            &client(),
+            &PeerTls::default(),
+            "uuid",
+            "tag",
+        )
+        .await;
         assert!(response.is_ok());
     }
 
@@ -391,12 +452,16 @@ mod tests {
             .collect::<Vec<&str>>();
         assert_eq!(uri.len(), 2);
 
-        let addr = format!("http://{}:{}", uri[0], uri[1]);
+        let registrar = Endpoint::new(uri[0], uri[1].parse().unwrap()); //#[allow_ci]
 
-        let ip = uri[0];
-        let port = uri[1].parse().unwrap(); //#[allow_ci]
-
-        let response = do_activate_agent(ip, port, "uuid", "tag").await;
+        let response = do_activate_agent(
+            &registrar,
+            &client(),
+            &PeerTls::default(),
+            "uuid",
+            "tag",
+        )
+        .await;
         assert!(response.is_err());
         assert_eq!(response.err().unwrap().http_code().unwrap(), 404); //#[allow_ci]
     }
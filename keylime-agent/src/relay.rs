@@ -0,0 +1,327 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Relay of the quotes of nested agents
+//!
+//! In nested environments, e.g. VMs running on an attested host, the agent
+//! of the host can relay the quotes of the agents of its guests, listed in
+//! 'relay_guests'. The verifier requests the quote of a guest from the host,
+//! which forwards the request to the guest and returns the response of the
+//! guest unchanged, together with a quote of the host bound to it.
+//!
+//! The nonce of the host quote is the hex encoded SHA-256 digest of the
+//! nonce of the verifier followed by the raw response of the guest, so that
+//! the verifier can check that both quotes were produced for its request,
+//! with the guest running on this host.
+
+use crate::{
+    common::{JsonWrapper, API_VERSION},
+    config::KeylimeConfig,
+    endpoint::Endpoint,
+    error::{Error, Result},
+    http_client::HttpClient,
+    peer_tls::PeerTls,
+    quotes_handler::KeylimeQuote,
+    service::{self, IntegrityQuote, PcrRequest, ServiceError},
+    verifiers, QuoteData,
+};
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use log::*;
+use openssl::sha::Sha256;
+use serde::{Deserialize, Serialize};
+
+/// The algorithm binding the host quote to the guest response
+const BINDING_ALG: &str = "sha256";
+
+/// The query parameters used by the host, which are not forwarded
+const HOST_PARAMS: [&str; 3] = ["host_mask", "host_pcrs", "host_pcr_bank"];
+
+/// An agent of a guest whose quotes are relayed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Guest {
+    pub uuid: String,
+    pub endpoint: Endpoint,
+}
+
+/// Parse the comma separated list of the guests, as "uuid@host:port"
+pub(crate) fn parse_guests(list: &str) -> Result<Vec<Guest>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|guest| !guest.is_empty())
+        .map(|guest| {
+            let invalid = || {
+                Error::Configuration(format!(
+                    "Invalid relay guest '{guest}': expected uuid@host:port"
+                ))
+            };
+            let (uuid, address) =
+                guest.split_once('@').ok_or_else(invalid)?;
+            let (host, port) =
+                address.rsplit_once(':').ok_or_else(invalid)?;
+            let port = port.parse::<u16>().map_err(|_| invalid())?;
+            if uuid.is_empty() || host.is_empty() {
+                return Err(invalid());
+            }
+            Ok(Guest {
+                uuid: uuid.to_string(),
+                endpoint: Endpoint::new(host, port.into()),
+            })
+        })
+        .collect()
+}
+
+/// The guests whose quotes are relayed, and how they are contacted
+#[derive(Debug)]
+pub(crate) struct Relay {
+    guests: Vec<Guest>,
+    client: HttpClient,
+    tls: PeerTls,
+}
+
+impl Relay {
+    /// The relay configured in 'relay_guests', if any
+    pub(crate) fn new(
+        config: &KeylimeConfig,
+        client: HttpClient,
+    ) -> Result<Option<Self>> {
+        let guests = parse_guests(&config.agent.relay_guests)?;
+        if guests.is_empty() {
+            return Ok(None);
+        }
+        let tls = PeerTls::new(
+            &config.agent.relay_guest_tls,
+            &config.agent.relay_guest_tls_ca,
+            &config.agent.relay_guest_tls_pins,
+            "",
+            config.agent.fips_mode,
+        )?;
+        info!("Relaying the quotes of {} guest agents", guests.len());
+        Ok(Some(Relay {
+            guests,
+            client,
+            tls,
+        }))
+    }
+
+    fn guest(&self, uuid: &str) -> Option<&Guest> {
+        self.guests.iter().find(|g| g.uuid == uuid)
+    }
+}
+
+/// The quote of the guest, bound to the quote of the host
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct LayeredEvidence {
+    pub guest_uuid: String,
+    /// The raw JSON response of the guest agent
+    pub guest_response: String,
+    pub binding_alg: String,
+    pub host: KeylimeQuote,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RelayParams {
+    nonce: String,
+    host_mask: Option<String>,
+    host_pcrs: Option<String>,
+    host_pcr_bank: Option<String>,
+}
+
+/// The nonce of the host quote, bound to the nonce of the verifier and the
+/// response of the guest
+fn binding_nonce(nonce: &str, guest_response: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(nonce.as_bytes());
+    hasher.update(guest_response);
+    hex::encode(hasher.finish())
+}
+
+/// The query string forwarded to the guest, without the host parameters
+fn guest_query(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|param| {
+            let name = param.split('=').next().unwrap_or_default();
+            !param.is_empty() && !HOST_PARAMS.contains(&name)
+        })
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+/// Quote the host over the binding nonce, over the requested PCRs of the
+/// host if any
+fn host_quote(
+    req: &HttpRequest,
+    data: &QuoteData,
+    param: &RelayParams,
+    nonce: &str,
+) -> std::result::Result<KeylimeQuote, ServiceError> {
+    if param.host_mask.is_none() && param.host_pcrs.is_none() {
+        return service::identity_quote(data, nonce, None);
+    }
+
+    let IntegrityQuote {
+        mut quote,
+        ima_ml,
+        mut reservations,
+    } = service::integrity_quote(
+        data,
+        nonce,
+        None,
+        &PcrRequest {
+            mask: param.host_mask.as_deref(),
+            pcrs: param.host_pcrs.as_deref(),
+            pcr_bank: param.host_pcr_bank.as_deref(),
+        },
+        "0",
+        None,
+        None,
+        verifiers::evidence_scope(req),
+    )?;
+    if let Some((file, range)) = ima_ml {
+        quote.ima_measurement_list = Some(service::read_ima_ml(
+            data,
+            &file,
+            &range,
+            &mut reservations,
+        )?);
+    }
+    Ok(quote)
+}
+
+async fn relay_quote(
+    req: &HttpRequest,
+    relay: &Relay,
+    data: &QuoteData,
+    guest_uuid: &str,
+    kind: &str,
+    param: &RelayParams,
+) -> std::result::Result<LayeredEvidence, ServiceError> {
+    if kind != "identity" && kind != "integrity" {
+        return Err(ServiceError::BadRequest(format!(
+            "unknown quote type '{kind}', expected 'identity' or 'integrity'"
+        )));
+    }
+    let Some(guest) = relay.guest(guest_uuid) else {
+        return Err(ServiceError::BadRequest(format!(
+            "agent {guest_uuid} is not a guest of this agent"
+        )));
+    };
+
+    let path = format!(
+        "/{API_VERSION}/quotes/{kind}?{}",
+        guest_query(req.query_string())
+    );
+    let (status, body) = relay
+        .client
+        .get(&guest.endpoint, &path, &relay.tls)
+        .await
+        .map_err(|e| {
+            warn!("Unable to reach the guest agent {guest_uuid}: {e}");
+            ServiceError::Unavailable(format!(
+                "unable to reach the guest agent {guest_uuid}"
+            ))
+        })?;
+    if status != 200 {
+        return Err(ServiceError::Unavailable(format!(
+            "the guest agent {guest_uuid} returned a {status} response"
+        )));
+    }
+    let guest_response = String::from_utf8(body).map_err(|_| {
+        ServiceError::Unavailable(format!(
+            "invalid response from the guest agent {guest_uuid}"
+        ))
+    })?;
+
+    let nonce = binding_nonce(&param.nonce, guest_response.as_bytes());
+    let host = host_quote(req, data, param, &nonce)?;
+
+    Ok(LayeredEvidence {
+        guest_uuid: guest_uuid.to_string(),
+        guest_response,
+        binding_alg: BINDING_ALG.to_string(),
+        host,
+    })
+}
+
+/// Handles the requests for the quotes of the guests
+pub(crate) async fn quote(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    param: web::Query<RelayParams>,
+    relay: web::Data<Relay>,
+    data: web::Data<QuoteData>,
+) -> impl Responder {
+    let (guest_uuid, kind) = path.into_inner();
+    match relay_quote(&req, &relay, &data, &guest_uuid, &kind, &param).await {
+        Ok(evidence) => {
+            info!("GET relayed {kind} quote of {guest_uuid} returning 200 response");
+            HttpResponse::Ok().json(JsonWrapper::success(evidence))
+        }
+        Err(e) => {
+            warn!(
+                "GET relayed {kind} quote of {guest_uuid} returning {} response. {e}",
+                e.status().as_u16()
+            );
+            e.to_response()
+        }
+    }
+}
+
+/// Register the relay endpoints in the API version scope
+pub(crate) fn configure(
+    cfg: &mut web::ServiceConfig,
+    relay: web::Data<Relay>,
+) {
+    _ = cfg.service(
+        web::scope("/relay").app_data(relay).service(
+            web::resource("/{guest_uuid}/quotes/{kind}")
+                .route(web::get().to(quote)),
+        ),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_guests() {
+        assert_eq!(
+            parse_guests("vm1@192.168.122.10:9002, vm2@[fe80::1]:9002")
+                .unwrap(), //#[allow_ci]
+            vec![
+                Guest {
+                    uuid: "vm1".to_string(),
+                    endpoint: Endpoint::new("192.168.122.10", 9002),
+                },
+                Guest {
+                    uuid: "vm2".to_string(),
+                    endpoint: Endpoint::new("fe80::1", 9002),
+                },
+            ]
+        );
+        assert!(parse_guests("").unwrap().is_empty()); //#[allow_ci]
+        for guest in ["vm1", "vm1@host", "@host:9002", "vm1@:9002", "vm1@h:x"]
+        {
+            assert!(parse_guests(guest).is_err());
+        }
+    }
+
+    #[test]
+    fn test_guest_query() {
+        assert_eq!(
+            guest_query("nonce=abc&mask=0x400&host_mask=0x1&partial=0"),
+            "nonce=abc&mask=0x400&partial=0"
+        );
+        assert_eq!(guest_query("host_pcrs=0,7&nonce=abc&"), "nonce=abc");
+    }
+
+    #[test]
+    fn test_binding_nonce() {
+        let nonce = binding_nonce("abc", b"{}");
+        assert_eq!(nonce.len(), 64);
+        assert!(nonce.len() <= keylime::tpm::MAX_NONCE_SIZE);
+        assert_ne!(nonce, binding_nonce("abc", b"{ }"));
+        assert_ne!(nonce, binding_nonce("abd", b"{}"));
+    }
+}
@@ -0,0 +1,370 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+use crate::error::{Error, Result};
+use actix_web::{
+    dev::ServiceRequest,
+    http::header::{CONTENT_LENGTH, TRANSFER_ENCODING},
+};
+use log::*;
+use std::{
+    fs,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Maximum size of the request bodies, reserved from the memory budget for
+/// the requests whose size is not known in advance
+pub(crate) const MAX_REQUEST_BODY: usize = 2 * 1024 * 1024;
+
+/// Number of file descriptors kept free for the work of the requests
+/// already admitted, e.g. opening the measurement lists or the TPM device
+const FD_HEADROOM: usize = 32;
+
+/// Directory listing the file descriptors open in the process
+const PROC_SELF_FD: &str = "/proc/self/fd";
+
+/// Interval between the counts of the open file descriptors, which are
+/// not listed again for every request
+const FD_SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Accounts for the memory used by in-flight payloads, quote responses and
+/// log buffers.
+///
+/// A limit of 0 disables the enforcement, but the usage is still tracked.
+#[derive(Debug, Default)]
+pub(crate) struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+/// Memory reserved from a MemoryBudget, released when dropped
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    budget: Arc<MemoryBudget>,
+    size: usize,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserve `size` bytes from the budget.
+    ///
+    /// Returns None if the reservation would exceed the limit.
+    pub(crate) fn try_reserve(
+        self: &Arc<Self>,
+        size: usize,
+    ) -> Option<Reservation> {
+        let mut used = self.used.load(Ordering::Relaxed);
+        loop {
+            let new = used.checked_add(size)?;
+            if self.limit != 0 && new > self.limit {
+                debug!(
+                    "Memory budget exceeded: {used} bytes in use, {size} requested, limit is {}",
+                    self.limit
+                );
+                return None;
+            }
+            match self.used.compare_exchange_weak(
+                used,
+                new,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    return Some(Reservation {
+                        budget: self.clone(),
+                        size,
+                    })
+                }
+                Err(current) => used = current,
+            }
+        }
+    }
+
+    /// The number of bytes currently reserved
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+}
+
+impl Reservation {
+    pub(crate) fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let _ = self.budget.used.fetch_sub(self.size, Ordering::AcqRel);
+    }
+}
+
+/// The soft limit of open file descriptors, None if unlimited
+fn open_files_limit() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: getrlimit only writes to the provided struct
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    usize::try_from(limit.rlim_cur).ok()
+}
+
+/// Admission of the incoming requests. A request is refused before any work
+/// is started, including the parsing of its body, when the process is close
+/// to the limit of open file descriptors or when the memory budget can not
+/// hold its body. The open file descriptors are counted at most once per
+/// FD_SAMPLE_INTERVAL, the requests in between using the last count.
+#[derive(Debug)]
+pub(crate) struct Admission {
+    fd_limit: Option<usize>,
+    memory_budget: Arc<MemoryBudget>,
+    // Last count of the open file descriptors, 0 if unknown
+    open_files: AtomicUsize,
+    // Milliseconds since `started` after which the count is renewed
+    next_sample: AtomicU64,
+    started: Instant,
+}
+
+impl Admission {
+    /// The limit of open file descriptors is the one in effect when called,
+    /// i.e. after set_rlimit
+    pub(crate) fn new(memory_budget: Arc<MemoryBudget>) -> Self {
+        Self::with_fd_limit(open_files_limit(), memory_budget)
+    }
+
+    fn with_fd_limit(
+        fd_limit: Option<usize>,
+        memory_budget: Arc<MemoryBudget>,
+    ) -> Self {
+        Admission {
+            fd_limit,
+            memory_budget,
+            open_files: AtomicUsize::new(0),
+            next_sample: AtomicU64::new(0),
+            started: Instant::now(),
+        }
+    }
+
+    /// Number of open file descriptors, counted again only if the last
+    /// count is older than FD_SAMPLE_INTERVAL. Only one of the concurrent
+    /// requests counts them.
+    fn open_files(&self) -> usize {
+        let now = self.started.elapsed().as_millis() as u64;
+        let next = self.next_sample.load(Ordering::Relaxed);
+        if now >= next
+            && self
+                .next_sample
+                .compare_exchange(
+                    next,
+                    now + FD_SAMPLE_INTERVAL.as_millis() as u64,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+        {
+            // The count is unavailable without procfs, in which case the
+            // limit is not enforced
+            let open = fs::read_dir(PROC_SELF_FD)
+                .map(|dir| dir.count())
+                .unwrap_or_default();
+            self.open_files.store(open, Ordering::Relaxed);
+            return open;
+        }
+        self.open_files.load(Ordering::Relaxed)
+    }
+
+    /// Check whether the request can be served. The memory for its body is
+    /// reserved, twice the size to account for the parsed copy, and
+    /// released when the returned reservation is dropped. Returns an error
+    /// message if the request has to be refused.
+    pub(crate) fn admit(
+        &self,
+        req: &ServiceRequest,
+    ) -> std::result::Result<Option<Reservation>, String> {
+        if let Some(limit) = self.fd_limit {
+            let open = self.open_files();
+            if open > 0 && open + FD_HEADROOM >= limit {
+                return Err(format!(
+                    "Too many open files: {open} open, limit is {limit}"
+                ));
+            }
+        }
+
+        let headers = req.headers();
+        let body_size = match headers
+            .get(CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            Some(size) => size.min(MAX_REQUEST_BODY),
+            None if headers.contains_key(TRANSFER_ENCODING) => {
+                MAX_REQUEST_BODY
+            }
+            None => 0,
+        };
+        if body_size == 0 {
+            return Ok(None);
+        }
+        self.memory_budget
+            .try_reserve(2 * body_size)
+            .map(Some)
+            .ok_or_else(|| "Memory budget exceeded".to_string())
+    }
+}
+
+/// Parse a size using the same syntax accepted for the tmpfs size, e.g.
+/// "512k", "64m" or "1g". An empty string is considered 0.
+pub(crate) fn parse_size(size: &str) -> Result<usize> {
+    let size = size.trim();
+    if size.is_empty() {
+        return Ok(0);
+    }
+
+    let (number, multiplier) = match size.chars().last() {
+        Some('k') | Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('m') | Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('g') | Some('G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+
+    number
+        .parse::<usize>()?
+        .checked_mul(multiplier)
+        .ok_or_else(|| Error::Conversion(format!("size {size} is too large")))
+}
+
+/// Process resource limits that can be set on startup
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum ResourceLimit {
+    OpenFiles,
+    AddressSpace,
+}
+
+/// Set both the soft and hard limits of the given resource.
+///
+/// Raising the hard limit requires privileges, so this has to be called
+/// before dropping them.
+pub(crate) fn set_rlimit(
+    resource: ResourceLimit,
+    value: libc::rlim_t,
+) -> Result<()> {
+    let res = match resource {
+        ResourceLimit::OpenFiles => libc::RLIMIT_NOFILE,
+        ResourceLimit::AddressSpace => libc::RLIMIT_AS,
+    };
+
+    let limit = libc::rlimit {
+        rlim_cur: value,
+        rlim_max: value,
+    };
+
+    // SAFETY: setrlimit only reads the provided struct
+    if unsafe { libc::setrlimit(res, &limit) } != 0 {
+        let e = std::io::Error::last_os_error();
+        error!("Failed to set resource limit {resource:?} to {value}: {e}");
+        return Err(Error::Io(e));
+    }
+
+    info!("Resource limit {resource:?} set to {value}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("").unwrap(), 0); //#[allow_ci]
+        assert_eq!(parse_size("0").unwrap(), 0); //#[allow_ci]
+        assert_eq!(parse_size("100").unwrap(), 100); //#[allow_ci]
+        assert_eq!(parse_size("4k").unwrap(), 4096); //#[allow_ci]
+        assert_eq!(parse_size("1m").unwrap(), 1048576); //#[allow_ci]
+        assert_eq!(parse_size("2G").unwrap(), 2147483648); //#[allow_ci]
+        assert!(parse_size("1x").is_err());
+        assert!(parse_size("m").is_err());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = Arc::new(MemoryBudget::new(100));
+
+        let first = budget.try_reserve(60);
+        assert!(first.is_some());
+        assert_eq!(budget.used(), 60);
+
+        // Exceeds the limit
+        assert!(budget.try_reserve(50).is_none());
+        assert_eq!(budget.used(), 60);
+
+        // Releasing the first reservation makes room again
+        drop(first);
+        assert_eq!(budget.used(), 0);
+        let second = budget.try_reserve(100);
+        assert!(second.is_some());
+        assert_eq!(second.unwrap().size(), 100); //#[allow_ci]
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_admission() {
+        use actix_web::test::TestRequest;
+
+        let budget = Arc::new(MemoryBudget::new(100));
+        let admission = Admission::with_fd_limit(None, budget.clone());
+
+        // Requests without a body do not reserve memory
+        let req = TestRequest::get().to_srv_request();
+        assert!(admission.admit(&req).unwrap().is_none()); //#[allow_ci]
+
+        let req = TestRequest::post()
+            .insert_header((CONTENT_LENGTH, "40"))
+            .to_srv_request();
+        let reservation = admission.admit(&req).unwrap(); //#[allow_ci]
+        assert_eq!(budget.used(), 80);
+        assert!(admission.admit(&req).is_err());
+        drop(reservation);
+        assert_eq!(budget.used(), 0);
+
+        // Bodies of unknown size reserve the maximum size
+        let req = TestRequest::post()
+            .insert_header((TRANSFER_ENCODING, "chunked"))
+            .to_srv_request();
+        assert!(admission.admit(&req).is_err());
+
+        // Close to the limit of open file descriptors
+        let admission = Admission::with_fd_limit(Some(FD_HEADROOM), budget);
+        let req = TestRequest::get().to_srv_request();
+        assert!(admission.admit(&req).is_err());
+
+        // The count is reused until the interval elapsed
+        let open = admission.open_files.load(Ordering::Relaxed);
+        assert!(open > 0);
+        admission.open_files.store(1, Ordering::Relaxed);
+        assert_eq!(admission.open_files(), 1);
+        admission.next_sample.store(0, Ordering::Relaxed);
+        assert!(admission.open_files() > 1);
+    }
+
+    #[test]
+    fn test_memory_budget_unlimited() {
+        let budget = Arc::new(MemoryBudget::new(0));
+        let r = budget.try_reserve(usize::MAX);
+        assert!(r.is_some());
+        assert!(budget.try_reserve(1).is_none());
+    }
+}
@@ -0,0 +1,345 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Signing of the API responses with the AK
+//!
+//! Deployments that have to run with mTLS disabled have no authenticity
+//! for the responses of the agent. When enabled, every response is signed
+//! with the AK, over a digest binding the body to the request and to the
+//! time of the response. The client can send a nonce, in the
+//! X-Keylime-Nonce header or in the 'nonce' query parameter, which is
+//! included in the signed digest to protect against replays.
+//!
+//! The signed data is the SHA-256 digest of:
+//!
+//! ```text
+//! <timestamp>\n<nonce>\n<method> <path>\n<body>
+//! ```
+//!
+//! where the timestamp is the value of the X-Keylime-Timestamp header, in
+//! seconds since the Unix epoch, and the nonce is empty if none was sent.
+//! This 32 bytes digest is the message signed by the AK: the TPM hashes it
+//! again with the hash algorithm of the agent (the 'hash_alg' of the
+//! quotes) and signs that digest with the signing scheme of the agent
+//! ('sign_alg'). A verifier thus checks the signature as a regular
+//! signature over the SHA-256 digest, e.g. RSASSA-PKCS1-v1_5 with SHA-256
+//! over it for 'rsassa' and 'sha256'. The TPMT_SIGNATURE is sent base64
+//! encoded in the X-Keylime-Signature header.
+//!
+//! The signature covers the whole body, which is buffered before being
+//! sent. The buffered body is reserved from the memory budget, and the
+//! response is replaced with a 503 error if the budget is exceeded.
+//!
+//! Streamed bodies, i.e. the integrity quotes carrying the IMA measurement
+//! list, are sent unsigned, without the X-Keylime-Signature header, as
+//! buffering them would defeat the streaming. Their content is already
+//! authenticated by the TPM quote: the quote is signed by the AK over the
+//! nonce of the verifier, and the measurement list is checked against the
+//! quoted PCRs.
+
+use crate::{
+    common::JsonWrapper,
+    resources::{MemoryBudget, Reservation},
+    QuoteData, Result,
+};
+use actix_web::{
+    body::{BodySize, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue},
+    web::{self, Bytes, BytesMut},
+    HttpResponse,
+};
+use base64::{engine::general_purpose, Engine as _};
+use log::*;
+use openssl::hash::{hash, MessageDigest};
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::poll_fn,
+    pin::{pin, Pin},
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tss_esapi::traits::Marshall;
+
+pub(crate) const SIGNATURE_HEADER: &str = "x-keylime-signature";
+pub(crate) const TIMESTAMP_HEADER: &str = "x-keylime-timestamp";
+pub(crate) const NONCE_HEADER: &str = "x-keylime-nonce";
+
+/// The nonce sent by the client, either in the X-Keylime-Nonce header or
+/// in the 'nonce' query parameter
+pub(crate) fn request_nonce(req: &ServiceRequest) -> Option<String> {
+    if let Some(nonce) = req
+        .headers()
+        .get(NONCE_HEADER)
+        .and_then(|v| v.to_str().ok())
+    {
+        return Some(nonce.to_string());
+    }
+    web::Query::<HashMap<String, String>>::from_query(req.query_string())
+        .ok()
+        .and_then(|q| q.get("nonce").cloned())
+}
+
+/// The digest signed for a response
+pub(crate) fn signed_digest(
+    timestamp: u64,
+    nonce: Option<&str>,
+    method: &str,
+    path: &str,
+    body: &[u8],
+) -> Result<Vec<u8>> {
+    let mut data = format!(
+        "{timestamp}\n{}\n{method} {path}\n",
+        nonce.unwrap_or_default()
+    )
+    .into_bytes();
+    data.extend_from_slice(body);
+    Ok(hash(MessageDigest::sha256(), &data)?.to_vec())
+}
+
+/// A buffered response body, holding its reservation from the memory
+/// budget until it is sent
+struct ReservedBody {
+    body: Bytes,
+    _reservations: Vec<Reservation>,
+}
+
+impl MessageBody for ReservedBody {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Sized(self.body.len() as u64)
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<std::result::Result<Bytes, Self::Error>>> {
+        let body = std::mem::take(&mut self.get_mut().body);
+        if body.is_empty() {
+            return Poll::Ready(None);
+        }
+        Poll::Ready(Some(Ok(body)))
+    }
+}
+
+/// Read the whole body, reserving the buffered bytes from the memory budget
+/// as they are read. Returns None if the budget is exceeded.
+async fn buffer_body<B>(
+    body: B,
+    budget: &Arc<MemoryBudget>,
+) -> std::result::Result<Option<ReservedBody>, actix_web::Error>
+where
+    B: MessageBody,
+{
+    let mut body = pin!(body);
+    let mut buffer = BytesMut::new();
+    let mut reservations = Vec::new();
+    while let Some(chunk) = poll_fn(|cx| body.as_mut().poll_next(cx)).await {
+        let chunk = chunk.map_err(|e| e.into())?;
+        match budget.try_reserve(chunk.len()) {
+            Some(r) => reservations.push(r),
+            None => return Ok(None),
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+    Ok(Some(ReservedBody {
+        body: buffer.freeze(),
+        _reservations: reservations,
+    }))
+}
+
+/// Sign the response, replacing it with an error response if the signature
+/// can not be generated
+pub(crate) async fn sign<B>(
+    res: ServiceResponse<B>,
+    data: &QuoteData,
+    nonce: Option<String>,
+) -> std::result::Result<ServiceResponse<BoxBody>, actix_web::Error>
+where
+    B: MessageBody + 'static,
+{
+    if let BodySize::Stream = res.response().body().size() {
+        debug!(
+            "{} {} returning a streamed response, not signed",
+            res.request().method(),
+            res.request().path()
+        );
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let (mut res, body) = res.into_parts();
+    let Some(body) = buffer_body(body, &data.memory_budget).await? else {
+        warn!(
+            "{} {} returning 503 response. Memory budget exceeded signing the response",
+            req.method(),
+            req.path()
+        );
+        let response =
+            HttpResponse::ServiceUnavailable().json(JsonWrapper::error(
+                503,
+                "Memory budget exceeded, try again later".to_string(),
+            ));
+        return Ok(ServiceResponse::new(req, response));
+    };
+
+    let timestamp = data.clock.unix_time();
+
+    let signature = signed_digest(
+        timestamp,
+        nonce.as_deref(),
+        req.method().as_str(),
+        req.path(),
+        &body.body,
+    )
+    .and_then(|digest| {
+        let mut ctx = data.tpm();
+        Ok(ctx
+            .sign(data.ak_handle, &digest, data.hash_alg, data.sign_alg)?
+            .marshall()?)
+    });
+
+    let signature = match signature {
+        Ok(signature) => signature,
+        Err(e) => {
+            warn!(
+                "{} {} returning 500 response. Unable to sign the response: {e}",
+                req.method(),
+                req.path()
+            );
+            let response = HttpResponse::InternalServerError()
+                .json(JsonWrapper::error(500, "Unable to sign the response"));
+            return Ok(ServiceResponse::new(req, response));
+        }
+    };
+
+    let headers = res.headers_mut();
+    _ = headers.insert(
+        HeaderName::from_static(SIGNATURE_HEADER),
+        HeaderValue::from_str(&general_purpose::STANDARD.encode(signature))?,
+    );
+    _ = headers.insert(
+        HeaderName::from_static(TIMESTAMP_HEADER),
+        HeaderValue::from(timestamp),
+    );
+    // The nonce is echoed for convenience, as it is covered by the signature
+    if let Some(Ok(nonce)) = nonce.as_deref().map(HeaderValue::from_str) {
+        _ = headers.insert(HeaderName::from_static(NONCE_HEADER), nonce);
+    }
+
+    Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(body))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_request_nonce() {
+        let req = TestRequest::with_uri("/v2.1/quotes/integrity?nonce=abc")
+            .to_srv_request();
+        assert_eq!(request_nonce(&req), Some("abc".to_string()));
+
+        let req = TestRequest::with_uri("/v2.1/quotes/integrity?nonce=abc")
+            .insert_header((NONCE_HEADER, "def"))
+            .to_srv_request();
+        assert_eq!(request_nonce(&req), Some("def".to_string()));
+
+        let req = TestRequest::with_uri("/version").to_srv_request();
+        assert_eq!(request_nonce(&req), None);
+    }
+
+    #[test]
+    fn test_signed_digest() {
+        let digest =
+            signed_digest(1000, Some("abc"), "GET", "/version", b"{}")
+                .unwrap(); //#[allow_ci]
+        let expected =
+            hash(MessageDigest::sha256(), b"1000\nabc\nGET /version\n{}")
+                .unwrap(); //#[allow_ci]
+        assert_eq!(digest, expected.to_vec());
+
+        let digest =
+            signed_digest(1000, None, "GET", "/version", b"{}").unwrap(); //#[allow_ci]
+        let expected =
+            hash(MessageDigest::sha256(), b"1000\n\nGET /version\n{}")
+                .unwrap(); //#[allow_ci]
+        assert_eq!(digest, expected.to_vec());
+    }
+
+    #[actix_rt::test]
+    async fn test_buffer_body() {
+        let budget = Arc::new(MemoryBudget::new(10));
+        let body = buffer_body("0123456789", &budget)
+            .await
+            .unwrap() //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        assert_eq!(&body.body[..], b"0123456789");
+        assert_eq!(budget.used(), 10);
+
+        // The reservation is held until the body is dropped
+        assert!(buffer_body("0", &budget).await.unwrap().is_none()); //#[allow_ci]
+        drop(body);
+        assert_eq!(budget.used(), 0);
+        assert!(buffer_body("01234567890", &budget)
+            .await
+            .unwrap() //#[allow_ci]
+            .is_none());
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[cfg(feature = "testing")]
+    #[actix_rt::test]
+    async fn test_sign() {
+        use actix_web::{test, App};
+
+        let data = web::Data::new(QuoteData::fixture().unwrap()); //#[allow_ci]
+        let signing_data = data.clone();
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(move |req, srv| {
+                    let data = signing_data.clone();
+                    let nonce = request_nonce(&req);
+                    let fut = actix_web::dev::Service::call(srv, req);
+                    async move { sign(fut.await?, &data, nonce).await }
+                })
+                .route("/test", web::get().to(|| async { "body" }))
+                .route(
+                    "/stream",
+                    web::get().to(|| async {
+                        HttpResponse::Ok().streaming(futures::stream::once(
+                            async {
+                                Ok::<_, actix_web::Error>(Bytes::from_static(
+                                    b"body",
+                                ))
+                            },
+                        ))
+                    }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/test?nonce=1234").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(resp.headers().contains_key(SIGNATURE_HEADER));
+        assert!(resp.headers().contains_key(TIMESTAMP_HEADER));
+        assert_eq!(
+            resp.headers().get(NONCE_HEADER).unwrap(), //#[allow_ci]
+            "1234"
+        );
+        let body = test::read_body(resp).await;
+        assert_eq!(&body[..], b"body");
+
+        // Streamed bodies are passed through unsigned
+        let req = TestRequest::get().uri("/stream").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert!(!resp.headers().contains_key(SIGNATURE_HEADER));
+        let body = test::read_body(resp).await;
+        assert_eq!(&body[..], b"body");
+    }
+}
@@ -5,7 +5,10 @@
 use actix_web::rt;
 use crate::config::{AgentConfig, KeylimeConfig};
 use crate::crypto;
+#[cfg(feature = "with-zmq")]
+use crate::endpoint::Endpoint;
 use crate::error::*;
+use crate::events::{AgentEvent, EventPublisher};
 use crate::secure_mount;
 use keylime::list_parser::parse_list;
 use log::*;
@@ -305,8 +308,7 @@ fn process_revocation(
 #[cfg(feature = "with-zmq")]
 fn listen_zmq(
     mut revocation_tx: Sender<RevocationMessage>,
-    ip: String,
-    port: u32,
+    notifier: Endpoint,
     mut shutdown_rx: oneshot::Receiver<String>,
 ) -> Result<rt::task::JoinHandle<Result<()>>> {
     // Connect to the service via 0mq
@@ -314,8 +316,11 @@ fn listen_zmq(
     let mysock = context.socket(zmq::SUB)?;
 
     mysock.set_subscribe(b"")?;
+    if notifier.is_ipv6() {
+        mysock.set_ipv6(true)?;
+    }
 
-    let endpoint = format!("tcp://{ip}:{port}");
+    let endpoint = notifier.url("tcp", "");
 
     info!(
         "Connecting to revocation notification endpoint at {}...",
@@ -393,8 +398,7 @@ fn listen_zmq(
 pub(crate) async fn zmq_worker(
     mut zmq_rx: Receiver<ZmqMessage>,
     mut revocation_tx: Sender<RevocationMessage>,
-    ip: String,
-    port: u32,
+    notifier: Endpoint,
 ) -> Result<()> {
     debug!("Starting ZMQ revocation listener worker");
 
@@ -416,8 +420,7 @@ pub(crate) async fn zmq_worker(
                 shutdown_tx = Some(tx);
                 task = match listen_zmq(
                     revocation_tx.clone(),
-                    ip.clone(),
-                    port,
+                    notifier.clone(),
                     rx,
                 ) {
                     Ok(t) => Some(t),
@@ -439,6 +442,7 @@ pub(crate) async fn zmq_worker(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn worker(
     mut revocation_rx: Receiver<RevocationMessage>,
     revocation_cert_path: impl AsRef<Path>,
@@ -447,6 +451,7 @@ pub(crate) async fn worker(
     allow_payload_revocation_actions: bool,
     work_dir: impl AsRef<Path>,
     mount: impl AsRef<Path>,
+    events: EventPublisher,
 ) -> Result<()> {
     debug!("Starting revocation worker");
 
@@ -473,9 +478,19 @@ pub(crate) async fn worker(
                         ) {
                             Ok(_) => {
                                 info!("Revocation processed successfully");
+                                events.publish(
+                                    AgentEvent::RevocationReceived {
+                                        processed: true,
+                                    },
+                                );
                             }
                             Err(e) => {
                                 error!("Failed to process revocation: {}", e);
+                                events.publish(
+                                    AgentEvent::RevocationReceived {
+                                        processed: false,
+                                    },
+                                );
                             }
                         }
                     }
@@ -0,0 +1,171 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Configuration secrets sealed to the TPM
+//!
+//! Instead of keeping secrets, e.g. the TPM owner password, in plain text in
+//! the configuration file, the options can reference a file with the secret
+//! sealed to the TPM as "tpm-sealed:/path/to/blob". The secret is unsealed at
+//! startup, which only succeeds on the same TPM and while the PCRs of the
+//! policy have the values they had when the secret was sealed.
+
+use crate::{
+    config::KeylimeConfig,
+    error::{Error, Result},
+    serialization::{deserialize_as_base64, serialize_as_base64},
+    service,
+};
+use keylime::{algorithms::HashAlgorithm, list_parser::parse_list, tpm};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{fs, io::Read, path::Path};
+use tss_esapi::{
+    structures::{Private, Public},
+    traits::{Marshall, UnMarshall},
+};
+
+/// Prefix of the configuration values referencing a sealed secret
+pub(crate) const SEALED_PREFIX: &str = "tpm-sealed:";
+
+/// The file format of a sealed secret
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct SealedBlob {
+    pcr_bank: String,
+    pcrs: Vec<u32>,
+    #[serde(
+        serialize_with = "serialize_as_base64",
+        deserialize_with = "deserialize_as_base64"
+    )]
+    public: Vec<u8>,
+    #[serde(
+        serialize_with = "serialize_as_base64",
+        deserialize_with = "deserialize_as_base64"
+    )]
+    private: Vec<u8>,
+}
+
+impl SealedBlob {
+    fn from_sealed(sealed: &tpm::SealedData) -> Result<Self> {
+        Ok(SealedBlob {
+            pcr_bank: sealed.pcr_bank.to_string(),
+            pcrs: service::mask_to_pcrs(sealed.mask),
+            public: sealed.public.marshall()?,
+            private: sealed.private.value().to_vec(),
+        })
+    }
+
+    fn to_sealed(&self) -> Result<tpm::SealedData> {
+        let pcrs: Vec<String> =
+            self.pcrs.iter().map(ToString::to_string).collect();
+        let mask = service::pcrs_to_mask(pcrs.iter().map(String::as_str))
+            .map_err(Error::Configuration)?;
+        Ok(tpm::SealedData {
+            public: Public::unmarshall(&self.public)?,
+            private: Private::try_from(self.private.clone())?,
+            mask,
+            pcr_bank: HashAlgorithm::try_from(self.pcr_bank.as_str())?,
+        })
+    }
+}
+
+/// The path of the sealed secret referenced by the configuration value, if
+/// any
+fn sealed_path(value: &str) -> Option<&Path> {
+    value
+        .strip_prefix(SEALED_PREFIX)
+        .map(|path| Path::new(path.trim()))
+}
+
+/// Resolve the configuration value of the option `name`, unsealing the
+/// secret when the value references a sealed secret, and returning the
+/// value unchanged otherwise
+pub(crate) fn resolve(
+    ctx: &mut tpm::Context,
+    name: &str,
+    value: &str,
+) -> Result<String> {
+    let Some(path) = sealed_path(value) else {
+        return Ok(value.to_string());
+    };
+    let blob: SealedBlob = serde_json::from_slice(&fs::read(path)?)?;
+    let secret = ctx.unseal(&blob.to_sealed()?).map_err(|e| {
+        error!(
+            "Unable to unseal {name} from {}, the PCRs {:?} may have changed since it was sealed: {e}",
+            path.display(),
+            blob.pcrs
+        );
+        Error::Tpm(e)
+    })?;
+    info!("Unsealed {name} from {}", path.display());
+    String::from_utf8(secret).map_err(|_| {
+        Error::Configuration(format!(
+            "The secret sealed in {} for {name} is not valid UTF-8",
+            path.display()
+        ))
+    })
+}
+
+/// Seal the secret read from `input` to the PCRs of `sealed_config_pcrs` in
+/// the bank of `tpm_hash_alg`, and write it to `path`
+pub(crate) fn seal_to_file(
+    ctx: &mut tpm::Context,
+    config: &KeylimeConfig,
+    mut input: impl Read,
+    path: &Path,
+) -> Result<()> {
+    let pcrs = parse_list(&config.agent.sealed_config_pcrs)?;
+    let mask = service::pcrs_to_mask(pcrs.into_iter()).map_err(|e| {
+        Error::Configuration(format!("sealed_config_pcrs: {e}"))
+    })?;
+    let pcr_bank =
+        HashAlgorithm::try_from(config.agent.tpm_hash_alg.as_str())?;
+
+    let mut secret = String::new();
+    _ = input.read_to_string(&mut secret)?;
+    let secret = secret.trim_end_matches(['\r', '\n']);
+    if secret.is_empty() {
+        return Err(Error::Configuration(
+            "The secret to seal is empty".to_string(),
+        ));
+    }
+
+    let sealed = ctx.seal(secret.as_bytes(), mask, pcr_bank)?;
+    let blob = SealedBlob::from_sealed(&sealed)?;
+    fs::write(path, serde_json::to_vec(&blob)?)?;
+    info!(
+        "Sealed the secret to the {pcr_bank} PCRs {:?} in {}",
+        blob.pcrs,
+        path.display()
+    );
+    println!("{SEALED_PREFIX}{}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sealed_path() {
+        assert_eq!(
+            sealed_path("tpm-sealed:/var/lib/keylime/owner.sealed"),
+            Some(Path::new("/var/lib/keylime/owner.sealed"))
+        );
+        assert_eq!(sealed_path("password"), None);
+        assert_eq!(sealed_path(""), None);
+    }
+
+    #[test]
+    fn test_blob_roundtrip() {
+        let blob = SealedBlob {
+            pcr_bank: "sha256".to_string(),
+            pcrs: vec![0, 7],
+            public: vec![1, 2],
+            private: vec![3],
+        };
+        let json = serde_json::to_string(&blob).unwrap(); //#[allow_ci]
+        assert!(json.contains("\"public\":\"AQI=\""));
+        let decoded: SealedBlob = serde_json::from_str(&json).unwrap(); //#[allow_ci]
+        assert_eq!(decoded, blob);
+    }
+}
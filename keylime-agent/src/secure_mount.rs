@@ -76,13 +76,17 @@ fn check_mount(secure_dir: &Path) -> Result<bool> {
 /*
  * Return: Result wrap secure mount directory or error code
  *
- * Mounted the work directory as tmpfs, which is owned by root. Same
+ * Mounted the secure directory as tmpfs, which is owned by root. Same
  * implementation as the original python version, but the chown/geteuid
  * functions are unsafe function in Rust to use.
  */
-pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
+pub(crate) fn mount(
+    secure_dir: &Path,
+    secure_size: &str,
+    context: &str,
+) -> Result<PathBuf> {
     // Mount the directory to file system
-    let secure_dir_path = Path::new(work_dir).join("secure");
+    let secure_dir_path = secure_dir.to_path_buf();
 
     // If the directory is not mount to file system, mount the directory to
     // file system.
@@ -90,7 +94,7 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
         // Create directory if the directory is not exist. The
         // directory permission is set to 448.
         if !secure_dir_path.exists() {
-            fs::create_dir(&secure_dir_path).map_err(|e| {
+            fs::create_dir_all(&secure_dir_path).map_err(|e| {
                 Error::SecureMount(format!(
                     "unable to create secure dir path: {e:?}"
                 ))
@@ -110,13 +114,20 @@ pub(crate) fn mount(work_dir: &Path, secure_size: &str) -> Result<PathBuf> {
             &secure_dir_path
         );
 
+        // The files in the secure mount get the SELinux context of the
+        // mount, if set
+        let mut options = format!("size={secure_size},mode=0700");
+        if !context.is_empty() {
+            options.push_str(&format!(",context=\"{context}\""));
+        }
+
         // mount tmpfs with secure directory
         match Command::new("mount")
             .args([
                 "-t",
                 "tmpfs",
                 "-o",
-                format!("size={secure_size},mode=0700").as_str(),
+                options.as_str(),
                 "tmpfs",
                 secure_dir_path.to_str().unwrap(), //#[allow_ci]
             ])
@@ -148,7 +159,8 @@ mod tests {
     fn test_secure_mount() {
         let temp_workdir = tempfile::tempdir().unwrap(); //#[allow_ci]
         let secure_size = "1m";
-        let test_mount = mount(temp_workdir.path(), secure_size);
-        assert!(check_mount(temp_workdir.path()).is_ok());
+        let secure_dir = temp_workdir.path().join("secure");
+        let test_mount = mount(&secure_dir, secure_size, "");
+        assert!(check_mount(&secure_dir).is_ok());
     }
 }
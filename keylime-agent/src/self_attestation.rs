@@ -0,0 +1,392 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Periodic self-attestation
+//!
+//! The agent quotes its own PCRs and replays the IMA measurement list and
+//! the measured boot log against the quoted values, as the verifier does.
+//! A log that does not match the PCRs, because it was truncated or because a
+//! PCR was extended without a matching log entry, is reported before the
+//! verifier fails the attestation.
+
+use crate::{
+    events::{AgentEvent, EventPublisher},
+    QuoteData, Result,
+};
+use actix_web::web;
+use keylime::{algorithms::HashAlgorithm, event_log, hashing, ima, tpm};
+use log::*;
+use openssl::hash::MessageDigest;
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt,
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    time::Duration,
+};
+use tokio::sync::mpsc::Receiver;
+
+/// The PCR extended by IMA
+pub(crate) const IMA_PCR: u32 = 10;
+
+/// The PCRs extended during the boot, which are not extended again while the
+/// system is running
+const BOOT_PCRS: std::ops::RangeInclusive<u32> = 0..=9;
+
+/// An inconsistency between the logs and the PCRs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Inconsistency {
+    ImaLogTruncated { previous: u64, current: u64 },
+    ImaPcrMismatch { entries: u64 },
+    BootLogInvalid(String),
+    BootPcrMismatch { pcr: u32 },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::ImaLogTruncated { previous, current } => write!(
+                f,
+                "The IMA measurement list shrank from {previous} to {current} entries"
+            ),
+            Inconsistency::ImaPcrMismatch { entries } => write!(
+                f,
+                "Replaying the {entries} entries of the IMA measurement list does not result in the quoted value of PCR {IMA_PCR}"
+            ),
+            Inconsistency::BootLogInvalid(e) => {
+                write!(f, "The measured boot log is invalid: {e}")
+            }
+            Inconsistency::BootPcrMismatch { pcr } => write!(
+                f,
+                "Replaying the measured boot log does not result in the quoted value of PCR {pcr}"
+            ),
+        }
+    }
+}
+
+/// Result of replaying the IMA measurement list
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ImaReplay {
+    /// Number of entries in the list
+    pub entries: u64,
+    /// Whether the running PCR value matched the expected value after some
+    /// entry. Entries added after the quote was generated are not included
+    /// in the quoted value, thus any prefix of the list may match.
+    pub matched: bool,
+}
+
+/// Replay the entries of the IMA measurement list extended into the IMA PCR
+/// for the PCR bank of `alg`, comparing the running value to `expected`
+pub(crate) fn replay_ima<R: BufRead>(
+    reader: R,
+    alg: HashAlgorithm,
+    expected: &[u8],
+) -> Result<ImaReplay> {
+    let md: MessageDigest = alg.into();
+    let backend = hashing::default_backend();
+    let ima_start = ima::Digest::start(HashAlgorithm::Sha1);
+    let ff = ima::Digest::ff(alg);
+    let mut running = vec![0u8; md.size()];
+    let mut matched = running == expected;
+    let mut entries = 0;
+    // The template hashes, with None for the ones computed from the event
+    // data, which are hashed in parallel once the list is read
+    let mut template_hashes = Vec::new();
+    let mut event_datas = Vec::new();
+
+    // The file names are not necessarily valid UTF-8
+    for line in reader.split(b'\n') {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+        entries += 1;
+
+        if line.split(|b| *b == b' ').next()
+            != Some(IMA_PCR.to_string().as_bytes())
+        {
+            continue;
+        }
+
+        let entry = ima::Entry::try_from(line.as_slice())?;
+
+        // Time of measure, time of use (ToMToU) errors are logged with a
+        // zero digest and extended with a digest with all bits set. The
+        // logged template hash is the one extended into the SHA-1 bank.
+        if entry.template_hash == ima_start {
+            template_hashes.push(Some(ff.value().to_vec()));
+        } else if alg == HashAlgorithm::Sha1 {
+            template_hashes.push(Some(entry.template_hash.value().to_vec()));
+        } else {
+            let mut event_data = vec![];
+            entry.event_data.encode(&mut event_data)?;
+            event_datas.push(event_data);
+            template_hashes.push(None);
+        }
+    }
+
+    let mut computed =
+        hashing::digest_all(backend, alg, &event_datas)?.into_iter();
+    for template_hash in template_hashes {
+        let Some(template_hash) = template_hash.or_else(|| computed.next())
+        else {
+            break;
+        };
+        running.extend_from_slice(&template_hash);
+        running = backend.digest(alg, &running)?;
+        if running == expected {
+            matched = true;
+        }
+    }
+
+    Ok(ImaReplay { entries, matched })
+}
+
+/// Compare the PCR values resulting from the boot log with the quoted ones
+pub(crate) fn check_boot_pcrs(
+    replayed: &BTreeMap<u32, Vec<u8>>,
+    quoted: &BTreeMap<u32, Vec<u8>>,
+) -> Vec<Inconsistency> {
+    replayed
+        .iter()
+        .filter(|(pcr, _)| BOOT_PCRS.contains(pcr))
+        .filter(|(pcr, value)| quoted.get(pcr) != Some(value))
+        .map(|(pcr, _)| Inconsistency::BootPcrMismatch { pcr: *pcr })
+        .collect()
+}
+
+/// Quote the PCRs and compare them with the logs. The number of IMA entries
+/// seen in the previous check is used to detect the truncation of the list.
+pub(crate) fn check(
+    data: &QuoteData,
+    ima_entries: &mut Option<u64>,
+) -> Result<Vec<Inconsistency>> {
+    let mut inconsistencies = Vec::new();
+
+    let mut boot_pcrs = None;
+    if let Some(file) = &data.measuredboot_ml_file {
+        let mut buf = Vec::new();
+        {
+            let mut f = file.lock().unwrap(); //#[allow_ci]
+            let _ = f.seek(SeekFrom::Start(0))?;
+            let _ = f.read_to_end(&mut buf)?;
+        }
+        match event_log::parse(&buf) {
+            Ok(events) if event_log::has_bank(&events, data.hash_alg) => {
+                match event_log::replay(&events, data.hash_alg) {
+                    Ok(pcrs) => boot_pcrs = Some(pcrs),
+                    Err(e) => inconsistencies
+                        .push(Inconsistency::BootLogInvalid(e.to_string())),
+                }
+            }
+            Ok(_) => debug!(
+                "The measured boot log has no {} digests, skipping the boot PCRs",
+                data.hash_alg
+            ),
+            Err(e) => inconsistencies
+                .push(Inconsistency::BootLogInvalid(e.to_string())),
+        }
+    }
+
+    let mut mask = 0u32;
+    if data.ima_ml_file.is_some() {
+        mask |= 1 << IMA_PCR;
+    }
+    if let Some(pcrs) = &boot_pcrs {
+        for pcr in pcrs.keys().filter(|pcr| BOOT_PCRS.contains(pcr)) {
+            mask |= 1 << pcr;
+        }
+    }
+    if mask == 0 {
+        return Ok(inconsistencies);
+    }
+
+    let mut nonce = [0u8; 20];
+    openssl::rand::rand_bytes(&mut nonce)?;
+    let quote = {
+        let mut ctx = data.tpm();
+        ctx.quote(
+            &nonce,
+            mask,
+            &data.pub_key,
+            data.ak_handle,
+            data.hash_alg,
+            data.sign_alg,
+        )?
+    };
+    let quoted = tpm::quote_pcr_values(&quote, data.hash_alg)?;
+
+    if let Some(pcrs) = &boot_pcrs {
+        inconsistencies.extend(check_boot_pcrs(pcrs, &quoted));
+    }
+
+    // The list is read after the quote, so that all the entries included in
+    // the quoted value are in the list
+    if let (Some(file), Some(expected)) =
+        (&data.ima_ml_file, quoted.get(&IMA_PCR))
+    {
+        let mut f = file.lock().unwrap(); //#[allow_ci]
+        let _ = f.seek(SeekFrom::Start(0))?;
+        let replay =
+            replay_ima(BufReader::new(&mut *f), data.hash_alg, expected)?;
+
+        match *ima_entries {
+            Some(previous) if replay.entries < previous => inconsistencies
+                .push(Inconsistency::ImaLogTruncated {
+                    previous,
+                    current: replay.entries,
+                }),
+            _ if !replay.matched => {
+                inconsistencies.push(Inconsistency::ImaPcrMismatch {
+                    entries: replay.entries,
+                })
+            }
+            _ => {}
+        }
+        *ima_entries = Some(replay.entries);
+    }
+
+    Ok(inconsistencies)
+}
+
+#[derive(Debug)]
+pub(crate) enum SelfAttestationMessage {
+    Shutdown,
+}
+
+/// Report the inconsistencies, which the verifier will detect as well
+pub(crate) fn report(
+    inconsistencies: &[Inconsistency],
+    events: &EventPublisher,
+) {
+    for inconsistency in inconsistencies {
+        error!("Self-attestation failed: {inconsistency}");
+        events.publish(AgentEvent::SelfAttestationFailed {
+            inconsistency: inconsistency.to_string(),
+        });
+    }
+}
+
+/// Periodically attest the agent against its own logs
+pub(crate) async fn worker(
+    data: web::Data<QuoteData>,
+    interval: Duration,
+    events: EventPublisher,
+    mut self_attestation_rx: Receiver<SelfAttestationMessage>,
+) -> Result<()> {
+    debug!("Starting self-attestation worker");
+
+    let mut ima_entries = None;
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            message = self_attestation_rx.recv() => {
+                match message {
+                    Some(SelfAttestationMessage::Shutdown) | None => {
+                        self_attestation_rx.close();
+                        break;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                match check(&data, &mut ima_entries) {
+                    Ok(inconsistencies) => report(&inconsistencies, &events),
+                    Err(e) => warn!("Failed to perform the self-attestation: {e}"),
+                }
+            }
+        }
+    }
+
+    debug!("Shutting down self-attestation worker");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::hash::hash;
+    use std::path::Path;
+
+    const IMA_LOG: &str = "10 1d8d532d463c9f8c205d0df7787669a85f93e260 ima-ng sha1:0000000000000000000000000000000000000000 boot_aggregate
+10 c156ebdcbfcd28fe1060ef4cdec0aab04d3a9b63 ima-ng sha1:19f13b42c2745066347e76454788c0fe083643f3 /init
+10 790ff4fe72889b071a0f7585112710be6d0084fe ima-ng sha1:c90333979f56f38bbd41b81806015b0de502f3cc /bin/sh
+";
+
+    fn extend(pcr: &[u8], template_hash: &str) -> Vec<u8> {
+        let mut buf = pcr.to_vec();
+        buf.extend(hex::decode(template_hash).unwrap()); //#[allow_ci]
+        hash(MessageDigest::sha1(), &buf).unwrap().to_vec() //#[allow_ci]
+    }
+
+    #[test]
+    fn test_replay_ima() {
+        let pcr =
+            extend(&[0u8; 20], "1d8d532d463c9f8c205d0df7787669a85f93e260");
+        let pcr = extend(&pcr, "c156ebdcbfcd28fe1060ef4cdec0aab04d3a9b63");
+
+        // The quote was generated before the last entry was added
+        let replay =
+            replay_ima(IMA_LOG.as_bytes(), HashAlgorithm::Sha1, &pcr)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(
+            replay,
+            ImaReplay {
+                entries: 3,
+                matched: true
+            }
+        );
+
+        let pcr = extend(&pcr, "790ff4fe72889b071a0f7585112710be6d0084fe");
+        assert!(
+            replay_ima(IMA_LOG.as_bytes(), HashAlgorithm::Sha1, &pcr)
+                .unwrap() //#[allow_ci]
+                .matched
+        );
+
+        // PCR extended without an entry in the list
+        let pcr = extend(&pcr, "790ff4fe72889b071a0f7585112710be6d0084fe");
+        assert!(
+            !replay_ima(IMA_LOG.as_bytes(), HashAlgorithm::Sha1, &pcr)
+                .unwrap() //#[allow_ci]
+                .matched
+        );
+
+        let log = std::fs::read(
+            Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("test-data/ima/ascii_runtime_measurements"),
+        )
+        .unwrap(); //#[allow_ci]
+        let replay =
+            replay_ima(log.as_slice(), HashAlgorithm::Sha256, &[0u8; 32])
+                .unwrap(); //#[allow_ci]
+        assert_eq!(replay.entries, 826);
+    }
+
+    #[test]
+    fn test_check_boot_pcrs() {
+        let replayed =
+            BTreeMap::from([(0, vec![1u8]), (7, vec![2u8]), (14, vec![3u8])]);
+        let quoted = BTreeMap::from([(0, vec![1u8]), (7, vec![4u8])]);
+        assert_eq!(
+            check_boot_pcrs(&replayed, &quoted),
+            [Inconsistency::BootPcrMismatch { pcr: 7 }]
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_check() {
+        let data = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let mut ima_entries = Some(10000);
+        let inconsistencies = check(&data, &mut ima_entries).unwrap(); //#[allow_ci]
+        if data.ima_ml_file.is_some() {
+            assert!(inconsistencies.contains(
+                &Inconsistency::ImaLogTruncated {
+                    previous: 10000,
+                    current: 826
+                }
+            ));
+            assert_eq!(ima_entries, Some(826));
+        }
+    }
+}
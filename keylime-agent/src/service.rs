@@ -0,0 +1,1017 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Agent operations shared by the REST and gRPC frontends
+//!
+//! The frontends only decode the requests and encode the responses, while
+//! the validation of the parameters and the interaction with the TPM and the
+//! workers are done here.
+
+use crate::{
+    common::{AuthTag, JsonWrapper, SymmKey},
+    crypto,
+    events::AgentEvent,
+    keys_handler::{
+        self, DeliveryError, DeliverySession, KeyInventory, KeyMessage,
+        KeylimeKeyCertification, KeylimeKeys, KeylimeUKey, KeylimeVKey,
+        SymmKeyMessage, UKey, VKey,
+    },
+    measured_boot, payloads,
+    quotes_handler::{
+        Continuity, KeylimeQuote, PcrSelection, CONTINUITY_VERSION,
+    },
+    resources::Reservation,
+    tpm,
+    tpm_sharing::{self, HandleRange},
+    verifiers::EvidenceScope,
+    Error, QuoteData,
+};
+use actix_web::{http::StatusCode, HttpResponse};
+use base64::{engine::general_purpose, Engine as _};
+use keylime::{
+    algorithms::HashAlgorithm,
+    ima::{self, EntryRange},
+    key_delivery::{self, KeyDeliveryError},
+};
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    convert::{TryFrom, TryInto},
+    fs::File,
+    io::{Read, Seek},
+    os::unix::fs::FileExt,
+};
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tss_esapi::{
+    structures::{Data, PcrSlot, PublicBuffer},
+    traits::Marshall,
+};
+
+/// Error returned by the agent operations, classified by how it should be
+/// reported to the client
+#[derive(Error, Debug)]
+pub(crate) enum ServiceError {
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unavailable(String),
+    #[error("{0}")]
+    Internal(String),
+}
+
+impl ServiceError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            ServiceError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ServiceError::Conflict(_) => StatusCode::CONFLICT,
+            ServiceError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ServiceError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Report an agent error with the given message, only logging the
+    /// details. Transient errors, like a busy TPM, are reported as
+    /// unavailable so that the client can retry later.
+    pub(crate) fn internal(
+        err: impl Into<Error>,
+        message: impl Into<String>,
+    ) -> Self {
+        let err = err.into();
+        let message = message.into();
+        debug!("{message}: {err}");
+        if err.is_retryable() {
+            ServiceError::Unavailable(format!("{message}, try again later"))
+        } else {
+            ServiceError::Internal(message)
+        }
+    }
+
+    /// The REST response reporting the error
+    pub(crate) fn to_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status()).json(JsonWrapper::error(
+            self.status().as_u16(),
+            self.to_string(),
+        ))
+    }
+}
+
+impl From<KeyDeliveryError> for ServiceError {
+    fn from(err: KeyDeliveryError) -> Self {
+        ServiceError::BadRequest(err.to_string())
+    }
+}
+
+pub(crate) type ServiceResult<T> = Result<T, ServiceError>;
+
+/// An integrity quote, with the location of the requested part of the IMA
+/// measurement list. The list itself is read by the frontend.
+pub(crate) struct IntegrityQuote {
+    pub quote: KeylimeQuote,
+    pub ima_ml: Option<(File, EntryRange)>,
+    // Memory reserved for the evidence included in the quote
+    pub reservations: Vec<Reservation>,
+}
+
+/// Number of PCRs which can be included in an integrity quote
+const NUM_PCRS: u32 = 24;
+const ALL_PCRS_MASK: u32 = (1 << NUM_PCRS) - 1;
+
+/// PCR 16 is extended with the digest of the NK and always quoted
+const NK_PCR: u32 = 16;
+
+/// PCRs requested by the verifier for an integrity quote
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct PcrRequest<'a> {
+    /// Hex encoded mask of the PCRs
+    pub mask: Option<&'a str>,
+    /// Comma separated list of the PCR indexes, instead of the mask
+    pub pcrs: Option<&'a str>,
+    /// Hash algorithm of the PCR bank, if not the one of the quote signature
+    pub pcr_bank: Option<&'a str>,
+}
+
+/// The PCRs and banks the verifiers can request in the integrity quotes
+#[derive(Debug, Clone)]
+pub(crate) struct PcrPolicy {
+    allowed_mask: u32,
+    banks: Vec<HashAlgorithm>,
+}
+
+impl Default for PcrPolicy {
+    /// Any PCR of the bank of the signature
+    fn default() -> Self {
+        PcrPolicy {
+            allowed_mask: ALL_PCRS_MASK,
+            banks: Vec::new(),
+        }
+    }
+}
+
+impl PcrPolicy {
+    /// Build the policy from the configured PCRs and banks, restricted to
+    /// the banks allocated in the TPM. Empty lists allow all the PCRs and
+    /// all the allocated banks.
+    pub(crate) fn new(
+        allowed_pcrs: &[&str],
+        banks: &[&str],
+        allocated: &[HashAlgorithm],
+    ) -> Result<Self, Error> {
+        let allowed_mask = if allowed_pcrs.is_empty() {
+            ALL_PCRS_MASK
+        } else {
+            pcrs_to_mask(allowed_pcrs.iter().copied()).map_err(|e| {
+                Error::Configuration(format!("quote_allowed_pcrs: {e}"))
+            })?
+        };
+
+        let mut allowed_banks = Vec::new();
+        for bank in banks {
+            let bank = HashAlgorithm::try_from(*bank)?;
+            if allocated.contains(&bank) {
+                allowed_banks.push(bank);
+            } else {
+                warn!("quote_pcr_banks: the {bank} PCR bank is not allocated in the TPM");
+            }
+        }
+        if banks.is_empty() {
+            allowed_banks = allocated.to_vec();
+        }
+
+        Ok(PcrPolicy {
+            allowed_mask,
+            banks: allowed_banks,
+        })
+    }
+
+    /// Validate the requested PCRs against the policy, returning the mask of
+    /// the PCRs and the bank to quote. The bank defaults to `default_bank`,
+    /// which is always allowed.
+    pub(crate) fn select(
+        &self,
+        request: &PcrRequest,
+        default_bank: HashAlgorithm,
+    ) -> ServiceResult<(u32, HashAlgorithm)> {
+        let mask = match (request.mask, request.pcrs) {
+            (Some(mask), None) => parse_mask(mask)?,
+            (None, Some(pcrs)) => {
+                pcrs_to_mask(pcrs.split(',').map(str::trim))
+                    .map_err(ServiceError::BadRequest)?
+            }
+            (Some(_), Some(_)) => {
+                return Err(ServiceError::BadRequest(
+                    "only one of 'mask' and 'pcrs' can be given".to_string(),
+                ))
+            }
+            (None, None) => {
+                return Err(ServiceError::BadRequest(
+                    "uri must contain key 'mask' or 'pcrs'".to_string(),
+                ))
+            }
+        };
+
+        let denied = mask & !(self.allowed_mask | 1 << NK_PCR);
+        if denied != 0 {
+            return Err(ServiceError::BadRequest(format!(
+                "PCRs {:?} are not allowed by the agent configuration",
+                mask_to_pcrs(denied)
+            )));
+        }
+
+        let bank = match request.pcr_bank {
+            None => default_bank,
+            Some(bank) => {
+                let bank = HashAlgorithm::try_from(bank).map_err(|e| {
+                    ServiceError::BadRequest(format!("pcr_bank: {e}"))
+                })?;
+                if bank != default_bank && !self.banks.contains(&bank) {
+                    return Err(ServiceError::BadRequest(format!(
+                        "PCR bank {bank} is not allowed by the agent configuration"
+                    )));
+                }
+                bank
+            }
+        };
+
+        Ok((mask, bank))
+    }
+}
+
+fn parse_mask(mask: &str) -> ServiceResult<u32> {
+    if !mask.chars().all(char::is_alphanumeric) {
+        return Err(ServiceError::BadRequest(format!(
+            "mask should be strictly alphanumeric: {mask}"
+        )));
+    }
+
+    u32::from_str_radix(mask.trim_start_matches("0x"), 16).map_err(|_| {
+        ServiceError::BadRequest(format!(
+            "mask should be a hex encoded 32-bit integer: {mask}"
+        ))
+    })
+}
+
+pub(crate) fn pcrs_to_mask<'a>(
+    pcrs: impl Iterator<Item = &'a str>,
+) -> Result<u32, String> {
+    pcrs.filter(|pcr| !pcr.is_empty()).try_fold(0, |mask, pcr| {
+        match pcr.parse::<u32>() {
+            Ok(index) if index < NUM_PCRS => Ok(mask | 1 << index),
+            _ => Err(format!("PCR should be an index from 0 to 23: {pcr}")),
+        }
+    })
+}
+
+pub(crate) fn mask_to_pcrs(mask: u32) -> Vec<u32> {
+    (0..32).filter(|i| mask & 1 << i != 0).collect()
+}
+
+fn check_nonce(nonce: &str) -> ServiceResult<()> {
+    // nonce can only be in alphanumerical format
+    if !nonce.chars().all(char::is_alphanumeric) {
+        return Err(ServiceError::BadRequest(format!(
+            "nonce should be strictly alphanumeric: {nonce}"
+        )));
+    }
+
+    if nonce.len() > tpm::MAX_NONCE_SIZE {
+        return Err(ServiceError::BadRequest(format!(
+            "Nonce is too long (max size: {}): {}",
+            tpm::MAX_NONCE_SIZE,
+            nonce.len()
+        )));
+    }
+    Ok(())
+}
+
+/// Whether the verifier asked for the agent to contribute to the nonce, with
+/// the 'agent_nonce' parameter set to '1'
+pub(crate) fn agent_nonce_requested(
+    agent_nonce: Option<&str>,
+) -> ServiceResult<bool> {
+    match agent_nonce {
+        None | Some("0") => Ok(false),
+        Some("1") => Ok(true),
+        Some(other) => Err(ServiceError::BadRequest(format!(
+            "agent_nonce should be '0' or '1': {other}"
+        ))),
+    }
+}
+
+fn tpm_quote(
+    data: &QuoteData,
+    nonce: &str,
+    agent_nonce: bool,
+    mask: u32,
+    pcr_bank: HashAlgorithm,
+) -> ServiceResult<KeylimeQuote> {
+    // must unwrap here due to lock mechanism
+    // https://github.com/rust-lang-nursery/failure/issues/192
+    let mut context = data.tpm();
+
+    // The agent mixes its own entropy into the qualifying data, so that
+    // weak or repeated verifier nonces do not produce identical quotes
+    let agent_nonce = match agent_nonce && data.enable_agent_nonce {
+        true => {
+            Some(context.get_random(tpm::AGENT_NONCE_SIZE).map_err(|e| {
+                ServiceError::internal(e, "Unable to read the agent nonce")
+            })?)
+        }
+        false => {
+            if agent_nonce {
+                debug!("Agent nonce requested, but 'enable_agent_nonce' is disabled");
+            }
+            None
+        }
+    };
+    let qualifying_data = match &agent_nonce {
+        Some(agent_nonce) => {
+            tpm::combine_nonces(nonce.as_bytes(), agent_nonce).map_err(
+                |e| ServiceError::internal(e, "Unable to combine the nonces"),
+            )?
+        }
+        None => nonce.as_bytes().to_vec(),
+    };
+
+    let (quote, clock_info) = context
+        .quote_with_clock_info(
+            &qualifying_data,
+            mask,
+            &data.pub_key,
+            data.ak_handle,
+            data.hash_alg,
+            data.sign_alg,
+            pcr_bank,
+        )
+        .map_err(|e| {
+            tpm_sharing::report_exhaustion(&e);
+            ServiceError::internal(e, "Unable to retrieve quote")
+        })?;
+
+    Ok(KeylimeQuote {
+        quote,
+        hash_alg: data.hash_alg.to_string(),
+        enc_alg: data.enc_alg.to_string(),
+        sign_alg: data.sign_alg.to_string(),
+        boot_epoch: data.boot_epoch,
+        boot: data.boot.clone(),
+        asset_tag: data.asset_tag.clone(),
+        agent_nonce: agent_nonce.map(hex::encode),
+        continuity: data.agent_starts.map(|agent_starts| Continuity {
+            version: CONTINUITY_VERSION,
+            agent_starts,
+            tpm_reset_count: clock_info.reset_count(),
+            tpm_restart_count: clock_info.restart_count(),
+        }),
+        ..Default::default()
+    })
+}
+
+/// The NK public key in PEM format
+pub(crate) fn pubkey(data: &QuoteData) -> ServiceResult<String> {
+    crypto::pkey_pub_to_pem(&data.pub_key).map_err(|e| {
+        debug!("Unable to retrieve public key: {:?}", e);
+        ServiceError::Internal("Unable to retrieve public key".to_string())
+    })
+}
+
+/// Generate a quote for the tenant, which does not include any PCR
+pub(crate) fn identity_quote(
+    data: &QuoteData,
+    nonce: &str,
+    agent_nonce: Option<&str>,
+) -> ServiceResult<KeylimeQuote> {
+    check_nonce(nonce)?;
+    let agent_nonce = agent_nonce_requested(agent_nonce)?;
+
+    debug!("Calling Identity Quote with nonce: {}", nonce);
+
+    let mut quote = tpm_quote(data, nonce, agent_nonce, 0, data.hash_alg)?;
+    quote.pubkey = Some(pubkey(data).map_err(|_| {
+        ServiceError::Internal("Unable to retrieve quote".to_string())
+    })?);
+
+    data.events
+        .publish(AgentEvent::AttestationServed { quote: "identity" });
+    Ok(quote)
+}
+
+/// Read the measured boot event log, reserving the memory needed to keep
+/// it both raw and base64 encoded
+pub(crate) fn read_measured_boot_log(
+    data: &QuoteData,
+    reservations: &mut Vec<Reservation>,
+) -> ServiceResult<Option<Vec<u8>>> {
+    let Some(measuredboot_ml_file) = &data.measuredboot_ml_file else {
+        return Ok(None);
+    };
+
+    let mut ml = Vec::<u8>::new();
+    let mut f = measuredboot_ml_file.lock().unwrap(); //#[allow_ci]
+    if let Err(e) = f.rewind() {
+        debug!("Failed to rewind measured boot file: {}", e);
+        return Err(ServiceError::Internal(
+            "Unable to retrieve quote".to_string(),
+        ));
+    }
+    if let Err(e) = f.read_to_end(&mut ml) {
+        warn!("Could not read TPM2 event log: {}", e);
+        return Ok(None);
+    }
+
+    let size = ml.len() + ml.len().div_ceil(3) * 4;
+    match data.memory_budget.try_reserve(size) {
+        Some(r) => reservations.push(r),
+        None => {
+            return Err(ServiceError::Unavailable(
+                "Memory budget exceeded, try again later".to_string(),
+            ))
+        }
+    }
+    Ok(Some(ml))
+}
+
+/// Locate the part of the IMA measurement list starting from the given
+/// entry
+pub(crate) fn locate_ima_ml(
+    data: &QuoteData,
+    nth_entry: u64,
+) -> ServiceResult<Option<(File, EntryRange)>> {
+    let Some(ima_file) = &data.ima_ml_file else {
+        return Ok(None);
+    };
+
+    let mut ima_ml = data.ima_ml.lock().unwrap(); //#[allow_ci]
+    let mut f = ima_file.lock().unwrap(); //#[allow_ci]
+    ima_ml
+        .locate(&mut f, nth_entry)
+        .and_then(|range| Ok((f.try_clone()?, range)))
+        .map(Some)
+        .map_err(|e| ServiceError::internal(e, "Unable to retrieve quote"))
+}
+
+/// Read the located part of the IMA measurement list, for the responses
+/// which are not streamed. Memory for the whole range is reserved.
+pub(crate) fn read_ima_ml(
+    data: &QuoteData,
+    file: &File,
+    range: &EntryRange,
+    reservations: &mut Vec<Reservation>,
+) -> ServiceResult<String> {
+    let len = usize::try_from(range.end - range.start).map_err(|e| {
+        ServiceError::Internal(format!("Invalid measurement list size: {e}"))
+    })?;
+    match data.memory_budget.try_reserve(len) {
+        Some(r) => reservations.push(r),
+        None => {
+            return Err(ServiceError::Unavailable(
+                "Memory budget exceeded, try again later".to_string(),
+            ))
+        }
+    }
+
+    let mut buf = vec![0; len];
+    file.read_exact_at(&mut buf, range.start).map_err(|e| {
+        ServiceError::internal(e, "Unable to read measurement list")
+    })?;
+    // File names are not necessarily valid UTF-8
+    Ok(ima::escape_non_utf8(&buf).into_owned())
+}
+
+/// Generate a quote for the verifier including the PCRs selected by the
+/// mask. The measured boot log is included if PCR 0 is selected, unless it
+/// matches the ETag of the log the verifier already has.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn integrity_quote(
+    data: &QuoteData,
+    nonce: &str,
+    agent_nonce: Option<&str>,
+    pcrs: &PcrRequest,
+    partial: &str,
+    ima_ml_entry: Option<&str>,
+    mb_etag: Option<&str>,
+    scope: EvidenceScope,
+) -> ServiceResult<IntegrityQuote> {
+    check_nonce(nonce)?;
+    let agent_nonce = agent_nonce_requested(agent_nonce)?;
+
+    let (mask_value, pcr_bank) =
+        data.pcr_policy.select(pcrs, data.hash_alg)?;
+
+    // If partial="0", include the public key in the quote
+    let pubkey = match partial {
+        "0" => Some(pubkey(data)?),
+        "1" => None,
+        _ => {
+            return Err(ServiceError::BadRequest(
+                "uri must contain key 'partial' and value '0' or '1'"
+                    .to_string(),
+            ))
+        }
+    };
+
+    debug!(
+        "Calling Integrity Quote with nonce: {}, mask: {:#x}, PCR bank: {}",
+        nonce, mask_value, pcr_bank
+    );
+
+    // If an index was provided, the request is for the entries starting from the given index
+    // (iterative attestation). Otherwise the request is for the whole list.
+    let nth_entry = match ima_ml_entry {
+        None => 0,
+        Some(idx) => idx.parse::<u64>().unwrap_or(0),
+    };
+
+    let id_quote = tpm_quote(data, nonce, agent_nonce, mask_value, pcr_bank)?;
+
+    // Memory reserved for the response while it is being built and sent
+    let mut reservations = Vec::new();
+
+    // If PCR 0 is included in the mask, obtain the measured boot, unless it
+    // is not in the evidence scope of the verifier
+    let include_mb = scope.measured_boot
+        && tpm::check_mask(mask_value, &PcrSlot::Slot0).map_err(|e| {
+            debug!("Unable to check PCR mask: {:?}", e);
+            ServiceError::Internal("Unable to retrieve quote".to_string())
+        })?;
+    let (mb_measurement_list, mb_measurement_list_etag) = match include_mb {
+        true => match read_measured_boot_log(data, &mut reservations)? {
+            Some(ml) => {
+                let etag = measured_boot::etag(&ml).map_err(|e| {
+                    ServiceError::internal(e, "Unable to retrieve quote")
+                })?;
+                let ml = (mb_etag != Some(etag.as_str()))
+                    .then(|| general_purpose::STANDARD.encode(ml));
+                (ml, Some(etag))
+            }
+            None => (None, None),
+        },
+        false => (None, None),
+    };
+
+    let ima_ml = if scope.ima {
+        locate_ima_ml(data, nth_entry)?
+    } else {
+        None
+    };
+
+    // Generate the final quote based on the ID quote
+    let quote = KeylimeQuote {
+        pubkey,
+        mb_measurement_list,
+        mb_measurement_list_etag,
+        ima_measurement_list_entry: ima_ml
+            .as_ref()
+            .map(|(_, range)| range.nth_entry),
+        pcr_selection: Some(PcrSelection {
+            hash_alg: pcr_bank.to_string(),
+            pcrs: mask_to_pcrs(mask_value | 1 << NK_PCR),
+        }),
+        ..id_quote
+    };
+
+    data.events
+        .publish(AgentEvent::AttestationServed { quote: "integrity" });
+
+    Ok(IntegrityQuote {
+        quote,
+        ima_ml,
+        reservations,
+    })
+}
+
+fn decrypt_key(
+    data: &QuoteData,
+    encrypted_key: &str,
+) -> ServiceResult<SymmKey> {
+    // get key and decode it from web data
+    let encrypted_key = key_delivery::decode_encrypted_key(encrypted_key)?;
+
+    // Uses NK (key for encrypting data from verifier or tenant to agent in transit) to
+    // decrypt U and V keys, which will be combined into one key that can decrypt the
+    // payload.
+    //
+    // Reference:
+    // https://github.com/keylime/keylime/blob/f3c31b411dd3dd971fd9d614a39a150655c6797c/ \
+    // keylime/crypto.py#L118
+    let decrypted_key =
+        crypto::rsa_oaep_decrypt(&data.priv_key, &encrypted_key).map_err(
+            |e| {
+                ServiceError::BadRequest(format!(
+                    "Failed to decrypt encrypted_key: {e}"
+                ))
+            },
+        )?;
+
+    decrypted_key.as_slice().try_into().map_err(|e| {
+        ServiceError::BadRequest(format!("Invalid decrypted key: {e}"))
+    })
+}
+
+/// Decrypt the U key and check its auth tag and payload
+fn u_key(data: &QuoteData, body: &KeylimeUKey) -> ServiceResult<UKey> {
+    let decrypted_key = decrypt_key(data, &body.encrypted_key)?;
+
+    let auth_tag = match body.decode_auth_tag()? {
+        (Some(alg), auth_tag) => AuthTag::new(alg, &auth_tag),
+        (None, auth_tag) => AuthTag::try_from(auth_tag.as_slice()),
+    }
+    .map_err(ServiceError::BadRequest)?;
+
+    if !data.auth_tag_algs.contains(&auth_tag.alg()) {
+        return Err(ServiceError::BadRequest(format!(
+            "auth tag algorithm {} is not allowed by the agent configuration",
+            auth_tag.alg()
+        )));
+    }
+
+    // Account for the memory used by the decoded payload until the U key is
+    // discarded
+    let reservation = match &body.payload {
+        Some(payload) => {
+            Some(data.memory_budget.try_reserve(payload.len()).ok_or_else(
+                || {
+                    ServiceError::Unavailable(
+                        "Memory budget exceeded, try again later".to_string(),
+                    )
+                },
+            )?)
+        }
+        None => None,
+    };
+
+    let payload = body.decode_payload()?;
+
+    Ok(UKey {
+        decrypted_key,
+        auth_tag,
+        payload: payload.map(Into::into),
+        delivery: body.payload_delivery()?,
+        session: body.session()?,
+        ttl: body.ttl,
+        reservation,
+    })
+}
+
+/// Decrypt the U key and send it to the keys worker
+pub(crate) async fn deliver_u_key(
+    data: &QuoteData,
+    body: &KeylimeUKey,
+) -> ServiceResult<()> {
+    debug!("Received ukey");
+
+    let m = KeyMessage::UKey(u_key(data, body)?);
+
+    debug!("Sending UKey message to keys worker");
+
+    send_key(data, m, "UKey").await
+}
+
+/// Decrypt the V key and send it to the keys worker
+pub(crate) async fn deliver_v_key(
+    data: &QuoteData,
+    body: &KeylimeVKey,
+) -> ServiceResult<()> {
+    debug!("Received vkey");
+
+    let decrypted_key = decrypt_key(data, &body.encrypted_key)?;
+
+    let m = KeyMessage::VKey(VKey {
+        decrypted_key,
+        session: body.session()?,
+    });
+
+    debug!("Sending VKey message to keys worker");
+
+    send_key(data, m, "VKey").await
+}
+
+/// Decrypt both key halves and send them to the keys worker, which combines
+/// them at once
+pub(crate) async fn deliver_keys(
+    data: &QuoteData,
+    body: &KeylimeKeys,
+) -> ServiceResult<()> {
+    debug!("Received ukey and vkey");
+
+    let (ukey, vkey) = body.split();
+    let ukey = u_key(data, &ukey)?;
+    let vkey = VKey {
+        decrypted_key: decrypt_key(data, &vkey.encrypted_key)?,
+        session: vkey.session()?,
+    };
+    let m = KeyMessage::Keys(Box::new((ukey, vkey)));
+
+    debug!("Sending Keys message to keys worker");
+
+    send_key(data, m, "Keys").await
+}
+
+/// Send the key to the keys worker and wait until it is accepted
+async fn send_key(
+    data: &QuoteData,
+    m: KeyMessage,
+    kind: &str,
+) -> ServiceResult<()> {
+    let (resp_tx, resp_rx) = oneshot::channel::<SymmKeyMessage>();
+
+    data.keys_tx.send((m, Some(resp_tx))).await.map_err(|_| {
+        ServiceError::Internal(format!(
+            "Failed to send {kind} message to keys worker"
+        ))
+    })?;
+
+    match resp_rx.await {
+        Ok(SymmKeyMessage::Delivery(Ok(()))) => Ok(()),
+        Ok(SymmKeyMessage::Delivery(Err(e))) => Err(match e {
+            DeliveryError::InvalidAuthTag { .. }
+            | DeliveryError::InvalidKeys => {
+                ServiceError::BadRequest(e.to_string())
+            }
+            DeliveryError::TooManySessions => {
+                ServiceError::Unavailable(e.to_string())
+            }
+            _ => ServiceError::Conflict(e.to_string()),
+        }),
+        _ => Err(ServiceError::Internal(format!(
+            "Failed to receive the {kind} delivery result from keys worker"
+        ))),
+    }
+}
+
+/// Event data extended into the 'key_wipe_pcr' PCR when the keys are wiped
+const KEY_WIPE_EVENT: &[u8] = b"keylime: delivered keys wiped";
+
+/// What was removed by a wipe of the delivered keys
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) struct KeyWipe {
+    /// The keys held before the wipe
+    pub(crate) keys: KeyInventory,
+    /// Number of payload files removed from the secure mount
+    pub(crate) payload_files: usize,
+    /// The PCR extended to record the wipe, if configured
+    pub(crate) pcr: Option<u32>,
+}
+
+async fn request_keys(
+    data: &QuoteData,
+    m: KeyMessage,
+    kind: &str,
+) -> ServiceResult<KeyInventory> {
+    let (resp_tx, resp_rx) = oneshot::channel::<SymmKeyMessage>();
+
+    data.keys_tx.send((m, Some(resp_tx))).await.map_err(|_| {
+        ServiceError::Internal(format!(
+            "Failed to send {kind} message to keys worker"
+        ))
+    })?;
+
+    match resp_rx.await {
+        Ok(SymmKeyMessage::Keys(inventory)) => Ok(inventory),
+        _ => Err(ServiceError::Internal(format!(
+            "Failed to receive the {kind} result from keys worker"
+        ))),
+    }
+}
+
+/// List the metadata of the delivered keys held by the agent
+pub(crate) async fn list_keys(
+    data: &QuoteData,
+) -> ServiceResult<KeyInventory> {
+    request_keys(data, KeyMessage::ListKeys, "ListKeys").await
+}
+
+/// Overwrite and discard the delivered keys and the payloads in the secure
+/// mount, when the agent is decommissioned. If configured, the wipe is
+/// recorded in the TPM by extending 'key_wipe_pcr'.
+pub(crate) async fn wipe_keys(data: &QuoteData) -> ServiceResult<KeyWipe> {
+    let keys = request_keys(data, KeyMessage::WipeKeys, "WipeKeys").await?;
+
+    let payload_files = payloads::wipe(&data.secure_mount).map_err(|e| {
+        ServiceError::internal(e, "Failed to wipe the payload files")
+    })?;
+
+    if let Some(pcr) = data.key_wipe_pcr {
+        let mut context = data.tpm();
+        context
+            .extend_pcr(pcr, data.hash_alg, KEY_WIPE_EVENT)
+            .map_err(|e| {
+                ServiceError::internal(
+                    e,
+                    format!("Failed to record the key wipe in PCR {pcr}"),
+                )
+            })?;
+        info!("Recorded the key wipe in PCR {pcr}");
+    }
+
+    Ok(KeyWipe {
+        keys,
+        payload_files,
+        pcr: data.key_wipe_pcr,
+    })
+}
+
+/// Compute the HMAC of the challenge using the bootstrap key, to prove the
+/// U and V keys were combined successfully
+pub(crate) async fn verify_key(
+    data: &QuoteData,
+    challenge: &str,
+) -> ServiceResult<String> {
+    if challenge.is_empty() {
+        return Err(ServiceError::BadRequest(
+            "No challenge provided.".to_string(),
+        ));
+    }
+
+    if !challenge.chars().all(char::is_alphanumeric) {
+        return Err(ServiceError::BadRequest(format!(
+            "Parameters should be strictly alphanumeric: {challenge}"
+        )));
+    }
+
+    // Send a message requesting the symmetric key
+    let key = keys_handler::get_symm_key(data.keys_tx.clone())
+        .await
+        .map_err(|_| {
+            ServiceError::Internal("Failed to get bootstrap key.".to_string())
+        })?
+        .ok_or_else(|| {
+            ServiceError::BadRequest(
+                "Bootstrap key not yet available.".to_string(),
+            )
+        })?;
+
+    crypto::compute_hmac(key.as_ref(), challenge.as_bytes())
+        .map(hex::encode)
+        .map_err(|e| {
+            warn!("GET key challenge failed: {:?}", e);
+            ServiceError::Internal("GET key challenge failed".to_string())
+        })
+}
+
+/// Certify a key persisted in the TPM with the AK, so that the owner of the
+/// key can prove that it is resident in the same TPM as the enrolled AK. If
+/// the name is given, it must match the name of the key under the handle.
+pub(crate) fn certify_key(
+    data: &QuoteData,
+    handle: &str,
+    name: Option<&str>,
+    nonce: &str,
+) -> ServiceResult<KeylimeKeyCertification> {
+    check_nonce(nonce)?;
+
+    // Any persistent key can be certified, including the ones of the other
+    // TPM users, so the range of the agent does not apply
+    let persistent = HandleRange::default();
+    match tpm_sharing::parse_handle(handle) {
+        Ok(value) if persistent.contains(value) => {}
+        _ => {
+            return Err(ServiceError::BadRequest(format!(
+                "Handle is not in the persistent objects range {persistent}: {handle}"
+            )))
+        }
+    }
+
+    let qualifying_data =
+        Data::try_from(nonce.as_bytes().to_vec()).map_err(|e| {
+            ServiceError::BadRequest(format!("Invalid nonce: {e}"))
+        })?;
+
+    let certified = {
+        let mut context = data.tpm();
+        context
+            .certify_persistent_key(handle, qualifying_data, data.ak_handle)
+            .map_err(|e| {
+                debug!("Unable to certify key {handle}: {:?}", e);
+                tpm_sharing::report_exhaustion(&e);
+                if e.is_retryable() {
+                    ServiceError::Unavailable(format!(
+                        "Unable to certify key {handle}, try again later"
+                    ))
+                } else {
+                    ServiceError::BadRequest(format!(
+                        "Unable to certify key {handle}"
+                    ))
+                }
+            })?
+    };
+
+    let key_name = hex::encode(certified.name.value());
+    if let Some(expected) = name {
+        if !expected.eq_ignore_ascii_case(&key_name) {
+            return Err(ServiceError::BadRequest(format!(
+                "Name of key {handle} does not match: {key_name}"
+            )));
+        }
+    }
+
+    let encode = |buffer: tss_esapi::Result<Vec<u8>>| {
+        buffer
+            .map(|b| general_purpose::STANDARD.encode(b))
+            .map_err(|e| {
+                debug!("Unable to encode key certification: {:?}", e);
+                ServiceError::Internal(
+                    "Unable to encode key certification".to_string(),
+                )
+            })
+    };
+
+    Ok(KeylimeKeyCertification {
+        name: key_name,
+        public: encode(
+            PublicBuffer::try_from(certified.public)
+                .and_then(|p| p.marshall()),
+        )?,
+        certify_info: encode(certified.attest.marshall())?,
+        signature: encode(certified.signature.marshall())?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pcr_policy_select() {
+        let policy = PcrPolicy::new(
+            &["0", "1", "7", "10"],
+            &["sha384", "sha512"],
+            &[HashAlgorithm::Sha256, HashAlgorithm::Sha384],
+        )
+        .unwrap(); //#[allow_ci]
+        let default = HashAlgorithm::Sha256;
+
+        let request = PcrRequest {
+            mask: Some("0x400"),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.select(&request, default).unwrap(), //#[allow_ci]
+            (0x400, HashAlgorithm::Sha256)
+        );
+
+        let request = PcrRequest {
+            pcrs: Some("0, 7 ,16"),
+            pcr_bank: Some("sha384"),
+            ..Default::default()
+        };
+        assert_eq!(
+            policy.select(&request, default).unwrap(), //#[allow_ci]
+            (0x10081, HashAlgorithm::Sha384)
+        );
+
+        for request in [
+            PcrRequest::default(),
+            PcrRequest {
+                mask: Some("0x1"),
+                pcrs: Some("0"),
+                ..Default::default()
+            },
+            // Not allowed by the policy
+            PcrRequest {
+                pcrs: Some("0,8"),
+                ..Default::default()
+            },
+            PcrRequest {
+                pcrs: Some("24"),
+                ..Default::default()
+            },
+            PcrRequest {
+                mask: Some("zz"),
+                ..Default::default()
+            },
+            // Not allocated in the TPM
+            PcrRequest {
+                mask: Some("0x1"),
+                pcr_bank: Some("sha512"),
+                ..Default::default()
+            },
+        ] {
+            assert!(matches!(
+                policy.select(&request, default),
+                Err(ServiceError::BadRequest(_))
+            ));
+        }
+
+        // The default policy allows all the PCRs of the default bank
+        let request = PcrRequest {
+            mask: Some("ffffff"),
+            ..Default::default()
+        };
+        assert!(PcrPolicy::default().select(&request, default).is_ok());
+        assert!(PcrPolicy::new(&["32"], &[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_agent_nonce_requested() {
+        assert!(!agent_nonce_requested(None).unwrap()); //#[allow_ci]
+        assert!(!agent_nonce_requested(Some("0")).unwrap()); //#[allow_ci]
+        assert!(agent_nonce_requested(Some("1")).unwrap()); //#[allow_ci]
+        assert!(matches!(
+            agent_nonce_requested(Some("yes")),
+            Err(ServiceError::BadRequest(_))
+        ));
+    }
+}
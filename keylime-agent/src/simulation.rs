@@ -0,0 +1,533 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Simulation of the Keylime services interacting with the agent
+//!
+//! Provides an in-process registrar, verifier and tenant, so that the flows
+//! of the agent can be run end to end in `cargo test`, without the Python
+//! services. The registrar is served by a mock server, to which the agent
+//! registers as it does with the real one. The verifier and the tenant are
+//! clients of the agent API, served by the test on a local port.
+//!
+//! The credential of the registrar and the checks of the quotes are done
+//! with the same TPM used by the agent, as the software TPM is the only one
+//! available when testing.
+
+use crate::{
+    common::{JsonWrapper, SymmKey, API_VERSION},
+    crypto::{
+        self,
+        testing::{encrypt_aead, pkey_pub_from_pem, rsa_oaep_encrypt},
+    },
+    endpoint::Endpoint,
+    keys_handler::{KeylimeHMAC, KeylimeUKey, KeylimeVKey},
+    quotes_handler::KeylimeQuote,
+    Error, Result,
+};
+use base64::{engine::general_purpose, Engine as _};
+use keylime::tpm;
+use openssl::rand::rand_bytes;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+use tss_esapi::{
+    interface_types::resource_handles::Hierarchy,
+    structures::{Public, PublicBuffer},
+    traits::UnMarshall,
+};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+/// Length of the credential wrapped by the registrar, and of the keys
+/// delivered by the tenant
+const KEY_LEN: usize = 32;
+
+#[derive(Debug, Clone)]
+struct RegisteredAgent {
+    aik_tpm: Vec<u8>,
+    secret: Vec<u8>,
+    active: bool,
+}
+
+type Agents = Arc<Mutex<HashMap<String, RegisteredAgent>>>;
+
+/// A failure response with the status set by the registrar
+fn failure(code: u16, status: &str) -> ResponseTemplate {
+    ResponseTemplate::new(code).set_body_json(json!({
+        "code": code,
+        "status": status,
+        "results": {},
+    }))
+}
+
+fn response(code: u16, results: Value) -> ResponseTemplate {
+    ResponseTemplate::new(code).set_body_json(json!({
+        "code": code,
+        "status": if code == 200 { "Success" } else { "Failure" },
+        "results": results,
+    }))
+}
+
+/// Decode the base64 encoded TPM2B_PUBLIC
+fn decode_public(encoded: Option<&Value>) -> Option<(Vec<u8>, Public)> {
+    let decoded =
+        general_purpose::STANDARD.decode(encoded?.as_str()?).ok()?;
+    let public = Public::try_from(PublicBuffer::unmarshall(&decoded).ok()?);
+    Some((decoded, public.ok()?))
+}
+
+struct RegistrarResponder {
+    tpm: Arc<Mutex<tpm::Context>>,
+    agents: Agents,
+}
+
+impl RegistrarResponder {
+    fn register(&self, uuid: &str, body: &[u8]) -> ResponseTemplate {
+        let Ok(body) = serde_json::from_slice::<Value>(body) else {
+            return response(400, json!({}));
+        };
+        let (Some((_, ek)), Some((aik_tpm, ak))) = (
+            decode_public(body.get("ek_tpm")),
+            decode_public(body.get("aik_tpm")),
+        ) else {
+            return response(400, json!({}));
+        };
+
+        let mut secret = vec![0; KEY_LEN];
+        if rand_bytes(&mut secret).is_err() {
+            return response(500, json!({}));
+        }
+
+        let mut ctx = self.tpm.lock().unwrap(); //#[allow_ci]
+        let Ok(blob) =
+            tpm::testing::make_credential(ctx.as_mut(), ek, ak, &secret)
+        else {
+            return response(500, json!({}));
+        };
+
+        let agent = RegisteredAgent {
+            aik_tpm,
+            secret,
+            active: false,
+        };
+        _ = self
+            .agents
+            .lock()
+            .unwrap() //#[allow_ci]
+            .insert(uuid.to_string(), agent);
+
+        response(
+            200,
+            json!({ "blob": general_purpose::STANDARD.encode(blob) }),
+        )
+    }
+
+    fn activate(&self, uuid: &str, body: &[u8]) -> ResponseTemplate {
+        let mut agents = self.agents.lock().unwrap(); //#[allow_ci]
+        let Some(agent) = agents.get_mut(uuid) else {
+            return response(404, json!({}));
+        };
+        let auth_tag = serde_json::from_slice::<Value>(body)
+            .ok()
+            .and_then(|b| b.get("auth_tag")?.as_str().map(str::to_string));
+
+        // The agent proves it decrypted the credential with the HMAC of its
+        // UUID, keyed with the base64 encoded credential
+        let mackey = general_purpose::STANDARD.encode(&agent.secret);
+        let expected =
+            crypto::compute_hmac(mackey.as_bytes(), uuid.as_bytes())
+                .map(hex::encode);
+        match (auth_tag, expected) {
+            (Some(tag), Ok(expected)) if tag == expected => {
+                agent.active = true;
+                response(200, json!({}))
+            }
+            _ => failure(
+                400,
+                &format!(
+                    "Auth tag for agent {uuid} does not match expected value"
+                ),
+            ),
+        }
+    }
+}
+
+impl Respond for RegistrarResponder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let uuid = request
+            .url
+            .path_segments()
+            .and_then(|mut s| s.next_back())
+            .unwrap_or_default();
+        match request.method.to_string().as_str() {
+            "POST" => self.register(uuid, &request.body),
+            "PUT" => self.activate(uuid, &request.body),
+            _ => response(405, json!({})),
+        }
+    }
+}
+
+/// A registrar accepting the registration of any agent, which is activated
+/// once it proves the AK is in the same TPM as the EK
+pub(crate) struct MockRegistrar {
+    server: MockServer,
+    agents: Agents,
+}
+
+impl MockRegistrar {
+    pub(crate) async fn start(tpm: Arc<Mutex<tpm::Context>>) -> Self {
+        let agents = Agents::default();
+        let server = MockServer::start().await;
+        Mock::given(wiremock::matchers::path_regex(format!(
+            "^/{API_VERSION}/agents/[^/]+$"
+        )))
+        .respond_with(RegistrarResponder {
+            tpm,
+            agents: agents.clone(),
+        })
+        .mount(&server)
+        .await;
+
+        MockRegistrar { server, agents }
+    }
+
+    pub(crate) fn endpoint(&self) -> Endpoint {
+        let addr = self.server.address();
+        Endpoint::new(&addr.ip().to_string(), addr.port().into())
+    }
+
+    pub(crate) fn is_active(&self, uuid: &str) -> bool {
+        self.agents
+            .lock()
+            .unwrap() //#[allow_ci]
+            .get(uuid)
+            .is_some_and(|a| a.active)
+    }
+
+    /// The marshalled TPM2B_PUBLIC of the AK registered by the agent
+    pub(crate) fn aik_tpm(&self, uuid: &str) -> Option<Vec<u8>> {
+        self.agents
+            .lock()
+            .unwrap() //#[allow_ci]
+            .get(uuid)
+            .map(|a| a.aik_tpm.clone())
+    }
+}
+
+/// A fresh nonce, in the alphanumeric format accepted by the agent
+fn nonce() -> Result<String> {
+    let mut nonce = [0u8; 10];
+    rand_bytes(&mut nonce)?;
+    Ok(hex::encode(nonce))
+}
+
+/// A verifier requesting integrity quotes and checking them against the AK
+/// registered by the agent
+pub(crate) struct MockVerifier {
+    tpm: Arc<Mutex<tpm::Context>>,
+    ak: Public,
+}
+
+impl MockVerifier {
+    pub(crate) fn new(
+        tpm: Arc<Mutex<tpm::Context>>,
+        aik_tpm: &[u8],
+    ) -> Result<Self> {
+        let ak = Public::try_from(PublicBuffer::unmarshall(aik_tpm)?)?;
+        Ok(MockVerifier { tpm, ak })
+    }
+
+    /// Request an integrity quote of the PCRs in the mask with a fresh
+    /// nonce, and check that the quote is signed by the AK, includes the
+    /// nonce and matches the PCR values
+    pub(crate) async fn attest(
+        &self,
+        agent: &Endpoint,
+        mask: &str,
+    ) -> Result<KeylimeQuote> {
+        let nonce = nonce()?;
+        let url = agent.url(
+            "http",
+            &format!(
+                "{API_VERSION}/quotes/integrity?nonce={nonce}&mask={mask}&partial=1"
+            ),
+        );
+        let resp = reqwest::get(&url).await?;
+        if !resp.status().is_success() {
+            return Err(Error::Other(format!(
+                "Quote request failed with {}",
+                resp.status()
+            )));
+        }
+        let quote = resp.json::<JsonWrapper<KeylimeQuote>>().await?.results;
+
+        let mut ctx = self.tpm.lock().unwrap(); //#[allow_ci]
+        let ctx = ctx.as_mut();
+        let ak =
+            ctx.load_external_public(self.ak.clone(), Hierarchy::Null)?;
+        let checked = tpm::testing::check_quote(
+            ctx,
+            ak,
+            &quote.quote,
+            nonce.as_bytes(),
+        );
+        ctx.flush_context(ak.into())?;
+        checked?;
+
+        Ok(quote)
+    }
+}
+
+/// A tenant delivering the U and V halves of a key to the agent, with an
+/// optional payload encrypted with the key
+pub(crate) struct MockTenant {
+    agent_uuid: String,
+}
+
+impl MockTenant {
+    pub(crate) fn new(agent_uuid: &str) -> Self {
+        MockTenant {
+            agent_uuid: agent_uuid.to_string(),
+        }
+    }
+
+    /// Deliver a new key and check that the agent combined it, by
+    /// requesting the HMAC of a challenge. Returns the delivered key.
+    pub(crate) async fn deliver(
+        &self,
+        agent: &Endpoint,
+        payload: Option<&[u8]>,
+    ) -> Result<SymmKey> {
+        let client = reqwest::Client::new();
+
+        let pubkey = client
+            .get(agent.url("http", &format!("{API_VERSION}/keys/pubkey")))
+            .send()
+            .await?
+            .json::<JsonWrapper<HashMap<String, String>>>()
+            .await?
+            .results;
+        let pubkey =
+            pkey_pub_from_pem(pubkey.get("pubkey").ok_or_else(|| {
+                Error::Other("No pubkey in the response".to_string())
+            })?)?;
+
+        let mut k = [0u8; KEY_LEN];
+        let mut u = [0u8; KEY_LEN];
+        let mut iv = [0u8; 16];
+        rand_bytes(&mut k)?;
+        rand_bytes(&mut u)?;
+        rand_bytes(&mut iv)?;
+        let k = SymmKey::try_from(&k[..]).map_err(Error::Other)?;
+        let u = SymmKey::try_from(&u[..]).map_err(Error::Other)?;
+        let v = k.xor(&u)?;
+
+        let payload = payload
+            .map(|p| encrypt_aead(k.as_ref(), &iv, p))
+            .transpose()?;
+        let auth_tag =
+            crypto::compute_hmac(k.as_ref(), self.agent_uuid.as_bytes())?;
+
+        let ukey = KeylimeUKey {
+            auth_tag: hex::encode(auth_tag),
+            auth_tag_alg: None,
+            encrypted_key: general_purpose::STANDARD
+                .encode(rsa_oaep_encrypt(&pubkey, u.as_ref())?),
+            payload: payload.map(|p| general_purpose::STANDARD.encode(p)),
+            session_id: None,
+            sequence: None,
+            payload_delivery: None,
+            ttl: None,
+        };
+        let vkey = KeylimeVKey {
+            encrypted_key: general_purpose::STANDARD
+                .encode(rsa_oaep_encrypt(&pubkey, v.as_ref())?),
+            session_id: None,
+            sequence: None,
+        };
+
+        for (half, body) in [
+            ("ukey", serde_json::to_value(ukey)?),
+            ("vkey", serde_json::to_value(vkey)?),
+        ] {
+            let resp = client
+                .post(
+                    agent.url("http", &format!("{API_VERSION}/keys/{half}")),
+                )
+                .json(&body)
+                .send()
+                .await?;
+            if !resp.status().is_success() {
+                return Err(Error::Other(format!(
+                    "Delivery of the {half} failed with {}",
+                    resp.status()
+                )));
+            }
+        }
+
+        let challenge = nonce()?;
+        let hmac = client
+            .get(agent.url(
+                "http",
+                &format!("{API_VERSION}/keys/verify?challenge={challenge}"),
+            ))
+            .send()
+            .await?
+            .json::<JsonWrapper<KeylimeHMAC>>()
+            .await?
+            .results
+            .hmac;
+        let expected =
+            crypto::compute_hmac(k.as_ref(), challenge.as_bytes())?;
+        if hmac != hex::encode(expected) {
+            return Err(Error::Other(
+                "The agent did not combine the delivered key".to_string(),
+            ));
+        }
+
+        Ok(k)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::KeylimeConfig,
+        events::EventPublisher,
+        http_client::{HttpClient, HttpClientOptions},
+        keys_handler::{self, KeyMessage, SymmKeyMessage},
+        payloads::PayloadMessage,
+        quotes_handler, QuoteData,
+    };
+    use actix_web::{rt, web, App, HttpServer};
+    use keylime::algorithms::{
+        EncryptionAlgorithm, HashAlgorithm, SignAlgorithm,
+    };
+    use tokio::sync::{mpsc, oneshot};
+
+    #[actix_rt::test]
+    async fn test_simulation() {
+        let mut config = KeylimeConfig::default();
+        let mut fixture = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let uuid = fixture.agent_uuid.clone();
+        let tpm = fixture.tpmcontext.clone();
+
+        // Register and activate a new AK with the mock registrar
+        let registrar = MockRegistrar::start(tpm.clone()).await;
+        config.agent.registrar_ip = registrar.endpoint().host().to_string();
+        config.agent.registrar_port = registrar.endpoint().port();
+        let (ek, ak, ak_handle) = {
+            let mut ctx = tpm.lock().unwrap(); //#[allow_ci]
+            let ek = ctx.create_ek(EncryptionAlgorithm::Rsa, None).unwrap(); //#[allow_ci]
+            let ak = ctx
+                .create_ak(
+                    ek.key_handle,
+                    HashAlgorithm::Sha256,
+                    SignAlgorithm::RsaSsa,
+                )
+                .unwrap(); //#[allow_ci]
+            let ak_handle = ctx.load_ak(ek.key_handle, &ak).unwrap(); //#[allow_ci]
+            (ek, ak, ak_handle)
+        };
+        crate::register_and_activate(
+            &config,
+            &tpm,
+            &crate::tpm_sharing::TpmLock::default(),
+            &uuid,
+            &ek,
+            ak_handle,
+            &ak,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            &EventPublisher::default(),
+            &HttpClient::new(HttpClientOptions::default()).unwrap(), //#[allow_ci]
+        )
+        .await
+        .unwrap(); //#[allow_ci]
+        assert!(registrar.is_active(&uuid));
+        fixture.ak_handle = ak_handle;
+
+        // Serve the quotes and keys API, with a keys worker forwarding the
+        // combined key to a fake payloads worker
+        let (keys_tx, keys_rx) = mpsc::channel::<(
+            KeyMessage,
+            Option<oneshot::Sender<SymmKeyMessage>>,
+        )>(1);
+        let (payload_tx, mut payload_rx) = mpsc::channel::<PayloadMessage>(1);
+        fixture.keys_tx = keys_tx;
+        fixture.payload_tx = payload_tx.clone();
+        _ = rt::spawn(keys_handler::worker(
+            true,
+            uuid.clone(),
+            keys_rx,
+            payload_tx,
+            None,
+            None,
+            None,
+            EventPublisher::default(),
+        ));
+
+        let quotedata = web::Data::new(fixture);
+        let server = HttpServer::new(move || {
+            App::new().app_data(quotedata.clone()).service(
+                web::scope(&format!("/{API_VERSION}"))
+                    .route(
+                        "/quotes/integrity",
+                        web::get().to(quotes_handler::integrity),
+                    )
+                    .route(
+                        "/keys/pubkey",
+                        web::get().to(keys_handler::pubkey),
+                    )
+                    .route("/keys/ukey", web::post().to(keys_handler::u_key))
+                    .route("/keys/vkey", web::post().to(keys_handler::v_key))
+                    .route(
+                        "/keys/verify",
+                        web::get().to(keys_handler::verify),
+                    ),
+            )
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap(); //#[allow_ci]
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        _ = rt::spawn(server);
+        let agent = Endpoint::new(&addr.ip().to_string(), addr.port().into());
+
+        // The verifier checks the quote with the registered AK
+        let aik_tpm = registrar.aik_tpm(&uuid).unwrap(); //#[allow_ci]
+        let verifier = MockVerifier::new(tpm.clone(), &aik_tpm).unwrap(); //#[allow_ci]
+        let quote = verifier.attest(&agent, "0x400").await.unwrap(); //#[allow_ci]
+        assert_eq!(quote.hash_alg, "sha256");
+
+        // The tenant delivers a key and a payload
+        let tenant = MockTenant::new(&uuid);
+        let k = tenant.deliver(&agent, Some(b"payload")).await.unwrap(); //#[allow_ci]
+        match payload_rx.recv().await {
+            Some(PayloadMessage::RunPayload(payload)) => {
+                assert_eq!(payload.symm_key.as_ref(), k.as_ref());
+                let decrypted = crypto::decrypt_aead(
+                    k.as_ref(),
+                    payload.encrypted_payload.as_ref(),
+                )
+                .unwrap(); //#[allow_ci]
+                assert_eq!(decrypted, b"payload");
+            }
+            _ => panic!("Expected the payload to be run"), //#[allow_ci]
+        }
+
+        handle.stop(false).await;
+        let mut ctx = tpm.lock().unwrap(); //#[allow_ci]
+        ctx.as_mut().flush_context(ak_handle.into()).unwrap(); //#[allow_ci]
+        ctx.as_mut().flush_context(ek.key_handle.into()).unwrap(); //#[allow_ci]
+    }
+}
@@ -0,0 +1,193 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Export of the attestation result for a SPIRE node attestor plugin
+//!
+//! Once the U and V keys are combined successfully, the agent has proven to
+//! the tenant and the verifier that it runs on the attested machine. The
+//! result is written to a file readable by a local SPIRE agent plugin, which
+//! can send it as the node attestation data. The proof is an HMAC of the
+//! result contents using the bootstrap key, which is known by the tenant, so
+//! that the server side plugin can verify it.
+//!
+//! Trust model: the proof is a bearer token. Whoever reads the file can
+//! present it, so the file must only be readable by the SPIRE agent, e.g.
+//! through the group of the file. To bound the replay of a leaked proof,
+//! each result carries a random nonce and expires after PROOF_VALIDITY. The
+//! agent issues a new result every REFRESH_INTERVAL while it holds the
+//! bootstrap key, and removes it when the key is wiped or expires. The
+//! server side plugin must check the HMAC, reject the expired results and
+//! reject a nonce already seen before its expiry, so that each result is
+//! accepted at most once.
+
+use crate::{clock::Clock, common::SymmKey, crypto, Error, Result};
+use chrono::{Duration as ChronoDuration, SecondsFormat};
+use log::*;
+use openssl::rand::rand_bytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, Permissions},
+    io::Write,
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    time::Duration,
+};
+
+/// Time after which the server side plugin rejects an attestation result
+pub(crate) const PROOF_VALIDITY: Duration = Duration::from_secs(300);
+
+/// Interval at which a new attestation result is issued, well before the
+/// previous one expires
+pub(crate) const REFRESH_INTERVAL: Duration = Duration::from_secs(120);
+
+/// Length of the random nonce of the attestation results
+const NONCE_LEN: usize = 16;
+
+/// The attestation result read by the SPIRE node attestor plugin
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) struct AttestationResult {
+    pub agent_uuid: String,
+    /// Time the result was issued, while the agent held the bootstrap key
+    pub attested_at: String,
+    /// Time after which the result must be rejected
+    pub expires_at: String,
+    /// Hex encoded random nonce, accepted at most once
+    pub nonce: String,
+    /// Hex encoded HMAC of
+    /// "<agent_uuid>:<attested_at>:<expires_at>:<nonce>" using the bootstrap
+    /// key
+    pub proof: String,
+}
+
+impl AttestationResult {
+    pub(crate) fn new(
+        agent_uuid: &str,
+        key: &SymmKey,
+        clock: &dyn Clock,
+    ) -> Result<Self> {
+        let now = clock.now();
+        let attested_at = now.to_rfc3339_opts(SecondsFormat::Secs, true);
+        let validity = ChronoDuration::from_std(PROOF_VALIDITY)
+            .map_err(|e| Error::Other(e.to_string()))?;
+        let expires_at =
+            (now + validity).to_rfc3339_opts(SecondsFormat::Secs, true);
+        let mut nonce = [0u8; NONCE_LEN];
+        rand_bytes(&mut nonce)?;
+        let nonce = hex::encode(nonce);
+
+        let proof = crypto::compute_hmac(
+            key.as_ref(),
+            format!("{agent_uuid}:{attested_at}:{expires_at}:{nonce}")
+                .as_bytes(),
+        )?;
+
+        Ok(AttestationResult {
+            agent_uuid: agent_uuid.to_string(),
+            attested_at,
+            expires_at,
+            nonce,
+            proof: hex::encode(proof),
+        })
+    }
+
+    /// Atomically replace the file with the attestation result, so that the
+    /// plugin never reads a partial result
+    pub(crate) fn store(&self, path: &Path) -> Result<()> {
+        let dir = path.parent().ok_or_else(|| {
+            Error::Configuration(format!(
+                "Invalid SPIRE attestation path {}",
+                path.display()
+            ))
+        })?;
+
+        let mut file = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.flush()?;
+        fs::set_permissions(file.path(), Permissions::from_mode(0o640))?;
+        let _ = file.persist(path)?;
+        Ok(())
+    }
+}
+
+/// Export the attestation result after the bootstrap key was derived
+pub(crate) fn export(
+    path: &Path,
+    agent_uuid: &str,
+    key: &SymmKey,
+    clock: &dyn Clock,
+) {
+    match AttestationResult::new(agent_uuid, key, clock)
+        .and_then(|result| result.store(path))
+    {
+        Ok(()) => info!(
+            "Attestation result for SPIRE written to {}",
+            path.display()
+        ),
+        Err(e) => warn!(
+            "Failed to write attestation result for SPIRE to {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+/// Remove the attestation result when the bootstrap key is wiped, so that
+/// the proof can not be used anymore
+pub(crate) fn remove(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => info!(
+            "Attestation result for SPIRE removed from {}",
+            path.display()
+        ),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+        Err(e) => warn!(
+            "Failed to remove attestation result for SPIRE from {}: {}",
+            path.display(),
+            e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{clock::FixedClock, common::AES_128_KEY_LEN};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_export() {
+        let key = SymmKey::try_from(&[0x42; AES_128_KEY_LEN][..]).unwrap(); //#[allow_ci]
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("keylime-attestation.json");
+
+        export(&path, "agent-uuid", &key, &FixedClock::at(1_700_000_000));
+
+        let result: AttestationResult =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap(); //#[allow_ci]
+        assert_eq!(result.agent_uuid, "agent-uuid");
+        assert_eq!(result.attested_at, "2023-11-14T22:13:20Z");
+        assert_eq!(result.expires_at, "2023-11-14T22:18:20Z");
+        assert_eq!(result.nonce.len(), 2 * NONCE_LEN);
+        let proof = hex::decode(&result.proof).unwrap(); //#[allow_ci]
+        assert!(crypto::verify_hmac(
+            key.as_ref(),
+            format!(
+                "agent-uuid:{}:{}:{}",
+                result.attested_at, result.expires_at, result.nonce
+            )
+            .as_bytes(),
+            &proof,
+        )
+        .is_ok());
+
+        // Each result has its own nonce
+        export(&path, "agent-uuid", &key, &FixedClock::at(1_700_000_000));
+        let refreshed: AttestationResult =
+            serde_json::from_slice(&fs::read(&path).unwrap()).unwrap(); //#[allow_ci]
+        assert_ne!(refreshed.nonce, result.nonce);
+        assert_ne!(refreshed.proof, result.proof);
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode(); //#[allow_ci]
+        assert_eq!(mode & 0o777, 0o640);
+    }
+}
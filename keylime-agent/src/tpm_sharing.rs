@@ -0,0 +1,295 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Coexistence with other TPM users, like tpm2-pkcs11
+//!
+//! The TPM is shared by all the processes of the system. The resource
+//! manager, either the kernel one or tpm2-abrmd, isolates the transient
+//! objects and sessions of each connection, but the handles of the
+//! persistent objects, the slots for the loaded objects and the sessions
+//! are shared by all the users.
+//!
+//! The agent is restricted to a configured range of persistent handles, can
+//! take an advisory lock on a file around its use of the TPM, so that other
+//! TPM users running sequences of commands can coordinate with it, and
+//! reports when the TPM runs out of handles or memory.
+
+use crate::{
+    config::KeylimeConfig,
+    error::{Error, Result},
+};
+use keylime::tpm::{self, TpmError};
+use log::*;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    ops::{Deref, DerefMut},
+    os::unix::io::AsRawFd,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// The range of the handles of the persistent objects
+const PERSISTENT_FIRST: u32 = 0x8100_0000;
+const PERSISTENT_LAST: u32 = 0x81ff_ffff;
+
+/// A range of persistent handles, inclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct HandleRange {
+    first: u32,
+    last: u32,
+}
+
+impl Default for HandleRange {
+    fn default() -> Self {
+        HandleRange {
+            first: PERSISTENT_FIRST,
+            last: PERSISTENT_LAST,
+        }
+    }
+}
+
+impl fmt::Display for HandleRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{:08x}-0x{:08x}", self.first, self.last)
+    }
+}
+
+/// Parse a handle in hexadecimal, with or without the 0x prefix
+pub(crate) fn parse_handle(handle: &str) -> Result<u32> {
+    let handle = handle.trim();
+    let digits = handle
+        .strip_prefix("0x")
+        .or_else(|| handle.strip_prefix("0X"))
+        .unwrap_or(handle);
+    u32::from_str_radix(digits, 16).map_err(|e| {
+        Error::Configuration(format!("invalid TPM handle '{handle}': {e}"))
+    })
+}
+
+impl HandleRange {
+    /// Parse a range in the format 'first-last', where an empty string is
+    /// the whole range of the persistent objects
+    pub(crate) fn parse(range: &str) -> Result<Self> {
+        let range = range.trim();
+        if range.is_empty() {
+            return Ok(HandleRange::default());
+        }
+
+        let Some((first, last)) = range.split_once('-') else {
+            return Err(Error::Configuration(format!(
+                "invalid handle range '{range}', expected 'first-last'"
+            )));
+        };
+        let (first, last) = (parse_handle(first)?, parse_handle(last)?);
+        if first > last || first < PERSISTENT_FIRST || last > PERSISTENT_LAST
+        {
+            return Err(Error::Configuration(format!(
+                "invalid handle range '{range}', expected a range within 0x{PERSISTENT_FIRST:08x}-0x{PERSISTENT_LAST:08x}"
+            )));
+        }
+
+        Ok(HandleRange { first, last })
+    }
+
+    pub(crate) fn contains(&self, handle: u32) -> bool {
+        (self.first..=self.last).contains(&handle)
+    }
+}
+
+/// Whether the TCTI connects through a resource manager, which isolates the
+/// transient objects and sessions of the agent from the other TPM users
+pub(crate) fn uses_resource_manager(tcti: &str) -> bool {
+    let (name, conf) = tcti.split_once(':').unwrap_or((tcti, ""));
+    match name {
+        "tabrmd" => true,
+        "device" => Path::new(conf)
+            .file_name()
+            .is_some_and(|f| f.to_string_lossy().starts_with("tpmrm")),
+        _ => false,
+    }
+}
+
+/// Check how the TPM is accessed before connecting to it
+pub(crate) fn check(config: &KeylimeConfig) -> Result<()> {
+    let tcti = tpm::tcti_conf();
+    if !uses_resource_manager(&tcti) {
+        if config.agent.tpm_require_resource_manager {
+            let message = format!("The TPM is accessed through '{tcti}' without a resource manager, but 'tpm_require_resource_manager' is enabled");
+            error!("{message}");
+            return Err(Error::Configuration(message));
+        }
+        warn!("The TPM is accessed through '{tcti}' without a resource manager, other TPM users like tpm2-pkcs11 can not use the TPM while the agent is running");
+    }
+
+    Ok(())
+}
+
+/// Report the persistent objects in the range of the agent and the
+/// capacity left in the TPM
+pub(crate) fn report_usage(ctx: &mut tpm::Context, range: &HandleRange) {
+    match ctx.list_persistent_handles() {
+        Ok(handles) => {
+            let in_range =
+                handles.iter().filter(|h| range.contains(**h)).count();
+            info!(
+                "TPM persistent objects: {} in total, {in_range} in the agent range {range}",
+                handles.len()
+            );
+        }
+        Err(e) => warn!("Unable to list the TPM persistent objects: {e}"),
+    }
+
+    match ctx.handle_usage() {
+        Ok(usage) => {
+            debug!("TPM capacity left: {usage:?}");
+            if usage.persistent_available == Some(0) {
+                warn!("The TPM has no space left for persistent objects");
+            }
+            if usage.loaded_available == Some(0)
+                || usage.transient_available == Some(0)
+            {
+                warn!("The TPM has no space left for loaded objects and sessions, the agent may fail to use the TPM");
+            }
+        }
+        Err(e) => warn!("Unable to read the TPM capacity: {e}"),
+    }
+}
+
+/// Report the TPM running out of handles or memory, which can be caused by
+/// other TPM users, and is otherwise only seen as a failed operation
+pub(crate) fn report_exhaustion(e: &TpmError) {
+    if e.is_resource_exhausted() {
+        error!("The TPM ran out of handles or memory for objects and sessions ({e}): check whether other TPM users, like tpm2-pkcs11, are holding TPM resources");
+    }
+}
+
+/// An advisory lock on a file, taken while the agent uses the TPM
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TpmLock {
+    file: Option<Arc<File>>,
+}
+
+impl TpmLock {
+    /// Open the lock file, if the path is not empty
+    pub(crate) fn open(path: &str) -> Result<Self> {
+        if path.is_empty() {
+            return Ok(TpmLock::default());
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+        info!("Using {path} as TPM lock file");
+        Ok(TpmLock {
+            file: Some(Arc::new(file)),
+        })
+    }
+
+    fn acquire(&self) -> Option<FileLock<'_>> {
+        let file = self.file.as_ref()?;
+        // SAFETY: the descriptor is valid while the file is open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            warn!(
+                "Unable to lock the TPM lock file: {}",
+                std::io::Error::last_os_error()
+            );
+            return None;
+        }
+        Some(FileLock { file })
+    }
+}
+
+struct FileLock<'a> {
+    file: &'a File,
+}
+
+impl Drop for FileLock<'_> {
+    fn drop(&mut self) {
+        // SAFETY: the descriptor is valid while the file is open
+        _ = unsafe { libc::flock(self.file.as_raw_fd(), libc::LOCK_UN) };
+    }
+}
+
+/// The TPM context, locked for the agent and for the cooperating TPM users
+pub(crate) struct TpmGuard<'a> {
+    // The file lock is released before the context
+    _lock: Option<FileLock<'a>>,
+    ctx: MutexGuard<'a, tpm::Context>,
+}
+
+/// Lock the TPM context, and then the lock file, so that the agent threads
+/// wait on the mutex and not on the file
+pub(crate) fn lock<'a>(
+    tpm: &'a Mutex<tpm::Context>,
+    tpm_lock: &'a TpmLock,
+) -> TpmGuard<'a> {
+    let ctx = tpm.lock().unwrap(); //#[allow_ci]
+    TpmGuard {
+        _lock: tpm_lock.acquire(),
+        ctx,
+    }
+}
+
+impl Deref for TpmGuard<'_> {
+    type Target = tpm::Context;
+
+    fn deref(&self) -> &Self::Target {
+        &self.ctx
+    }
+}
+
+impl DerefMut for TpmGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.ctx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_range() {
+        let range = HandleRange::parse("0x81010000-0x8101ffff").unwrap(); //#[allow_ci]
+        assert!(range.contains(0x81010001));
+        assert!(!range.contains(0x81000001));
+        assert_eq!(range.to_string(), "0x81010000-0x8101ffff");
+
+        assert_eq!(HandleRange::parse("").unwrap(), HandleRange::default()); //#[allow_ci]
+        assert!(HandleRange::parse("0x81010000").is_err());
+        assert!(HandleRange::parse("0x8101ffff-0x81010000").is_err());
+        assert!(HandleRange::parse("0x01000000-0x8101ffff").is_err());
+    }
+
+    #[test]
+    fn test_uses_resource_manager() {
+        assert!(uses_resource_manager("device:/dev/tpmrm0"));
+        assert!(uses_resource_manager("tabrmd:bus_type=system"));
+        assert!(uses_resource_manager("tabrmd"));
+        assert!(!uses_resource_manager("device:/dev/tpm0"));
+        assert!(!uses_resource_manager("mssim:host=localhost,port=2321"));
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("tpm.lock");
+        let tpm_lock = TpmLock::open(&path.to_string_lossy()).unwrap(); //#[allow_ci]
+        let guard = tpm_lock.acquire();
+        assert!(guard.is_some());
+
+        // The lock is held on the open file description, so another one
+        // can not take it while the guard exists
+        let other = File::open(&path).unwrap(); //#[allow_ci]
+        let locked = |file: &File| unsafe {
+            libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+        };
+        assert_ne!(locked(&other), 0);
+        drop(guard);
+        assert_eq!(locked(&other), 0);
+
+        assert!(TpmLock::open("").unwrap().acquire().is_none()); //#[allow_ci]
+    }
+}
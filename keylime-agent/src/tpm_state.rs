@@ -0,0 +1,210 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Detection of TPM clears and PCR bank reallocations
+//!
+//! The TPM clock information and the allocated PCR banks are stored with the
+//! agent data. The reset and restart counters and the clock never go
+//! backwards, except when the TPM is cleared, so lower values than the
+//! stored ones show that the TPM was cleared. A different set of allocated
+//! PCR banks shows that the PCR allocation was changed. In both cases the
+//! stored TPM data does not describe the TPM anymore, and the verifier would
+//! fail to validate the quotes without an obvious reason.
+
+use crate::{
+    events::{AgentEvent, EventPublisher},
+    QuoteData, Result,
+};
+use actix_web::web;
+use keylime::tpm;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::{fmt, time::Duration};
+use tokio::sync::mpsc::Receiver;
+use tss_esapi::handles::KeyHandle;
+
+/// The TPM state compared across the agent runs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct TpmState {
+    pub clock: u64,
+    pub reset_count: u32,
+    pub restart_count: u32,
+    pub pcr_banks: Vec<String>,
+}
+
+/// A change of the TPM that invalidates the stored TPM data
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum TpmChange {
+    Cleared,
+    PcrBanksChanged {
+        previous: Vec<String>,
+        current: Vec<String>,
+    },
+}
+
+impl fmt::Display for TpmChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TpmChange::Cleared => write!(f, "The TPM was cleared"),
+            TpmChange::PcrBanksChanged { previous, current } => write!(
+                f,
+                "The PCR banks allocation changed from [{}] to [{}]",
+                previous.join(", "),
+                current.join(", ")
+            ),
+        }
+    }
+}
+
+impl TpmState {
+    /// Read the current state, using the AK to obtain the clock information
+    pub(crate) fn read(
+        ctx: &mut tpm::Context,
+        ak_handle: KeyHandle,
+    ) -> Result<Self> {
+        let clock_info = ctx.read_clock_info(ak_handle)?;
+        let mut pcr_banks: Vec<String> = ctx
+            .get_pcr_banks()?
+            .iter()
+            .map(|alg| format!("{alg:?}").to_lowercase())
+            .collect();
+        pcr_banks.sort();
+
+        Ok(TpmState {
+            clock: clock_info.clock(),
+            reset_count: clock_info.reset_count(),
+            restart_count: clock_info.restart_count(),
+            pcr_banks,
+        })
+    }
+
+    /// The changes of the TPM since the previous state was read
+    pub(crate) fn changes(&self, previous: &TpmState) -> Vec<TpmChange> {
+        let mut changes = Vec::new();
+
+        // The clock can only lose the time not saved to NV on a TPM reset,
+        // which increments the reset counter
+        let counters = (self.reset_count, self.restart_count);
+        let previous_counters =
+            (previous.reset_count, previous.restart_count);
+        if counters < previous_counters
+            || (counters == previous_counters && self.clock < previous.clock)
+        {
+            changes.push(TpmChange::Cleared);
+        }
+
+        if self.pcr_banks != previous.pcr_banks {
+            changes.push(TpmChange::PcrBanksChanged {
+                previous: previous.pcr_banks.clone(),
+                current: self.pcr_banks.clone(),
+            });
+        }
+
+        changes
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum TpmStateMessage {
+    Shutdown,
+}
+
+/// Report the changes prominently, as the TPM data can not be trusted
+pub(crate) fn report(changes: &[TpmChange], events: &EventPublisher) {
+    for change in changes {
+        error!("{change}: the TPM data stored by the agent is stale, and the verifier will not be able to validate the quotes until the agent is registered again");
+        events.publish(AgentEvent::TpmStateChanged {
+            change: change.to_string(),
+        });
+    }
+}
+
+/// Periodically check whether the TPM changed while the agent is running
+pub(crate) async fn worker(
+    data: web::Data<QuoteData>,
+    mut state: TpmState,
+    interval: Duration,
+    events: EventPublisher,
+    mut tpm_state_rx: Receiver<TpmStateMessage>,
+) -> Result<()> {
+    debug!("Starting TPM state worker");
+
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            message = tpm_state_rx.recv() => {
+                match message {
+                    Some(TpmStateMessage::Shutdown) | None => {
+                        tpm_state_rx.close();
+                        break;
+                    }
+                }
+            }
+            _ = ticker.tick() => {
+                let current = {
+                    let mut ctx = data.tpm();
+                    TpmState::read(&mut ctx, data.ak_handle)
+                };
+                match current {
+                    Ok(current) => {
+                        report(&current.changes(&state), &events);
+                        state = current;
+                    }
+                    Err(e) => warn!("Failed to read the TPM state: {e}"),
+                }
+            }
+        }
+    }
+
+    debug!("Shutting down TPM state worker");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(clock: u64, reset_count: u32, restart_count: u32) -> TpmState {
+        TpmState {
+            clock,
+            reset_count,
+            restart_count,
+            pcr_banks: vec!["sha1".to_string(), "sha256".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_changes() {
+        let previous = state(1000, 5, 2);
+
+        // Time passed, the machine was rebooted or resumed
+        assert!(state(2000, 5, 2).changes(&previous).is_empty());
+        assert!(state(500, 6, 0).changes(&previous).is_empty());
+        assert!(state(1500, 5, 3).changes(&previous).is_empty());
+
+        // Counters or clock went backwards
+        assert_eq!(state(10, 0, 0).changes(&previous), [TpmChange::Cleared]);
+        assert_eq!(state(500, 5, 2).changes(&previous), [TpmChange::Cleared]);
+
+        let mut current = state(2000, 5, 2);
+        current.pcr_banks = vec!["sha256".to_string()];
+        assert_eq!(
+            current.changes(&previous),
+            [TpmChange::PcrBanksChanged {
+                previous: previous.pcr_banks.clone(),
+                current: vec!["sha256".to_string()],
+            }]
+        );
+    }
+
+    #[cfg(feature = "testing")]
+    #[test]
+    fn test_read() {
+        let data = QuoteData::fixture().unwrap(); //#[allow_ci]
+        let mut ctx = data.tpm();
+        let first = TpmState::read(&mut ctx, data.ak_handle).unwrap(); //#[allow_ci]
+        let second = TpmState::read(&mut ctx, data.ak_handle).unwrap(); //#[allow_ci]
+        assert!(!first.pcr_banks.is_empty());
+        assert!(second.changes(&first).is_empty());
+    }
+}
@@ -0,0 +1,599 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Identities of the verifiers allowed to contact the agent
+//!
+//! Besides the clients trusted through the trusted_client_ca option, which
+//! have access to the whole API, an agent can serve verifiers from other
+//! administrative domains. Each verifier identity has its own CA bundle and
+//! can be restricted to the expected subject alternative names, to a subset
+//! of the API endpoints and to a subset of the evidence included in the
+//! integrity quotes.
+//!
+//! The identities are loaded from a TOML file in the format:
+//!
+//! ```toml
+//! [verifier.tenant]
+//! ca_certs = ["/var/lib/keylime/cv_ca/tenant-cacert.crt"]
+//! allowed_sans = ["verifier.tenant.example.com"]
+//! allowed_endpoints = ["/quotes/integrity", "/notifications/revocation"]
+//! evidence = ["ima"]
+//! ```
+//!
+//! The allowed endpoints are relative to the API version and apply to all
+//! the supported versions.
+
+use crate::{common::request_api_version, crypto, Error, Result};
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{
+    dev::{Extensions, ServiceRequest},
+    rt::net::TcpStream,
+    HttpMessage, HttpRequest,
+};
+use config::{Config, File, FileFormat};
+use log::*;
+use openssl::{
+    stack::Stack,
+    x509::{
+        store::{X509Store, X509StoreBuilder},
+        X509StoreContext, X509,
+    },
+};
+use serde::Deserialize;
+use std::{
+    any::Any,
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// The evidence that can be included in the integrity quotes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct EvidenceScope {
+    pub ima: bool,
+    pub measured_boot: bool,
+}
+
+impl EvidenceScope {
+    /// The scope of the clients trusted through the trusted_client_ca option
+    pub(crate) const ALL: EvidenceScope = EvidenceScope {
+        ima: true,
+        measured_boot: true,
+    };
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifierEntry {
+    ca_certs: Vec<String>,
+    #[serde(default)]
+    allowed_sans: Vec<String>,
+    #[serde(default)]
+    allowed_endpoints: Vec<String>,
+    evidence: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VerifiersFile {
+    #[serde(default)]
+    verifier: HashMap<String, VerifierEntry>,
+}
+
+pub(crate) struct VerifierIdentity {
+    pub name: String,
+    ca_store: X509Store,
+    ca_certs: Vec<X509>,
+    allowed_sans: Vec<String>,
+    allowed_endpoints: Vec<String>,
+    pub evidence: EvidenceScope,
+}
+
+impl fmt::Debug for VerifierIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VerifierIdentity")
+            .field("name", &self.name)
+            .field("allowed_sans", &self.allowed_sans)
+            .field("allowed_endpoints", &self.allowed_endpoints)
+            .field("evidence", &self.evidence)
+            .finish()
+    }
+}
+
+fn build_store(certs: &[X509]) -> Result<X509Store> {
+    let mut builder = X509StoreBuilder::new()?;
+    for cert in certs {
+        builder.add_cert(cert.clone())?;
+    }
+    Ok(builder.build())
+}
+
+/// Check that the certificate was issued by one of the CAs in the store
+fn verify_chain(store: &X509Store, cert: &X509, chain: &[X509]) -> bool {
+    let mut intermediates = match Stack::new() {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+    for c in chain {
+        if intermediates.push(c.clone()).is_err() {
+            return false;
+        }
+    }
+
+    let Ok(mut context) = X509StoreContext::new() else {
+        return false;
+    };
+    context
+        .init(store, cert, &intermediates, |c| c.verify_cert())
+        .unwrap_or(false)
+}
+
+impl VerifierIdentity {
+    fn new(
+        name: &str,
+        entry: VerifierEntry,
+        base_dir: &Path,
+    ) -> Result<Self> {
+        if entry.ca_certs.is_empty() {
+            return Err(Error::Configuration(format!(
+                "Verifier identity {name} has no CA certificates"
+            )));
+        }
+
+        let paths: Vec<PathBuf> =
+            entry.ca_certs.iter().map(|c| base_dir.join(c)).collect();
+        let ca_certs = crypto::load_x509_cert_list(
+            paths.iter().map(PathBuf::as_path).collect(),
+        )?;
+        if ca_certs.is_empty() {
+            return Err(Error::Configuration(format!(
+                "Could not load any CA certificate for verifier identity {name}"
+            )));
+        }
+
+        let evidence = match entry.evidence {
+            None => EvidenceScope::ALL,
+            Some(list) => {
+                let mut scope = EvidenceScope {
+                    ima: false,
+                    measured_boot: false,
+                };
+                for e in list {
+                    match e.as_str() {
+                        "ima" => scope.ima = true,
+                        "measured_boot" => scope.measured_boot = true,
+                        other => {
+                            return Err(Error::Configuration(format!(
+                                "Invalid evidence {other} for verifier identity {name}: expected 'ima' or 'measured_boot'"
+                            )))
+                        }
+                    }
+                }
+                scope
+            }
+        };
+
+        Ok(VerifierIdentity {
+            name: name.to_string(),
+            ca_store: build_store(&ca_certs)?,
+            ca_certs,
+            allowed_sans: entry.allowed_sans,
+            allowed_endpoints: entry
+                .allowed_endpoints
+                .iter()
+                .map(|e| e.trim_end_matches('/').to_string())
+                .collect(),
+            evidence,
+        })
+    }
+
+    /// Check that the certificate chains to the CAs of the verifier and,
+    /// if any SANs are expected, that it contains one of them
+    fn matches(&self, cert: &X509, chain: &[X509]) -> bool {
+        if !verify_chain(&self.ca_store, cert, chain) {
+            return false;
+        }
+
+        if self.allowed_sans.is_empty() {
+            return true;
+        }
+
+        let Some(sans) = cert.subject_alt_names() else {
+            return false;
+        };
+        sans.iter().any(|san| {
+            let name = match (san.dnsname(), san.ipaddress()) {
+                (Some(dns), _) => dns.to_string(),
+                (None, Some(ip)) => match ip.len() {
+                    4 => <[u8; 4]>::try_from(ip)
+                        .map(|a| IpAddr::from(a).to_string())
+                        .unwrap_or_default(),
+                    16 => <[u8; 16]>::try_from(ip)
+                        .map(|a| IpAddr::from(a).to_string())
+                        .unwrap_or_default(),
+                    _ => return false,
+                },
+                _ => return false,
+            };
+            self.allowed_sans
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&name))
+        })
+    }
+
+    /// Check whether the request path is allowed for this verifier. The
+    /// endpoints are restricted in the scope of every supported API version.
+    /// The version discovery is allowed for all clients, and any other path,
+    /// including the unsupported API versions, is denied.
+    fn allows_path(&self, path: &str) -> bool {
+        if self.allowed_endpoints.is_empty() || path == "/version" {
+            return true;
+        }
+        let Some(version) = request_api_version(path) else {
+            return false;
+        };
+        path.strip_prefix('/')
+            .and_then(|p| p.strip_prefix(version))
+            .is_some_and(|endpoint| self.allows(endpoint))
+    }
+
+    /// Check whether the endpoint, relative to the API version scope, is
+    /// allowed for this verifier. An empty list allows all endpoints.
+    fn allows(&self, endpoint: &str) -> bool {
+        self.allowed_endpoints.is_empty()
+            || self.allowed_endpoints.iter().any(|allowed| {
+                endpoint == allowed
+                    || endpoint
+                        .strip_prefix(allowed.as_str())
+                        .is_some_and(|rest| rest.starts_with('/'))
+            })
+    }
+}
+
+/// The evidence scope of the verifier which sent the request
+pub(crate) fn evidence_scope(req: &HttpRequest) -> EvidenceScope {
+    req.extensions()
+        .get::<Arc<VerifierIdentity>>()
+        .map_or(EvidenceScope::ALL, |identity| identity.evidence)
+}
+
+/// The certificate presented by the client on the TLS connection
+#[derive(Debug, Clone)]
+pub(crate) struct PeerCertificate {
+    cert: X509,
+    chain: Vec<X509>,
+}
+
+impl PeerCertificate {
+    /// The client certificate followed by its chain, DER encoded, as
+    /// provided by the gRPC server
+    #[cfg(feature = "grpc")]
+    pub(crate) fn from_der<T: AsRef<[u8]>>(certs: &[T]) -> Result<Self> {
+        let mut certs = certs.iter().map(|c| X509::from_der(c.as_ref()));
+        let Some(cert) = certs.next() else {
+            return Err(Error::Other(
+                "Client certificate not available".to_string(),
+            ));
+        };
+        Ok(PeerCertificate {
+            cert: cert?,
+            chain: certs.collect::<std::result::Result<_, _>>()?,
+        })
+    }
+}
+
+/// Store the client certificate in the connection data, so that the verifier
+/// identity can be checked for each request. To be used as the on_connect
+/// callback of the HTTP server.
+pub(crate) fn on_connect(conn: &dyn Any, ext: &mut Extensions) {
+    let Some(stream) = conn.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+
+    let ssl = stream.ssl();
+    if let Some(cert) = ssl.peer_certificate() {
+        let chain = ssl
+            .peer_cert_chain()
+            .map(|c| c.iter().map(|c| c.to_owned()).collect())
+            .unwrap_or_default();
+        _ = ext.insert(PeerCertificate { cert, chain });
+    }
+}
+
+pub(crate) struct Verifiers {
+    default_store: X509Store,
+    identities: Vec<Arc<VerifierIdentity>>,
+}
+
+impl fmt::Debug for Verifiers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Verifiers")
+            .field("identities", &self.identities)
+            .finish()
+    }
+}
+
+impl Verifiers {
+    /// Load the verifier identities from the file. The relative CA
+    /// certificate paths are relative to the directory of the file.
+    pub(crate) fn load(
+        path: &Path,
+        trusted_client_ca: &[X509],
+    ) -> Result<Self> {
+        let file: VerifiersFile = Config::builder()
+            .add_source(File::new(
+                &path.display().to_string(),
+                FileFormat::Toml,
+            ))
+            .build()?
+            .try_deserialize()?;
+
+        let base_dir = path.parent().unwrap_or(Path::new("/"));
+        let mut entries: Vec<(String, VerifierEntry)> =
+            file.verifier.into_iter().collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut identities = Vec::new();
+        for (name, entry) in entries {
+            identities.push(Arc::new(VerifierIdentity::new(
+                &name, entry, base_dir,
+            )?));
+        }
+
+        if identities.is_empty() {
+            warn!("No verifier identities found in {}", path.display());
+        }
+
+        Ok(Verifiers {
+            default_store: build_store(trusted_client_ca)?,
+            identities,
+        })
+    }
+
+    /// The CA certificates of all verifier identities, to be trusted in the
+    /// TLS handshake
+    pub(crate) fn ca_certs(&self) -> Vec<X509> {
+        let mut certs: Vec<X509> = Vec::new();
+        for cert in self.identities.iter().flat_map(|i| i.ca_certs.iter()) {
+            if !certs.contains(cert) {
+                certs.push(cert.clone());
+            }
+        }
+        certs
+    }
+
+    /// Find the identity of the client. Returns None for the clients trusted
+    /// through the trusted_client_ca option, which are not restricted.
+    fn identify(
+        &self,
+        peer: &PeerCertificate,
+    ) -> std::result::Result<Option<Arc<VerifierIdentity>>, ()> {
+        if let Some(identity) = self
+            .identities
+            .iter()
+            .find(|i| i.matches(&peer.cert, &peer.chain))
+        {
+            return Ok(Some(identity.clone()));
+        }
+        if verify_chain(&self.default_store, &peer.cert, &peer.chain) {
+            return Ok(None);
+        }
+        Err(())
+    }
+
+    /// Check that the client is allowed to access the requested endpoint.
+    /// The identity of the verifier is stored in the request extensions for
+    /// the handlers. Returns an error message if the request is forbidden.
+    pub(crate) fn authorize(
+        &self,
+        req: &ServiceRequest,
+    ) -> std::result::Result<(), String> {
+        let Some(peer) = req.conn_data::<PeerCertificate>() else {
+            return Err("Client certificate not available".to_string());
+        };
+
+        if let Some(identity) = self.check(peer, req.path())? {
+            debug!(
+                "Request {} authorized for verifier {}",
+                req.path(),
+                identity.name
+            );
+            _ = req.extensions_mut().insert(identity);
+        }
+        Ok(())
+    }
+
+    /// Check that the peer is allowed to access the path, returning the
+    /// identity of the verifier, if any
+    pub(crate) fn check(
+        &self,
+        peer: &PeerCertificate,
+        path: &str,
+    ) -> std::result::Result<Option<Arc<VerifierIdentity>>, String> {
+        let identity =
+            match self.identify(peer) {
+                Ok(Some(identity)) => identity,
+                Ok(None) => return Ok(None),
+                Err(()) => return Err(
+                    "Client certificate does not match any verifier identity"
+                        .to_string(),
+                ),
+            };
+
+        if !identity.allows_path(path) {
+            return Err(format!(
+                "Endpoint {path} is not allowed for verifier {}",
+                identity.name
+            ));
+        }
+        Ok(Some(identity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::SUPPORTED_API_VERSIONS;
+    use openssl::{
+        asn1::Asn1Time,
+        bn::BigNum,
+        hash::MessageDigest,
+        pkey::{PKey, Private},
+        x509::{
+            extension::{BasicConstraints, SubjectAlternativeName},
+            X509Builder, X509Name,
+        },
+    };
+
+    fn issue(
+        cn: &str,
+        san: Option<&str>,
+        issuer: Option<(&X509, &PKey<Private>)>,
+    ) -> (X509, PKey<Private>) {
+        let key = crypto::testing::rsa_generate(2048).unwrap(); //#[allow_ci]
+        let mut name = X509Name::builder().unwrap(); //#[allow_ci]
+        name.append_entry_by_text("CN", cn).unwrap(); //#[allow_ci]
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap(); //#[allow_ci]
+        builder.set_version(2).unwrap(); //#[allow_ci]
+        builder
+            .set_serial_number(
+                &BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap(), //#[allow_ci]
+            )
+            .unwrap(); //#[allow_ci]
+        builder.set_subject_name(&name).unwrap(); //#[allow_ci]
+        builder.set_pubkey(&key).unwrap(); //#[allow_ci]
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap()) //#[allow_ci]
+            .unwrap(); //#[allow_ci]
+        if let Some(san) = san {
+            let ext = SubjectAlternativeName::new()
+                .dns(san)
+                .build(&builder.x509v3_context(issuer.map(|i| &**i.0), None))
+                .unwrap(); //#[allow_ci]
+            builder.append_extension(ext).unwrap(); //#[allow_ci]
+        }
+        match issuer {
+            Some((ca, ca_key)) => {
+                builder.set_issuer_name(ca.subject_name()).unwrap(); //#[allow_ci]
+                builder.sign(ca_key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+            }
+            None => {
+                let ext =
+                    BasicConstraints::new().critical().ca().build().unwrap(); //#[allow_ci]
+                builder.append_extension(ext).unwrap(); //#[allow_ci]
+                builder.set_issuer_name(&name).unwrap(); //#[allow_ci]
+                builder.sign(&key, MessageDigest::sha256()).unwrap(); //#[allow_ci]
+            }
+        }
+        (builder.build(), key)
+    }
+
+    #[test]
+    fn test_identify() {
+        let (default_ca, default_key) = issue("default-ca", None, None);
+        let (tenant_ca, tenant_key) = issue("tenant-ca", None, None);
+        let (other_ca, other_key) = issue("other-ca", None, None);
+
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        crypto::write_x509(&tenant_ca, &dir.path().join("tenant-ca.crt"))
+            .unwrap(); //#[allow_ci]
+        let path = dir.path().join("verifiers.toml");
+        std::fs::write(
+            &path,
+            r#"
+            [verifier.tenant]
+            ca_certs = ["tenant-ca.crt"]
+            allowed_sans = ["verifier.tenant.example.com"]
+            allowed_endpoints = ["/quotes/integrity", "/keys/"]
+            evidence = ["ima"]
+
+            [verifier.quotes]
+            ca_certs = ["tenant-ca.crt"]
+            allowed_sans = ["verifier.quotes.example.com"]
+            allowed_endpoints = ["/quotes/integrity"]
+            "#,
+        )
+        .unwrap(); //#[allow_ci]
+
+        let verifiers =
+            Verifiers::load(&path, std::slice::from_ref(&default_ca))
+                .unwrap(); //#[allow_ci]
+        assert_eq!(verifiers.ca_certs(), vec![tenant_ca.clone()]);
+
+        let peer = |cert: X509| PeerCertificate {
+            cert,
+            chain: Vec::new(),
+        };
+
+        let (cert, _) = issue(
+            "tenant",
+            Some("verifier.tenant.example.com"),
+            Some((&tenant_ca, &tenant_key)),
+        );
+        let identity = verifiers.identify(&peer(cert)).unwrap().unwrap(); //#[allow_ci]
+        assert_eq!(identity.name, "tenant");
+        assert!(identity.evidence.ima);
+        assert!(!identity.evidence.measured_boot);
+        assert!(identity.allows("/quotes/integrity"));
+        assert!(identity.allows("/keys/ukey"));
+        assert!(!identity.allows("/quotes/identity"));
+        assert!(!identity.allows("/quotes/integrityx"));
+
+        // The endpoints are restricted under every supported API version
+        let (cert, _) = issue(
+            "tenant",
+            Some("verifier.tenant.example.com"),
+            Some((&tenant_ca, &tenant_key)),
+        );
+        let tenant = peer(cert);
+        for version in SUPPORTED_API_VERSIONS {
+            assert!(verifiers
+                .check(&tenant, &format!("/{version}/quotes/integrity"))
+                .is_ok());
+            assert!(verifiers
+                .check(&tenant, &format!("/{version}/keys/ukey"))
+                .is_ok());
+            assert!(verifiers
+                .check(&tenant, &format!("/{version}/quotes/identity"))
+                .is_err());
+        }
+        let (cert, _) = issue(
+            "quotes",
+            Some("verifier.quotes.example.com"),
+            Some((&tenant_ca, &tenant_key)),
+        );
+        let quotes = peer(cert);
+        for version in SUPPORTED_API_VERSIONS {
+            for endpoint in ["/keys/ukey", "/keys/delivered", "/keys/pubkey"]
+            {
+                assert!(verifiers
+                    .check(&quotes, &format!("/{version}{endpoint}"))
+                    .is_err());
+            }
+        }
+        assert!(verifiers.check(&tenant, "/version").is_ok());
+        assert!(verifiers.check(&tenant, "/v9.9/quotes/identity").is_err());
+        assert!(verifiers.check(&tenant, "/v2.2x/quotes/identity").is_err());
+
+        // Unexpected SAN
+        let (cert, _) = issue(
+            "tenant",
+            Some("other.example.com"),
+            Some((&tenant_ca, &tenant_key)),
+        );
+        assert!(verifiers.identify(&peer(cert)).is_err());
+
+        // Clients trusted through trusted_client_ca are not restricted
+        let (cert, _) =
+            issue("verifier", None, Some((&default_ca, &default_key)));
+        assert!(verifiers.identify(&peer(cert)).unwrap().is_none()); //#[allow_ci]
+
+        let (cert, _) = issue("other", None, Some((&other_ca, &other_key)));
+        assert!(verifiers.identify(&peer(cert)).is_err());
+    }
+}
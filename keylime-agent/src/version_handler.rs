@@ -1,7 +1,9 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2022 Keylime Authors
 
-use crate::common::{JsonWrapper, API_VERSION};
+use crate::common::{
+    JsonWrapper, LATEST_API_VERSION, SUPPORTED_API_VERSIONS,
+};
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use log::*;
 use serde::{Deserialize, Serialize};
@@ -9,6 +11,9 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug)]
 struct KeylimeVersion {
     supported_version: String,
+    // All the versions served, for the clients negotiating the version
+    #[serde(default)]
+    supported_versions: Vec<String>,
 }
 
 // This is the handler for the GET request for the API version
@@ -20,7 +25,11 @@ pub async fn version(req: HttpRequest) -> impl Responder {
     );
 
     let response = JsonWrapper::success(KeylimeVersion {
-        supported_version: API_VERSION[1..].to_string(),
+        supported_version: LATEST_API_VERSION[1..].to_string(),
+        supported_versions: SUPPORTED_API_VERSIONS
+            .iter()
+            .map(|v| v[1..].to_string())
+            .collect(),
     });
 
     HttpResponse::Ok().json(response)
@@ -46,6 +55,7 @@ mod tests {
 
         let body: JsonWrapper<KeylimeVersion> =
             test::read_body_json(resp).await;
-        assert_eq!(body.results.supported_version, API_VERSION[1..]);
+        assert_eq!(body.results.supported_version, "2.2");
+        assert_eq!(body.results.supported_versions, vec!["2.1", "2.2"]);
     }
 }
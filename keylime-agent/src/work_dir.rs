@@ -0,0 +1,310 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Layout of the agent work directory
+//!
+//! The files of the agent are placed in a subdirectory of the keylime_dir
+//! for each component using them:
+//!
+//! - cert_dir: the TLS key and certificates, the IAK and IDevID certificates
+//!   and the CA certificates of the verifier and tenant;
+//! - tpmdata_dir: the TPM context of the agent, the generated UUID and the
+//!   local appraisal database;
+//! - secure_dir: the tmpfs secure mount, holding the delivered keys and
+//!   payloads;
+//! - audit_dir: the attestation results written by the agent, if enabled.
+//!
+//! The directories and the files moved to them are labeled with the SELinux
+//! context set in 'work_dir_context', if any.
+//!
+//! Older agents placed all the files directly in the keylime_dir. The files
+//! found there are moved to their subdirectory at startup, unless their path
+//! was set explicitly in the configuration. The CA certificate is copied
+//! instead, since the cv_ca directory is shared with the verifier, registrar
+//! and tenant running on the same host, and may hold the CA private key.
+
+use crate::{
+    config::{self, KeylimeConfig},
+    error::{Error, Result},
+    lsm, permissions,
+};
+use log::*;
+use std::{
+    fs,
+    os::unix::fs::{DirBuilderExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Layout {
+    pub keylime_dir: PathBuf,
+    pub cert_dir: PathBuf,
+    pub tpmdata_dir: PathBuf,
+    pub secure_dir: PathBuf,
+    pub audit_dir: PathBuf,
+    /// SELinux context of the directories, if set
+    pub context: String,
+}
+
+impl Layout {
+    pub(crate) fn new(config: &KeylimeConfig) -> Self {
+        Layout {
+            keylime_dir: PathBuf::from(&config.agent.keylime_dir),
+            cert_dir: PathBuf::from(&config.agent.cert_dir),
+            tpmdata_dir: PathBuf::from(&config.agent.tpmdata_dir),
+            secure_dir: PathBuf::from(&config.agent.secure_dir),
+            audit_dir: PathBuf::from(&config.agent.audit_dir),
+            context: config.agent.work_dir_context.clone(),
+        }
+    }
+
+    /// The directories only accessed by the agent. The secure mount is
+    /// created with its own permissions when mounted.
+    fn private_dirs(&self) -> [&Path; 3] {
+        [&self.cert_dir, &self.tpmdata_dir, &self.audit_dir]
+    }
+
+    /// Create the missing directories, only accessible by their owner
+    pub(crate) fn create(&self) -> Result<()> {
+        for dir in self.private_dirs() {
+            if dir.exists() {
+                let mode = fs::metadata(dir)?.permissions().mode();
+                if mode & 0o002 != 0 {
+                    warn!(
+                        "Directory {} is writable by all users",
+                        dir.display()
+                    );
+                }
+            } else {
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(dir)
+                    .map_err(|e| {
+                        Error::Configuration(
+                            lsm::explain_denial(&e, dir).unwrap_or(format!(
+                                "Unable to create directory {}: {e}",
+                                dir.display()
+                            )),
+                        )
+                    })?;
+                info!("Directory {} created", dir.display());
+            }
+            self.label(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Set the SELinux context of the file or directory, if configured
+    fn label(&self, path: &Path) -> Result<()> {
+        if self.context.is_empty() || path == self.keylime_dir {
+            return Ok(());
+        }
+        lsm::label_tree(path, &self.context).map_err(|e| {
+            Error::Configuration(format!(
+                "Unable to set the SELinux context {} of {}: {e}",
+                self.context,
+                path.display()
+            ))
+        })
+    }
+
+    /// Give the ownership of the directories to the user the agent runs as.
+    /// The keylime_dir itself is left to the administrator.
+    pub(crate) fn chown(&self, user_group: &str) -> Result<()> {
+        for dir in self.private_dirs() {
+            if dir != self.keylime_dir {
+                permissions::chown(user_group, dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the files of the flat layout of older agents to their
+    /// subdirectory. Only the files placed by default are moved, and never
+    /// over an existing file.
+    pub(crate) fn migrate(&self, config: &KeylimeConfig) -> Result<()> {
+        let files = [
+            (
+                &self.cert_dir,
+                config::DEFAULT_SERVER_KEY,
+                &config.agent.server_key,
+            ),
+            (
+                &self.cert_dir,
+                config::DEFAULT_SERVER_CERT,
+                &config.agent.server_cert,
+            ),
+            (
+                &self.cert_dir,
+                config::DEFAULT_IAK_CERT,
+                &config.agent.iak_cert,
+            ),
+            (
+                &self.cert_dir,
+                config::DEFAULT_IDEVID_CERT,
+                &config.agent.idevid_cert,
+            ),
+            (
+                &self.tpmdata_dir,
+                config::DEFAULT_AGENT_DATA_PATH,
+                &config.agent.agent_data_path,
+            ),
+            (
+                &self.tpmdata_dir,
+                config::DEFAULT_UUID_PATH,
+                &config.agent.uuid_path,
+            ),
+            (
+                &self.tpmdata_dir,
+                config::DEFAULT_LOCAL_APPRAISAL_DB,
+                &config.agent.local_appraisal_db,
+            ),
+        ];
+
+        for (dir, name, path) in files {
+            if Path::new(path) == dir.join(name) {
+                self.migrate_file(name, dir)?;
+            }
+        }
+
+        // The shared CA directory is left in place
+        if config
+            .agent
+            .trusted_client_ca
+            .split(',')
+            .map(str::trim)
+            .any(|ca| {
+                Path::new(ca)
+                    == self.cert_dir.join(config::DEFAULT_TRUSTED_CLIENT_CA)
+            })
+        {
+            self.copy_file(
+                config::DEFAULT_TRUSTED_CLIENT_CA,
+                &self.cert_dir,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn copy_file(&self, name: &str, dir: &Path) -> Result<()> {
+        let old = self.keylime_dir.join(name);
+        let new = dir.join(name);
+        if old == new || !old.exists() || new.exists() {
+            return Ok(());
+        }
+        let copy = || -> std::io::Result<()> {
+            if let Some(parent) = new.parent() {
+                fs::DirBuilder::new()
+                    .recursive(true)
+                    .mode(0o700)
+                    .create(parent)?;
+            }
+            _ = fs::copy(&old, &new)?;
+            Ok(())
+        };
+        copy().map_err(|e| {
+            Error::Configuration(format!(
+                "Unable to copy {} to {}: {e}",
+                old.display(),
+                new.display()
+            ))
+        })?;
+        info!("Copied {} to {}", old.display(), new.display());
+        self.label(&new)
+    }
+
+    fn migrate_file(&self, name: &str, dir: &Path) -> Result<()> {
+        let old = self.keylime_dir.join(name);
+        let new = dir.join(name);
+        if old == new || !old.exists() || new.exists() {
+            return Ok(());
+        }
+        fs::rename(&old, &new).map_err(|e| {
+            Error::Configuration(format!(
+                "Unable to move {} to {}: {e}",
+                old.display(),
+                new.display()
+            ))
+        })?;
+        info!("Moved {} to {}", old.display(), new.display());
+        self.label(&new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::AgentConfig;
+
+    fn layout_config(keylime_dir: &Path) -> KeylimeConfig {
+        let dir = |name: &str| keylime_dir.join(name).display().to_string();
+        KeylimeConfig {
+            agent: AgentConfig {
+                keylime_dir: keylime_dir.display().to_string(),
+                cert_dir: dir("certs"),
+                tpmdata_dir: dir("tpmdata"),
+                secure_dir: dir("secure"),
+                audit_dir: dir("audit"),
+                server_key: dir("certs/server-private.pem"),
+                server_cert: dir("certs/server-cert.crt"),
+                trusted_client_ca: format!(
+                    "{}, /etc/ca.crt",
+                    dir("certs/cv_ca/cacert.crt")
+                ),
+                agent_data_path: dir("tpmdata/agent_data.json"),
+                uuid_path: "/etc/agent_uuid".to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_create_and_migrate() {
+        let temp = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let keylime_dir = temp.path();
+        for file in ["server-private.pem", "agent_data.json", "agent_uuid"] {
+            fs::write(keylime_dir.join(file), file).unwrap(); //#[allow_ci]
+        }
+        fs::create_dir(keylime_dir.join("cv_ca")).unwrap(); //#[allow_ci]
+        fs::write(keylime_dir.join("cv_ca/cacert.crt"), "ca").unwrap(); //#[allow_ci]
+
+        let config = layout_config(keylime_dir);
+        let layout = Layout::new(&config);
+        layout.create().unwrap(); //#[allow_ci]
+        for dir in layout.private_dirs() {
+            let mode = fs::metadata(dir).unwrap().permissions().mode(); //#[allow_ci]
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        // A file already in its subdirectory is not replaced
+        fs::write(layout.tpmdata_dir.join("agent_data.json"), "new").unwrap(); //#[allow_ci]
+
+        layout.migrate(&config).unwrap(); //#[allow_ci]
+        assert_eq!(
+            fs::read_to_string(layout.cert_dir.join("server-private.pem"))
+                .unwrap(), //#[allow_ci]
+            "server-private.pem"
+        );
+        assert_eq!(
+            fs::read_to_string(layout.cert_dir.join("cv_ca/cacert.crt"))
+                .unwrap(), //#[allow_ci]
+            "ca"
+        );
+        // The shared CA directory is kept for the other components
+        assert!(keylime_dir.join("cv_ca/cacert.crt").exists());
+        assert!(!keylime_dir.join("server-private.pem").exists());
+        assert_eq!(
+            fs::read_to_string(layout.tpmdata_dir.join("agent_data.json"))
+                .unwrap(), //#[allow_ci]
+            "new"
+        );
+        assert!(keylime_dir.join("agent_data.json").exists());
+        // Set explicitly, so not moved
+        assert!(keylime_dir.join("agent_uuid").exists());
+        assert!(!layout.tpmdata_dir.join("agent_uuid").exists());
+
+        // Migrating again has no effect
+        layout.migrate(&config).unwrap(); //#[allow_ci]
+    }
+}
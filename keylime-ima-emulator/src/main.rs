@@ -69,13 +69,14 @@ fn ml_extend(
     let pcr_digest: MessageDigest = pcr_hash_alg.into();
     let mut running_hash = ima::Digest::start(pcr_hash_alg);
     let ff_hash = ima::Digest::ff(pcr_hash_alg);
-    for line in reader.by_ref().lines().skip(position) {
+    // The file names are not necessarily valid UTF-8
+    for line in reader.by_ref().split(b'\n').skip(position) {
         let line = line?;
         if line.is_empty() {
             continue;
         }
 
-        let entry: ima::Entry = line.as_str().try_into()?;
+        let entry: ima::Entry = line.as_slice().try_into()?;
 
         position += 1;
 
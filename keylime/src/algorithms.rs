@@ -73,6 +73,23 @@ impl From<HashAlgorithm> for HashingAlgorithm {
     }
 }
 
+impl TryFrom<HashingAlgorithm> for HashAlgorithm {
+    type Error = AlgorithmError;
+
+    fn try_from(value: HashingAlgorithm) -> Result<Self, Self::Error> {
+        match value {
+            HashingAlgorithm::Sha1 => Ok(HashAlgorithm::Sha1),
+            HashingAlgorithm::Sha256 => Ok(HashAlgorithm::Sha256),
+            HashingAlgorithm::Sha384 => Ok(HashAlgorithm::Sha384),
+            HashingAlgorithm::Sha512 => Ok(HashAlgorithm::Sha512),
+            HashingAlgorithm::Sm3_256 => Ok(HashAlgorithm::Sm3_256),
+            _ => Err(AlgorithmError::Hash(format!(
+                "Hash algorithm {value:?} is not supported by Keylime"
+            ))),
+        }
+    }
+}
+
 impl From<HashAlgorithm> for MessageDigest {
     fn from(hash_algorithm: HashAlgorithm) -> Self {
         match hash_algorithm {
@@ -0,0 +1,319 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Parser for the TCG binary event log of the measured boot
+//!
+//! Implements the formats defined in the TCG PC Client Platform Firmware
+//! Profile Specification: the crypto agile log, which starts with the
+//! "Spec ID Event03" event, and the legacy log containing only SHA-1
+//! digests.
+
+use crate::{algorithms::HashAlgorithm, hashing};
+use openssl::hash::MessageDigest;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Event type of the events that are not extended into the PCRs
+pub const EV_NO_ACTION: u32 = 0x3;
+
+const SPEC_ID_EVENT03: &[u8] = b"Spec ID Event03\0";
+const STARTUP_LOCALITY: &[u8] = b"StartupLocality\0";
+
+#[derive(Error, Debug)]
+pub enum EventLogError {
+    #[error("Truncated event log: {0}")]
+    Truncated(&'static str),
+    #[error("Unknown digest algorithm 0x{0:04x} in the event log")]
+    UnknownAlgorithm(u16),
+    #[error("The event log has no digests for {0}")]
+    MissingBank(HashAlgorithm),
+    #[error("OpenSSL error: {0}")]
+    OpenSSL(#[from] openssl::error::ErrorStack),
+    #[error("Hashing error: {0}")]
+    Hashing(#[from] hashing::HashingError),
+}
+
+type Result<T> = std::result::Result<T, EventLogError>;
+
+/// A single event of the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub pcr_index: u32,
+    pub event_type: u32,
+    pub digests: Vec<(HashAlgorithm, Vec<u8>)>,
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    /// The digest of this event for the given algorithm
+    pub fn digest(&self, alg: HashAlgorithm) -> Option<&[u8]> {
+        self.digests
+            .iter()
+            .find(|(a, _)| *a == alg)
+            .map(|(_, d)| d.as_slice())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, what: &'static str) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(EventLogError::Truncated(what));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    fn u16(&mut self, what: &'static str) -> Result<u16> {
+        let b = self.take(2, what)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self, what: &'static str) -> Result<u32> {
+        let b = self.take(4, what)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+}
+
+fn algorithm(id: u16) -> Result<HashAlgorithm> {
+    match id {
+        0x0004 => Ok(HashAlgorithm::Sha1),
+        0x000b => Ok(HashAlgorithm::Sha256),
+        0x000c => Ok(HashAlgorithm::Sha384),
+        0x000d => Ok(HashAlgorithm::Sha512),
+        0x0012 => Ok(HashAlgorithm::Sm3_256),
+        id => Err(EventLogError::UnknownAlgorithm(id)),
+    }
+}
+
+fn digest_size(alg: HashAlgorithm) -> usize {
+    let digest: MessageDigest = alg.into();
+    digest.size()
+}
+
+/// Parse the digest sizes announced in the "Spec ID Event03" event
+fn parse_spec_id(data: &[u8]) -> Result<Vec<(u16, usize)>> {
+    let mut r = Reader { data };
+    let _ = r.take(SPEC_ID_EVENT03.len(), "spec ID signature")?;
+    // platformClass, specVersionMinor, specVersionMajor, specErrata and
+    // uintnSize
+    let _ = r.take(8, "spec ID version")?;
+    let count = r.u32("spec ID algorithm count")?;
+    let mut sizes = Vec::new();
+    for _ in 0..count {
+        let id = r.u16("spec ID algorithm")?;
+        let size = r.u16("spec ID digest size")?;
+        sizes.push((id, size as usize));
+    }
+    Ok(sizes)
+}
+
+/// Parse the events of a binary event log
+pub fn parse(data: &[u8]) -> Result<Vec<Event>> {
+    Ok(parse_with_offsets(data)?
+        .into_iter()
+        .map(|(_, event)| event)
+        .collect())
+}
+
+/// Parse the events of a binary event log, together with the offset of each
+/// event in the log, so that the log can be transferred from a given event
+pub fn parse_with_offsets(data: &[u8]) -> Result<Vec<(usize, Event)>> {
+    let mut r = Reader { data };
+    let mut events: Vec<(usize, Event)> = Vec::new();
+    let mut sizes: Option<Vec<(u16, usize)>> = None;
+
+    while !r.data.is_empty() {
+        let offset = data.len() - r.data.len();
+        let pcr_index = r.u32("PCR index")?;
+        let event_type = r.u32("event type")?;
+
+        let digests = match &sizes {
+            None => vec![(
+                HashAlgorithm::Sha1,
+                r.take(20, "SHA-1 digest")?.to_vec(),
+            )],
+            Some(sizes) => {
+                let count = r.u32("digest count")?;
+                let mut digests = Vec::new();
+                for _ in 0..count {
+                    let id = r.u16("digest algorithm")?;
+                    let size = match sizes.iter().find(|(i, _)| *i == id) {
+                        Some((_, size)) => *size,
+                        None => digest_size(algorithm(id)?),
+                    };
+                    let digest = r.take(size, "digest")?;
+                    if let Ok(alg) = algorithm(id) {
+                        digests.push((alg, digest.to_vec()));
+                    }
+                }
+                digests
+            }
+        };
+
+        let size = r.u32("event size")?;
+        let data = r.take(size as usize, "event data")?.to_vec();
+
+        // The first event announces the crypto agile format of the
+        // following events
+        if events.is_empty()
+            && sizes.is_none()
+            && event_type == EV_NO_ACTION
+            && data.starts_with(SPEC_ID_EVENT03)
+        {
+            sizes = Some(parse_spec_id(&data)?);
+        }
+
+        events.push((
+            offset,
+            Event {
+                pcr_index,
+                event_type,
+                digests,
+                data,
+            },
+        ));
+    }
+
+    Ok(events)
+}
+
+/// Compute the PCR values expected from the events, for the PCR bank of
+/// the given algorithm
+pub fn replay(
+    events: &[Event],
+    alg: HashAlgorithm,
+) -> Result<BTreeMap<u32, Vec<u8>>> {
+    let md: MessageDigest = alg.into();
+    let backend = hashing::default_backend();
+    let mut pcrs: BTreeMap<u32, Vec<u8>> = BTreeMap::new();
+
+    for event in events {
+        if event.event_type == EV_NO_ACTION {
+            // The locality from which the TPM was started is the initial
+            // value of PCR 0
+            if event.pcr_index == 0
+                && event.data.starts_with(STARTUP_LOCALITY)
+            {
+                if let Some(locality) = event.data.get(STARTUP_LOCALITY.len())
+                {
+                    let mut initial = vec![0u8; md.size()];
+                    initial[md.size() - 1] = *locality;
+                    _ = pcrs.insert(0, initial);
+                }
+            }
+            continue;
+        }
+
+        let digest =
+            event.digest(alg).ok_or(EventLogError::MissingBank(alg))?;
+        let pcr = pcrs
+            .entry(event.pcr_index)
+            .or_insert_with(|| vec![0u8; md.size()]);
+        let mut buf = Vec::with_capacity(pcr.len() + digest.len());
+        buf.extend_from_slice(pcr);
+        buf.extend_from_slice(digest);
+        *pcr = backend.digest(alg, &buf)?;
+    }
+
+    Ok(pcrs)
+}
+
+/// Check whether the log contains events for the PCR bank of the given
+/// algorithm
+pub fn has_bank(events: &[Event], alg: HashAlgorithm) -> bool {
+    events
+        .iter()
+        .filter(|e| e.event_type != EV_NO_ACTION)
+        .all(|e| e.digest(alg).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::hash::hash;
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        hash(MessageDigest::sha256(), data).unwrap().to_vec() //#[allow_ci]
+    }
+
+    fn header() -> Vec<u8> {
+        let mut spec = SPEC_ID_EVENT03.to_vec();
+        spec.extend([0u8; 8]);
+        spec.extend(2u32.to_le_bytes());
+        spec.extend(0x0004u16.to_le_bytes());
+        spec.extend(20u16.to_le_bytes());
+        spec.extend(0x000bu16.to_le_bytes());
+        spec.extend(32u16.to_le_bytes());
+        spec.push(0);
+
+        let mut log = Vec::new();
+        log.extend(0u32.to_le_bytes());
+        log.extend(EV_NO_ACTION.to_le_bytes());
+        log.extend([0u8; 20]);
+        log.extend((spec.len() as u32).to_le_bytes());
+        log.extend(spec);
+        log
+    }
+
+    fn event(log: &mut Vec<u8>, pcr: u32, event_type: u32, data: &[u8]) {
+        log.extend(pcr.to_le_bytes());
+        log.extend(event_type.to_le_bytes());
+        log.extend(2u32.to_le_bytes());
+        log.extend(0x0004u16.to_le_bytes());
+        log.extend(hash(MessageDigest::sha1(), data).unwrap().to_vec()); //#[allow_ci]
+        log.extend(0x000bu16.to_le_bytes());
+        log.extend(sha256(data));
+        log.extend((data.len() as u32).to_le_bytes());
+        log.extend(data);
+    }
+
+    #[test]
+    fn test_parse_and_replay() {
+        let mut log = header();
+        let mut locality = STARTUP_LOCALITY.to_vec();
+        locality.push(3);
+        event(&mut log, 0, EV_NO_ACTION, &locality);
+        event(&mut log, 0, 0x8, b"CRTM version");
+        event(&mut log, 7, 0x80000001, b"SecureBoot");
+
+        let events = parse(&log).unwrap(); //#[allow_ci]
+        assert_eq!(events.len(), 4);
+        assert!(has_bank(&events, HashAlgorithm::Sha256));
+        assert!(!has_bank(&events, HashAlgorithm::Sha384));
+
+        let pcrs = replay(&events, HashAlgorithm::Sha256).unwrap(); //#[allow_ci]
+        let mut pcr0 = vec![0u8; 32];
+        pcr0[31] = 3;
+        pcr0.extend(sha256(b"CRTM version"));
+        let mut pcr7 = vec![0u8; 32];
+        pcr7.extend(sha256(b"SecureBoot"));
+        assert_eq!(pcrs.get(&0), Some(&sha256(&pcr0)));
+        assert_eq!(pcrs.get(&7), Some(&sha256(&pcr7)));
+        assert_eq!(pcrs.len(), 2);
+
+        assert!(matches!(
+            replay(&events, HashAlgorithm::Sha384),
+            Err(EventLogError::MissingBank(HashAlgorithm::Sha384))
+        ));
+
+        let offsets: Vec<usize> = parse_with_offsets(&log)
+            .unwrap() //#[allow_ci]
+            .into_iter()
+            .map(|(offset, _)| offset)
+            .collect();
+        assert_eq!(offsets[0], 0);
+        assert_eq!(offsets[1], header().len());
+        assert_eq!(offsets.len(), 4);
+
+        // A log cut in the middle of an event is detected
+        assert!(matches!(
+            parse(&log[..log.len() - 3]),
+            Err(EventLogError::Truncated(_))
+        ));
+    }
+}
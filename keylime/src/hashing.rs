@@ -0,0 +1,419 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Hashing backends
+//!
+//! The digests computed by the agent, e.g. when replaying the IMA
+//! measurement list or the measured boot log, or when measuring files, go
+//! through a [`HashBackend`]. OpenSSL is always available. The backends of
+//! the ring crate (feature "ring") and of the sha1 and sha2 crates (feature
+//! "sha2") can be compiled in, and one of them selected at startup with
+//! [`set_default`], e.g. to use the NEON acceleration of ARM devices whose
+//! OpenSSL build does not.
+//!
+//! A single digest is inherently sequential. Independent inputs, e.g. the
+//! entries of the IMA measurement list or the files of a payload, are
+//! hashed in parallel with [`digest_all`] and [`digest_files`] when they
+//! are large enough for the threads to pay off.
+
+use crate::algorithms::HashAlgorithm;
+use openssl::hash::{Hasher, MessageDigest};
+use std::{
+    fs,
+    io::{self, Read},
+    num::NonZeroUsize,
+    path::Path,
+    sync::OnceLock,
+    thread,
+};
+use thiserror::Error;
+
+/// Inputs whose total size is below this are hashed on the calling thread
+const PARALLEL_THRESHOLD: u64 = 1 << 20;
+
+/// Size of the buffer used to read the hashed files
+const READ_BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum HashingError {
+    #[error("The {backend} hashing backend does not support {alg}")]
+    Unsupported {
+        backend: &'static str,
+        alg: HashAlgorithm,
+    },
+    #[error(
+        "Unknown hashing backend '{0}', the available backends are: {1}"
+    )]
+    UnknownBackend(String, String),
+    #[error("The hashing backend is already set to {0}")]
+    AlreadySet(&'static str),
+    #[error("A hashing thread panicked")]
+    Thread,
+    #[error("IO error: {0}")]
+    Io(#[from] io::Error),
+    #[error("OpenSSL error: {0}")]
+    OpenSSL(#[from] openssl::error::ErrorStack),
+}
+
+type Result<T> = std::result::Result<T, HashingError>;
+
+/// An incremental digest computation
+pub trait Digester: Send {
+    fn update(&mut self, data: &[u8]) -> Result<()>;
+    fn finish(self: Box<Self>) -> Result<Vec<u8>>;
+}
+
+/// An implementation of the hash algorithms
+pub trait HashBackend: Send + Sync {
+    /// The name of the backend, as set in the configuration
+    fn name(&self) -> &'static str;
+
+    fn digester(&self, alg: HashAlgorithm) -> Result<Box<dyn Digester>>;
+
+    fn digest(&self, alg: HashAlgorithm, data: &[u8]) -> Result<Vec<u8>> {
+        let mut digester = self.digester(alg)?;
+        digester.update(data)?;
+        digester.finish()
+    }
+}
+
+/// The backend of OpenSSL, supporting all the algorithms
+#[derive(Debug)]
+pub struct OpensslBackend;
+
+impl Digester for Hasher {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        Ok(Hasher::update(self, data)?)
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<Vec<u8>> {
+        Ok(Hasher::finish(&mut self)?.to_vec())
+    }
+}
+
+impl HashBackend for OpensslBackend {
+    fn name(&self) -> &'static str {
+        "openssl"
+    }
+
+    fn digester(&self, alg: HashAlgorithm) -> Result<Box<dyn Digester>> {
+        Ok(Box::new(Hasher::new(MessageDigest::from(alg))?))
+    }
+}
+
+/// The backend of the ring crate, which does not support SM3
+#[cfg(feature = "ring")]
+#[derive(Debug)]
+pub struct RingBackend;
+
+#[cfg(feature = "ring")]
+impl Digester for ring::digest::Context {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        ring::digest::Context::update(self, data);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<Vec<u8>> {
+        Ok(ring::digest::Context::finish(*self).as_ref().to_vec())
+    }
+}
+
+#[cfg(feature = "ring")]
+impl HashBackend for RingBackend {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+
+    fn digester(&self, alg: HashAlgorithm) -> Result<Box<dyn Digester>> {
+        use ring::digest::{
+            Context, SHA1_FOR_LEGACY_USE_ONLY, SHA256, SHA384, SHA512,
+        };
+        let algorithm = match alg {
+            HashAlgorithm::Sha1 => &SHA1_FOR_LEGACY_USE_ONLY,
+            HashAlgorithm::Sha256 => &SHA256,
+            HashAlgorithm::Sha384 => &SHA384,
+            HashAlgorithm::Sha512 => &SHA512,
+            HashAlgorithm::Sm3_256 => {
+                return Err(HashingError::Unsupported {
+                    backend: self.name(),
+                    alg,
+                })
+            }
+        };
+        Ok(Box::new(Context::new(algorithm)))
+    }
+}
+
+/// The backend of the sha1 and sha2 crates, which does not support SM3
+#[cfg(feature = "sha2")]
+#[derive(Debug)]
+pub struct Sha2Backend;
+
+#[cfg(feature = "sha2")]
+struct Sha2Digester<D>(D);
+
+#[cfg(feature = "sha2")]
+impl<D: sha2::Digest + Send> Digester for Sha2Digester<D> {
+    fn update(&mut self, data: &[u8]) -> Result<()> {
+        self.0.update(data);
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<Vec<u8>> {
+        Ok(self.0.finalize().to_vec())
+    }
+}
+
+#[cfg(feature = "sha2")]
+impl HashBackend for Sha2Backend {
+    fn name(&self) -> &'static str {
+        "sha2"
+    }
+
+    fn digester(&self, alg: HashAlgorithm) -> Result<Box<dyn Digester>> {
+        use sha2::Digest;
+        Ok(match alg {
+            HashAlgorithm::Sha1 => Box::new(Sha2Digester(sha1::Sha1::new())),
+            HashAlgorithm::Sha256 => {
+                Box::new(Sha2Digester(sha2::Sha256::new()))
+            }
+            HashAlgorithm::Sha384 => {
+                Box::new(Sha2Digester(sha2::Sha384::new()))
+            }
+            HashAlgorithm::Sha512 => {
+                Box::new(Sha2Digester(sha2::Sha512::new()))
+            }
+            HashAlgorithm::Sm3_256 => {
+                return Err(HashingError::Unsupported {
+                    backend: self.name(),
+                    alg,
+                })
+            }
+        })
+    }
+}
+
+/// The backends compiled in
+static BACKENDS: &[&dyn HashBackend] = &[
+    &OpensslBackend,
+    #[cfg(feature = "ring")]
+    &RingBackend,
+    #[cfg(feature = "sha2")]
+    &Sha2Backend,
+];
+
+static DEFAULT_BACKEND: OnceLock<&'static dyn HashBackend> = OnceLock::new();
+
+/// The names of the backends compiled in
+pub fn available() -> Vec<&'static str> {
+    BACKENDS.iter().map(|b| b.name()).collect()
+}
+
+/// The backend with the given name, if compiled in
+pub fn backend(name: &str) -> Result<&'static dyn HashBackend> {
+    BACKENDS
+        .iter()
+        .find(|b| b.name() == name)
+        .copied()
+        .ok_or_else(|| {
+            HashingError::UnknownBackend(
+                name.to_string(),
+                available().join(", "),
+            )
+        })
+}
+
+/// Set the backend returned by [`default_backend`]. It can only be set
+/// once, before any digest is computed with the default backend.
+pub fn set_default(name: &str) -> Result<()> {
+    let selected = backend(name)?;
+    let current = *DEFAULT_BACKEND.get_or_init(|| selected);
+    if current.name() != selected.name() {
+        return Err(HashingError::AlreadySet(current.name()));
+    }
+    Ok(())
+}
+
+/// The backend set with [`set_default`], OpenSSL if none was set
+pub fn default_backend() -> &'static dyn HashBackend {
+    *DEFAULT_BACKEND.get_or_init(|| &OpensslBackend)
+}
+
+/// Compute the digest of the data read from `reader`
+pub fn digest_reader(
+    backend: &dyn HashBackend,
+    alg: HashAlgorithm,
+    mut reader: impl Read,
+) -> Result<Vec<u8>> {
+    let mut digester = backend.digester(alg)?;
+    let mut buf = vec![0u8; READ_BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => digester.update(&buf[..n])?,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    digester.finish()
+}
+
+/// Apply `f` to the items, spread over the available CPUs when the total
+/// `size` of the items is at least `PARALLEL_THRESHOLD`, keeping the order
+/// of the items in the results
+fn parallel_map<T, R, F>(items: &[T], size: u64, f: F) -> Result<Vec<R>>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R> + Sync,
+{
+    let threads = thread::available_parallelism()
+        .map_or(1, NonZeroUsize::get)
+        .min(items.len());
+    if threads <= 1 || size < PARALLEL_THRESHOLD {
+        return items.iter().map(f).collect();
+    }
+
+    let f = &f;
+    thread::scope(|scope| {
+        let workers: Vec<_> = items
+            .chunks(items.len().div_ceil(threads))
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk.iter().map(f).collect::<Result<Vec<R>>>()
+                })
+            })
+            .collect();
+        let mut results = Vec::with_capacity(items.len());
+        for worker in workers {
+            results.extend(worker.join().map_err(|_| HashingError::Thread)??);
+        }
+        Ok(results)
+    })
+}
+
+/// Compute the digests of independent inputs, in parallel if they are
+/// large enough
+pub fn digest_all<T: AsRef<[u8]> + Sync>(
+    backend: &dyn HashBackend,
+    alg: HashAlgorithm,
+    inputs: &[T],
+) -> Result<Vec<Vec<u8>>> {
+    let size = inputs.iter().map(|i| i.as_ref().len() as u64).sum();
+    parallel_map(inputs, size, |input| backend.digest(alg, input.as_ref()))
+}
+
+/// Compute the digests of the content of the files, in parallel if they are
+/// large enough
+pub fn digest_files<P: AsRef<Path> + Sync>(
+    backend: &dyn HashBackend,
+    alg: HashAlgorithm,
+    paths: &[P],
+) -> Result<Vec<Vec<u8>>> {
+    let size = paths
+        .iter()
+        .filter_map(|p| fs::metadata(p).ok())
+        .map(|m| m.len())
+        .sum();
+    parallel_map(paths, size, |path| {
+        digest_reader(backend, alg, fs::File::open(path)?)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const ALGORITHMS: [HashAlgorithm; 4] = [
+        HashAlgorithm::Sha1,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Sha384,
+        HashAlgorithm::Sha512,
+    ];
+
+    #[test]
+    fn test_backends() {
+        assert_eq!(
+            hex::encode(OpensslBackend.digest(HashAlgorithm::Sha256, b"abc").unwrap()), //#[allow_ci]
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        for backend in BACKENDS {
+            for alg in ALGORITHMS {
+                let expected = openssl::hash::hash(alg.into(), &data)
+                    .unwrap() //#[allow_ci]
+                    .to_vec();
+                assert_eq!(backend.digest(alg, &data).unwrap(), expected); //#[allow_ci]
+                assert_eq!(
+                    digest_reader(*backend, alg, data.as_slice()).unwrap(), //#[allow_ci]
+                    expected
+                );
+            }
+        }
+        assert_eq!(available()[0], "openssl");
+        assert!(matches!(
+            backend("md5"),
+            Err(HashingError::UnknownBackend(..))
+        ));
+    }
+
+    #[cfg(feature = "ring")]
+    #[test]
+    fn test_ring_unsupported() {
+        assert!(matches!(
+            RingBackend.digester(HashAlgorithm::Sm3_256),
+            Err(HashingError::Unsupported { .. })
+        ));
+    }
+
+    #[test]
+    fn test_digest_all() {
+        // Large enough to be hashed in parallel
+        let inputs: Vec<Vec<u8>> =
+            (0..64u8).map(|i| vec![i; 32 * 1024]).collect();
+        let digests =
+            digest_all(&OpensslBackend, HashAlgorithm::Sha256, &inputs)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(digests.len(), inputs.len());
+        for (input, digest) in inputs.iter().zip(&digests) {
+            assert_eq!(
+                digest,
+                &OpensslBackend.digest(HashAlgorithm::Sha256, input).unwrap() //#[allow_ci]
+            );
+        }
+        assert!(digest_all::<Vec<u8>>(
+            &OpensslBackend,
+            HashAlgorithm::Sha256,
+            &[]
+        )
+        .unwrap() //#[allow_ci]
+        .is_empty());
+    }
+
+    #[test]
+    fn test_digest_files() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let paths: Vec<_> =
+            (0..3).map(|i| dir.path().join(i.to_string())).collect();
+        for (i, path) in paths.iter().enumerate() {
+            let mut file = fs::File::create(path).unwrap(); //#[allow_ci]
+            file.write_all(&vec![i as u8; 1000]).unwrap(); //#[allow_ci]
+        }
+        let digests =
+            digest_files(default_backend(), HashAlgorithm::Sha1, &paths)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(
+            digests[2],
+            OpensslBackend
+                .digest(HashAlgorithm::Sha1, &[2u8; 1000])
+                .unwrap() //#[allow_ci]
+        );
+        assert!(digest_files(
+            &OpensslBackend,
+            HashAlgorithm::Sha1,
+            &[dir.path().join("missing")]
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,299 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Sources of the agent identity
+//!
+//! The agent UUID can be set statically, generated once and persisted, or
+//! derived from the EK, the DMI product UUID or the content of a file. Each
+//! source implements the `IdentityProvider` trait, so that applications
+//! embedding the agent can provide their own source.
+
+use log::*;
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+use thiserror::Error;
+use uuid::Uuid;
+
+/// The UUID of the machine set by the firmware
+pub const DMI_PRODUCT_UUID: &str = "/sys/class/dmi/id/product_uuid";
+
+#[derive(Error, Debug)]
+pub enum IdentityError {
+    #[error("Invalid UUID '{0}'")]
+    InvalidUuid(String),
+    #[error("The EK hash is required for the identity but not available")]
+    MissingEkHash,
+    #[error("Unable to access identity file {path}: {source}")]
+    Io { path: PathBuf, source: io::Error },
+}
+
+/// Information about the machine available when the identity is obtained
+#[derive(Debug, Default, Clone)]
+pub struct IdentityContext {
+    /// SHA-256 hash of the EK public key in PEM format
+    pub ek_hash: Option<String>,
+}
+
+/// A source of the agent UUID
+pub trait IdentityProvider: fmt::Debug + Send + Sync {
+    /// Short description of the source, used in logs
+    fn name(&self) -> &str;
+
+    /// Obtain the agent UUID
+    fn uuid(&self, ctx: &IdentityContext) -> Result<String, IdentityError>;
+}
+
+fn parse_uuid(value: &str) -> Result<String, IdentityError> {
+    Uuid::parse_str(value.trim())
+        .map(|u| u.to_string())
+        .map_err(|_| IdentityError::InvalidUuid(value.trim().to_string()))
+}
+
+fn read_file(path: &Path) -> Result<String, IdentityError> {
+    fs::read_to_string(path).map_err(|source| IdentityError::Io {
+        path: path.to_path_buf(),
+        source,
+    })
+}
+
+/// A UUID set in the configuration
+#[derive(Debug, Clone)]
+pub struct StaticIdentity {
+    uuid: String,
+}
+
+impl StaticIdentity {
+    pub fn new(uuid: &str) -> Self {
+        StaticIdentity {
+            uuid: uuid.to_string(),
+        }
+    }
+}
+
+impl IdentityProvider for StaticIdentity {
+    fn name(&self) -> &str {
+        "static"
+    }
+
+    fn uuid(&self, _ctx: &IdentityContext) -> Result<String, IdentityError> {
+        parse_uuid(&self.uuid)
+    }
+}
+
+/// A random UUID, generated on the first run and stored in a file, so that
+/// it is kept across restarts
+#[derive(Debug, Clone)]
+pub struct GeneratedIdentity {
+    path: PathBuf,
+}
+
+impl GeneratedIdentity {
+    pub fn new(path: &Path) -> Self {
+        GeneratedIdentity {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl IdentityProvider for GeneratedIdentity {
+    fn name(&self) -> &str {
+        "generated"
+    }
+
+    fn uuid(&self, _ctx: &IdentityContext) -> Result<String, IdentityError> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => match parse_uuid(&content) {
+                Ok(uuid) => return Ok(uuid),
+                Err(e) => warn!(
+                    "Replacing the generated UUID stored in {}: {e}",
+                    self.path.display()
+                ),
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(source) => {
+                return Err(IdentityError::Io {
+                    path: self.path.clone(),
+                    source,
+                })
+            }
+        }
+
+        let uuid = Uuid::new_v4().to_string();
+        info!("Generated a new UUID: {uuid}");
+        fs::write(&self.path, format!("{uuid}\n")).map_err(|source| {
+            IdentityError::Io {
+                path: self.path.clone(),
+                source,
+            }
+        })?;
+        Ok(uuid)
+    }
+}
+
+/// The hash of the EK public key
+#[derive(Debug, Clone, Default)]
+pub struct EkHashIdentity;
+
+impl IdentityProvider for EkHashIdentity {
+    fn name(&self) -> &str {
+        "EK hash"
+    }
+
+    fn uuid(&self, ctx: &IdentityContext) -> Result<String, IdentityError> {
+        ctx.ek_hash.clone().ok_or(IdentityError::MissingEkHash)
+    }
+}
+
+/// The product UUID set by the firmware in the DMI tables
+#[derive(Debug, Clone)]
+pub struct DmiIdentity {
+    path: PathBuf,
+}
+
+impl Default for DmiIdentity {
+    fn default() -> Self {
+        DmiIdentity {
+            path: PathBuf::from(DMI_PRODUCT_UUID),
+        }
+    }
+}
+
+impl IdentityProvider for DmiIdentity {
+    fn name(&self) -> &str {
+        "DMI product UUID"
+    }
+
+    fn uuid(&self, _ctx: &IdentityContext) -> Result<String, IdentityError> {
+        parse_uuid(&read_file(&self.path)?)
+    }
+}
+
+/// A UUID provisioned in a file, e.g. by the machine image
+#[derive(Debug, Clone)]
+pub struct FileIdentity {
+    path: PathBuf,
+}
+
+impl FileIdentity {
+    pub fn new(path: &Path) -> Self {
+        FileIdentity {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl IdentityProvider for FileIdentity {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn uuid(&self, _ctx: &IdentityContext) -> Result<String, IdentityError> {
+        parse_uuid(&read_file(&self.path)?)
+    }
+}
+
+/// Get the identity provider for the value of the 'uuid' option:
+///
+/// * "generate": a random UUID persisted in `generated_path`
+/// * "hash_ek": the hash of the EK public key
+/// * "dmi": the DMI product UUID
+/// * "file:<path>": the UUID stored in the file
+/// * any other value is used as the UUID. A misformatted UUID is replaced
+///   with a generated one.
+pub fn from_config(
+    value: &str,
+    generated_path: &Path,
+) -> Box<dyn IdentityProvider> {
+    match value {
+        "generate" => Box::new(GeneratedIdentity::new(generated_path)),
+        "hash_ek" => Box::new(EkHashIdentity),
+        "dmi" => Box::new(DmiIdentity::default()),
+        v => match v.strip_prefix("file:") {
+            Some(path) => Box::new(FileIdentity::new(Path::new(path))),
+            None if Uuid::parse_str(v).is_ok() => {
+                Box::new(StaticIdentity::new(v))
+            }
+            None => {
+                warn!("Misformatted UUID: {v}, using a generated UUID");
+                Box::new(GeneratedIdentity::new(generated_path))
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_static() {
+        let ctx = IdentityContext::default();
+        let provider = from_config(
+            "D432FBB3-D2F1-4A97-9EF7-75BD81C00000",
+            Path::new("/nonexistent"),
+        );
+        assert_eq!(
+            provider.uuid(&ctx).unwrap(), //#[allow_ci]
+            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
+        );
+    }
+
+    #[test]
+    fn test_generated() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("agent_uuid");
+        let ctx = IdentityContext::default();
+
+        let first = from_config("generate", &path).uuid(&ctx).unwrap(); //#[allow_ci]
+        let second = from_config("generate", &path).uuid(&ctx).unwrap(); //#[allow_ci]
+        assert!(Uuid::parse_str(&first).is_ok());
+        assert_eq!(first, second);
+
+        // A misformatted UUID falls back to the generated one
+        let fallback =
+            from_config("D432FBB3-D2F1-4A97-9EF7-75BD81C0000X", &path)
+                .uuid(&ctx)
+                .unwrap(); //#[allow_ci]
+        assert_eq!(fallback, first);
+    }
+
+    #[test]
+    fn test_ek_hash() {
+        let provider = from_config("hash_ek", Path::new("/nonexistent"));
+        assert!(matches!(
+            provider.uuid(&IdentityContext::default()),
+            Err(IdentityError::MissingEkHash)
+        ));
+        let ctx = IdentityContext {
+            ek_hash: Some("abcd".to_string()),
+        };
+        assert_eq!(provider.uuid(&ctx).unwrap(), "abcd"); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_file() {
+        let dir = tempfile::tempdir().unwrap(); //#[allow_ci]
+        let path = dir.path().join("uuid");
+        let value = format!("file:{}", path.display());
+        let ctx = IdentityContext::default();
+
+        assert!(matches!(
+            from_config(&value, &path).uuid(&ctx),
+            Err(IdentityError::Io { .. })
+        ));
+
+        fs::write(&path, "not-a-uuid").unwrap(); //#[allow_ci]
+        assert!(matches!(
+            from_config(&value, &path).uuid(&ctx),
+            Err(IdentityError::InvalidUuid(_))
+        ));
+
+        fs::write(&path, "D432FBB3-D2F1-4A97-9EF7-75BD81C00000\n").unwrap(); //#[allow_ci]
+        assert_eq!(
+            from_config(&value, &path).uuid(&ctx).unwrap(), //#[allow_ci]
+            "d432fbb3-d2f1-4a97-9ef7-75bd81c00000"
+        );
+    }
+}
@@ -9,7 +9,9 @@
 
 use crate::algorithms::HashAlgorithm;
 use openssl::hash::MessageDigest;
+use std::borrow::Cow;
 use std::convert::{TryFrom, TryInto};
+use std::fmt::Write as _;
 use std::io::{Error, ErrorKind, Result, Write};
 
 pub trait Encode {
@@ -128,17 +130,98 @@ impl EncodeLegacy for Digest {
     }
 }
 
-struct Name {
-    name: String,
+/// Split the bytes at the first `n - 1` spaces
+fn split_fields(value: &[u8], n: usize) -> Vec<&[u8]> {
+    value.splitn(n, |b| *b == b' ').collect()
+}
+
+fn invalid_input(value: &[u8]) -> Error {
+    Error::new(ErrorKind::InvalidInput, escape_non_utf8(value))
+}
+
+fn decode_hex(value: &[u8]) -> Result<Vec<u8>> {
+    hex::decode(value).map_err(|_| {
+        Error::new(ErrorKind::InvalidInput, "invalid hex encoding")
+    })
+}
+
+/// Escape the bytes which are not part of a valid UTF-8 sequence as `\xNN`,
+/// the notation used by the kernel for unprintable characters, and the
+/// backslashes as `\\`.
+///
+/// The file names measured by IMA are written to the measurement list as
+/// the raw bytes of the path, which are not necessarily valid UTF-8. The
+/// escaped names can be sent as JSON strings without losing the entries,
+/// and the verifier recovers the raw bytes with `unescape_non_utf8` to
+/// compute the template hash. Escaping the backslashes keeps a name which
+/// contains the text `\xNN` distinct from one with the raw byte.
+pub fn escape_non_utf8(data: &[u8]) -> Cow<'_, str> {
+    let mut escaped = String::new();
+    let mut rest = data;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                if rest.len() == data.len() && !valid.contains('\\') {
+                    return Cow::Borrowed(valid);
+                }
+                escaped.push_str(&valid.replace('\\', "\\\\"));
+                return Cow::Owned(escaped);
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                escaped.push_str(
+                    &std::str::from_utf8(valid)
+                        .unwrap_or("")
+                        .replace('\\', "\\\\"),
+                );
+                let len = e.error_len().unwrap_or(invalid.len());
+                for b in &invalid[..len] {
+                    let _ = write!(escaped, "\\x{b:02x}");
+                }
+                rest = &invalid[len..];
+            }
+        }
+    }
 }
 
-impl TryFrom<&str> for Name {
-    type Error = std::io::Error;
+/// Recover the raw bytes of a name escaped by `escape_non_utf8`
+pub fn unescape_non_utf8(escaped: &str) -> Result<Vec<u8>> {
+    let mut raw = Vec::with_capacity(escaped.len());
+    let mut bytes = escaped.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            raw.push(b);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'\\') => raw.push(b'\\'),
+            Some(b'x') => {
+                let hex = [
+                    bytes.next().unwrap_or_default(),
+                    bytes.next().unwrap_or_default(),
+                ];
+                raw.extend(decode_hex(&hex)?);
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "invalid escape sequence",
+                ))
+            }
+        }
+    }
+    Ok(raw)
+}
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        Ok(Self {
-            name: value.to_string(),
-        })
+struct Name {
+    name: Vec<u8>,
+}
+
+impl From<&[u8]> for Name {
+    fn from(value: &[u8]) -> Self {
+        Self {
+            name: value.to_vec(),
+        }
     }
 }
 
@@ -146,9 +229,8 @@ const TCG_EVENT_NAME_LEN_MAX: usize = 255;
 
 impl Encode for Name {
     fn encode(&self, writer: &mut dyn Write) -> Result<()> {
-        let bytes = self.name.as_bytes();
-        writer.write_all(&((bytes.len() + 1) as u32).to_le_bytes())?;
-        writer.write_all(bytes)?;
+        writer.write_all(&((self.name.len() + 1) as u32).to_le_bytes())?;
+        writer.write_all(&self.name)?;
         writer.write_all(&[0u8])?; // NUL
         Ok(())
     }
@@ -156,9 +238,17 @@ impl Encode for Name {
 
 impl EncodeLegacy for Name {
     fn encode_legacy(&self, writer: &mut dyn Write) -> Result<()> {
-        let bytes = self.name.as_bytes();
-        writer.write_all(bytes)?;
-        writer.write_all(&vec![0u8; TCG_EVENT_NAME_LEN_MAX - bytes.len()])?;
+        if self.name.len() > TCG_EVENT_NAME_LEN_MAX {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "event name too long",
+            ));
+        }
+        writer.write_all(&self.name)?;
+        writer.write_all(&vec![
+            0u8;
+            TCG_EVENT_NAME_LEN_MAX - self.name.len()
+        ])?;
         Ok(())
     }
 }
@@ -167,13 +257,11 @@ struct Signature {
     value: Vec<u8>,
 }
 
-impl TryFrom<&str> for Signature {
+impl TryFrom<&[u8]> for Signature {
     type Error = std::io::Error;
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        let value = hex::decode(value).map_err(|_| {
-            Error::new(ErrorKind::InvalidInput, "invalid hex encoding")
-        })?;
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        let value = decode_hex(value)?;
         // basic checks on signature
         if value.len() < 9 {
             return Err(Error::new(
@@ -204,14 +292,12 @@ struct Buffer {
     value: Vec<u8>,
 }
 
-impl TryFrom<&str> for Buffer {
+impl TryFrom<&[u8]> for Buffer {
     type Error = std::io::Error;
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
         Ok(Self {
-            value: hex::decode(value).map_err(|_| {
-                Error::new(ErrorKind::InvalidInput, "invalid hex encoding")
-            })?,
+            value: decode_hex(value)?,
         })
     }
 }
@@ -225,7 +311,24 @@ impl Encode for Buffer {
 }
 
 pub trait EventData: Encode {
-    fn path(&self) -> &str;
+    /// The path or name of the event, with the bytes which are not valid
+    /// UTF-8 escaped
+    fn path(&self) -> Cow<'_, str> {
+        escape_non_utf8(self.raw_path())
+    }
+    /// The path or name of the event as written in the measurement list
+    fn raw_path(&self) -> &[u8];
+    fn digest(&self) -> &Digest;
+}
+
+impl TryFrom<&[u8]> for Digest {
+    type Error = std::io::Error;
+
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        std::str::from_utf8(value)
+            .map_err(|_| invalid_input(value))
+            .and_then(Digest::try_from)
+    }
 }
 
 struct Ima {
@@ -233,25 +336,29 @@ struct Ima {
     path: Name,
 }
 
-impl TryFrom<&str> for Ima {
+impl TryFrom<&[u8]> for Ima {
     type Error = std::io::Error;
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        let tokens: Vec<&str> = value.splitn(2, ' ').collect();
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        let tokens = split_fields(value, 2);
         if tokens.len() != 2 {
-            return Err(Error::new(ErrorKind::InvalidInput, value));
+            return Err(invalid_input(value));
         }
         Ok(Self {
             digest: Digest::try_from(tokens[0])?,
-            path: Name::try_from(tokens[1])?,
+            path: Name::from(tokens[1]),
         })
     }
 }
 
 impl EventData for Ima {
-    fn path(&self) -> &str {
+    fn raw_path(&self) -> &[u8] {
         &self.path.name
     }
+
+    fn digest(&self) -> &Digest {
+        &self.digest
+    }
 }
 
 impl Encode for Ima {
@@ -267,26 +374,30 @@ struct ImaNg {
     path: Name,
 }
 
-impl TryFrom<&str> for ImaNg {
+impl TryFrom<&[u8]> for ImaNg {
     type Error = std::io::Error;
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        let tokens: Vec<&str> = value.splitn(2, ' ').collect();
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        let tokens = split_fields(value, 2);
         if tokens.len() != 2 {
-            return Err(Error::new(ErrorKind::InvalidInput, value));
+            return Err(invalid_input(value));
         }
 
         Ok(Self {
             digest: Digest::try_from(tokens[0])?,
-            path: Name::try_from(tokens[1])?,
+            path: Name::from(tokens[1]),
         })
     }
 }
 
 impl EventData for ImaNg {
-    fn path(&self) -> &str {
+    fn raw_path(&self) -> &[u8] {
         &self.path.name
     }
+
+    fn digest(&self) -> &Digest {
+        &self.digest
+    }
 }
 
 impl Encode for ImaNg {
@@ -304,28 +415,34 @@ struct ImaSig {
 }
 
 impl EventData for ImaSig {
-    fn path(&self) -> &str {
+    fn raw_path(&self) -> &[u8] {
         &self.path.name
     }
+
+    fn digest(&self) -> &Digest {
+        &self.digest
+    }
 }
 
-impl TryFrom<&str> for ImaSig {
+impl TryFrom<&[u8]> for ImaSig {
     type Error = std::io::Error;
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
         // extract signature first
-        let (value, signature) = value
-            .rsplit_once(' ')
-            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, value))?;
+        let split = value
+            .iter()
+            .rposition(|b| *b == b' ')
+            .ok_or_else(|| invalid_input(value))?;
+        let (value, signature) = (&value[..split], &value[split + 1..]);
 
         // parse d-ng|n-ng as in ima-ng
-        let tokens: Vec<&str> = value.splitn(2, ' ').collect();
+        let tokens = split_fields(value, 2);
         if tokens.len() != 2 {
-            return Err(Error::new(ErrorKind::InvalidInput, value));
+            return Err(invalid_input(value));
         }
 
         let digest = Digest::try_from(tokens[0])?;
-        let path = Name::try_from(tokens[1])?;
+        let path = Name::from(tokens[1]);
         let signature = if !signature.is_empty() {
             Some(Signature::try_from(signature)?)
         } else {
@@ -359,26 +476,30 @@ struct ImaBuf {
     data: Buffer,
 }
 
-impl TryFrom<&str> for ImaBuf {
+impl TryFrom<&[u8]> for ImaBuf {
     type Error = std::io::Error;
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        let tokens: Vec<&str> = value.splitn(3, ' ').collect();
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        let tokens = split_fields(value, 3);
         if tokens.len() != 3 {
-            return Err(Error::new(ErrorKind::InvalidInput, value));
+            return Err(invalid_input(value));
         }
         Ok(Self {
             digest: Digest::try_from(tokens[0])?,
-            name: Name::try_from(tokens[1])?,
+            name: Name::from(tokens[1]),
             data: Buffer::try_from(tokens[2])?,
         })
     }
 }
 
 impl EventData for ImaBuf {
-    fn path(&self) -> &str {
+    fn raw_path(&self) -> &[u8] {
         &self.name.name
     }
+
+    fn digest(&self) -> &Digest {
+        &self.digest
+    }
 }
 
 impl Encode for ImaBuf {
@@ -396,54 +517,126 @@ pub struct Entry {
     pub event_data: Box<dyn EventData>,
 }
 
-impl TryFrom<&str> for Entry {
+/// Parse an entry from a line of the measurement list, without the
+/// trailing newline. The paths are kept as raw bytes, as they may not be
+/// valid UTF-8.
+impl TryFrom<&[u8]> for Entry {
     type Error = std::io::Error;
 
-    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
-        let tokens: Vec<&str> = value.splitn(4, ' ').collect();
+    fn try_from(value: &[u8]) -> std::result::Result<Self, Self::Error> {
+        let tokens = split_fields(value, 4);
         if tokens.len() != 4 {
-            return Err(Error::new(ErrorKind::InvalidInput, value));
+            return Err(invalid_input(value));
         }
 
         let template_hash = Digest {
             algorithm: HashAlgorithm::Sha1,
-            value: hex::decode(tokens[1]).map_err(|_| {
-                Error::new(ErrorKind::InvalidInput, "invalid hex encoding")
-            })?,
+            value: decode_hex(tokens[1])?,
         };
         let mode = tokens[2];
         let event = tokens[3];
 
         match mode {
-            "ima" => Ok(Self {
+            b"ima" => Ok(Self {
                 template_hash,
                 event_data: Box::new(Ima::try_from(event)?),
             }),
-            "ima-ng" => Ok(Self {
+            b"ima-ng" => Ok(Self {
                 template_hash,
                 event_data: Box::new(ImaNg::try_from(event)?),
             }),
-            "ima-sig" => Ok(Self {
+            b"ima-sig" => Ok(Self {
                 template_hash,
                 event_data: Box::new(ImaSig::try_from(event)?),
             }),
-            "ima-buf" => Ok(Self {
+            b"ima-buf" => Ok(Self {
                 template_hash,
                 event_data: Box::new(ImaBuf::try_from(event)?),
             }),
-            template => Err(Error::new(
-                ErrorKind::Other,
-                format!("unrecognized template \"{template}\"",),
-            )),
+            template => Err(Error::other(format!(
+                "unrecognized template \"{}\"",
+                escape_non_utf8(template)
+            ))),
         }
     }
 }
 
+impl TryFrom<&str> for Entry {
+    type Error = std::io::Error;
+
+    fn try_from(value: &str) -> std::result::Result<Self, Self::Error> {
+        Entry::try_from(value.as_bytes())
+    }
+}
+
+/// Parse the entries of an IMA measurement list in the ASCII format, one
+/// entry per line. Each line is parsed on its own, so that a malformed entry
+/// does not prevent the parsing of the following ones.
+pub fn parse_entries(
+    data: &[u8],
+) -> impl Iterator<Item = Result<Entry>> + '_ {
+    data.split(|b| *b == b'\n')
+        .filter(|line| !line.is_empty())
+        .map(Entry::try_from)
+}
+
 // Unit Testing
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_entries() {
+        let data = b"10 d7026dc672344d3ee372217bdbc7395947788671 ima 6f66d1d8e2fffcc12dfcb78c04b81fe5b8bbae4e /usr/bin/kmod\n10 invalid\n\xff\n";
+        let entries: Vec<_> = parse_entries(data).collect();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(
+            entries[0].as_ref().unwrap().event_data.path(), //#[allow_ci]
+            "/usr/bin/kmod"
+        );
+        assert!(entries[1].is_err());
+        assert!(entries[2].is_err());
+    }
+
+    #[test]
+    fn test_escape_non_utf8() {
+        assert!(matches!(
+            escape_non_utf8("/usr/bin/ls \u{e9}".as_bytes()),
+            Cow::Borrowed("/usr/bin/ls \u{e9}")
+        ));
+        assert_eq!(escape_non_utf8(b"/tmp/\xe9t\xe9"), "/tmp/\\xe9t\\xe9");
+        assert_eq!(escape_non_utf8(b"a\xff\xc3"), "a\\xff\\xc3");
+
+        // A name with the text of an escape sequence is not confused with
+        // the raw byte
+        let text = br"/tmp/\xe9";
+        let byte = b"/tmp/\xe9";
+        assert_eq!(escape_non_utf8(text), r"/tmp/\\xe9");
+        assert_eq!(escape_non_utf8(byte), r"/tmp/\xe9");
+        for raw in [&text[..], &byte[..], b"a\\\xff\\", "\u{e9}".as_bytes()] {
+            assert_eq!(
+                unescape_non_utf8(&escape_non_utf8(raw)).unwrap(), //#[allow_ci]
+                raw
+            );
+        }
+        assert!(unescape_non_utf8(r"a\b").is_err());
+        assert!(unescape_non_utf8(r"a\xzz").is_err());
+    }
+
+    #[test]
+    fn test_parse_non_utf8_path() {
+        // Latin-1 encoded file name, next to a name escaped by the kernel
+        let line = b"10 0000000000000000000000000000000000000000 ima-ng sha1:bc026ae66d81713e4e852465e980784dc96651f8 /tmp/\xe9t\xe9 a\\x20b";
+        let entry = Entry::try_from(&line[..]).unwrap(); //#[allow_ci]
+        assert_eq!(entry.event_data.raw_path(), b"/tmp/\xe9t\xe9 a\\x20b");
+        assert_eq!(entry.event_data.path(), "/tmp/\\xe9t\\xe9 a\\\\x20b");
+
+        // The template hash is computed over the raw bytes of the path
+        let mut buf = vec![];
+        entry.event_data.encode(&mut buf).unwrap(); //#[allow_ci]
+        assert_eq!(&buf[28..36], b"/tmp/\xe9t\xe9");
+    }
+
     #[test]
     fn test_parse_ima() {
         let entry: Entry = "10 d7026dc672344d3ee372217bdbc7395947788671 ima 6f66d1d8e2fffcc12dfcb78c04b81fe5b8bbae4e /usr/bin/kmod"
@@ -465,6 +658,10 @@ mod tests {
         let entry: Entry = "10 7936eb315fb4e74b99e7d461bc5c96049e1ee092 ima-ng sha1:bc026ae66d81713e4e852465e980784dc96651f8 /usr/lib/systemd/systemd"
             .try_into().expect("unable to parse ima-ng template");
         assert_eq!(entry.event_data.path(), "/usr/lib/systemd/systemd");
+        assert_eq!(
+            hex::encode(entry.event_data.digest().value()),
+            "bc026ae66d81713e4e852465e980784dc96651f8"
+        );
         let mut buf = vec![];
         entry
             .event_data
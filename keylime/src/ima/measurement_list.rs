@@ -1,12 +1,28 @@
 // SPDX-License-Identifier: Apache-2.0
 // Copyright 2021 Keylime Authors
 
+use super::escape_non_utf8;
 use std::{
     collections::HashSet,
     fs::File,
-    io::{prelude::*, Error, SeekFrom},
+    io::{prelude::*, BufReader, Error, SeekFrom},
 };
 
+/// Size of the buffer used when scanning the IMA measurement list
+pub const READ_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Location of a range of entries inside the IMA measurement list file.
+///
+/// `start` and `end` are byte offsets into the file; the range contains
+/// the entries from `nth_entry` up to (but excluding) `num_entries`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryRange {
+    pub start: u64,
+    pub end: u64,
+    pub nth_entry: u64,
+    pub num_entries: u64,
+}
+
 /// MeasurementList models the IMA measurement lists's last two known
 /// numbers of entries in the log and filesizes at that point
 #[derive(Debug)]
@@ -43,47 +59,73 @@ impl MeasurementList {
         })
     }
 
-    /// Read the IMA measurement list starting from a given entry.
+    /// Locate the IMA measurement list entries starting from a given entry.
     /// The entry may be of any value 0 <= entry <= entries_in_log where
     /// entries_in_log + 1 indicates that the client wants to read the next entry
     /// once available. If the entry is outside this range, the function will
-    /// automatically read from the 0-th entry.
-    /// This function returns the measurement list and the entry from where it
-    /// was read and the current number of entries in the file.
-    pub fn read(
+    /// automatically locate the 0-th entry.
+    /// The file is scanned in chunks of READ_CHUNK_SIZE bytes so that the
+    /// memory used does not depend on the size of the measurement list.
+    /// A trailing entry that is not yet terminated by a newline is not
+    /// included in the returned range.
+    pub fn locate(
         &mut self,
         ima_file: &mut File,
         nth_entry: u64,
-    ) -> Result<(String, u64, u64), Error> {
+    ) -> Result<EntryRange, Error> {
         // Try to find the closest entry to the nth_entry
         let (mut num_entries, filesize) = self.find(nth_entry);
 
-        let mut ml = None;
-        let mut filedata = String::new();
         let _ = ima_file.seek(SeekFrom::Start(filesize))?;
-        let _ = ima_file.read_to_string(&mut filedata)?;
-        let mut offset: usize = 0;
+        let mut reader = BufReader::with_capacity(READ_CHUNK_SIZE, ima_file);
+        let mut line = Vec::new();
+        let mut offset = filesize;
+        let mut start = None;
 
         loop {
             if nth_entry == num_entries {
-                ml = Some(&filedata[offset..]);
+                start = Some(offset);
             }
-            let s = &filedata[offset..];
-            let idx = match s.find('\n') {
-                None => break,
-                Some(i) => i,
-            };
-            offset = offset + idx + 1;
+            line.clear();
+            let n = reader.read_until(b'\n', &mut line)?;
+            if n == 0 || line.last() != Some(&b'\n') {
+                break;
+            }
+            offset += n as u64;
             num_entries += 1;
         }
 
-        let _ = self.update(num_entries, filesize + offset as u64);
+        let _ = self.update(num_entries, offset);
 
-        match ml {
-            None => self.read(ima_file, 0),
-            Some(slice) => Ok((String::from(slice), nth_entry, num_entries)),
+        match start {
+            None => self.locate(reader.into_inner(), 0),
+            Some(start) => Ok(EntryRange {
+                start,
+                end: offset,
+                nth_entry,
+                num_entries,
+            }),
         }
     }
+
+    /// Read the IMA measurement list starting from a given entry.
+    /// See `locate` for the accepted values of `nth_entry`.
+    /// This function returns the measurement list and the entry from where it
+    /// was read and the current number of entries in the file. The bytes of
+    /// the file names which are not valid UTF-8 and the backslashes are
+    /// escaped.
+    pub fn read(
+        &mut self,
+        ima_file: &mut File,
+        nth_entry: u64,
+    ) -> Result<(String, u64, u64), Error> {
+        let range = self.locate(ima_file, nth_entry)?;
+        let mut buf = vec![0u8; (range.end - range.start) as usize];
+        let _ = ima_file.seek(SeekFrom::Start(range.start))?;
+        ima_file.read_exact(&mut buf)?;
+        let ml = escape_non_utf8(&buf).into_owned();
+        Ok((ml, range.nth_entry, range.num_entries))
+    }
 }
 
 impl Default for MeasurementList {
@@ -130,4 +172,34 @@ mod tests {
         assert_eq!(nth_entry, 0);
         assert_eq!(ml.find("0-entry").unwrap(), 0); //#[allow_ci]
     }
+
+    #[test]
+    fn locate_measurement_list_test() {
+        let mut ima_ml = MeasurementList::new();
+
+        // The last entry is not complete yet and must not be counted
+        let filedata = "0-entry\n1-entry\n2-entry\n3-ent";
+        let mut tf = NamedTempFile::new().unwrap(); //#[allow_ci]
+        tf.write_all(filedata.as_bytes()).unwrap(); //#[allow_ci]
+        tf.flush().unwrap(); //#[allow_ci]
+
+        let mut ima_file = File::open(tf.path()).unwrap(); //#[allow_ci]
+
+        let range = ima_ml.locate(&mut ima_file, 1).unwrap(); //#[allow_ci]
+        assert_eq!(
+            range,
+            EntryRange {
+                start: 8,
+                end: 24,
+                nth_entry: 1,
+                num_entries: 3,
+            }
+        );
+
+        // The cached offsets are used on the second lookup
+        let range = ima_ml.locate(&mut ima_file, 3).unwrap(); //#[allow_ci]
+        assert_eq!(range.start, 24);
+        assert_eq!(range.end, 24);
+        assert_eq!(range.num_entries, 3);
+    }
 }
@@ -0,0 +1,312 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Messages of the U and V key delivery
+//!
+//! The messages are sent by the tenant and the verifier, and decoded here
+//! without any access to the TPM or the keys of the agent, so that the
+//! decoding can be tested and fuzzed on its own.
+
+use crate::{
+    algorithms::{AlgorithmError, HashAlgorithm},
+    payload::{PayloadDelivery, PayloadError},
+};
+use base64::{engine::general_purpose, Engine as _};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Maximum length of a delivery session ID
+pub const MAX_SESSION_ID_LEN: usize = 64;
+
+#[derive(Error, Debug)]
+pub enum KeyDeliveryError {
+    #[error("Invalid base64 encoding in encrypted_key: {0}")]
+    EncryptedKey(base64::DecodeError),
+    #[error("Invalid hex encoding in auth_tag: {0}")]
+    AuthTag(hex::FromHexError),
+    #[error("{0}")]
+    AuthTagAlgorithm(#[from] AlgorithmError),
+    #[error("Invalid base64 encoding in payload: {0}")]
+    Payload(base64::DecodeError),
+    #[error("sequence requires a session_id")]
+    SequenceWithoutSession,
+    #[error("{0}")]
+    PayloadDelivery(#[from] PayloadError),
+    #[error("session_id should have up to {MAX_SESSION_ID_LEN} alphanumeric, '-' or '_' characters: {0}")]
+    SessionId(String),
+}
+
+type Result<T> = std::result::Result<T, KeyDeliveryError>;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimeUKey {
+    pub auth_tag: String,
+    // Hash algorithm of the auth tag HMAC, implied by the tag length if not
+    // given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_tag_alg: Option<String>,
+    pub encrypted_key: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    // How the payload is handed to its consumer, the configured default if
+    // not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_delivery: Option<String>,
+    // Seconds after which the key and the payload are discarded, the
+    // configured default if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimeVKey {
+    pub encrypted_key: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+}
+
+/// Both key halves delivered in a single request, by the tenants holding
+/// both of them. The keys are combined atomically, instead of waiting for
+/// the other half of the two-step delivery.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KeylimeKeys {
+    pub encrypted_u_key: String,
+    pub encrypted_v_key: String,
+    pub auth_tag: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_tag_alg: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload_delivery: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+/// The delivery a U or V key belongs to. Both halves of a delivery carry the
+/// same session ID and sequence number, and the sequence number increases
+/// with each delivery, so that retransmitted and out-of-order halves are
+/// matched with their counterpart.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeliverySession {
+    pub id: String,
+    pub sequence: u64,
+}
+
+impl DeliverySession {
+    /// Validate the delivery session the key belongs to, if any. The
+    /// sequence number defaults to 0 when only the session ID is given.
+    pub fn from_request(
+        session_id: Option<&str>,
+        sequence: Option<u64>,
+    ) -> Result<Option<Self>> {
+        let Some(id) = session_id else {
+            return match sequence {
+                Some(_) => Err(KeyDeliveryError::SequenceWithoutSession),
+                None => Ok(None),
+            };
+        };
+
+        if id.is_empty()
+            || id.len() > MAX_SESSION_ID_LEN
+            || !id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(KeyDeliveryError::SessionId(id.to_string()));
+        }
+
+        Ok(Some(DeliverySession {
+            id: id.to_string(),
+            sequence: sequence.unwrap_or(0),
+        }))
+    }
+}
+
+/// Decode the base64 encoded key, still encrypted with the NK
+pub fn decode_encrypted_key(encrypted_key: &str) -> Result<Vec<u8>> {
+    general_purpose::STANDARD
+        .decode(encrypted_key)
+        .map_err(KeyDeliveryError::EncryptedKey)
+}
+
+impl KeylimeUKey {
+    /// The auth tag and the algorithm of its HMAC, if given
+    pub fn decode_auth_tag(
+        &self,
+    ) -> Result<(Option<HashAlgorithm>, Vec<u8>)> {
+        let auth_tag =
+            hex::decode(&self.auth_tag).map_err(KeyDeliveryError::AuthTag)?;
+        let alg = self
+            .auth_tag_alg
+            .as_deref()
+            .map(HashAlgorithm::try_from)
+            .transpose()?;
+        Ok((alg, auth_tag))
+    }
+
+    /// The encrypted payload, if any
+    pub fn decode_payload(&self) -> Result<Option<Vec<u8>>> {
+        self.payload
+            .as_deref()
+            .map(|p| general_purpose::STANDARD.decode(p))
+            .transpose()
+            .map_err(KeyDeliveryError::Payload)
+    }
+
+    pub fn session(&self) -> Result<Option<DeliverySession>> {
+        DeliverySession::from_request(
+            self.session_id.as_deref(),
+            self.sequence,
+        )
+    }
+
+    /// The delivery mode of the payload, if given
+    pub fn payload_delivery(&self) -> Result<Option<PayloadDelivery>> {
+        Ok(self
+            .payload_delivery
+            .as_deref()
+            .map(PayloadDelivery::try_from)
+            .transpose()?)
+    }
+}
+
+impl KeylimeVKey {
+    pub fn session(&self) -> Result<Option<DeliverySession>> {
+        DeliverySession::from_request(
+            self.session_id.as_deref(),
+            self.sequence,
+        )
+    }
+}
+
+impl KeylimeKeys {
+    /// The U and V key messages of the two-step delivery carrying the same
+    /// keys, auth tag, payload and session
+    pub fn split(&self) -> (KeylimeUKey, KeylimeVKey) {
+        let ukey = KeylimeUKey {
+            auth_tag: self.auth_tag.clone(),
+            auth_tag_alg: self.auth_tag_alg.clone(),
+            encrypted_key: self.encrypted_u_key.clone(),
+            payload: self.payload.clone(),
+            session_id: self.session_id.clone(),
+            sequence: self.sequence,
+            payload_delivery: self.payload_delivery.clone(),
+            ttl: self.ttl,
+        };
+        let vkey = KeylimeVKey {
+            encrypted_key: self.encrypted_v_key.clone(),
+            session_id: self.session_id.clone(),
+            sequence: self.sequence,
+        };
+        (ukey, vkey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_ukey() {
+        let ukey = KeylimeUKey {
+            auth_tag: "00ff".to_string(),
+            auth_tag_alg: Some("sha256".to_string()),
+            encrypted_key: "AAEC".to_string(),
+            payload: Some("AwQF".to_string()),
+            session_id: Some("s-1".to_string()),
+            sequence: Some(2),
+            payload_delivery: Some("memfd".to_string()),
+            ttl: Some(3600),
+        };
+        assert_eq!(
+            ukey.decode_auth_tag().unwrap(), //#[allow_ci]
+            (Some(HashAlgorithm::Sha256), vec![0x00, 0xff])
+        );
+        assert_eq!(ukey.decode_payload().unwrap(), Some(vec![3, 4, 5])); //#[allow_ci]
+        assert_eq!(
+            ukey.payload_delivery().unwrap(), //#[allow_ci]
+            Some(PayloadDelivery::Memfd)
+        );
+        assert_eq!(
+            ukey.session().unwrap(), //#[allow_ci]
+            Some(DeliverySession {
+                id: "s-1".to_string(),
+                sequence: 2
+            })
+        );
+        assert_eq!(
+            decode_encrypted_key(&ukey.encrypted_key).unwrap(), //#[allow_ci]
+            vec![0, 1, 2]
+        );
+
+        let ukey = KeylimeUKey {
+            auth_tag: "0g".to_string(),
+            auth_tag_alg: Some("md4".to_string()),
+            encrypted_key: "!".to_string(),
+            payload: Some("!".to_string()),
+            session_id: None,
+            sequence: Some(1),
+            payload_delivery: Some("pipe".to_string()),
+            ttl: None,
+        };
+        assert!(matches!(
+            ukey.decode_auth_tag(),
+            Err(KeyDeliveryError::AuthTag(_))
+        ));
+        assert!(ukey.decode_payload().is_err());
+        assert!(ukey.payload_delivery().is_err());
+        assert!(decode_encrypted_key(&ukey.encrypted_key).is_err());
+        assert!(matches!(
+            ukey.session(),
+            Err(KeyDeliveryError::SequenceWithoutSession)
+        ));
+    }
+
+    #[test]
+    fn test_split_keys() {
+        let keys = KeylimeKeys {
+            encrypted_u_key: "AAEC".to_string(),
+            encrypted_v_key: "AwQF".to_string(),
+            auth_tag: "00ff".to_string(),
+            auth_tag_alg: None,
+            payload: None,
+            payload_delivery: None,
+            session_id: Some("s-1".to_string()),
+            sequence: None,
+            ttl: Some(60),
+        };
+        let (ukey, vkey) = keys.split();
+        assert_eq!(ukey.encrypted_key, "AAEC");
+        assert_eq!(vkey.encrypted_key, "AwQF");
+        assert_eq!(ukey.auth_tag, "00ff");
+        assert_eq!(ukey.payload, None);
+        assert_eq!(ukey.ttl, Some(60));
+        assert_eq!(ukey.session().unwrap(), vkey.session().unwrap()); //#[allow_ci]
+    }
+
+    #[test]
+    fn test_delivery_session() {
+        assert_eq!(DeliverySession::from_request(None, None).unwrap(), None); //#[allow_ci]
+        assert_eq!(
+            DeliverySession::from_request(Some("abc"), None).unwrap(), //#[allow_ci]
+            Some(DeliverySession {
+                id: "abc".to_string(),
+                sequence: 0
+            })
+        );
+        for id in ["", "a b", "../a", &"a".repeat(MAX_SESSION_ID_LEN + 1)] {
+            assert!(DeliverySession::from_request(Some(id), None).is_err());
+        }
+    }
+}
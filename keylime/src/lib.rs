@@ -1,6 +1,11 @@
 pub mod algorithms;
+pub mod event_log;
+pub mod hashing;
+pub mod identity;
 pub mod ima;
+pub mod key_delivery;
 pub mod list_parser;
+pub mod payload;
 pub mod tpm;
 
 #[macro_use]
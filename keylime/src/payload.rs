@@ -0,0 +1,111 @@
+// SPDX-License-Identifier: Apache-2.0
+// Copyright 2026 Keylime Authors
+
+//! Metadata shipped in the payload delivered to the agent
+
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::{Component, Path},
+};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum PayloadError {
+    #[error("The action list is not valid UTF-8: {0}")]
+    Utf8(#[from] std::str::Utf8Error),
+    #[error("Action {0} is outside the payload directory")]
+    OutsidePayload(String),
+    #[error(
+        "Unknown payload delivery mode {0}, expected files, socket or memfd"
+    )]
+    Delivery(String),
+}
+
+/// How the decrypted payload is handed to its consumer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PayloadDelivery {
+    /// Written to the secure mount, extracted and run
+    Files,
+    /// Written to a unix socket
+    Socket,
+    /// Passed over a unix socket as a sealed memfd
+    Memfd,
+}
+
+impl TryFrom<&str> for PayloadDelivery {
+    type Error = PayloadError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "files" => Ok(PayloadDelivery::Files),
+            "socket" => Ok(PayloadDelivery::Socket),
+            "memfd" => Ok(PayloadDelivery::Memfd),
+            _ => Err(PayloadError::Delivery(value.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for PayloadDelivery {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadDelivery::Files => write!(f, "files"),
+            PayloadDelivery::Socket => write!(f, "socket"),
+            PayloadDelivery::Memfd => write!(f, "memfd"),
+        }
+    }
+}
+
+/// Parse the action_list file of the payload, listing one revocation action
+/// per line. The actions are relative to the directory the payload is
+/// extracted to, and must not refer to files outside of it.
+pub fn parse_action_list(data: &[u8]) -> Result<Vec<&str>, PayloadError> {
+    std::str::from_utf8(data)?
+        .split('\n')
+        .map(str::trim)
+        .filter(|action| !action.is_empty())
+        .map(|action| {
+            if Path::new(action).components().all(|c| {
+                matches!(c, Component::Normal(_) | Component::CurDir)
+            }) {
+                Ok(action)
+            } else {
+                Err(PayloadError::OutsidePayload(action.to_string()))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_action_list() {
+        assert_eq!(
+            parse_action_list(b"local_action_a.py\n\n  ./b.sh \n").unwrap(), //#[allow_ci]
+            vec!["local_action_a.py", "./b.sh"]
+        );
+        assert!(parse_action_list(b"").unwrap().is_empty()); //#[allow_ci]
+
+        for list in [&b"/usr/bin/rm"[..], b"a\n../b", b"a/../../b", b"\xff"] {
+            assert!(parse_action_list(list).is_err());
+        }
+    }
+
+    #[test]
+    fn test_payload_delivery() {
+        for delivery in [
+            PayloadDelivery::Files,
+            PayloadDelivery::Socket,
+            PayloadDelivery::Memfd,
+        ] {
+            assert_eq!(
+                PayloadDelivery::try_from(delivery.to_string().as_str())
+                    .unwrap(), //#[allow_ci]
+                delivery
+            );
+        }
+        assert!(PayloadDelivery::try_from("pipe").is_err());
+    }
+}
@@ -20,7 +20,7 @@ use tss_esapi::{
     abstraction::{
         ak,
         cipher::Cipher,
-        ek,
+        ek, nv,
         pcr::{read_all, PcrData},
         DefaultKey,
     },
@@ -29,26 +29,29 @@ use tss_esapi::{
     },
     constants::{
         response_code::Tss2ResponseCodeKind, session_type::SessionType,
+        CapabilityType, PropertyTag,
     },
     handles::{
-        AuthHandle, KeyHandle, PcrHandle, PersistentTpmHandle, TpmHandle,
+        AuthHandle, KeyHandle, NvIndexTpmHandle, ObjectHandle, PcrHandle,
+        PersistentTpmHandle, SessionHandle, TpmHandle,
     },
     interface_types::{
         algorithm::{AsymmetricAlgorithm, HashingAlgorithm, PublicAlgorithm},
         ecc::EccCurve,
         key_bits::RsaKeyBits,
-        resource_handles::Hierarchy,
+        resource_handles::{Hierarchy, NvAuth},
         session_handles::AuthSession,
         structure_tags::AttestationType,
     },
     structures::{
-        Attest, AttestInfo, Data, Digest, DigestValues, EccParameter,
-        EccPoint, EccScheme, EncryptedSecret, HashScheme, IdObject,
-        KeyDerivationFunctionScheme, PcrSelectionList,
-        PcrSelectionListBuilder, PcrSlot, PublicBuilder,
-        PublicEccParametersBuilder, PublicKeyRsa, PublicRsaParametersBuilder,
-        RsaExponent, RsaScheme, Signature, SignatureScheme,
-        SymmetricDefinitionObject,
+        Attest, AttestInfo, CapabilityData, ClockInfo, Data, Digest,
+        DigestValues, EccParameter, EccPoint, EccScheme, EncryptedSecret,
+        HashScheme, IdObject, KeyDerivationFunctionScheme, KeyedHashScheme,
+        MaxBuffer, PcrSelectionList, PcrSelectionListBuilder, PcrSlot,
+        Private, Public as TssPublic, PublicBuilder,
+        PublicEccParametersBuilder, PublicKeyRsa, PublicKeyedHashParameters,
+        PublicRsaParametersBuilder, RsaExponent, RsaScheme, SensitiveData,
+        Signature, SignatureScheme, SymmetricDefinitionObject,
     },
     tcti_ldr::TctiNameConf,
     traits::Marshall,
@@ -56,8 +59,28 @@ use tss_esapi::{
     Error::Tss2Error,
 };
 
+/// First handle of the persistent objects range
+const PERSISTENT_FIRST: u32 = 0x8100_0000;
+
+/// Maximum number of handles returned by the capability query
+const MAX_HANDLES: u32 = 128;
+
 /// Maximum size of nonce used in `quote`.
 pub const MAX_NONCE_SIZE: usize = 64;
+
+/// Size of the random contribution of the agent to the quote nonce
+pub const AGENT_NONCE_SIZE: usize = 32;
+
+/// Data sealed to the TPM, which can only be unsealed while the PCRs
+/// selected by `mask` in the bank of `pcr_bank` have the values they had
+/// when the data was sealed
+#[derive(Debug, Clone)]
+pub struct SealedData {
+    pub public: TssPublic,
+    pub private: Private,
+    pub mask: u32,
+    pub pcr_bank: HashAlgorithm,
+}
 const TPML_DIGEST_SIZE: usize = std::mem::size_of::<TPML_DIGEST>();
 const TPML_PCR_SELECTION_SIZE: usize =
     std::mem::size_of::<TPML_PCR_SELECTION>();
@@ -380,8 +403,120 @@ impl From<tss_esapi::Error> for TpmError {
     }
 }
 
+impl TpmError {
+    /// The error returned by the TSS, if any
+    pub fn tss_error(&self) -> Option<&tss_esapi::Error> {
+        match self {
+            TpmError::TSSCreateEKError { e }
+            | TpmError::TSSCreateAKError { e }
+            | TpmError::TSSLoadAKError { e }
+            | TpmError::TSSNewPersistentHandleError { e, .. }
+            | TpmError::TSSHandleFromPersistentHandleError { e, .. }
+            | TpmError::TSSCreatePrimaryError { e }
+            | TpmError::TSSPCRSelectionBuildError { e }
+            | TpmError::TSSDigestFromAuthPolicyError { e }
+            | TpmError::TSSPublicKeyFromIDevID { e }
+            | TpmError::TSSPublicKeyFromIAK { e }
+            | TpmError::TSSObjectAttributesBuildError { e }
+            | TpmError::TSSPublicRSAParametersBuildError { e }
+            | TpmError::TSSPublicECCParametersBuildError { e }
+            | TpmError::TSSIDevIDKeyBuildError { e }
+            | TpmError::TSSIAKKeyBuildError { e }
+            | TpmError::TSSECCParameterFromIDevIDError { e }
+            | TpmError::TSSECCParameterFromIAKError { e }
+            | TpmError::TSSReadPublicError { e }
+            | TpmError::TSSSymmetricDefinitionFromCipher { e }
+            | TpmError::TSSStartAuthenticationSessionError { e }
+            | TpmError::TSSSessionSetAttributesError { e }
+            | TpmError::TSSDigestFromValue { e }
+            | TpmError::TSSMarshallAttestError { e }
+            | TpmError::TSSMarshallSignatureError { e }
+            | TpmError::TSSPCRListError { e }
+            | TpmError::TSSQuoteError { e } => Some(e),
+            TpmError::TSSTctiContextError { error }
+            | TpmError::TctiNameError { error, .. } => Some(error),
+            TpmError::Tss2 { err, .. } => Some(err),
+            _ => None,
+        }
+    }
+
+    /// Whether the operation may succeed if retried later, as the TPM was
+    /// busy or the PCRs changed while quoting
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, TpmError::TooManyAttestationMismatches { .. })
+            || self.tss_error().is_some_and(is_retryable)
+    }
+
+    /// Whether the TPM ran out of handles or memory for the objects and
+    /// sessions, which may be held by other TPM users
+    pub fn is_resource_exhausted(&self) -> bool {
+        let Some(Tss2Error(rc)) = self.tss_error() else {
+            return false;
+        };
+        matches!(
+            rc.kind(),
+            Some(
+                Tss2ResponseCodeKind::ObjectMemory
+                    | Tss2ResponseCodeKind::SessionMemory
+                    | Tss2ResponseCodeKind::Memory
+                    | Tss2ResponseCodeKind::ObjectHandles
+                    | Tss2ResponseCodeKind::SessionHandles
+                    | Tss2ResponseCodeKind::NvSpace
+            )
+        )
+    }
+}
+
+/// Whether the TPM response code reports a transient condition, like the
+/// TPM being busy or out of memory for the loaded objects and sessions
+pub fn is_retryable(err: &tss_esapi::Error) -> bool {
+    let Tss2Error(rc) = err else {
+        return false;
+    };
+    matches!(
+        rc.kind(),
+        Some(
+            Tss2ResponseCodeKind::Retry
+                | Tss2ResponseCodeKind::Yielded
+                | Tss2ResponseCodeKind::Canceled
+                | Tss2ResponseCodeKind::Testing
+                | Tss2ResponseCodeKind::NvRate
+                | Tss2ResponseCodeKind::NvUnavailable
+                | Tss2ResponseCodeKind::ObjectMemory
+                | Tss2ResponseCodeKind::SessionMemory
+                | Tss2ResponseCodeKind::Memory
+                | Tss2ResponseCodeKind::PcrChanged
+        )
+    )
+}
+
 type Result<T> = std::result::Result<T, TpmError>;
 
+/// The TPM capacity left for objects and sessions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandleUsage {
+    /// Number of additional transient objects that can be created
+    pub transient_available: Option<u32>,
+    /// Number of additional persistent objects that can be created
+    pub persistent_available: Option<u32>,
+    /// Number of additional objects and sessions that can be loaded
+    pub loaded_available: Option<u32>,
+}
+
+/// The TCTI configuration used to connect to the TPM, from the TCTI
+/// environment variable or the first TPM device found
+pub fn tcti_conf() -> String {
+    match std::env::var("TCTI") {
+        Ok(val) => val,
+        Err(_) => if std::path::Path::new("/dev/tpmrm0").exists() {
+            "device:/dev/tpmrm0"
+        } else {
+            "device:/dev/tpm0"
+        }
+        .to_string(),
+    }
+}
+
 /// Holds the output of create_ek.
 #[derive(Clone, Debug)]
 pub struct EKResult {
@@ -411,6 +546,15 @@ pub struct IDevIDResult {
     pub handle: tss_esapi::handles::KeyHandle,
 }
 
+/// Holds the output of certify_persistent_key.
+#[derive(Clone, Debug)]
+pub struct CertifyResult {
+    pub public: tss_esapi::structures::Public,
+    pub name: tss_esapi::structures::Name,
+    pub attest: Attest,
+    pub signature: Signature,
+}
+
 /// Holds the Public result from create_idevid_public_from_default_template
 #[derive(Clone, Debug)]
 pub struct IDevIDPublic {
@@ -444,15 +588,7 @@ impl AsMut<tss_esapi::Context> for Context {
 impl Context {
     /// Creates a connection context.
     pub fn new() -> Result<Self> {
-        let tcti_path = match std::env::var("TCTI") {
-            Ok(val) => val,
-            Err(_) => if std::path::Path::new("/dev/tpmrm0").exists() {
-                "device:/dev/tpmrm0"
-            } else {
-                "device:/dev/tpm0"
-            }
-            .to_string(),
-        };
+        let tcti_path = tcti_conf();
 
         let tcti = TctiNameConf::from_str(&tcti_path).map_err(|error| {
             TpmError::TctiNameError {
@@ -473,7 +609,22 @@ impl Context {
         alg: EncryptionAlgorithm,
         handle: Option<&str>,
     ) -> Result<EKResult> {
-        // Retrieve EK handle, EK pub cert, and TPM pub object
+        let mut ek = self.create_ek_object(alg, handle)?;
+        ek.ek_cert = self.read_ek_cert(alg);
+        Ok(ek)
+    }
+
+    /// Creates an EK without reading the certificate from the TPM NVRAM.
+    ///
+    /// The `ek_cert` field of the returned `EKResult` is always `None`.
+    /// This allows the caller to use a previously cached certificate, as
+    /// reading the NVRAM can be slow on some TPMs.
+    pub fn create_ek_object(
+        &mut self,
+        alg: EncryptionAlgorithm,
+        handle: Option<&str>,
+    ) -> Result<EKResult> {
+        // Retrieve EK handle and TPM pub object
         let key_handle = match handle {
             Some(v) => {
                 if v.is_empty() {
@@ -513,25 +664,186 @@ impl Context {
                     .map_err(|e| TpmError::TSSCreateEKError { e })?
             }
         };
-        let cert = match ek::retrieve_ek_pubcert(&mut self.inner, alg.into())
-        {
-            Ok(v) => Some(v),
-            Err(_) => {
-                warn!("No EK certificate found in TPM NVRAM");
-                None
-            }
-        };
         let (tpm_pub, _, _) = self
             .inner
             .read_public(key_handle)
             .map_err(|e| TpmError::TSSReadPublicError { e })?;
         Ok(EKResult {
             key_handle,
-            ek_cert: cert,
+            ek_cert: None,
             public: tpm_pub,
         })
     }
 
+    /// Reads the EK certificate from the TPM NVRAM, if available.
+    pub fn read_ek_cert(
+        &mut self,
+        alg: EncryptionAlgorithm,
+    ) -> Option<Vec<u8>> {
+        match ek::retrieve_ek_pubcert(&mut self.inner, alg.into()) {
+            Ok(v) => Some(v),
+            Err(_) => {
+                warn!("No EK certificate found in TPM NVRAM");
+                None
+            }
+        }
+    }
+
+    /// Read the whole content of an NV index, authorized by the index
+    /// itself with an empty password, as for the EK certificate
+    pub fn read_nv(&mut self, index: u32) -> Result<Vec<u8>> {
+        let nv_index = NvIndexTpmHandle::new(index)?;
+        let nv_handle = self.inner.execute_without_session(|ctx| {
+            ctx.tr_from_tpm_public(TpmHandle::NvIndex(nv_index))
+        })?;
+        let result = self.inner.execute_with_nullauth_session(|ctx| {
+            nv::read_full(ctx, NvAuth::NvIndex(nv_handle.into()), nv_index)
+        });
+
+        // Release the ESYS resource; the NV index is not undefined
+        let mut object = nv_handle;
+        self.inner.tr_close(&mut object)?;
+        result.map_err(TpmError::from)
+    }
+
+    /// Read random bytes from the TPM random number generator
+    pub fn get_random(&mut self, len: usize) -> Result<Vec<u8>> {
+        let random = self
+            .inner
+            .execute_without_session(|ctx| ctx.get_random(len))?;
+        Ok(random.to_vec())
+    }
+
+    /// The primary storage key of the owner hierarchy. The same key is
+    /// derived again from the template to unseal the data.
+    fn create_storage_primary(&mut self) -> Result<KeyHandle> {
+        let public =
+            tss_esapi::utils::create_restricted_decryption_rsa_public(
+                SymmetricDefinitionObject::AES_128_CFB,
+                RsaKeyBits::Rsa2048,
+                RsaExponent::default(),
+            )?;
+        let primary = self
+            .inner
+            .execute_with_nullauth_session(|ctx| {
+                ctx.create_primary(
+                    Hierarchy::Owner,
+                    public,
+                    None,
+                    None,
+                    None,
+                    None,
+                )
+            })
+            .map_err(|e| TpmError::TSSCreatePrimaryError { e })?;
+        Ok(primary.key_handle)
+    }
+
+    /// Start a session with a policy on the current values of the PCRs
+    fn pcr_policy_session(
+        &mut self,
+        session_type: SessionType,
+        mask: u32,
+        pcr_bank: HashAlgorithm,
+    ) -> Result<AuthSession> {
+        let selection = PcrSelectionListBuilder::new()
+            .with_selection(pcr_bank.into(), &read_mask(mask)?)
+            .build()?;
+        let session = self.create_empty_session(session_type)?;
+        if let Err(e) = self.inner.policy_pcr(
+            session.try_into()?,
+            Digest::default(),
+            selection,
+        ) {
+            self.flush_session(session)?;
+            return Err(e.into());
+        }
+        Ok(session)
+    }
+
+    fn flush_session(&mut self, session: AuthSession) -> Result<()> {
+        let handle: ObjectHandle = SessionHandle::from(session).into();
+        self.inner.flush_context(handle)?;
+        Ok(())
+    }
+
+    /// Seal the data under the storage primary key, with a policy on the
+    /// current values of the PCRs selected by `mask` in the `pcr_bank`
+    pub fn seal(
+        &mut self,
+        data: &[u8],
+        mask: u32,
+        pcr_bank: HashAlgorithm,
+    ) -> Result<SealedData> {
+        let trial =
+            self.pcr_policy_session(SessionType::Trial, mask, pcr_bank)?;
+        let policy = self.inner.policy_get_digest(trial.try_into()?);
+        self.flush_session(trial)?;
+
+        let attributes = ObjectAttributesBuilder::new()
+            .with_fixed_tpm(true)
+            .with_fixed_parent(true)
+            .with_no_da(true)
+            .with_admin_with_policy(true)
+            .build()
+            .map_err(|e| TpmError::TSSObjectAttributesBuildError { e })?;
+        let public = PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::KeyedHash)
+            .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+            .with_object_attributes(attributes)
+            .with_auth_policy(policy?)
+            .with_keyed_hash_parameters(PublicKeyedHashParameters::new(
+                KeyedHashScheme::Null,
+            ))
+            .with_keyed_hash_unique_identifier(Digest::default())
+            .build()?;
+        let sensitive = SensitiveData::try_from(data.to_vec())?;
+
+        let primary = self.create_storage_primary()?;
+        let result = self.inner.execute_with_nullauth_session(|ctx| {
+            ctx.create(primary, public, None, Some(sensitive), None, None)
+        });
+        self.inner.flush_context(primary.into())?;
+        let sealed = result?;
+
+        Ok(SealedData {
+            public: sealed.out_public,
+            private: sealed.out_private,
+            mask,
+            pcr_bank,
+        })
+    }
+
+    /// Unseal the data, which fails if the values of the PCRs of the policy
+    /// changed since the data was sealed
+    pub fn unseal(&mut self, sealed: &SealedData) -> Result<Vec<u8>> {
+        let primary = self.create_storage_primary()?;
+        let object = self.inner.execute_with_nullauth_session(|ctx| {
+            ctx.load(primary, sealed.private.clone(), sealed.public.clone())
+        });
+        self.inner.flush_context(primary.into())?;
+        let object = object?;
+
+        let result = self
+            .pcr_policy_session(
+                SessionType::Policy,
+                sealed.mask,
+                sealed.pcr_bank,
+            )
+            .and_then(|session| {
+                let data = self
+                    .inner
+                    .execute_with_session(Some(session), |ctx| {
+                        ctx.unseal(object.into())
+                    })
+                    .map_err(TpmError::from);
+                self.flush_session(session)?;
+                data
+            });
+        self.inner.flush_context(object.into())?;
+        Ok(result?.value().to_vec())
+    }
+
     /// Creates an AK.
     pub fn create_ak(
         &mut self,
@@ -1024,14 +1336,20 @@ impl Context {
             )
         })?;
 
-        self.inner
+        let result = self
+            .inner
             .execute_with_sessions(
                 (Some(AuthSession::Password), Some(ek_auth), None),
                 |context| {
                     context.activate_credential(ak, ek, credential, secret)
                 },
             )
-            .map_err(TpmError::from)
+            .map_err(TpmError::from);
+
+        // The session slots are shared with the other TPM users
+        let handle: ObjectHandle = SessionHandle::from(ek_auth).into();
+        self.inner.flush_context(handle)?;
+        result
     }
 
     /// This function certifies an attestation key with the IAK, using any qualifying data provided,
@@ -1041,6 +1359,214 @@ impl Context {
         qualifying_data: Data,
         ak: KeyHandle,
         iak: KeyHandle,
+    ) -> Result<(Attest, Signature)> {
+        self.certify(qualifying_data, ak.into(), iak)
+    }
+
+    /// This function certifies a key persisted in the TPM under the given
+    /// handle (e.g. "0x81010010") with the AK, using the qualifying data
+    /// provided. The key must be usable with an empty password
+    /// authorization.
+    pub fn certify_persistent_key(
+        &mut self,
+        handle: &str,
+        qualifying_data: Data,
+        ak: KeyHandle,
+    ) -> Result<CertifyResult> {
+        let value = u32::from_str_radix(handle.trim_start_matches("0x"), 16)
+            .map_err(|e| TpmError::NumParse {
+                origin: handle.to_string(),
+                e,
+            })?;
+        let persistent = PersistentTpmHandle::new(value).map_err(|e| {
+            TpmError::TSSNewPersistentHandleError {
+                handle: handle.to_string(),
+                e,
+            }
+        })?;
+        let mut object = self
+            .inner
+            .tr_from_tpm_public(TpmHandle::Persistent(persistent))
+            .map_err(|e| TpmError::TSSHandleFromPersistentHandleError {
+                handle: handle.to_string(),
+                e,
+            })?;
+
+        let result = self
+            .inner
+            .read_public(object.into())
+            .map_err(|e| TpmError::TSSReadPublicError { e })
+            .and_then(|(public, name, _)| {
+                let (attest, signature) =
+                    self.certify(qualifying_data, object, ak)?;
+                Ok(CertifyResult {
+                    public,
+                    name,
+                    attest,
+                    signature,
+                })
+            });
+
+        // Release the ESYS resource; the persistent object is not evicted
+        self.inner.tr_close(&mut object)?;
+        result
+    }
+
+    /// Read the TPM clock information from an attestation of the AK signed
+    /// by itself, as TPM2_ReadClock is not available in the ESAPI bindings
+    pub fn read_clock_info(&mut self, ak: KeyHandle) -> Result<ClockInfo> {
+        let (attest, _) = self.certify(Data::default(), ak.into(), ak)?;
+        Ok(*attest.clock_info())
+    }
+
+    /// Sign `data` with a signing key resident in the TPM, which can be a
+    /// restricted key like the AK. The data is hashed by the TPM, producing
+    /// the ticket that proves to the TPM that the digest was not generated
+    /// by it, thus it must fit in a TPM2B_MAX_BUFFER.
+    pub fn sign(
+        &mut self,
+        key: KeyHandle,
+        data: &[u8],
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+    ) -> Result<Signature> {
+        let buffer = MaxBuffer::try_from(data.to_vec())?;
+        let (digest, ticket) =
+            self.inner.hash(buffer, hash_alg.into(), Hierarchy::Owner)?;
+        self.inner
+            .execute_with_nullauth_session(|ctx| {
+                ctx.sign(
+                    key,
+                    digest,
+                    sign_alg.to_signature_scheme(hash_alg),
+                    ticket,
+                )
+            })
+            .map_err(TpmError::from)
+    }
+
+    /// List the persistent objects in the TPM
+    pub fn list_persistent_handles(&mut self) -> Result<Vec<u32>> {
+        let (capability, _) = self.inner.get_capability(
+            CapabilityType::Handles,
+            PERSISTENT_FIRST,
+            MAX_HANDLES,
+        )?;
+        match capability {
+            CapabilityData::Handles(list) => Ok(list
+                .iter()
+                .map(|handle| u32::from(*handle))
+                .filter(|handle| handle >> 24 == PERSISTENT_FIRST >> 24)
+                .collect()),
+            _ => Err(TpmError::Other(
+                "Unexpected capability data for the handles".to_string(),
+            )),
+        }
+    }
+
+    /// Get the capacity left in the TPM for objects and sessions
+    pub fn handle_usage(&mut self) -> Result<HandleUsage> {
+        Ok(HandleUsage {
+            transient_available: self
+                .inner
+                .get_tpm_property(PropertyTag::HrTransientAvail)?,
+            persistent_available: self
+                .inner
+                .get_tpm_property(PropertyTag::HrPersistentAvail)?,
+            loaded_available: self
+                .inner
+                .get_tpm_property(PropertyTag::HrLoadedAvail)?,
+        })
+    }
+
+    /// Get the hashing algorithms of the PCR banks allocated in the TPM
+    pub fn get_pcr_banks(&mut self) -> Result<Vec<HashingAlgorithm>> {
+        let (capability, _) =
+            self.inner
+                .get_capability(CapabilityType::AssignedPcr, 0, 1)?;
+        match capability {
+            CapabilityData::AssignedPcr(list) => Ok(list
+                .get_selections()
+                .iter()
+                .filter(|selection| !selection.is_empty())
+                .map(|selection| selection.hashing_algorithm())
+                .collect()),
+            _ => Err(TpmError::Other(
+                "Unexpected capability data for the assigned PCRs"
+                    .to_string(),
+            )),
+        }
+    }
+
+    /// Extend the PCR in the bank of the hash algorithm with the digest of
+    /// the event data, to record an event of the agent in the TPM
+    pub fn extend_pcr(
+        &mut self,
+        pcr: u32,
+        hash_alg: HashAlgorithm,
+        event: &[u8],
+    ) -> Result<()> {
+        let handle = PcrHandle::try_from(pcr).map_err(|_| {
+            TpmError::Other(format!("PCR {pcr} can not be extended"))
+        })?;
+
+        let hashing_algo = HashingAlgorithm::from(hash_alg);
+        let mut hasher =
+            Hasher::new(hash_alg_to_message_digest(hashing_algo)?)
+                .map_err(|e| TpmError::OpenSSLHasherNew { e })?;
+        hasher
+            .update(event)
+            .map_err(|e| TpmError::OpenSSLHasherUpdate { e })?;
+        let hashvec = hasher
+            .finish()
+            .map_err(|e| TpmError::OpenSSLHasherFinish { e })?;
+        let mut digest = DigestValues::new();
+        digest.set(
+            hashing_algo,
+            Digest::try_from(hashvec.as_ref())
+                .map_err(|e| TpmError::TSSDigestFromValue { e })?,
+        );
+
+        self.inner
+            .execute_with_nullauth_session(|ctx| {
+                ctx.pcr_extend(handle, digest.to_owned())
+            })
+            .map_err(TpmError::from)
+    }
+
+    /// Read the values of the PCRs selected by `mask` from the bank of the
+    /// hash algorithm, ordered by the PCR index
+    pub fn read_pcrs(
+        &mut self,
+        mask: u32,
+        pcr_bank: HashAlgorithm,
+    ) -> Result<Vec<(u32, Vec<u8>)>> {
+        let hashing_algo = HashingAlgorithm::from(pcr_bank);
+        let pcrlist = PcrSelectionListBuilder::new()
+            .with_selection(hashing_algo, &read_mask(mask)?)
+            .build()?;
+        let (_, pcr_data) = make_pcr_blob(&mut self.inner, pcrlist)?;
+        let bank = pcr_data.pcr_bank(hashing_algo).ok_or_else(|| {
+            TpmError::Other(format!("PCR bank {pcr_bank} not allocated"))
+        })?;
+
+        let mut values: Vec<(u32, Vec<u8>)> = bank
+            .into_iter()
+            .map(|(slot, digest)| {
+                (u32::from(*slot).trailing_zeros(), digest.value().to_vec())
+            })
+            .collect();
+        values.sort();
+        Ok(values)
+    }
+
+    /// Certify the object with the signing key, producing an attestation
+    /// document and signature
+    fn certify(
+        &mut self,
+        qualifying_data: Data,
+        object: ObjectHandle,
+        sign_key: KeyHandle,
     ) -> Result<(Attest, Signature)> {
         self.inner
             .execute_with_sessions(
@@ -1051,8 +1577,8 @@ impl Context {
                 ),
                 |context| {
                     context.certify(
-                        ak.into(),
-                        iak,
+                        object,
+                        sign_key,
                         qualifying_data,
                         SignatureScheme::Null,
                     )
@@ -1106,10 +1632,49 @@ impl Context {
         hash_alg: HashAlgorithm,
         sign_alg: SignAlgorithm,
     ) -> Result<String> {
-        let nk_digest = pubkey_to_tpm_digest(pubkey, hash_alg)?;
+        self.quote_pcr_bank(
+            nonce, mask, pubkey, ak_handle, hash_alg, sign_alg, hash_alg,
+        )
+    }
+
+    /// Calculates a TPM quote of `nonce` over the PCRs indicated with
+    /// `mask` in the bank of `pcr_bank`, which may differ from the hash
+    /// algorithm `hash_alg` of the AK signature.
+    #[allow(clippy::too_many_arguments)]
+    pub fn quote_pcr_bank(
+        &mut self,
+        nonce: &[u8],
+        mask: u32,
+        pubkey: &PKeyRef<Public>,
+        ak_handle: KeyHandle,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+        pcr_bank: HashAlgorithm,
+    ) -> Result<String> {
+        self.quote_with_clock_info(
+            nonce, mask, pubkey, ak_handle, hash_alg, sign_alg, pcr_bank,
+        )
+        .map(|(quote, _)| quote)
+    }
+
+    /// Calculates a TPM quote as `quote_pcr_bank`, also returning the clock
+    /// information signed in the quote, with the TPM reset and restart
+    /// counters.
+    #[allow(clippy::too_many_arguments)]
+    pub fn quote_with_clock_info(
+        &mut self,
+        nonce: &[u8],
+        mask: u32,
+        pubkey: &PKeyRef<Public>,
+        ak_handle: KeyHandle,
+        hash_alg: HashAlgorithm,
+        sign_alg: SignAlgorithm,
+        pcr_bank: HashAlgorithm,
+    ) -> Result<(String, ClockInfo)> {
+        let nk_digest = pubkey_to_tpm_digest(pubkey, pcr_bank)?;
 
         let pcrlist =
-            self.build_pcr_list(nk_digest, mask, hash_alg.into())?;
+            self.build_pcr_list(nk_digest, mask, pcr_bank.into())?;
 
         let (attestation, sig, pcrs_read, pcr_data) =
             self.inner.execute_with_nullauth_session(|ctx| {
@@ -1119,11 +1684,16 @@ impl Context {
                     nonce,
                     pcrlist,
                     sign_alg.to_signature_scheme(hash_alg),
+                    // The digest of the quoted PCRs is computed with the
+                    // hash algorithm of the signature
                     hash_alg.into(),
                 )
             })?;
 
-        encode_quote_string(attestation, sig, pcrs_read, pcr_data)
+        let clock_info = *attestation.clock_info();
+        let quote =
+            encode_quote_string(attestation, sig, pcrs_read, pcr_data)?;
+        Ok((quote, clock_info))
     }
 }
 
@@ -1256,6 +1826,24 @@ fn parse_cred_and_secret(
     Ok((credential, secret))
 }
 
+/// The qualifying data of a quote combining the nonce of the verifier with
+/// the random contribution of the agent, as the SHA-256 digest of the
+/// nonce followed by the agent nonce
+pub fn combine_nonces(nonce: &[u8], agent_nonce: &[u8]) -> Result<Vec<u8>> {
+    let mut hasher = Hasher::new(MessageDigest::sha256())
+        .map_err(|e| TpmError::OpenSSLHasherNew { e })?;
+    hasher
+        .update(nonce)
+        .map_err(|e| TpmError::OpenSSLHasherUpdate { e })?;
+    hasher
+        .update(agent_nonce)
+        .map_err(|e| TpmError::OpenSSLHasherUpdate { e })?;
+    let digest = hasher
+        .finish()
+        .map_err(|e| TpmError::OpenSSLHasherFinish { e })?;
+    Ok(digest.to_vec())
+}
+
 /// Takes a public PKey and returns a DigestValue of it.
 fn pubkey_to_tpm_digest<T: HasPublic>(
     pubkey: &PKeyRef<T>,
@@ -1352,6 +1940,24 @@ pub fn check_mask(mask: u32, pcr: &PcrSlot) -> Result<bool> {
     Ok(selected_pcrs.contains(pcr))
 }
 
+/// Get the PCR values included in a quote string, by PCR index, for the PCR
+/// bank of `hash_alg`.
+pub fn quote_pcr_values(
+    quote: &str,
+    hash_alg: HashAlgorithm,
+) -> Result<std::collections::BTreeMap<u32, Vec<u8>>> {
+    let (_, _, _, pcrdata) = testing::decode_quote_string(quote)?;
+    let bank = pcrdata.pcr_bank(hash_alg.into()).ok_or_else(|| {
+        TpmError::Other(format!("no {hash_alg} bank in the quote"))
+    })?;
+    Ok(bank
+        .into_iter()
+        .map(|(slot, digest)| {
+            (u32::from(*slot).trailing_zeros(), digest.value().to_vec())
+        })
+        .collect())
+}
+
 /// This encodes a quote string as input to Python Keylime's quote checking functionality.
 /// The quote, signature, and pcr blob are concatenated with ':' separators. To match the
 /// expected format, the quote, signature, and pcr blob must be base64 encoded before concatenation.
@@ -1859,6 +2465,38 @@ pub mod testing {
 
         Ok(())
     }
+
+    /// Create the keyblob sent by the registrar on registration, wrapping
+    /// the credential for the AK with the EK in the format produced by
+    /// tpm2_makecredential
+    pub fn make_credential(
+        context: &mut tss_esapi::Context,
+        ek_public: tss_esapi::structures::Public,
+        ak_public: tss_esapi::structures::Public,
+        credential: &[u8],
+    ) -> Result<Vec<u8>> {
+        let ak = context.load_external_public(ak_public, Hierarchy::Null)?;
+        let ak_name = context.read_public(ak).map(|(_, name, _)| name);
+        context.flush_context(ak.into())?;
+
+        let ek = context.load_external_public(ek_public, Hierarchy::Null)?;
+        let made = context.make_credential(
+            ek,
+            Digest::try_from(credential)?,
+            ak_name?,
+        );
+        context.flush_context(ek.into())?;
+        let (id_object, secret) = made?;
+
+        let mut keyblob = Vec::new();
+        keyblob.extend(TSS_MAGIC.to_be_bytes());
+        keyblob.extend(1u32.to_be_bytes());
+        keyblob.extend(u16::try_from(id_object.value().len())?.to_be_bytes());
+        keyblob.extend(id_object.value());
+        keyblob.extend(u16::try_from(secret.value().len())?.to_be_bytes());
+        keyblob.extend(secret.value());
+        Ok(keyblob)
+    }
 }
 
 #[cfg(test)]
@@ -1906,6 +2544,39 @@ pub mod tests {
             .is_ok());
     }
 
+    #[test]
+    fn test_combine_nonces() {
+        let combined =
+            combine_nonces(b"nonce", &[1u8; AGENT_NONCE_SIZE]).unwrap(); //#[allow_ci]
+        assert_eq!(combined.len(), 32);
+        assert!(combined.len() <= MAX_NONCE_SIZE);
+        assert_ne!(
+            combined,
+            combine_nonces(b"nonce", &[2u8; AGENT_NONCE_SIZE]).unwrap() //#[allow_ci]
+        );
+    }
+
+    #[test]
+    fn test_is_retryable() {
+        use tss_esapi::constants::response_code::Tss2ResponseCode;
+
+        // TPM_RC_RETRY
+        let retry =
+            tss_esapi::Error::Tss2Error(Tss2ResponseCode::from(0x922));
+        assert!(is_retryable(&retry));
+        assert!(TpmError::TSSQuoteError { e: retry }.is_retryable());
+
+        // TPM_RC_HANDLE
+        let handle =
+            tss_esapi::Error::Tss2Error(Tss2ResponseCode::from(0x08b));
+        assert!(!is_retryable(&handle));
+        assert!(!TpmError::from(handle).is_retryable());
+
+        assert!(TpmError::TooManyAttestationMismatches { attempts: 5 }
+            .is_retryable());
+        assert!(!TpmError::DataFromNonce.is_retryable());
+    }
+
     #[test]
     fn test_mask() {
         assert_eq!(read_mask(0x0).unwrap(), vec![]); //#[allow_ci]